@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are fine; only panics are bugs
+    let _ = payment_engine::fuzz::fuzz_process(data);
+});
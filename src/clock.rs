@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of the current time for what a run measures or stamps: its elapsed
+/// and phase timings, duration-based snapshots and the manifest's start and
+/// end times. [`SystemClock`] reads the system clocks, [`ManualClock`] only
+/// moves when a test advances it.
+///
+/// Dispute expiry doesn't use it: disputes expire against the input's own
+/// timestamps. Neither does `max_tps` pacing, which has to sleep in real time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Wall-clock time, for timestamps
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Monotonic time, for measuring durations
+    fn monotonic(&self) -> Instant;
+
+    /// Time since `earlier`, a reading of [`monotonic`](Self::monotonic)
+    fn elapsed(&self, earlier: Instant) -> Duration {
+        self.monotonic().saturating_duration_since(earlier)
+    }
+}
+
+/// The system's wall clock and monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that stands still until [`advance`](Self::advance)d, moving its
+/// wall-clock and monotonic time together. Shared through an `Arc`, a test
+/// keeps a handle to move the time of a run it configured with it.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    wall: DateTime<Utc>,
+    advanced: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Clock reading `now` as its wall-clock time
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self { start: Instant::now(), wall: now, advanced: Mutex::new(Duration::ZERO) }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        *self.advanced.lock().unwrap() += by;
+    }
+
+    fn advanced(&self) -> Duration {
+        *self.advanced.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.wall + self.advanced()
+    }
+
+    fn monotonic(&self) -> Instant {
+        self.start + self.advanced()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap());
+        let (now, started) = (clock.now(), clock.monotonic());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!((clock.now(), clock.elapsed(started)), (now, Duration::ZERO));
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), Utc.with_ymd_and_hms(2024, 6, 1, 12, 1, 30).unwrap());
+        assert_eq!(clock.elapsed(started), Duration::from_secs(90));
+        assert_eq!(clock.elapsed(clock.monotonic() + Duration::from_secs(1)), Duration::ZERO);
+    }
+}
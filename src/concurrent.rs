@@ -0,0 +1,266 @@
+use crate::models::{Account, AccountStorage, AccountStore, IdMap};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Shards of a [`ConcurrentAccountStore`] unless given. Client ids are
+/// assigned round-robin, so consecutive clients never share a shard.
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Account store that threads can update at the same time, for feeding an
+/// engine's accounts from several tasks.
+///
+/// Accounts are spread over shards, each behind its own lock, so feeders
+/// working on different clients rarely wait for each other. Taking a client's
+/// account locks its shard until the guard is dropped; don't hold two guards
+/// on one thread, as they may be in the same shard. The single-threaded
+/// [`AccountStore`] remains the engine's default and the faster choice with
+/// one feeder.
+#[derive(Debug)]
+pub struct ConcurrentAccountStore {
+    shards: Box<[Mutex<IdMap<u16, Account>>]>,
+}
+
+impl Default for ConcurrentAccountStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConcurrentAccountStore {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a store with this many shards, at least one
+    pub fn with_shards(shards: usize) -> Self {
+        Self { shards: (0..shards.max(1)).map(|_| Mutex::default()).collect() }
+    }
+
+    /// Lock the shard holding a client's account. An account left behind by
+    /// a panicking thread is still consistent, as every account operation
+    /// either completes or changes nothing.
+    fn shard(&self, client_id: u16) -> MutexGuard<'_, IdMap<u16, Account>> {
+        let shard = &self.shards[usize::from(client_id) % self.shards.len()];
+        shard.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The client's account, created empty if the client hasn't been seen,
+    /// locked until the guard is dropped
+    pub fn get_or_create(&self, client_id: u16) -> AccountGuard<'_> {
+        let mut shard = self.shard(client_id);
+        shard.entry(client_id).or_insert_with(|| Account::new(client_id));
+        AccountGuard { shard, client_id }
+    }
+
+    /// A copy of the client's account, if the client has been seen
+    pub fn get(&self, client_id: u16) -> Option<Account> {
+        self.shard(client_id).get(&client_id).cloned()
+    }
+
+    /// Number of clients with an account. Locks each shard in turn, so it
+    /// may miss accounts created meanwhile.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|shard| self.lock_shard(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies of all accounts, in no particular order, taken one shard at a time
+    pub fn get_all_accounts(&self) -> Vec<Account> {
+        (0..self.shards.len()).flat_map(|shard| self.lock_shard(shard).values().cloned().collect::<Vec<_>>()).collect()
+    }
+
+    /// Move the accounts into a single-threaded store, e.g. to hand them to
+    /// an engine once feeding is done
+    pub fn into_account_store(self) -> AccountStore {
+        let mut store = AccountStore::with_capacity(self.len());
+        for shard in self.shards.into_vec() {
+            for (_, account) in shard.into_inner().unwrap_or_else(PoisonError::into_inner) {
+                store.insert(account);
+            }
+        }
+        store
+    }
+
+    fn lock_shard(&self, shard: usize) -> MutexGuard<'_, IdMap<u16, Account>> {
+        self.shards[shard].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A client's account in a [`ConcurrentAccountStore`], with its shard locked
+pub struct AccountGuard<'a> {
+    shard: MutexGuard<'a, IdMap<u16, Account>>,
+    client_id: u16,
+}
+
+impl Deref for AccountGuard<'_> {
+    type Target = Account;
+
+    fn deref(&self) -> &Account {
+        &self.shard[&self.client_id]
+    }
+}
+
+impl DerefMut for AccountGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Account {
+        self.shard.get_mut(&self.client_id).expect("the account is created before its guard")
+    }
+}
+
+/// Each feeder holds its own copy of the reference
+impl<'s> AccountStorage for &'s ConcurrentAccountStore {
+    type Guard<'a>
+        = AccountGuard<'s>
+    where
+        Self: 'a;
+
+    fn get_or_create(&mut self, client_id: u16) -> AccountGuard<'s> {
+        ConcurrentAccountStore::get_or_create(self, client_id)
+    }
+
+    fn account(&self, client_id: u16) -> Option<Account> {
+        self.get(client_id)
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.get_all_accounts()
+    }
+
+    fn len(&self) -> usize {
+        ConcurrentAccountStore::len(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Deposit 0.01 `times` times into a client's account through the trait
+    fn deposit_cents<S: AccountStorage>(mut store: S, client_id: u16, times: usize) {
+        for _ in 0..times {
+            assert!(store.get_or_create(client_id).deposit(dec!(0.01)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_deposits_to_disjoint_clients() {
+        let store = Arc::new(ConcurrentAccountStore::with_shards(4));
+        let feeders: Vec<_> = (1..=8u16)
+            .map(|client| {
+                let store = store.clone();
+                tokio::spawn(async move { deposit_cents(&*store, client, 10_000) })
+            })
+            .collect();
+        for feeder in feeders {
+            feeder.await.unwrap();
+        }
+
+        assert_eq!(store.len(), 8);
+        for client in 1..=8 {
+            let account = store.get(client).unwrap();
+            assert_eq!((account.available, account.total), (dec!(100), dec!(100)));
+        }
+
+        // The same work through the single-threaded store gives the same accounts
+        let mut single = AccountStore::new();
+        for client in 1..=8 {
+            deposit_cents(&mut single, client, 10_000);
+        }
+        let balances = |mut accounts: Vec<Account>| {
+            accounts.sort_unstable_by_key(|account| account.client);
+            accounts.into_iter().map(|account| (account.client, account.available, account.total)).collect::<Vec<_>>()
+        };
+        let store = Arc::into_inner(store).unwrap();
+        assert_eq!(balances(AccountStorage::accounts(&&store)), balances(AccountStorage::accounts(&single)));
+        assert_eq!(balances(store.into_account_store().get_all_accounts()), balances(single.get_all_accounts()));
+    }
+
+    // Stress test of many threads updating one client. Every update holds
+    // and releases funds in several steps under one guard, so a lost update
+    // or a torn read would break the totals.
+    #[test]
+    fn test_same_client_stress() {
+        let store = ConcurrentAccountStore::new();
+        store.get_or_create(1).deposit(dec!(1000));
+        thread::scope(|scope| {
+            for thread_id in 0..8 {
+                let store = &store;
+                scope.spawn(move || {
+                    for i in 0..2_000 {
+                        let mut account = store.get_or_create(1);
+                        assert_eq!(account.available + account.held, account.total);
+                        if (thread_id + i) % 2 == 0 {
+                            assert!(account.hold(dec!(0.5)));
+                            assert!(account.release(dec!(0.5)));
+                            assert!(account.deposit(dec!(0.01)));
+                        } else {
+                            assert!(account.withdraw(dec!(0.01)));
+                        }
+                    }
+                });
+            }
+        });
+
+        // Half the updates deposit and half withdraw the same amount
+        let account = store.get(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(1000), Decimal::ZERO, dec!(1000)));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_accounts_spread_over_shards() {
+        let store = ConcurrentAccountStore::with_shards(0);
+        assert!(store.is_empty());
+        deposit_cents(&store, 3, 1);
+        assert_eq!(store.get(3).unwrap().total, dec!(0.01));
+        assert!(store.get(4).is_none());
+
+        let store = ConcurrentAccountStore::with_shards(4);
+        for client in 0..8 {
+            store.get_or_create(client);
+        }
+        assert!(store.shards.iter().all(|shard| shard.lock().unwrap().len() == 2));
+    }
+
+    // Throughput of disjoint feeders as their number grows. Run with
+    // `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_concurrent_store_scaling() {
+        use std::time::Instant;
+
+        const DEPOSITS: usize = 4_000_000;
+        let single = {
+            let start = Instant::now();
+            let mut store = AccountStore::new();
+            deposit_cents(&mut store, 0, DEPOSITS);
+            start.elapsed().as_secs_f64()
+        };
+        println!("AccountStore, feeders: 1: {:.0} deposits/s", DEPOSITS as f64 / single);
+
+        for feeders in [1, 2, 4, 8] {
+            let store = ConcurrentAccountStore::new();
+            let start = Instant::now();
+            thread::scope(|scope| {
+                for feeder in 0..feeders {
+                    let store = &store;
+                    scope.spawn(move || {
+                        // Each feeder works on its own 100 clients
+                        for i in 0..DEPOSITS / feeders {
+                            let client = (feeder * 100 + i % 100) as u16;
+                            store.get_or_create(client).deposit(dec!(0.01));
+                        }
+                    });
+                }
+            });
+            let elapsed = start.elapsed().as_secs_f64();
+            println!("ConcurrentAccountStore, feeders: {}: {:.0} deposits/s", feeders, DEPOSITS as f64 / elapsed);
+        }
+    }
+}
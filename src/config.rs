@@ -0,0 +1,1177 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use payment_engine::history::TraceFormat;
+use payment_engine::models::{AmountPrecision, Currency, DisputeClientCheck, Limits, PurgePolicy, WithdrawalFee};
+use rust_decimal::Decimal;
+use payment_engine::{
+    BatchSizeMode, ChangeStream, ClientFilter, ClientSample, ClosedAccountHandling, FixedWidthSpec, InputEncoding, InputFormat, InputMode, OutputColumns, OutputMode, Preset, ProcessingOptions,
+    SnapshotEvery, Verbosity,
+};
+
+/// Processing settings, given as CLI flags or as keys of a TOML config file.
+///
+/// Every field is optional so explicit flags can be layered over the config
+/// file, which is layered over the defaults of [`ProcessingOptions`].
+#[derive(Args, Deserialize, Debug, Default, Clone, PartialEq)]
+#[command(about = None, long_about = None)]
+pub struct Settings {
+    /// Log directory [default: logs]
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Batch size for processing transactions [default: 1000]
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+
+    /// Size batches so each takes about --target-batch-ms to apply, instead
+    /// of using --batch-size
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub auto_batch_size: Option<bool>,
+
+    /// Smallest batch with --auto-batch-size [default: 100]
+    #[arg(long)]
+    pub min_batch_size: Option<usize>,
+
+    /// Largest batch with --auto-batch-size [default: 100000]
+    #[arg(long)]
+    pub max_batch_size: Option<usize>,
+
+    /// Apply time per batch that --auto-batch-size aims for [default: 50]
+    #[arg(long)]
+    pub target_batch_ms: Option<u64>,
+
+    /// Parsed batches queued ahead of the engine (0 = read and apply on one task) [default: 2]
+    #[arg(long)]
+    pub pipeline_depth: Option<usize>,
+
+    /// Apply at most this many transactions per second on average [default: unlimited]
+    #[arg(long)]
+    pub max_tps: Option<u32>,
+
+    /// Write-ahead log for crash recovery; an existing log is replayed first
+    #[arg(long)]
+    pub wal: Option<PathBuf>,
+
+    /// File of deposit and withdrawal ids applied by previous runs; those are
+    /// skipped as duplicates and this run's ids are added
+    #[arg(long)]
+    pub seen_tx_ids: Option<PathBuf>,
+
+    /// Apply each batch with one task per client
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub parallel_batches: Option<bool>,
+
+    /// Apply each batch's transactions grouped by client
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub group_by_client: Option<bool>,
+
+    /// Spill stored transactions to disk beyond this many in memory (0 = never)
+    #[arg(long)]
+    pub max_in_memory_transactions: Option<usize>,
+
+    /// Directory for spilled transactions (defaults to the system temp dir)
+    #[arg(long)]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Expected number of transactions, used to pre-size the transaction store
+    #[arg(long)]
+    pub expected_transactions: Option<usize>,
+
+    /// Expected number of clients, used to pre-size the account store
+    #[arg(long)]
+    pub expected_clients: Option<usize>,
+
+    /// How to read the input file: streaming or mmap [default: streaming]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub input_mode: Option<InputMode>,
+
+    /// Input encoding: utf8-strict, utf8-lossy or windows-1252 [default: utf8-strict]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub encoding: Option<InputEncoding>,
+
+    /// Read fixed-width records instead of delimited lines, in the default
+    /// layout or with changes such as `amount=26:12,implied_decimal=false`
+    #[arg(long, num_args = 0..=1, default_missing_value = "default")]
+    #[serde(default, deserialize_with = "fixed_width_option")]
+    pub fixed_width: Option<FixedWidthSpec>,
+
+    /// Keep reading the input past its end, reopening a FIFO whose producer
+    /// closed it, until the --end-marker line
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub follow: Option<bool>,
+
+    /// Line ending a followed input [default: #EOF]
+    #[arg(long)]
+    pub end_marker: Option<String>,
+
+    /// Reject input lines longer than this many bytes (0 = unlimited) [default: 4096]
+    #[arg(long)]
+    pub max_line_length: Option<usize>,
+
+    /// Skip header lines repeated inside the input, e.g. from concatenated files
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub tolerate_repeated_headers: Option<bool>,
+
+    /// Skip this many records before processing, e.g. to resume a file
+    #[arg(long = "skip")]
+    pub skip_records: Option<usize>,
+
+    /// Stop after processing this many records (after any skipped)
+    #[arg(long, visible_alias = "take")]
+    pub max_records: Option<usize>,
+
+    /// Accounts CSV in the output format to start from, e.g. the previous
+    /// period's closing balances
+    #[arg(long)]
+    pub initial_accounts: Option<PathBuf>,
+
+    /// Accounts CSV in the output format to reconcile the final balances
+    /// against; exits with status 3 if any client differs
+    #[arg(long = "expected")]
+    pub expected_accounts: Option<PathBuf>,
+
+    /// Largest balance difference from `--expected` that still matches
+    #[arg(long)]
+    pub reconcile_tolerance: Option<Decimal>,
+
+    /// Only apply the transactions of these clients, e.g. `1,5,100-200`
+    #[arg(long, visible_alias = "client")]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub clients: Option<ClientFilter>,
+
+    /// Only apply the transactions of this fraction of the clients, e.g.
+    /// `0.01`, and estimate the totals of the full run
+    #[arg(long)]
+    pub sample: Option<f64>,
+
+    /// Seed choosing the clients of `--sample`, defaulting to 0
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Drop deposits and withdrawals of locked clients before parsing their
+    /// amounts instead of rejecting them
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub skip_locked: Option<bool>,
+
+    /// Write a snapshot of the balances while processing, every this many
+    /// records (`100000`) or this often (`30s`, `5m`, `1h`)
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub snapshot_every: Option<SnapshotEvery>,
+
+    /// Path of each snapshot, `{seq}` replaced by its number [default: snapshot_{seq}.csv]
+    #[arg(long = "snapshot-path")]
+    pub snapshot_path_template: Option<String>,
+
+    /// Print each transaction's outcome and the balances after it instead of
+    /// the final balances
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub dry_run: Option<bool>,
+
+    /// Format of the --dry-run trace: text or csv [default: text]
+    #[arg(long = "format")]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub trace_format: Option<TraceFormat>,
+
+    /// Write a per-client audit trail of every transaction to this directory
+    #[arg(long)]
+    pub history_out: Option<PathBuf>,
+
+    /// Write the audit trail as a single history.csv instead of one file per client
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub history_combined: Option<bool>,
+
+    /// Write a JSON manifest of the run to this path when it ends: input,
+    /// options, timestamps, counts and the output's row count and hash
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Drop finalized transactions from the store: never, after-chargeback,
+    /// or after-resolve-or-chargeback [default: never]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub purge_finalized: Option<PurgePolicy>,
+
+    /// Reject deposits larger than this amount
+    #[arg(long)]
+    pub max_deposit: Option<Decimal>,
+
+    /// Reject withdrawals larger than this amount
+    #[arg(long)]
+    pub max_withdrawal: Option<Decimal>,
+
+    /// Reject further withdrawals from a client once this many have been applied
+    #[arg(long)]
+    pub max_withdrawals_per_client: Option<u32>,
+
+    /// Reject disputes of a client that already has this many transactions under dispute
+    #[arg(long)]
+    pub max_open_disputes_per_client: Option<u32>,
+
+    /// Bundle of input and dispute policies: strict or lenient; the
+    /// individual settings override it
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub preset: Option<Preset>,
+
+    /// Amounts with more than 4 decimal places: reject, truncate or
+    /// round-half-even [default: round-half-even]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub amount_precision: Option<AmountPrecision>,
+
+    /// Let a dispute's amount contest only part of the original transaction
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub allow_partial_disputes: Option<bool>,
+
+    /// Let withdrawals be disputed; a chargeback credits the funds back
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub allow_withdrawal_disputes: Option<bool>,
+
+    /// Queue up to this many disputes per client that can't hold their funds
+    /// yet, instead of rejecting them
+    #[arg(long)]
+    pub max_deferred_disputes: Option<usize>,
+
+    /// Apply amend rows correcting an earlier deposit's amount
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub allow_admin_transactions: Option<bool>,
+
+    /// Whether a dispute, resolve or chargeback of a client without an
+    /// account creates an empty one: true or false [default: true, false
+    /// with --preset strict]
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub create_accounts_on_reference: Option<bool>,
+
+    /// Disputes, resolves and chargebacks under another client than the
+    /// original transaction: strict (reject) or trust-original [default: strict]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub dispute_client_check: Option<DisputeClientCheck>,
+
+    /// Read a timestamp in seconds since the Unix epoch from the column after
+    /// the amount (or currency)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub timestamps: Option<bool>,
+
+    /// Resolve disputes left open longer than this, in seconds or with an s,
+    /// m, h or d suffix such as 30d; needs --timestamps
+    #[arg(long, value_parser = parse_duration)]
+    #[serde(default, deserialize_with = "duration_option")]
+    pub dispute_expiry: Option<Duration>,
+
+    /// Fee charged on each withdrawal: none, flat like 0.5, percentage like
+    /// 1.5%, or both like 0.5+1.5% [default: none]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub withdrawal_fee: Option<WithdrawalFee>,
+
+    /// Field delimiter of the input: a single character, or \t for tabs [default: ,]
+    #[arg(long, value_parser = parse_delimiter)]
+    #[serde(default, deserialize_with = "delimiter_option")]
+    pub delimiter: Option<char>,
+
+    /// Decimal separator of input amounts: . or , [default: .]
+    #[arg(long)]
+    pub decimal_separator: Option<char>,
+
+    /// Accept amounts with thousands separators, such as +1 234.56
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub lenient_amounts: Option<bool>,
+
+    /// Thousands separator of --lenient-amounts: , or _ or a space [default: ,]
+    #[arg(long)]
+    pub thousands_separator: Option<char>,
+
+    /// Write each client's withdrawal fee total as a comment line to stderr
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub fee_summary: Option<bool>,
+
+    /// Write the time spent reading, parsing and applying, and the lines and
+    /// transactions per second, as comment lines to stderr
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub timing_summary: Option<bool>,
+
+    /// Write nothing but the accounts; errors only show in the exit status and the log
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true", conflicts_with = "verbose")]
+    pub quiet: Option<bool>,
+
+    /// Also write a progress line per batch and the run's counts to stderr
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub verbose: Option<bool>,
+
+    /// Add lock reason and dispute count columns to the account output
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub extended_output: Option<bool>,
+
+    /// Account columns to write, in order, e.g. `client,available,total`;
+    /// `name=header` renames a column in the header
+    #[arg(long = "columns")]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub output_columns: Option<OutputColumns>,
+
+    /// Write the accounts to this many files accounts_<part>.csv instead of
+    /// stdout, client c going to part c % N
+    #[arg(long)]
+    pub output_partitions: Option<usize>,
+
+    /// Directory for partitioned account files [default: working directory]
+    #[arg(long)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Write each account to its own file at this path, {id} in the file
+    /// name replaced by the client id, e.g. out/client_{id}.csv
+    #[arg(long)]
+    pub output_per_client: Option<String>,
+
+    /// Write the accounts to this file instead of stdout, gzip-compressed
+    /// if it ends in .gz
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Gzip the --output file whatever its extension
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub compress_output: Option<bool>,
+
+    /// Leave closed accounts, locked with a total of zero, out of the output
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub exclude_closed: Option<bool>,
+
+    /// Write closed accounts to this CSV instead of the output
+    #[arg(long)]
+    pub closed_accounts_file: Option<PathBuf>,
+
+    /// Leave --initial-accounts clients that no transaction was applied to
+    /// out of the output
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub omit_untouched: Option<bool>,
+
+    /// Write the accounts each batch changed to this file as JSON lines
+    #[arg(long)]
+    pub change_stream: Option<PathBuf>,
+
+    /// Read an optional currency column and keep a balance per currency
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub multi_currency: Option<bool>,
+
+    /// Currency of the plain balance columns and of rows without a currency [default: USD]
+    #[arg(long)]
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub base_currency: Option<Currency>,
+
+    /// Exit with status 4 when more than this many lines are skipped or
+    /// rejected [default: unlimited, errors are only logged]
+    #[arg(long)]
+    pub max_errors: Option<usize>,
+
+    /// Also write final account balances to this SQLite database
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    pub output_sqlite: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this port of localhost at /metrics while processing
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
+    /// Serve Prometheus metrics on this address instead, e.g. 0.0.0.0:9000 to
+    /// expose them on every interface
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Serve account balances and progress over HTTP on this address while processing
+    #[cfg(feature = "http")]
+    #[arg(long)]
+    pub serve: Option<std::net::SocketAddr>,
+}
+
+/// Deserialize an optional value through its `FromStr` impl, so config files
+/// accept the same spellings as the CLI
+fn from_str_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize an optional fixed-width layout from a table of `[offset,
+/// width]` fields, or from a string as given to `--fixed-width`
+fn fixed_width_option<'de, D>(deserializer: D) -> Result<Option<FixedWidthSpec>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct LayoutVisitor;
+
+    impl<'de> Visitor<'de> for LayoutVisitor {
+        type Value = FixedWidthSpec;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a fixed-width layout table or string")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            s.parse().map_err(E::custom)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            FixedWidthSpec::deserialize(MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(LayoutVisitor).map(Some)
+}
+
+/// Parse a field delimiter, accepting `\t` (as typed on a command line) for tabs
+fn parse_delimiter(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (s, chars.next(), chars.next()) {
+        ("\\t", _, _) => Ok('\t'),
+        (_, Some(delimiter), None) => Ok(delimiter),
+        _ => Err(format!("Invalid delimiter: {} (expected a single character or \\t)", s)),
+    }
+}
+
+/// Deserialize an optional delimiter with [`parse_delimiter`]
+fn delimiter_option<'de, D>(deserializer: D) -> Result<Option<char>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_delimiter(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Parse a duration given in seconds, or with an `s`, `m`, `h` or `d` suffix
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {} (expected seconds or a number with s, m, h or d)", s);
+    let (number, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        Some((i, 'd')) => (&s[..i], 86_400),
+        _ => (s, 1),
+    };
+    let count: u64 = number.parse().map_err(|_| invalid())?;
+    count.checked_mul(unit_secs).map(Duration::from_secs).ok_or_else(invalid)
+}
+
+/// Deserialize an optional duration with [`parse_duration`]
+fn duration_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Settings {
+    /// Parse a TOML config file. Returns the settings and any unknown keys,
+    /// which are ignored.
+    pub fn from_toml(text: &str) -> Result<(Self, Vec<String>)> {
+        let mut unknown = Vec::new();
+        let deserializer = toml::Deserializer::parse(text)?;
+        let settings = serde_ignored::deserialize(deserializer, |path| unknown.push(path.to_string()))?;
+        Ok((settings, unknown))
+    }
+
+    /// Load a TOML config file
+    pub fn load(path: &Path) -> Result<(Self, Vec<String>)> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {:?}", path))?;
+        Self::from_toml(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    /// Fill every setting missing here from `lower`
+    pub fn merge(self, lower: Settings) -> Settings {
+        // Quiet and verbose are one setting, so either flag turned on overrides both keys
+        let (quiet, verbose) = match (self.quiet, self.verbose) {
+            (Some(true), _) | (_, Some(true)) => (self.quiet, self.verbose),
+            _ => (self.quiet.or(lower.quiet), self.verbose.or(lower.verbose)),
+        };
+        Settings {
+            log_dir: self.log_dir.or(lower.log_dir),
+            batch_size: self.batch_size.or(lower.batch_size),
+            auto_batch_size: self.auto_batch_size.or(lower.auto_batch_size),
+            min_batch_size: self.min_batch_size.or(lower.min_batch_size),
+            max_batch_size: self.max_batch_size.or(lower.max_batch_size),
+            target_batch_ms: self.target_batch_ms.or(lower.target_batch_ms),
+            pipeline_depth: self.pipeline_depth.or(lower.pipeline_depth),
+            max_tps: self.max_tps.or(lower.max_tps),
+            wal: self.wal.or(lower.wal),
+            seen_tx_ids: self.seen_tx_ids.or(lower.seen_tx_ids),
+            parallel_batches: self.parallel_batches.or(lower.parallel_batches),
+            group_by_client: self.group_by_client.or(lower.group_by_client),
+            max_in_memory_transactions: self.max_in_memory_transactions.or(lower.max_in_memory_transactions),
+            spill_dir: self.spill_dir.or(lower.spill_dir),
+            expected_transactions: self.expected_transactions.or(lower.expected_transactions),
+            expected_clients: self.expected_clients.or(lower.expected_clients),
+            input_mode: self.input_mode.or(lower.input_mode),
+            encoding: self.encoding.or(lower.encoding),
+            fixed_width: self.fixed_width.or(lower.fixed_width),
+            follow: self.follow.or(lower.follow),
+            end_marker: self.end_marker.or(lower.end_marker),
+            max_line_length: self.max_line_length.or(lower.max_line_length),
+            tolerate_repeated_headers: self.tolerate_repeated_headers.or(lower.tolerate_repeated_headers),
+            skip_records: self.skip_records.or(lower.skip_records),
+            max_records: self.max_records.or(lower.max_records),
+            initial_accounts: self.initial_accounts.or(lower.initial_accounts),
+            expected_accounts: self.expected_accounts.or(lower.expected_accounts),
+            reconcile_tolerance: self.reconcile_tolerance.or(lower.reconcile_tolerance),
+            clients: self.clients.or(lower.clients),
+            sample: self.sample.or(lower.sample),
+            skip_locked: self.skip_locked.or(lower.skip_locked),
+            seed: self.seed.or(lower.seed),
+            snapshot_every: self.snapshot_every.or(lower.snapshot_every),
+            snapshot_path_template: self.snapshot_path_template.or(lower.snapshot_path_template),
+            dry_run: self.dry_run.or(lower.dry_run),
+            trace_format: self.trace_format.or(lower.trace_format),
+            history_out: self.history_out.or(lower.history_out),
+            history_combined: self.history_combined.or(lower.history_combined),
+            manifest: self.manifest.or(lower.manifest),
+            purge_finalized: self.purge_finalized.or(lower.purge_finalized),
+            max_deposit: self.max_deposit.or(lower.max_deposit),
+            max_withdrawal: self.max_withdrawal.or(lower.max_withdrawal),
+            max_withdrawals_per_client: self.max_withdrawals_per_client.or(lower.max_withdrawals_per_client),
+            max_open_disputes_per_client: self.max_open_disputes_per_client.or(lower.max_open_disputes_per_client),
+            preset: self.preset.or(lower.preset),
+            amount_precision: self.amount_precision.or(lower.amount_precision),
+            allow_partial_disputes: self.allow_partial_disputes.or(lower.allow_partial_disputes),
+            allow_withdrawal_disputes: self.allow_withdrawal_disputes.or(lower.allow_withdrawal_disputes),
+            max_deferred_disputes: self.max_deferred_disputes.or(lower.max_deferred_disputes),
+            allow_admin_transactions: self.allow_admin_transactions.or(lower.allow_admin_transactions),
+            create_accounts_on_reference: self.create_accounts_on_reference.or(lower.create_accounts_on_reference),
+            dispute_client_check: self.dispute_client_check.or(lower.dispute_client_check),
+            timestamps: self.timestamps.or(lower.timestamps),
+            dispute_expiry: self.dispute_expiry.or(lower.dispute_expiry),
+            withdrawal_fee: self.withdrawal_fee.or(lower.withdrawal_fee),
+            delimiter: self.delimiter.or(lower.delimiter),
+            decimal_separator: self.decimal_separator.or(lower.decimal_separator),
+            lenient_amounts: self.lenient_amounts.or(lower.lenient_amounts),
+            thousands_separator: self.thousands_separator.or(lower.thousands_separator),
+            fee_summary: self.fee_summary.or(lower.fee_summary),
+            timing_summary: self.timing_summary.or(lower.timing_summary),
+            quiet,
+            verbose,
+            extended_output: self.extended_output.or(lower.extended_output),
+            output_columns: self.output_columns.or(lower.output_columns),
+            output_partitions: self.output_partitions.or(lower.output_partitions),
+            output_dir: self.output_dir.or(lower.output_dir),
+            output_per_client: self.output_per_client.or(lower.output_per_client),
+            output: self.output.or(lower.output),
+            compress_output: self.compress_output.or(lower.compress_output),
+            exclude_closed: self.exclude_closed.or(lower.exclude_closed),
+            closed_accounts_file: self.closed_accounts_file.or(lower.closed_accounts_file),
+            omit_untouched: self.omit_untouched.or(lower.omit_untouched),
+            change_stream: self.change_stream.or(lower.change_stream),
+            multi_currency: self.multi_currency.or(lower.multi_currency),
+            base_currency: self.base_currency.or(lower.base_currency),
+            max_errors: self.max_errors.or(lower.max_errors),
+            #[cfg(feature = "sqlite")]
+            output_sqlite: self.output_sqlite.or(lower.output_sqlite),
+            #[cfg(feature = "metrics")]
+            metrics_port: self.metrics_port.or(lower.metrics_port),
+            #[cfg(feature = "metrics")]
+            metrics_addr: self.metrics_addr.or(lower.metrics_addr),
+            #[cfg(feature = "http")]
+            serve: self.serve.or(lower.serve),
+        }
+    }
+
+    /// Log directory, defaulting to `logs`
+    pub fn log_dir(&self) -> PathBuf {
+        self.log_dir.clone().unwrap_or_else(|| PathBuf::from("logs"))
+    }
+
+    /// Address to serve Prometheus metrics on: `metrics_addr`, or localhost
+    /// on `metrics_port`
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(&self) -> Option<std::net::SocketAddr> {
+        self.metrics_addr.or(self.metrics_port.map(|port| std::net::SocketAddr::from(([127, 0, 0, 1], port))))
+    }
+
+    /// Verbosity of the run from the quiet and verbose settings
+    fn verbosity(&self) -> Result<Verbosity> {
+        match (self.quiet.unwrap_or(false), self.verbose.unwrap_or(false)) {
+            (true, true) => anyhow::bail!("quiet and verbose can't both be set"),
+            (true, false) => Ok(Verbosity::Quiet),
+            (false, true) => Ok(Verbosity::Verbose),
+            (false, false) => Ok(Verbosity::Normal),
+        }
+    }
+
+    /// Build processing options, using the library defaults for unset settings
+    pub fn to_options(&self) -> Result<ProcessingOptions> {
+        let builder = match self.preset {
+            Some(preset) => ProcessingOptions::preset(preset),
+            None => ProcessingOptions::builder(),
+        };
+        let mut builder = builder
+            .wal_path(self.wal.clone())
+            .seen_tx_ids_path(self.seen_tx_ids.clone())
+            .max_in_memory_transactions(self.max_in_memory_transactions)
+            .spill_dir(self.spill_dir.clone())
+            .expected_transactions(self.expected_transactions)
+            .expected_clients(self.expected_clients)
+            .skip_records(self.skip_records.unwrap_or(0))
+            .max_records(self.max_records)
+            .max_tps(self.max_tps)
+            .client_filter(self.clients.clone())
+            .dry_run(self.dry_run.unwrap_or(false).then(|| self.trace_format.unwrap_or_default()))
+            .snapshot_every(self.snapshot_every)
+            .initial_accounts(self.initial_accounts.clone())
+            .expected_accounts(self.expected_accounts.clone())
+            .reconcile_tolerance(self.reconcile_tolerance.unwrap_or_default())
+            .limits(Limits {
+                max_deposit: self.max_deposit,
+                max_withdrawal: self.max_withdrawal,
+                max_withdrawals_per_client: self.max_withdrawals_per_client,
+                max_open_disputes_per_client: self.max_open_disputes_per_client,
+            })
+            .record_history(self.history_out.is_some())
+            .history_out(self.history_out.clone())
+            .timestamps(self.timestamps.unwrap_or(false))
+            .dispute_expiry(self.dispute_expiry)
+            .withdrawal_fee(self.withdrawal_fee.unwrap_or_default())
+            .fee_summary(self.fee_summary.unwrap_or(false))
+            .timing_summary(self.timing_summary.unwrap_or(false))
+            .verbosity(self.verbosity()?)
+            .extended_output(self.extended_output.unwrap_or(false))
+            .output_columns(self.output_columns.clone())
+            .output_partitions(self.output_partitions)
+            .output_dir(self.output_dir.clone())
+            .multi_currency(self.multi_currency.unwrap_or(false))
+            .parallel_batches(self.parallel_batches.unwrap_or(false))
+            .group_by_client_in_batch(self.group_by_client.unwrap_or(false))
+            .history_combined(self.history_combined.unwrap_or(false))
+            .skip_locked_fast_path(self.skip_locked.unwrap_or(false))
+            .max_deferred_disputes(self.max_deferred_disputes)
+            .manifest_path(self.manifest.clone());
+        if let Some(lenient_amounts) = self.lenient_amounts {
+            builder = builder.lenient_amounts(lenient_amounts);
+        }
+        if let Some(allow_partial_disputes) = self.allow_partial_disputes {
+            builder = builder.allow_partial_disputes(allow_partial_disputes);
+        }
+        if let Some(allow_withdrawal_disputes) = self.allow_withdrawal_disputes {
+            builder = builder.allow_withdrawal_disputes(allow_withdrawal_disputes);
+        }
+        if let Some(allow_admin_transactions) = self.allow_admin_transactions {
+            builder = builder.allow_admin_transactions(allow_admin_transactions);
+        }
+        if let Some(create_accounts_on_reference) = self.create_accounts_on_reference {
+            builder = builder.create_accounts_on_reference(create_accounts_on_reference);
+        }
+        if let Some(tolerate_repeated_headers) = self.tolerate_repeated_headers {
+            builder = builder.tolerate_repeated_headers(tolerate_repeated_headers);
+        }
+        if self.auto_batch_size == Some(true) {
+            builder = builder.batch_size_mode(BatchSizeMode::Auto {
+                min: self.min_batch_size.unwrap_or(100),
+                max: self.max_batch_size.unwrap_or(100_000),
+                target_batch_ms: self.target_batch_ms.unwrap_or(50),
+            });
+        } else if let Some(batch_size) = self.batch_size {
+            builder = builder.batch_size(batch_size);
+        }
+        match (&self.output, &self.output_per_client) {
+            (Some(_), Some(_)) => anyhow::bail!("output and output_per_client can't both be set"),
+            (Some(path), None) => builder = builder.output_mode(OutputMode::File(path.clone())),
+            (None, Some(template)) => builder = builder.output_mode(OutputMode::PerClientDir(template.clone())),
+            (None, None) => {}
+        }
+        match (self.sample, self.seed) {
+            (None, Some(_)) => anyhow::bail!("seed requires sample"),
+            (Some(rate), seed) => builder = builder.sample(ClientSample::new(rate, seed.unwrap_or(0))),
+            (None, None) => {}
+        }
+        if let Some(compress_output) = self.compress_output {
+            builder = builder.compress_output(compress_output);
+        }
+        // A closures file takes the closed accounts out of the output as well
+        match (&self.closed_accounts_file, self.exclude_closed) {
+            (Some(path), _) => builder = builder.closed_account_handling(ClosedAccountHandling::SeparateFile(path.clone())),
+            (None, Some(true)) => builder = builder.closed_account_handling(ClosedAccountHandling::Exclude),
+            (None, _) => {}
+        }
+        if let Some(omit_untouched) = self.omit_untouched {
+            builder = builder.emit_untouched_accounts(!omit_untouched);
+        }
+        if let Some(path) = &self.change_stream {
+            builder = builder.change_stream(ChangeStream::File(path.clone()));
+        }
+        if let Some(pipeline_depth) = self.pipeline_depth {
+            builder = builder.pipeline_depth(pipeline_depth);
+        }
+        if let Some(input_mode) = self.input_mode {
+            builder = builder.input_mode(input_mode);
+        }
+        if let Some(encoding) = self.encoding {
+            builder = builder.encoding(encoding);
+        }
+        if let Some(spec) = self.fixed_width {
+            builder = builder.input_format(InputFormat::FixedWidth(spec));
+        }
+        if let Some(follow) = self.follow {
+            builder = builder.follow(follow);
+        }
+        if let Some(end_marker) = &self.end_marker {
+            builder = builder.end_marker(end_marker.clone());
+        }
+        if let Some(max_line_length) = self.max_line_length {
+            builder = builder.max_line_length(max_line_length);
+        }
+        if let Some(base_currency) = self.base_currency {
+            builder = builder.base_currency(base_currency);
+        }
+        if let Some(amount_precision) = self.amount_precision {
+            builder = builder.amount_precision(amount_precision);
+        }
+        if let Some(delimiter) = self.delimiter {
+            builder = builder.delimiter(delimiter);
+        }
+        if let Some(decimal_separator) = self.decimal_separator {
+            builder = builder.decimal_separator(decimal_separator);
+        }
+        if let Some(thousands_separator) = self.thousands_separator {
+            builder = builder.thousands_separator(thousands_separator);
+        }
+        if let Some(dispute_client_check) = self.dispute_client_check {
+            builder = builder.dispute_client_check(dispute_client_check);
+        }
+        if let Some(purge_finalized) = self.purge_finalized {
+            builder = builder.purge_finalized(purge_finalized);
+        }
+        if let Some(template) = &self.snapshot_path_template {
+            builder = builder.snapshot_path_template(template.clone());
+        }
+        #[cfg(feature = "http")]
+        let builder = builder.serve_addr(self.serve);
+        #[cfg(feature = "sqlite")]
+        let builder = builder.sqlite_path(self.output_sqlite.clone());
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        settings: Settings,
+    }
+
+    fn cli(args: &[&str]) -> Settings {
+        Cli::parse_from(std::iter::once("payment-engine").chain(args.iter().copied())).settings
+    }
+
+    #[test]
+    fn test_cli_overrides_config_overrides_defaults() {
+        let (config, unknown) = Settings::from_toml(
+            r#"
+            batch_size = 250
+            log_dir = "/var/log/payments"
+            input_mode = "mmap"
+            parallel_batches = true
+            max_records = 100
+            pipeline_depth = 0
+            max_errors = 5
+            max_deposit = "2500.50"
+            withdrawal_fee = "0.5+1%"
+            dispute_client_check = "trust-original"
+            "#,
+        )
+        .unwrap();
+        assert!(unknown.is_empty());
+
+        let settings = cli(&["--batch-size", "50", "--input-mode", "streaming"]).merge(config);
+        let options = settings.to_options().unwrap();
+
+        // From the CLI
+        assert_eq!(options.batch_size_mode, BatchSizeMode::Fixed(50));
+        assert_eq!(options.input_mode, InputMode::Streaming);
+        // From the config file
+        assert_eq!(settings.log_dir(), PathBuf::from("/var/log/payments"));
+        assert!(options.parallel_batches);
+        assert_eq!(options.max_records, Some(100));
+        assert_eq!(options.pipeline_depth, 0);
+        assert_eq!(settings.max_errors, Some(5));
+        assert_eq!(options.limits.max_deposit, Some(Decimal::new(250050, 2)));
+        assert_eq!(options.limits.max_withdrawal, None);
+        assert_eq!(options.withdrawal_fee, WithdrawalFee::FlatPlusPercent(Decimal::new(5, 1), Decimal::ONE));
+        assert_eq!(options.dispute_client_check, DisputeClientCheck::TrustOriginal);
+        // Defaults
+        assert_eq!(options.max_line_length, ProcessingOptions::default().max_line_length);
+        assert_eq!(options.purge_finalized, PurgePolicy::Never);
+    }
+
+    #[test]
+    fn test_defaults_without_cli_or_config() {
+        let settings = cli(&[]).merge(Settings::default());
+        let options = settings.to_options().unwrap();
+
+        assert_eq!(settings.log_dir(), PathBuf::from("logs"));
+        assert_eq!(options.batch_size_mode, ProcessingOptions::default().batch_size_mode);
+        assert!(!options.parallel_batches);
+        assert!(cli(&["--parallel-batches"]).parallel_batches.unwrap());
+        assert!(!options.group_by_client_in_batch);
+        assert!(cli(&["--group-by-client"]).to_options().unwrap().group_by_client_in_batch);
+
+        assert_eq!((options.expected_clients, options.expected_transactions), (None, None));
+        let options = cli(&["--expected-clients", "500", "--expected-transactions", "10000"]).to_options().unwrap();
+        assert_eq!((options.expected_clients, options.expected_transactions), (Some(500), Some(10_000)));
+
+        assert_eq!(options.max_tps, None);
+        assert_eq!(cli(&["--max-tps", "5000"]).to_options().unwrap().max_tps, Some(5000));
+        assert!(cli(&["--max-tps", "0"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_auto_batch_size() {
+        let options = cli(&["--auto-batch-size", "--max-batch-size", "5000"]).to_options().unwrap();
+        assert_eq!(options.batch_size_mode, BatchSizeMode::Auto { min: 100, max: 5000, target_batch_ms: 50 });
+
+        let (config, _) = Settings::from_toml("auto_batch_size = true
+target_batch_ms = 20").unwrap();
+        let options = cli(&["--batch-size", "50"]).merge(config).to_options().unwrap();
+        assert_eq!(options.batch_size_mode, BatchSizeMode::Auto { min: 100, max: 100_000, target_batch_ms: 20 });
+    }
+
+    #[test]
+    fn test_skip_and_take() {
+        let options = cli(&["--skip", "10", "--take", "5"]).to_options().unwrap();
+        assert_eq!((options.skip_records, options.max_records), (10, Some(5)));
+
+        let (config, unknown) = Settings::from_toml("skip_records = 3").unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(config.to_options().unwrap().skip_records, 3);
+    }
+
+    #[test]
+    fn test_clients() {
+        let options = cli(&["--clients", "1,5,100-200"]).to_options().unwrap();
+        let filter = options.client_filter.unwrap();
+        assert!(filter.matches(5) && filter.matches(150) && !filter.matches(2));
+        assert_eq!(cli(&["--client", "2"]).to_options().unwrap().client_filter, Some(ClientFilter::from(2)));
+
+        let (config, _) = Settings::from_toml("clients = \"10-19\"").unwrap();
+        assert_eq!(config.to_options().unwrap().client_filter, Some(ClientFilter::Range(10..=19)));
+        assert!(Settings::from_toml("clients = \"5-1\"").is_err());
+    }
+
+    #[test]
+    fn test_manifest() {
+        assert_eq!(cli(&[]).to_options().unwrap().manifest_path, None);
+        let options = cli(&["--manifest", "run.json"]).to_options().unwrap();
+        assert_eq!(options.manifest_path, Some(PathBuf::from("run.json")));
+    }
+
+    #[test]
+    fn test_sample() {
+        let options = cli(&["--sample", "0.01", "--seed", "42"]).to_options().unwrap();
+        assert_eq!(options.sample, Some(ClientSample::new(0.01, 42)));
+        assert_eq!(cli(&["--sample", "0.5"]).to_options().unwrap().sample, Some(ClientSample::new(0.5, 0)));
+        assert!(cli(&["--seed", "42"]).to_options().is_err());
+        assert!(cli(&["--sample", "1.5"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_fixed_width() {
+        assert_eq!(cli(&[]).to_options().unwrap().input_format, InputFormat::Delimited);
+        let options = cli(&["--fixed-width"]).to_options().unwrap();
+        assert_eq!(options.input_format, InputFormat::FixedWidth(FixedWidthSpec::default()));
+        let options = cli(&["--fixed-width", "amount=26:12,implied_decimal=false"]).to_options().unwrap();
+        let InputFormat::FixedWidth(spec) = options.input_format else { panic!("{:?}", options.input_format) };
+        assert_eq!((spec.amount.width, spec.implied_decimal, spec.tx.offset), (12, false, 16));
+
+        let (settings, unknown) = Settings::from_toml(
+            r#"
+            [fixed_width]
+            client = [10, 4]
+            tx = [14, 12]
+            implied_decimal = false
+            "#,
+        )
+        .unwrap();
+        assert!(unknown.is_empty());
+        let InputFormat::FixedWidth(spec) = settings.to_options().unwrap().input_format else { panic!() };
+        assert_eq!((spec.client.width, spec.tx.offset, spec.tx.width, spec.amount.offset), (4, 14, 12, 26));
+        assert!(!spec.implied_decimal);
+
+        let (settings, _) = Settings::from_toml("fixed_width = \"default\"").unwrap();
+        assert_eq!(settings.fixed_width, Some(FixedWidthSpec::default()));
+        assert!(Settings::from_toml("[fixed_width]\ncurrency = [40, 3]").is_err());
+        assert!(Settings::from_toml("fixed_width = \"amount=26\"").is_err());
+        assert!(cli(&["--fixed-width", "tx=12:10"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_max_deferred_disputes() {
+        assert_eq!(cli(&[]).to_options().unwrap().max_deferred_disputes, None);
+        assert_eq!(cli(&["--max-deferred-disputes", "4"]).to_options().unwrap().max_deferred_disputes, Some(4));
+        assert!(cli(&["--max-deferred-disputes", "0"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_skip_locked() {
+        assert!(!cli(&[]).to_options().unwrap().skip_locked_fast_path);
+        assert!(cli(&["--skip-locked"]).to_options().unwrap().skip_locked_fast_path);
+        let (config, _) = Settings::from_toml("skip_locked = true").unwrap();
+        assert!(cli(&[]).merge(config).to_options().unwrap().skip_locked_fast_path);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let options = cli(&["--snapshot-every", "5m", "--snapshot-path", "out/accounts_{seq}.csv"]).to_options().unwrap();
+        assert_eq!(options.snapshot_every, Some(SnapshotEvery::Duration(std::time::Duration::from_secs(300))));
+        assert_eq!(options.snapshot_path_template, "out/accounts_{seq}.csv");
+
+        let (config, _) = Settings::from_toml("snapshot_every = \"100000\"").unwrap();
+        let options = config.to_options().unwrap();
+        assert_eq!(options.snapshot_every, Some(SnapshotEvery::Records(100_000)));
+        assert_eq!(options.snapshot_path_template, "snapshot_{seq}.csv");
+        assert!(cli(&["--snapshot-every", "0"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_reconciliation() {
+        let options = cli(&["--expected", "ledger.csv", "--reconcile-tolerance", "0.01"]).to_options().unwrap();
+        assert_eq!(options.expected_accounts, Some(PathBuf::from("ledger.csv")));
+        assert_eq!(options.reconcile_tolerance, Decimal::new(1, 2));
+        assert!(cli(&["--reconcile-tolerance=-1"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_columns() {
+        let options = cli(&["--columns", "client,total=balance"]).to_options().unwrap();
+        assert_eq!(options.output_columns, Some("client,total=balance".parse().unwrap()));
+        assert!(Settings::from_toml("output_columns = \"client,balance\"").is_err());
+        assert!(cli(&["--columns", "client,client"]).to_options().is_err());
+
+        let options = cli(&["--output-per-client", "out/client_{id}.csv"]).to_options().unwrap();
+        assert_eq!(options.output_mode, OutputMode::PerClientDir("out/client_{id}.csv".to_string()));
+        assert!(cli(&["--output-per-client", "out/clients.csv"]).to_options().is_err());
+
+        let options = cli(&["--output", "accounts.csv", "--compress-output"]).to_options().unwrap();
+        assert_eq!(options.output_mode, OutputMode::File(PathBuf::from("accounts.csv")));
+        assert!(options.compress_output);
+        assert!(cli(&["--compress-output"]).to_options().is_err());
+        assert!(cli(&["--output", "a.csv", "--output-per-client", "{id}.csv"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_closed_accounts() {
+        assert_eq!(cli(&[]).to_options().unwrap().closed_account_handling, ClosedAccountHandling::IncludeInOutput);
+        assert_eq!(cli(&["--exclude-closed"]).to_options().unwrap().closed_account_handling, ClosedAccountHandling::Exclude);
+        let separate = ClosedAccountHandling::SeparateFile(PathBuf::from("closed.csv"));
+        assert_eq!(cli(&["--closed-accounts-file", "closed.csv"]).to_options().unwrap().closed_account_handling, separate);
+        assert_eq!(cli(&["--closed-accounts-file", "closed.csv", "--exclude-closed"]).to_options().unwrap().closed_account_handling, separate);
+
+        let (settings, _) = Settings::from_toml("exclude_closed = true").unwrap();
+        assert_eq!(settings.to_options().unwrap().closed_account_handling, ClosedAccountHandling::Exclude);
+    }
+
+    #[test]
+    fn test_omit_untouched() {
+        assert!(cli(&[]).to_options().unwrap().emit_untouched_accounts);
+        assert!(!cli(&["--omit-untouched"]).to_options().unwrap().emit_untouched_accounts);
+
+        let (settings, _) = Settings::from_toml("omit_untouched = true").unwrap();
+        assert!(!settings.to_options().unwrap().emit_untouched_accounts);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_addr() {
+        assert_eq!(cli(&[]).metrics_addr(), None);
+        assert_eq!(cli(&["--metrics-port", "9000"]).metrics_addr(), Some("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(cli(&["--metrics-port", "9000", "--metrics-addr", "0.0.0.0:9100"]).metrics_addr(), Some("0.0.0.0:9100".parse().unwrap()));
+
+        let (settings, _) = Settings::from_toml(r#"metrics_addr = "[::1]:9000""#).unwrap();
+        assert_eq!(settings.metrics_addr(), Some("[::1]:9000".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_change_stream() {
+        assert!(cli(&[]).to_options().unwrap().change_stream.is_none());
+        let options = cli(&["--change-stream", "changes.ndjson"]).to_options().unwrap();
+        assert!(matches!(options.change_stream, Some(ChangeStream::File(path)) if path == Path::new("changes.ndjson")));
+
+        let (settings, _) = Settings::from_toml(r#"change_stream = "changes.ndjson""#).unwrap();
+        assert!(matches!(settings.to_options().unwrap().change_stream, Some(ChangeStream::File(_))));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_reported() {
+        let (settings, unknown) = Settings::from_toml(
+            r#"
+            batch_size = 10
+            batch_sise = 20
+            output_format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.batch_size, Some(10));
+        assert_eq!(unknown, vec!["batch_sise".to_string(), "output_format".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_config_values() {
+        assert!(Settings::from_toml("purge_finalized = \"sometimes\"").is_err());
+        assert!(Settings::from_toml("batch_size = \"many\"").is_err());
+        assert!(Settings::from_toml("base_currency = \"DOLLAR\"").is_err());
+        assert!(Settings::from_toml("dispute_client_check = \"lenient\"").is_err());
+
+        let (settings, _) = Settings::from_toml("batch_size = 0").unwrap();
+        assert!(settings.to_options().is_err());
+        assert!(cli(&["--output-partitions", "0"]).to_options().is_err());
+        assert!(cli(&["--auto-batch-size", "--min-batch-size", "500", "--max-batch-size", "100"]).to_options().is_err());
+
+        assert!(Settings::from_toml("delimiter = \";;\"").is_err());
+        let (settings, _) = Settings::from_toml("delimiter = \",\"\ndecimal_separator = \",\"").unwrap();
+        assert!(settings.to_options().is_err());
+    }
+
+    #[test]
+    fn test_delimiter_spellings() {
+        assert_eq!(cli(&["--delimiter", "\\t"]).delimiter, Some('\t'));
+        assert_eq!(cli(&["--delimiter", ";"]).to_options().unwrap().delimiter, ';');
+        assert!(Cli::try_parse_from(["payment-engine", "--delimiter", "tab"]).is_err());
+
+        // TOML escapes give a real tab; the CLI spelling is accepted too
+        let (settings, _) = Settings::from_toml("delimiter = \"\\t\"").unwrap();
+        assert_eq!(settings.delimiter, Some('\t'));
+        let (settings, _) = Settings::from_toml("delimiter = '\\t'\ndecimal_separator = \",\"").unwrap();
+        let options = settings.to_options().unwrap();
+        assert_eq!((options.delimiter, options.decimal_separator), ('\t', ','));
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let options = cli(&["--delimiter", ";", "--lenient-amounts", "--thousands-separator", " "]).to_options().unwrap();
+        assert!(options.lenient_amounts);
+        assert_eq!(options.thousands_separator, ' ');
+        assert!(!cli(&[]).to_options().unwrap().lenient_amounts);
+
+        let (settings, _) = Settings::from_toml("lenient_amounts = true\nthousands_separator = \"_\"").unwrap();
+        let options = settings.to_options().unwrap();
+        assert_eq!((options.lenient_amounts, options.thousands_separator), (true, '_'));
+        assert!(cli(&["--thousands-separator", "."]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_preset_under_explicit_settings() {
+        let options = cli(&["--preset", "lenient"]).to_options().unwrap();
+        assert!(options.lenient_amounts && options.allow_withdrawal_disputes);
+        assert_eq!(options.dispute_client_check, DisputeClientCheck::TrustOriginal);
+
+        // Explicit settings win, whichever layer they come from
+        let (config, _) = Settings::from_toml("preset = \"strict\"\namount_precision = \"truncate\"").unwrap();
+        let options = cli(&["--tolerate-repeated-headers"]).merge(config).to_options().unwrap();
+        assert_eq!(options.amount_precision, AmountPrecision::Truncate);
+        assert!(options.tolerate_repeated_headers && !options.lenient_amounts);
+        assert!(Settings::from_toml("preset = \"loose\"").is_err());
+    }
+
+    #[test]
+    fn test_create_accounts_on_reference() {
+        assert!(cli(&[]).to_options().unwrap().create_accounts_on_reference);
+        assert!(!cli(&["--preset", "strict"]).to_options().unwrap().create_accounts_on_reference);
+        assert!(!cli(&["--create-accounts-on-reference=false"]).to_options().unwrap().create_accounts_on_reference);
+        assert!(cli(&["--preset", "strict", "--create-accounts-on-reference"]).to_options().unwrap().create_accounts_on_reference);
+
+        let (settings, _) = Settings::from_toml("preset = \"lenient\"\ncreate_accounts_on_reference = false").unwrap();
+        assert!(!settings.to_options().unwrap().create_accounts_on_reference);
+    }
+
+    #[test]
+    fn test_dispute_expiry() {
+        let options = cli(&["--timestamps", "--dispute-expiry", "30d"]).to_options().unwrap();
+        assert!(options.timestamps);
+        assert_eq!(options.dispute_expiry, Some(Duration::from_secs(30 * 86_400)));
+        assert_eq!(cli(&["--dispute-expiry", "90"]).dispute_expiry, Some(Duration::from_secs(90)));
+        assert!(Cli::try_parse_from(["payment-engine", "--dispute-expiry", "1w"]).is_err());
+        assert!(cli(&["--dispute-expiry", "1h"]).to_options().is_err());
+
+        let (settings, _) = Settings::from_toml("timestamps = true\ndispute_expiry = \"12h\"").unwrap();
+        assert_eq!(settings.to_options().unwrap().dispute_expiry, Some(Duration::from_secs(12 * 3600)));
+    }
+
+    #[test]
+    fn test_follow() {
+        let options = cli(&["--follow", "--end-marker", "END"]).to_options().unwrap();
+        assert!(options.follow);
+        assert_eq!(options.end_marker, "END");
+        assert_eq!(cli(&["--follow"]).to_options().unwrap().end_marker, "#EOF");
+        assert!(cli(&["--follow", "--input-mode", "mmap"]).to_options().is_err());
+    }
+
+    #[test]
+    fn test_quiet_and_verbose() {
+        assert_eq!(cli(&[]).to_options().unwrap().verbosity, Verbosity::Normal);
+        assert_eq!(cli(&["-q"]).to_options().unwrap().verbosity, Verbosity::Quiet);
+        assert_eq!(cli(&["--verbose"]).to_options().unwrap().verbosity, Verbosity::Verbose);
+        assert!(Cli::try_parse_from(["payment-engine", "-q", "-v"]).is_err());
+
+        // Either flag overrides both keys of the config file
+        let (config, _) = Settings::from_toml("verbose = true").unwrap();
+        assert_eq!(cli(&["--quiet"]).merge(config.clone()).to_options().unwrap().verbosity, Verbosity::Quiet);
+        assert_eq!(cli(&[]).merge(config).to_options().unwrap().verbosity, Verbosity::Verbose);
+        let (config, _) = Settings::from_toml("quiet = true\nverbose = true").unwrap();
+        assert!(config.to_options().is_err());
+
+        // Turning one off leaves the other key alone
+        let (config, _) = Settings::from_toml("quiet = true").unwrap();
+        assert_eq!(cli(&["--quiet=false"]).merge(config.clone()).to_options().unwrap().verbosity, Verbosity::Normal);
+        assert_eq!(cli(&["--verbose=false"]).merge(config).to_options().unwrap().verbosity, Verbosity::Quiet);
+    }
+
+    #[test]
+    fn test_flags_turn_off_config_keys() {
+        let config = "auto_batch_size = true
+parallel_batches = true
+group_by_client = true
+follow = true
+tolerate_repeated_headers = true
+allow_partial_disputes = true
+";
+        let (config, _) = Settings::from_toml(config).unwrap();
+        let options = config.clone().to_options().unwrap();
+        assert!(matches!(options.batch_size_mode, BatchSizeMode::Auto { .. }));
+        assert!(options.parallel_batches && options.follow && options.tolerate_repeated_headers && options.allow_partial_disputes);
+
+        let flags = [
+            "--auto-batch-size=false",
+            "--parallel-batches=false",
+            "--group-by-client=false",
+            "--follow=false",
+            "--tolerate-repeated-headers=false",
+            "--allow-partial-disputes=false",
+        ];
+        let options = cli(&flags).merge(config).to_options().unwrap();
+        assert!(matches!(options.batch_size_mode, BatchSizeMode::Fixed(_)));
+        assert!(!options.parallel_batches && !options.group_by_client_in_batch && !options.follow);
+        assert!(!options.tolerate_repeated_headers && !options.allow_partial_disputes);
+
+        // A bare flag still turns it on
+        assert_eq!(cli(&["--follow"]).follow, Some(true));
+        assert_eq!(cli(&["--follow=true"]).follow, Some(true));
+        assert!(Cli::try_parse_from(["payment-engine", "--follow=maybe"]).is_err());
+    }
+}
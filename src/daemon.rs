@@ -0,0 +1,382 @@
+use crate::engine::PaymentEngine;
+use crate::error::{PaymentEngineError, Result};
+use crate::models::TransactionOutcome;
+use crate::processor::{create_engine, parse_line, write_accounts, LineFormat, ProcessingOptions};
+use crate::shared::SharedPaymentEngine;
+use crate::snapshot::write_atomically;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// A payment engine kept running between inputs, taking commands over a Unix
+/// domain socket.
+///
+/// Clients send newline-delimited lines: transactions in the input CSV
+/// format, or one of the commands `STATS`, `SNAPSHOT` and `SHUTDOWN`. Each
+/// line gets a one-line reply, in order:
+///
+/// - `OK` for an applied transaction or a header line
+/// - `REJECTED <reason>` for a transaction the engine refused
+/// - `ERROR <message>` for a line that could not be parsed or applied
+/// - `OK applied=<n> rejected=<n> errors=<n> accounts=<n> open_disputes=<n>`
+///   for `STATS`
+/// - `OK <rows>` for `SNAPSHOT`, followed by the accounts CSV header and that
+///   many rows
+///
+/// Transactions from all connections are applied one at a time, in the order
+/// they reach the engine, so a client's transactions should come from one
+/// connection. `SHUTDOWN` stops accepting lines on every connection, writes
+/// the final accounts and replies `OK` once they are written.
+///
+/// The engine rules in the options, such as limits, fees and dispute
+/// policies, apply as in a file run; options about reading or filtering an
+/// input file don't.
+pub struct Daemon {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    options: ProcessingOptions,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Asks a running [`Daemon`] to shut down, as the `SHUTDOWN` command does
+#[derive(Clone)]
+pub struct DaemonShutdown(watch::Sender<bool>);
+
+impl DaemonShutdown {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// State shared by the connections of a running daemon
+struct Service {
+    engine: SharedPaymentEngine,
+    options: Arc<ProcessingOptions>,
+    format: LineFormat,
+    shutdown: watch::Sender<bool>,
+    applied: AtomicUsize,
+    rejected: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Daemon {
+    /// Listen on `socket_path`. A socket file left behind by a daemon that is
+    /// no longer running is replaced; one with a daemon still listening is an
+    /// error. Must be called within a tokio runtime.
+    pub fn bind(socket_path: impl Into<PathBuf>, options: ProcessingOptions) -> Result<Self> {
+        options.validate()?;
+        let socket_path = socket_path.into();
+        let bind_error = |source| PaymentEngineError::BindSocket { path: socket_path.clone(), source };
+        if socket_path.exists() {
+            if std::os::unix::net::UnixStream::connect(&socket_path).is_ok() {
+                return Err(bind_error(io::Error::new(io::ErrorKind::AddrInUse, "a daemon is already listening")));
+            }
+            std::fs::remove_file(&socket_path).map_err(bind_error)?;
+        }
+        let listener = UnixListener::bind(&socket_path).map_err(bind_error)?;
+        info!("Listening on {:?}", socket_path);
+        Ok(Self { listener, socket_path, options, shutdown: watch::Sender::new(false) })
+    }
+
+    /// Handle to shut the daemon down from outside, e.g. on a signal
+    pub fn shutdown_handle(&self) -> DaemonShutdown {
+        DaemonShutdown(self.shutdown.clone())
+    }
+
+    /// Serve connections until shut down, then write the final accounts to
+    /// `output` if given, remove the socket file and return the engine
+    pub async fn run(self, output: Option<&Path>) -> Result<PaymentEngine> {
+        let (engine, task) = SharedPaymentEngine::spawn(create_engine(&self.options)?);
+        let service = Arc::new(Service {
+            engine,
+            format: LineFormat::new(&self.options, self.options.multi_currency),
+            options: Arc::new(self.options),
+            shutdown: self.shutdown,
+            applied: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        });
+
+        let mut stopping = service.shutdown.subscribe();
+        let mut connections = JoinSet::new();
+        let mut requesters = Vec::new();
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        connections.spawn(serve_connection(stream, service.clone()));
+                    }
+                    Err(e) => warn!("Failed to accept a connection: {}", e),
+                },
+                Some(finished) = connections.join_next() => requesters.extend(finished.ok().flatten()),
+                _ = stopping.wait_for(|&stop| stop) => break,
+            }
+        }
+
+        // Connections stop at their next line; let the ones mid-transaction finish
+        info!("Shutting down");
+        drop(self.listener);
+        while let Some(finished) = connections.join_next().await {
+            requesters.extend(finished.ok().flatten());
+        }
+        let service = Arc::into_inner(service).expect("every connection has finished");
+        let options = service.options;
+        drop(service.engine);
+        let engine = task.shutdown().await?;
+
+        let written = match output {
+            Some(path) => {
+                let mut contents = Vec::new();
+                write_accounts(&engine, &options, &mut contents)?;
+                write_atomically(path, &contents).map(|_| info!("Wrote final accounts to {:?}", path))
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = std::fs::remove_file(&self.socket_path) {
+            warn!("Failed to remove socket {:?}: {}", self.socket_path, e);
+        }
+        let reply = match &written {
+            Ok(()) => "OK\n".to_string(),
+            Err(e) => format!("ERROR {}\n", e),
+        };
+        for mut requester in requesters {
+            let _ = requester.write_all(reply.as_bytes()).await;
+            let _ = requester.flush().await;
+        }
+        written.map(|_| engine)
+    }
+}
+
+/// Answer a connection's lines until it closes or the daemon shuts down.
+/// Returns the writer of a connection that sent `SHUTDOWN`, to reply once
+/// the final accounts are written.
+async fn serve_connection(stream: UnixStream, service: Arc<Service>) -> Option<BufWriter<OwnedWriteHalf>> {
+    let (reader, writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut writer = BufWriter::new(writer);
+    let mut stopping = service.shutdown.subscribe();
+    let mut line_number = 0;
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = stopping.wait_for(|&stop| stop) => return None,
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to read from a connection: {}", e);
+                return None;
+            }
+        };
+        line_number += 1;
+
+        let reply = match line.trim() {
+            "SHUTDOWN" => {
+                service.shutdown.send_replace(true);
+                return Some(writer);
+            }
+            "STATS" => service.stats().await,
+            "SNAPSHOT" => service.snapshot().await,
+            _ => service.apply(line_number, &line).await,
+        };
+        let reply = reply.unwrap_or_else(|e| format!("ERROR {}\n", e));
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return None;
+        }
+        // Replies to pipelined lines go out together
+        if lines.get_ref().buffer().is_empty() && writer.flush().await.is_err() {
+            return None;
+        }
+    }
+}
+
+impl Service {
+    async fn apply(&self, line_number: usize, line: &str) -> Result<String> {
+        if line_number == 1 && self.format.is_header(line) {
+            return Ok("OK\n".to_string());
+        }
+        let outcome = match parse_line(line_number, line, self.format) {
+            Ok(transaction) => self.engine.submit(transaction).await,
+            Err(e) => Err(e),
+        };
+        match outcome {
+            Ok(TransactionOutcome::Applied) => {
+                self.applied.fetch_add(1, Ordering::Relaxed);
+                Ok("OK\n".to_string())
+            }
+            Ok(outcome) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Ok(format!("REJECTED {}\n", outcome.rejection_reason().unwrap_or_default()))
+            }
+            Err(PaymentEngineError::EngineStopped) => Err(PaymentEngineError::EngineStopped),
+            Err(e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                Ok(format!("ERROR {}\n", e))
+            }
+        }
+    }
+
+    async fn stats(&self) -> Result<String> {
+        let (accounts, open_disputes) =
+            self.engine.inspect(|engine| (engine.account_count(), engine.open_dispute_count())).await?;
+        Ok(format!(
+            "OK applied={} rejected={} errors={} accounts={} open_disputes={}\n",
+            self.applied.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            accounts,
+            open_disputes,
+        ))
+    }
+
+    async fn snapshot(&self) -> Result<String> {
+        let options = self.options.clone();
+        let csv = self
+            .engine
+            .inspect(move |engine| {
+                let mut csv = Vec::new();
+                write_accounts(engine, &options, &mut csv).map(|_| csv)
+            })
+            .await??;
+        let csv = String::from_utf8_lossy(&csv);
+        Ok(format!("OK {}\n{}", csv.lines().count().saturating_sub(1), csv))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncBufReadExt, Lines};
+    use tokio::net::unix::OwnedReadHalf;
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(path: &Path) -> Self {
+            let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+            Self { lines: BufReader::new(reader).lines(), writer }
+        }
+
+        async fn send(&mut self, lines: &str) {
+            self.writer.write_all(lines.as_bytes()).await.unwrap();
+        }
+
+        async fn reply(&mut self) -> String {
+            self.lines.next_line().await.unwrap().unwrap()
+        }
+
+        async fn request(&mut self, line: &str) -> String {
+            self.send(&format!("{}\n", line)).await;
+            self.reply().await
+        }
+    }
+
+    fn spawn_daemon(socket: &Path, output: Option<PathBuf>) -> (DaemonShutdown, tokio::task::JoinHandle<Result<PaymentEngine>>) {
+        let daemon = Daemon::bind(socket, ProcessingOptions::default()).unwrap();
+        let shutdown = daemon.shutdown_handle();
+        (shutdown, tokio::spawn(async move { daemon.run(output.as_deref()).await }))
+    }
+
+    #[tokio::test]
+    async fn test_replies_follow_framing() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("engine.sock");
+        let output = dir.path().join("accounts.csv");
+        let (_, daemon) = spawn_daemon(&socket, Some(output.clone()));
+
+        let mut client = Client::connect(&socket).await;
+        // Pipelined lines, the last one split over two writes
+        client.send("type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,25.0\ndepo").await;
+        client.send("sit,2,3,5\n").await;
+        assert_eq!(client.reply().await, "OK");
+        assert_eq!(client.reply().await, "OK");
+        assert_eq!(client.reply().await, "REJECTED insufficient_funds");
+        assert_eq!(client.reply().await, "OK");
+
+        assert!(client.request("bogus").await.starts_with("ERROR "));
+        assert!(client.request("deposit,1,4").await.starts_with("ERROR "));
+        assert!(client.request("").await.starts_with("ERROR "));
+        // A header after the first line is a malformed transaction
+        assert!(client.request("type,client,tx,amount").await.starts_with("ERROR "));
+        assert_eq!(client.request("dispute,1,1,").await, "OK");
+        assert_eq!(client.request("STATS").await, "OK applied=3 rejected=1 errors=4 accounts=2 open_disputes=1");
+
+        assert_eq!(client.request("SNAPSHOT").await, "OK 2");
+        assert_eq!(client.reply().await, "client,available,held,total,locked");
+        let mut rows = vec![client.reply().await, client.reply().await];
+        rows.sort();
+        assert_eq!(rows, ["1,0.0,10.0,10.0,false", "2,5,0,5,false"]);
+
+        assert_eq!(client.request("SHUTDOWN").await, "OK");
+        let engine = daemon.await.unwrap().unwrap();
+        assert_eq!(engine.get_account(1).unwrap().held, rust_decimal_macros::dec!(10));
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert!(written.starts_with("client,available,held,total,locked\n"));
+        assert_eq!(written.lines().count(), 3);
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_connections() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("engine.sock");
+        let (shutdown, daemon) = spawn_daemon(&socket, None);
+
+        let clients: Vec<_> = (1..=8u16)
+            .map(|client| {
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    let mut connection = Client::connect(&socket).await;
+                    for i in 0..100u32 {
+                        let tx = u32::from(client) * 1_000 + i;
+                        assert_eq!(connection.request(&format!("deposit,{},{},1.5", client, tx)).await, "OK");
+                    }
+                    assert_eq!(connection.request(&format!("withdrawal,{},{},50", client, u32::from(client) * 1_000 + 999)).await, "OK");
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        let mut client = Client::connect(&socket).await;
+        assert_eq!(client.request("STATS").await, "OK applied=808 rejected=0 errors=0 accounts=8 open_disputes=0");
+
+        // Shutting down from outside closes idle connections
+        shutdown.shutdown();
+        let engine = daemon.await.unwrap().unwrap();
+        assert_eq!(client.lines.next_line().await.unwrap(), None);
+        for client in 1..=8 {
+            assert_eq!(engine.get_account(client).unwrap().total, rust_decimal_macros::dec!(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_socket() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("engine.sock");
+        std::fs::write(&socket, "").unwrap();
+        let (_, daemon) = spawn_daemon(&socket, None);
+
+        assert!(matches!(
+            Daemon::bind(&socket, ProcessingOptions::default()),
+            Err(PaymentEngineError::BindSocket { .. })
+        ));
+
+        let mut client = Client::connect(&socket).await;
+        assert_eq!(client.request("SHUTDOWN").await, "OK");
+        assert_eq!(daemon.await.unwrap().unwrap().account_count(), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::error::{PaymentEngineError, Result};
+use crate::models::{Account, IdMap};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Read accounts from a CSV in the output format
+/// (`client,available,held,total,locked`). Comment lines starting with `#`,
+/// such as the timing line at the top of the output, are skipped.
+pub fn read_accounts<R: io::Read>(reader: R) -> Result<Vec<Account>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut accounts = Vec::new();
+    for (i, record) in reader.deserialize().enumerate() {
+        // Rows are numbered from the first one after the header
+        accounts.push(record.map_err(|source| PaymentEngineError::InvalidAccountRow { row: i + 1, source })?);
+    }
+    Ok(accounts)
+}
+
+/// Read accounts from an output CSV file, see [`read_accounts`]
+pub fn read_accounts_from_path(path: &Path) -> Result<Vec<Account>> {
+    let file = std::fs::File::open(path)
+        .map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?;
+    read_accounts(io::BufReader::new(file))
+}
+
+/// An account present in both files whose state differs
+#[derive(Debug, Clone)]
+pub struct AccountChange {
+    pub old: Account,
+    pub new: Account,
+}
+
+impl AccountChange {
+    pub fn client(&self) -> u16 {
+        self.new.client
+    }
+
+    pub fn available_delta(&self) -> Decimal {
+        self.new.available - self.old.available
+    }
+
+    pub fn held_delta(&self) -> Decimal {
+        self.new.held - self.old.held
+    }
+
+    pub fn total_delta(&self) -> Decimal {
+        self.new.total - self.old.total
+    }
+
+    pub fn lock_changed(&self) -> bool {
+        self.old.locked != self.new.locked
+    }
+}
+
+/// Differences between two sets of accounts, each list in client order
+#[derive(Debug, Clone, Default)]
+pub struct AccountsDiff {
+    /// Clients only present in the old accounts
+    pub only_in_old: Vec<u16>,
+    /// Clients only present in the new accounts
+    pub only_in_new: Vec<u16>,
+    /// Clients whose balances or lock state differ
+    pub changed: Vec<AccountChange>,
+}
+
+impl AccountsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_old.is_empty() && self.only_in_new.is_empty() && self.changed.is_empty()
+    }
+
+    /// Number of clients that differ
+    pub fn len(&self) -> usize {
+        self.only_in_old.len() + self.only_in_new.len() + self.changed.len()
+    }
+
+    /// Display the differences naming the two sides `old` and `new`, e.g.
+    /// "expected" and "actual"; the plain `Display` uses "old" and "new"
+    pub fn labeled<'a>(&'a self, old: &'a str, new: &'a str) -> impl fmt::Display + 'a {
+        LabeledDiff { diff: self, old, new }
+    }
+}
+
+/// An [`AccountsDiff`] displayed with names for its two sides
+struct LabeledDiff<'a> {
+    diff: &'a AccountsDiff,
+    old: &'a str,
+    new: &'a str,
+}
+
+/// Compare two sets of accounts by client.
+///
+/// Balances are compared at the 4 decimal places of the output, and deltas
+/// no larger than `tolerance` are ignored.
+pub fn diff_accounts(old: &[Account], new: &[Account], tolerance: Decimal) -> AccountsDiff {
+    let old_by_client: IdMap<u16, &Account> = old.iter().map(|a| (a.client, a)).collect();
+    let new_by_client: IdMap<u16, &Account> = new.iter().map(|a| (a.client, a)).collect();
+    let differs = |a: Decimal, b: Decimal| (a.round_dp(4) - b.round_dp(4)).abs() > tolerance;
+
+    let mut diff = AccountsDiff::default();
+    for (client, old_account) in &old_by_client {
+        let Some(new_account) = new_by_client.get(client) else {
+            diff.only_in_old.push(*client);
+            continue;
+        };
+        if differs(old_account.available, new_account.available)
+            || differs(old_account.held, new_account.held)
+            || differs(old_account.total, new_account.total)
+            || old_account.locked != new_account.locked
+        {
+            diff.changed.push(AccountChange { old: (*old_account).clone(), new: (*new_account).clone() });
+        }
+    }
+    diff.only_in_new = new_by_client.keys().filter(|client| !old_by_client.contains_key(client)).copied().collect();
+
+    diff.only_in_old.sort_unstable();
+    diff.only_in_new.sort_unstable();
+    diff.changed.sort_by_key(AccountChange::client);
+    diff
+}
+
+impl fmt::Display for AccountsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.labeled("old", "new").fmt(f)
+    }
+}
+
+impl fmt::Display for LabeledDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for client in &self.diff.only_in_old {
+            writeln!(f, "client {}: only in {}", client, self.old)?;
+        }
+        for client in &self.diff.only_in_new {
+            writeln!(f, "client {}: only in {}", client, self.new)?;
+        }
+        for change in &self.diff.changed {
+            write!(f, "client {}:", change.client())?;
+            let balances = [
+                ("available", change.old.available, change.new.available, change.available_delta()),
+                ("held", change.old.held, change.new.held, change.held_delta()),
+                ("total", change.old.total, change.new.total, change.total_delta()),
+            ];
+            for (name, old, new, delta) in balances {
+                if !delta.is_zero() {
+                    write!(f, " {} {} -> {} ({:+})", name, old.round_dp(4), new.round_dp(4), delta.round_dp(4))?;
+                }
+            }
+            if change.lock_changed() {
+                write!(f, " locked {} -> {}", change.old.locked, change.new.locked)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn account(client: u16, available: Decimal, held: Decimal, locked: bool) -> Account {
+        Account { client, available, held, total: available + held, locked, ..Default::default() }
+    }
+
+    #[test]
+    fn test_read_accounts_skips_timing_comment() {
+        let csv = "# Processing completed in 1.20ms\n\
+                   client,available,held,total,locked\n\
+                   1,74.5,0,74.5,false\n\
+                   2, 0.0000 ,50.1234,50.1234,true\n";
+        let accounts = read_accounts(csv.as_bytes()).unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].client, 2);
+        assert_eq!(accounts[1].held, dec!(50.1234));
+        assert!(accounts[1].locked);
+
+        let err = read_accounts("client,available,held,total,locked\n1,abc,0,0,false\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidAccountRow { row: 1, .. }));
+        let err = read_accounts_from_path(Path::new("missing-accounts.csv")).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::OpenFile { .. }));
+    }
+
+    #[test]
+    fn test_missing_clients() {
+        let old = [account(1, dec!(1), dec!(0), false), account(2, dec!(2), dec!(0), false)];
+        let new = [account(2, dec!(2), dec!(0), false), account(3, dec!(3), dec!(0), false)];
+        let diff = diff_accounts(&old, &new, Decimal::ZERO);
+
+        assert_eq!(diff.only_in_old, [1]);
+        assert_eq!(diff.only_in_new, [3]);
+        assert!(diff.changed.is_empty());
+        assert!(!diff.is_empty());
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff.to_string(), "client 1: only in old\nclient 3: only in new\n");
+        assert_eq!(
+            diff.labeled("expected", "actual").to_string(),
+            "client 1: only in expected\nclient 3: only in actual\n"
+        );
+    }
+
+    #[test]
+    fn test_numeric_deltas_and_tolerance() {
+        let old = [account(1, dec!(10), dec!(5), false), account(2, dec!(1.00001), dec!(0), false)];
+        let new = [account(1, dec!(12.5), dec!(5), false), account(2, dec!(1), dec!(0), false)];
+
+        // Client 2 only differs beyond the output precision
+        let diff = diff_accounts(&old, &new, Decimal::ZERO);
+        assert_eq!(diff.changed.len(), 1);
+        let change = &diff.changed[0];
+        assert_eq!(change.client(), 1);
+        assert_eq!(change.available_delta(), dec!(2.5));
+        assert_eq!(change.held_delta(), dec!(0));
+        assert_eq!(change.total_delta(), dec!(2.5));
+        assert_eq!(diff.to_string(), "client 1: available 10 -> 12.5 (+2.5) total 15 -> 17.5 (+2.5)\n");
+
+        assert!(diff_accounts(&old, &new, dec!(2.5)).is_empty());
+        assert!(!diff_accounts(&old, &new, dec!(2.4999)).is_empty());
+    }
+
+    #[test]
+    fn test_lock_state_change() {
+        let old = [account(1, dec!(10), dec!(0), false)];
+        let new = [account(1, dec!(10), dec!(0), true)];
+        let diff = diff_accounts(&old, &new, dec!(100));
+
+        assert_eq!(diff.changed.len(), 1);
+        assert!(diff.changed[0].lock_changed());
+        assert_eq!(diff.to_string(), "client 1: locked false -> true\n");
+        assert!(diff_accounts(&old, &old, Decimal::ZERO).is_empty());
+    }
+}
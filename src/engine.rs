@@ -1,534 +1,3377 @@
-use crate::error::PaymentEngineError;
-use crate::models::{Account, AccountStore, Transaction, TransactionStore, TransactionType};
-use anyhow::Result;
-use tracing::{debug, info, warn, error};
+use crate::error::{PaymentEngineError, Result};
+use crate::events::{EngineEvent, EventHandler};
+use crate::history::AuditEvent;
+use crate::metrics;
+use crate::models::{
+    Account, AccountStats, AccountStore, Currency, DeferredDispute, DisputeClientCheck, IdMap, IdSet, Limit, Limits, LockReason, PurgePolicy, Transaction, TransactionOutcome,
+    TransactionState, TransactionStore, TransactionType, TxId, WithdrawalFee,
+};
+use rust_decimal::Decimal;
+use std::collections::hash_map::Entry;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, trace, warn, error};
+
+/// What happened to each transaction of a batch, in the order they were given
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Id and outcome of each transaction. An error is a transaction the
+    /// engine couldn't process at all, e.g. a deposit without an amount.
+    pub outcomes: Vec<(TxId, Result<TransactionOutcome>)>,
+    // The consumed batch, emptied, so its allocation can be reused
+    buffer: Vec<Transaction>,
+}
+
+impl BatchResult {
+    /// Transactions that were applied
+    pub fn applied(&self) -> usize {
+        self.outcomes.iter().filter(|(_, outcome)| matches!(outcome, Ok(TransactionOutcome::Applied))).count()
+    }
+
+    /// Transactions the engine rejected, e.g. for insufficient funds
+    pub fn rejected(&self) -> usize {
+        self.outcomes.iter().filter(|(_, outcome)| outcome.as_ref().is_ok_and(|o| o.rejection_reason().is_some())).count()
+    }
+
+    /// Transactions that failed, with their errors
+    pub fn errors(&self) -> impl Iterator<Item = (TxId, &PaymentEngineError)> {
+        self.outcomes.iter().filter_map(|(tx, outcome)| outcome.as_ref().err().map(|e| (*tx, e)))
+    }
+
+    /// The batch's vector, empty but with its capacity, to fill with the next batch
+    pub fn into_buffer(self) -> Vec<Transaction> {
+        self.buffer
+    }
+}
+
+/// Client of each deposit and withdrawal of a batch, by tx id
+fn batch_clients(batch: &[Transaction]) -> IdMap<TxId, u16> {
+    let mut clients = IdMap::default();
+    for transaction in batch {
+        if matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+            clients.entry(transaction.tx).or_insert(transaction.client);
+        }
+    }
+    clients
+}
+
+/// Whether a deposit or withdrawal of the batch reuses the id of another
+/// client's, which only the one applied second is rejected for
+fn reuses_tx_id_across_clients(batch: &[Transaction]) -> bool {
+    let mut clients: IdMap<TxId, u16> = IdMap::default();
+    batch
+        .iter()
+        .filter(|transaction| matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal))
+        .any(|transaction| *clients.entry(transaction.tx).or_insert(transaction.client) != transaction.client)
+}
+
+/// Counts of what an engine holds and has done, see [`PaymentEngine::stats`].
+///
+/// Displays as a one-line summary for logs, e.g. `42,100 tx applied (40,000
+/// deposits, 2,000 withdrawals), 15 disputes open, 3 accounts locked, 7 rejects`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct EngineStats {
+    /// Transactions applied, of every type
+    pub transactions_applied: usize,
+    pub deposits_applied: usize,
+    pub withdrawals_applied: usize,
+    pub disputes_open: usize,
+    pub accounts_locked: usize,
+    /// Transactions rejected or failed
+    pub transactions_rejected: usize,
+}
+
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tx applied ({} deposits, {} withdrawals), {} disputes open, {} accounts locked, {} rejects",
+            grouped(self.transactions_applied),
+            grouped(self.deposits_applied),
+            grouped(self.withdrawals_applied),
+            grouped(self.disputes_open),
+            grouped(self.accounts_locked),
+            grouped(self.transactions_rejected)
+        )
+    }
+}
+
+/// A count with its thousands separated by commas
+fn grouped(count: usize) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Running counts behind [`EngineStats`], updated as transactions are applied
+/// so the stats never walk the stores
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    applied: usize,
+    deposits: usize,
+    withdrawals: usize,
+    locked_accounts: usize,
+    withdrawal_disputes_rejected: usize,
+}
+
+impl Counters {
+    fn add(&mut self, other: Counters) {
+        self.applied += other.applied;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.locked_accounts += other.locked_accounts;
+        self.withdrawal_disputes_rejected += other.withdrawal_disputes_rejected;
+    }
+}
 
 /// The payment engine that processes transactions
 pub struct PaymentEngine {
     accounts: AccountStore,
     transactions: TransactionStore,
+    purge_policy: PurgePolicy,
+    // Per-client audit trail, only kept when history recording is enabled
+    history: Option<IdMap<u16, Vec<AuditEvent>>>,
+    // Outcome of the transaction being processed, if it was rejected
+    last_rejection: Option<TransactionOutcome>,
+    // Transactions rejected or failed so far
+    rejected_transactions: usize,
+    counters: Counters,
+    limits: Limits,
+    // Withdrawals applied per client, only counted when a per-client limit is set
+    withdrawal_counts: IdMap<u16, u32>,
+    // Transactions rejected by a limit, per client
+    limit_rejections: IdMap<u16, usize>,
+    withdrawal_fee: WithdrawalFee,
+    // Whether a dispute's amount may contest part of the original transaction
+    allow_partial_disputes: bool,
+    // Whether withdrawals can be disputed, not just deposits
+    allow_withdrawal_disputes: bool,
+    // Disputes per client queued when their hold fails; `None` rejects them
+    max_deferred_disputes: Option<usize>,
+    // Whether admin transactions such as amendments are applied
+    allow_admin_transactions: bool,
+    // Whether a dispute, resolve or chargeback of a client without an
+    // account creates an empty one
+    create_accounts_on_reference: bool,
+    dispute_client_check: DisputeClientCheck,
+    // How long a timed dispute may stay open before it is resolved
+    dispute_expiry: Option<Duration>,
+    // Latest transaction timestamp seen, the time disputes expire against
+    latest_timestamp: Option<u64>,
+    // Highest tx id seen, including rejected transactions
+    max_tx_id: Option<TxId>,
+    // Clients whose account changed since they were last taken, only kept
+    // when change tracking is enabled
+    changed_clients: Option<IdSet<u16>>,
+    // Clients loaded with `load_accounts` that no transaction was applied to since
+    untouched: IdSet<u16>,
+    // Currency of transactions without one; transactions in it use the base balance
+    base_currency: Option<Currency>,
+    // Withdrawal fees charged, per client
+    fees: IdMap<u16, Decimal>,
+    // Dispute activity, per client
+    account_stats: IdMap<u16, AccountStats>,
+    event_handler: Option<EventHandler>,
+    // State from before the batch being applied, while it can be rolled back
+    journal: Option<Journal>,
+    // A committed journal emptied for the next batch, so tiny batches don't
+    // allocate a fresh one each time
+    spare_journal: Option<Journal>,
+    // Panics after applying this transaction, to test recovering from a panic
+    #[cfg(test)]
+    pub(crate) panic_after_tx: Option<TxId>,
+}
+
+/// What a batch's transactions changed, saved the first time each client or
+/// transaction is touched so the batch can be undone
+struct Journal {
+    rejected_transactions: usize,
+    counters: Counters,
+    clients: IdMap<u16, ClientState>,
+    // Ids of the journaled transactions; those without an entry in
+    // `entries` weren't stored before the batch
+    tx_ids: IdSet<TxId>,
+    entries: TransactionStore,
+}
+
+/// A client's account and counters as they were before a batch
+struct ClientState {
+    account: Option<Account>,
+    withdrawal_count: Option<u32>,
+    limit_rejections: Option<usize>,
+    fees: Option<Decimal>,
+    stats: Option<AccountStats>,
+    history_len: usize,
+    untouched: bool,
+}
+
+/// A state change of a dispute, resolve or chargeback. Handlers list the
+/// changes a transaction makes rather than making them one by one, and
+/// [`PaymentEngine::apply_effects`] makes all of them or none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Effect {
+    /// Move funds within the client's balance in the transaction's currency
+    Funds(FundsMove),
+    /// Set the amount of the transaction under dispute; zero ends the dispute
+    Disputed(Decimal),
+    /// Record that the transaction's dispute was resolved
+    Resolved,
+    /// Record that the transaction's dispute ended in a chargeback
+    ChargedBack,
+    /// Lock the account, unless it is locked already
+    Lock(LockReason),
+    /// Drop the transaction from the store, keeping only its id
+    Finalize,
+}
+
+/// A movement of funds within one balance of an account
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FundsMove {
+    /// From available to held, for a dispute
+    Hold(Decimal),
+    /// From held back to available, for a resolve
+    Release(Decimal),
+    /// Held funds removed, for a chargeback of a deposit
+    Chargeback(Decimal),
+    /// Funds credited to available, for a chargeback of a withdrawal
+    Credit(Decimal),
+}
+
+impl FundsMove {
+    /// Make the move, or return `false` leaving the account unchanged if its
+    /// balance can't cover it
+    fn apply(self, account: &mut Account) -> bool {
+        match self {
+            FundsMove::Hold(amount) => account.hold(amount),
+            FundsMove::Release(amount) => account.release(amount),
+            FundsMove::Chargeback(amount) => account.chargeback(amount),
+            FundsMove::Credit(amount) => account.chargeback_credit(amount),
+        }
+    }
+}
+
+/// Put back a client's saved value, or remove the client's entry if it had none
+fn restore<V>(map: &mut IdMap<u16, V>, client: u16, saved: Option<V>) {
+    match saved {
+        Some(value) => map.insert(client, value),
+        None => map.remove(&client),
+    };
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PaymentEngine {
     pub fn new() -> Self {
+        Self::with_stores(AccountStore::new(), TransactionStore::new())
+    }
+
+    /// Create an engine whose account and transaction stores are pre-sized
+    /// for the expected numbers of clients and stored transactions. Only
+    /// saves the maps' rehashing as they grow; behaves exactly like [`new`](Self::new).
+    /// Clients are capped at 65,536, one per id, and a transaction count too
+    /// large to allocate for is ignored.
+    pub fn with_capacity(expected_clients: usize, expected_transactions: usize) -> Self {
+        Self::with_stores(AccountStore::with_capacity(expected_clients), TransactionStore::with_capacity(expected_transactions))
+    }
+
+    /// Create an engine around an existing transaction store, e.g. one that spills to disk
+    pub fn with_transaction_store(transactions: TransactionStore) -> Self {
+        Self::with_stores(AccountStore::new(), transactions)
+    }
+
+    pub(crate) fn with_stores(accounts: AccountStore, transactions: TransactionStore) -> Self {
         Self {
-            accounts: AccountStore::new(),
-            transactions: TransactionStore::new(),
+            accounts,
+            transactions,
+            purge_policy: PurgePolicy::Never,
+            history: None,
+            last_rejection: None,
+            rejected_transactions: 0,
+            counters: Counters::default(),
+            limits: Limits::default(),
+            withdrawal_counts: IdMap::default(),
+            limit_rejections: IdMap::default(),
+            withdrawal_fee: WithdrawalFee::None,
+            allow_partial_disputes: false,
+            allow_withdrawal_disputes: false,
+            max_deferred_disputes: None,
+            allow_admin_transactions: false,
+            create_accounts_on_reference: true,
+            dispute_client_check: DisputeClientCheck::Strict,
+            dispute_expiry: None,
+            latest_timestamp: None,
+            max_tx_id: None,
+            changed_clients: None,
+            untouched: IdSet::default(),
+            base_currency: None,
+            fees: IdMap::default(),
+            account_stats: IdMap::default(),
+            event_handler: None,
+            journal: None,
+            spare_journal: None,
+            #[cfg(test)]
+            panic_after_tx: None,
         }
     }
 
-    /// Process a batch of transactions
-    pub async fn process_transaction_batch(&mut self, transactions: &mut Vec<Transaction>) -> Result<()> {
-        debug!("Processing batch of {} transactions", transactions.len());
-        
-        // Process each transaction in the batch
-        let mut tx_ids = Vec::with_capacity(transactions.len());
-        for transaction in transactions.drain(..) {
-            tx_ids.push(transaction.tx);
-            if let Err(e) = self.process_transaction(transaction).await {
-                // Log the error but continue processing other transactions
-                error!("Error processing transaction: {}", e);
-            }
+    /// Set when finalized transactions are dropped from the store
+    pub fn set_purge_policy(&mut self, purge_policy: PurgePolicy) {
+        self.purge_policy = purge_policy;
+    }
+
+    /// Record an audit event per client for every transaction applied or
+    /// rejected from now on. Off by default since the history grows with the input.
+    pub fn set_record_history(&mut self, record_history: bool) {
+        match (record_history, self.history.is_some()) {
+            (true, false) => self.history = Some(IdMap::default()),
+            (false, true) => self.history = None,
+            _ => {}
         }
-        
-        Ok(())
     }
 
-    /// Process a single transaction
-    pub async fn process_transaction(&mut self, transaction: Transaction) -> Result<()> {
-        debug!(
-            "Processing transaction: type={:?}, client={}, tx={}, amount={:?}",
-            transaction.transaction_type, transaction.client, transaction.tx, transaction.amount
-        );
+    /// Track the clients whose account a transaction changes or creates from
+    /// now on, to be taken with [`take_changed_clients`](Self::take_changed_clients).
+    /// Off by default.
+    pub fn set_track_changes(&mut self, track_changes: bool) {
+        match (track_changes, self.changed_clients.is_some()) {
+            (true, false) => self.changed_clients = Some(IdSet::default()),
+            (false, true) => self.changed_clients = None,
+            _ => {}
+        }
+    }
 
-        // Client accounts are locked and can't process further transactions
-        let account = self.accounts.get_or_create_account(transaction.client);
-        if account.locked && transaction.transaction_type != TransactionType::Dispute {
-            warn!("Account {} is locked, ignoring transaction", transaction.client);
-            return Ok(());
+    /// Clients whose account changed since the last call, in ascending order,
+    /// clearing the set. Empty unless change tracking is enabled.
+    pub fn take_changed_clients(&mut self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.changed_clients.as_mut().map_or_else(Vec::new, |changed| changed.drain().collect());
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Whether the client's account was loaded with
+    /// [`load_accounts`](Self::load_accounts) and no transaction was applied
+    /// to it since. Rejected transactions leave it untouched.
+    pub fn is_untouched(&self, client: u16) -> bool {
+        self.untouched.contains(&client)
+    }
+
+    /// Note that a client's account changed, if changes are tracked
+    fn mark_changed(&mut self, client: u16) {
+        if let Some(changed) = self.changed_clients.as_mut() {
+            changed.insert(client);
         }
+    }
 
-        match transaction.transaction_type {
-            TransactionType::Deposit => self.handle_deposit(transaction).await?,
-            TransactionType::Withdrawal => self.handle_withdrawal(transaction).await?,
-            TransactionType::Dispute => self.handle_dispute(transaction).await?,
-            TransactionType::Resolve => self.handle_resolve(transaction).await?,
-            TransactionType::Chargeback => self.handle_chargeback(transaction).await?,
+    /// Register a callback that receives an [`EngineEvent`] whenever a
+    /// transaction is applied or rejected, replacing any previous one
+    pub fn set_event_handler(&mut self, handler: Box<dyn Fn(EngineEvent) + Send + Sync>) {
+        self.event_handler = Some(EventHandler::from(handler));
+    }
+
+    /// Send an event to the registered handler, if any
+    fn emit(&self, event: EngineEvent) {
+        if let Some(handler) = &self.event_handler {
+            handler(event);
         }
+    }
 
-        Ok(())
+    /// Audit trail of a client, oldest first. Empty unless history recording is enabled.
+    pub fn account_history(&self, client: u16) -> &[AuditEvent] {
+        self.history
+            .as_ref()
+            .and_then(|history| history.get(&client))
+            .map_or(&[], Vec::as_slice)
     }
 
-    /// Handle a deposit transaction
-    async fn handle_deposit(&mut self, tx: Transaction) -> Result<()> {
-        let amount = tx.amount.ok_or_else(|| {
-            PaymentEngineError::MissingAmount(tx.tx)
-        })?;
+    /// Number of transactions rejected or failed so far
+    pub fn rejected_transactions(&self) -> usize {
+        self.rejected_transactions
+    }
 
-        let account = self.accounts.get_or_create_account(tx.client);
-        account.deposit(amount);
+    /// Counts of the transactions applied and rejected so far, the open
+    /// disputes and the locked accounts, from counters kept as they change
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            transactions_applied: self.counters.applied,
+            deposits_applied: self.counters.deposits,
+            withdrawals_applied: self.counters.withdrawals,
+            disputes_open: self.transactions.disputed_count(),
+            accounts_locked: self.counters.locked_accounts,
+            transactions_rejected: self.rejected_transactions,
+        }
+    }
 
-        // Store transaction for potential future disputes
-        self.transactions.add_transaction(tx);
+    /// Highest transaction id seen so far, whether the transaction was
+    /// applied or rejected, e.g. to allocate new ids above. A processing run
+    /// also counts the ids of rows it skipped or failed to parse, as long as
+    /// their tx field parsed. Rolling back a batch doesn't lower it.
+    pub fn max_tx_id(&self) -> Option<TxId> {
+        self.max_tx_id
+    }
 
-        Ok(())
+    /// Count a transaction id seen without applying its transaction
+    pub(crate) fn observe_tx_id(&mut self, tx: Option<TxId>) {
+        self.max_tx_id = self.max_tx_id.max(tx);
     }
 
-    /// Handle a withdrawal transaction
-    async fn handle_withdrawal(&mut self, tx: Transaction) -> Result<()> {
-        let amount = tx.amount.ok_or_else(|| {
-            PaymentEngineError::MissingAmount(tx.tx)
-        })?;
+    /// Set the limits checked before deposits and withdrawals are applied
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
 
-        let account = self.accounts.get_or_create_account(tx.client);
-        
-        if !account.has_sufficient_funds(amount) {
-            warn!("Insufficient funds for withdrawal: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
-            return Ok(());
-        }
+    /// Set the fee charged on each withdrawal
+    pub fn set_withdrawal_fee(&mut self, withdrawal_fee: WithdrawalFee) {
+        self.withdrawal_fee = withdrawal_fee;
+    }
 
-        account.withdraw(amount);
-        
-        // Store transaction for potential future disputes
-        self.transactions.add_transaction(tx);
+    /// Let disputes with an amount hold only that part of the original
+    /// transaction. Otherwise a dispute's amount is ignored and the whole
+    /// transaction is disputed.
+    pub fn set_allow_partial_disputes(&mut self, allow_partial_disputes: bool) {
+        self.allow_partial_disputes = allow_partial_disputes;
+    }
 
-        Ok(())
+    /// Let withdrawals be disputed. Nothing is held for a withdrawal dispute,
+    /// since the funds have left the account; a resolve leaves the withdrawal
+    /// standing and a chargeback credits the withdrawn amount back and locks
+    /// the account. Otherwise disputes of withdrawals are rejected.
+    pub fn set_allow_withdrawal_disputes(&mut self, allow_withdrawal_disputes: bool) {
+        self.allow_withdrawal_disputes = allow_withdrawal_disputes;
     }
 
-    /// Handle a dispute transaction
-    async fn handle_dispute(&mut self, tx: Transaction) -> Result<()> {
-        // Get the original transaction
-        let orig_tx = match self.transactions.get_transaction(tx.tx) {
-            Some(t) => t,
-            None => {
-                warn!("Transaction not found for dispute: tx={}", tx.tx);
-                return Ok(());
-            }
-        };
+    /// Queue up to `max` disputes per client whose funds can't be held yet
+    /// instead of rejecting them as `hold_failed`. A deferred dispute holds
+    /// its funds, oldest first, as soon as a later transaction of the client
+    /// leaves enough available, e.g. a deposit or a resolve. Until then a
+    /// resolve or chargeback of it is rejected as `dispute_deferred`.
+    pub fn set_max_deferred_disputes(&mut self, max: Option<usize>) {
+        self.max_deferred_disputes = max;
+    }
 
-        // Ensure the client matches
-        if orig_tx.client != tx.client {
-            warn!(
-                "Client mismatch for dispute: original={}, dispute={}",
-                orig_tx.client, tx.client
-            );
-            return Ok(());
-        }
+    /// Apply admin transactions: amendments correcting the amount of an
+    /// earlier deposit. Otherwise they are rejected as `admin_not_allowed`.
+    pub fn set_allow_admin_transactions(&mut self, allow_admin_transactions: bool) {
+        self.allow_admin_transactions = allow_admin_transactions;
+    }
 
-        // Ensure it's a transaction that can be disputed (deposit)
-        if orig_tx.transaction_type != TransactionType::Deposit {
-            warn!(
-                "Cannot dispute non-deposit transaction: tx={}, type={:?}",
-                tx.tx, orig_tx.transaction_type
-            );
-            return Ok(());
-        }
+    /// Let a dispute, resolve or chargeback create the empty account of a
+    /// client that has none, as every transaction did before. Otherwise it is
+    /// rejected as `unknown_client` and no account appears. On by default.
+    pub fn set_create_accounts_on_reference(&mut self, create_accounts_on_reference: bool) {
+        self.create_accounts_on_reference = create_accounts_on_reference;
+    }
 
-        // Ensure it's not already disputed
-        if self.transactions.is_disputed(tx.tx) {
-            warn!("Transaction already disputed: tx={}", tx.tx);
-            return Ok(());
-        }
+    /// Set how disputes, resolves and chargebacks under a different client
+    /// than the original transaction are handled
+    pub fn set_dispute_client_check(&mut self, dispute_client_check: DisputeClientCheck) {
+        self.dispute_client_check = dispute_client_check;
+    }
 
-        // Get the amount from the original transaction
-        let amount = orig_tx.amount.ok_or_else(|| {
-            PaymentEngineError::MissingAmount(tx.tx)
-        })?;
+    /// Let disputes opened by a row with a timestamp expire: once a later
+    /// timestamp shows the dispute has been open for longer than `expiry`, it
+    /// is resolved before the next transaction is applied, or by
+    /// [`expire_disputes`](Self::expire_disputes)
+    pub fn set_dispute_expiry(&mut self, expiry: Option<Duration>) {
+        self.dispute_expiry = expiry;
+    }
 
-        // Mark the transaction as disputed
-        self.transactions.set_disputed(tx.tx, true);
+    /// Set the currency kept in each account's base balance. Transactions in
+    /// other currencies go to the account's `other_currencies`.
+    pub fn set_base_currency(&mut self, currency: Currency) {
+        self.base_currency = Some(currency);
+    }
 
-        // Hold the funds
-        let account = self.accounts.get_or_create_account(tx.client);
-        if !account.hold(amount) {
-            warn!(
-                "Failed to hold funds for dispute: client={}, tx={}, amount={}",
-                tx.client, tx.tx, amount
-            );
-            // Reset dispute status since we couldn't hold the funds
-            self.transactions.set_disputed(tx.tx, false);
-        }
+    /// Dispute activity of a client, or `None` if it never had an applied dispute
+    pub fn get_account_stats(&self, client: u16) -> Option<&AccountStats> {
+        self.account_stats.get(&client)
+    }
 
-        Ok(())
+    /// Withdrawal fees charged so far, per client
+    pub fn fees(&self) -> BTreeMap<u16, Decimal> {
+        self.fees.iter().map(|(client, fee)| (*client, *fee)).collect()
     }
 
-    /// Handle a resolve transaction
-    async fn handle_resolve(&mut self, tx: Transaction) -> Result<()> {
-        // Get the original transaction
-        let orig_tx = match self.transactions.get_transaction(tx.tx) {
-            Some(t) => t,
-            None => {
-                warn!("Transaction not found for resolve: tx={}", tx.tx);
-                return Ok(());
-            }
-        };
+    /// Funds removed from base balances by chargebacks so far, per client
+    pub fn chargeback_totals(&self) -> BTreeMap<u16, Decimal> {
+        self.accounts
+            .iter()
+            .filter(|account| !account.chargeback_total.is_zero())
+            .map(|account| (account.client, account.chargeback_total))
+            .collect()
+    }
 
-        // Ensure the client matches
-        if orig_tx.client != tx.client {
-            warn!(
-                "Client mismatch for resolve: original={}, resolve={}",
-                orig_tx.client, tx.client
-            );
-            return Ok(());
-        }
+    /// Sums of the deposits and of the withdrawals applied to base balances
+    /// so far, across all clients
+    pub fn gross_totals(&self) -> (Decimal, Decimal) {
+        self.accounts
+            .iter()
+            .fold((Decimal::ZERO, Decimal::ZERO), |(deposited, withdrawn), account| {
+                (deposited.saturating_add(account.deposited_total), withdrawn.saturating_add(account.withdrawn_total))
+            })
+    }
 
-        // Ensure the transaction is disputed
-        if !self.transactions.is_disputed(tx.tx) {
-            warn!("Transaction not under dispute for resolve: tx={}", tx.tx);
-            return Ok(());
-        }
+    /// Number of chargebacks applied so far, of deposits and withdrawals
+    pub fn chargeback_count(&self) -> usize {
+        self.account_stats.values().map(|stats| stats.disputes_charged_back as usize).sum()
+    }
 
-        // Get the amount from the original transaction
-        let amount = orig_tx.amount.ok_or_else(|| {
-            PaymentEngineError::MissingAmount(tx.tx)
-        })?;
+    /// Number of disputes of withdrawals rejected as `not_disputable` so far,
+    /// counted apart from the other rejections since they are often the most
+    /// common one
+    pub fn withdrawal_disputes_rejected(&self) -> usize {
+        self.counters.withdrawal_disputes_rejected
+    }
 
-        // Mark the transaction as no longer disputed
-        self.transactions.set_disputed(tx.tx, false);
+    /// Number of disputes resolved by expiring so far
+    pub fn expired_dispute_count(&self) -> usize {
+        self.account_stats.values().map(|stats| stats.disputes_expired as usize).sum()
+    }
 
-        // Release the funds
-        let account = self.accounts.get_or_create_account(tx.client);
-        if !account.release(amount) {
-            warn!(
-                "Failed to release funds for resolve: client={}, tx={}, amount={}",
-                tx.client, tx.tx, amount
-            );
-            // Restore dispute status since we couldn't release the funds
-            self.transactions.set_disputed(tx.tx, true);
+    /// Number of transactions rejected by a limit so far, per client
+    pub fn limit_rejections(&self) -> BTreeMap<u16, usize> {
+        self.limit_rejections.iter().map(|(client, count)| (*client, *count)).collect()
+    }
+
+    /// Process a batch of transactions in order, consuming the batch.
+    ///
+    /// A transaction that fails, e.g. a deposit without an amount, is logged
+    /// and reported in the result; the rest of the batch is still applied.
+    pub async fn process_transaction_batch(&mut self, mut transactions: Vec<Transaction>) -> BatchResult {
+        trace!("Processing batch of {} transactions", transactions.len());
+        let start_time = Instant::now();
+
+        let mut outcomes = Vec::with_capacity(transactions.len());
+        for transaction in transactions.drain(..) {
+            let tx = transaction.tx;
+            outcomes.push((tx, self.process_batch_transaction(transaction)));
         }
 
-        Ok(())
+        metrics::batch_processed(start_time.elapsed());
+        BatchResult { outcomes, buffer: transactions }
     }
 
-    /// Handle a chargeback transaction
-    async fn handle_chargeback(&mut self, tx: Transaction) -> Result<()> {
-        // Get the original transaction
-        let orig_tx = match self.transactions.get_transaction(tx.tx) {
-            Some(t) => t,
-            None => {
-                warn!("Transaction not found for chargeback: tx={}", tx.tx);
-                return Ok(());
-            }
-        };
+    /// Process a batch of transactions with each client's transactions applied
+    /// on a separate task, consuming the batch.
+    ///
+    /// Transactions keep their relative order within a client. Each task gets a
+    /// shard engine holding only that client's account and the stored
+    /// transactions its disputes refer to; the shards are merged back afterwards.
+    /// Each transaction sees the latest timestamp of the batch up to it, so
+    /// disputes expire in a shard as they would applying the batch in order.
+    /// A client whose shard can't be set up, e.g. because a spilled transaction
+    /// can't be read, has its transactions applied on this engine afterwards.
+    /// A batch in which clients' deposits or withdrawals share an id is
+    /// applied in order instead, so the right one is rejected as a duplicate.
+    /// Fails only if a task panics, after merging the shards of the others.
+    pub async fn process_transaction_batch_parallel(&mut self, mut transactions: Vec<Transaction>) -> Result<BatchResult> {
+        if reuses_tx_id_across_clients(&transactions) {
+            return Ok(self.process_transaction_batch(transactions).await);
+        }
+        trace!("Processing batch of {} transactions in parallel", transactions.len());
+        let start_time = Instant::now();
+        let len = transactions.len();
 
-        // Ensure the client matches
-        if orig_tx.client != tx.client {
-            warn!(
-                "Client mismatch for chargeback: original={}, chargeback={}",
-                orig_tx.client, tx.client
-            );
-            return Ok(());
+        // Latest timestamp as of each transaction, which disputes expire against
+        let latest_timestamps: Vec<Option<u64>> = transactions
+            .iter()
+            .scan(self.latest_timestamp, |latest, transaction| {
+                *latest = (*latest).max(transaction.timestamp);
+                Some(*latest)
+            })
+            .collect();
+
+        // Group the batch by client, preserving intra-client order
+        let mut groups: IdMap<u16, Vec<(usize, Transaction)>> = IdMap::default();
+        let batch_clients = batch_clients(&transactions);
+        for (index, transaction) in transactions.drain(..).enumerate() {
+            let client = self.group_client(&transaction, &batch_clients);
+            groups.entry(client).or_default().push((index, transaction));
         }
 
-        // Ensure the transaction is disputed
-        if !self.transactions.is_disputed(tx.tx) {
-            warn!("Transaction not under dispute for chargeback: tx={}", tx.tx);
-            return Ok(());
+        let mut tasks = Vec::with_capacity(groups.len());
+        let mut unsharded = Vec::new();
+        for (client, group) in groups {
+            let mut shard = match self.take_shard(client, &group) {
+                Ok(shard) => shard,
+                Err(e) => {
+                    warn!("Applying client {} outside the parallel batch: {}", client, e);
+                    unsharded.push(group);
+                    continue;
+                }
+            };
+            let latest: Vec<Option<u64>> = group.iter().map(|(index, _)| latest_timestamps[*index]).collect();
+            tasks.push(tokio::spawn(async move {
+                let mut outcomes = Vec::with_capacity(group.len());
+                for ((index, transaction), latest) in group.into_iter().zip(latest) {
+                    shard.latest_timestamp = shard.latest_timestamp.max(latest);
+                    let tx = transaction.tx;
+                    outcomes.push((index, tx, shard.process_batch_transaction(transaction)));
+                }
+                (client, shard, outcomes)
+            }));
         }
 
-        // Get the amount from the original transaction
-        let amount = orig_tx.amount.ok_or_else(|| {
-            PaymentEngineError::MissingAmount(tx.tx)
-        })?;
+        let mut ordered: Vec<Option<(TxId, Result<TransactionOutcome>)>> = (0..len).map(|_| None).collect();
+        let mut failed = None;
+        for task in futures::future::join_all(tasks).await {
+            match task {
+                Ok((client, shard, outcomes)) => {
+                    self.merge_shard(client, shard);
+                    for (index, tx, outcome) in outcomes {
+                        ordered[index] = Some((tx, outcome));
+                    }
+                }
+                Err(e) => {
+                    error!("Parallel batch task failed: {}", e);
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        for (index, transaction) in unsharded.into_iter().flatten() {
+            let tx = transaction.tx;
+            ordered[index] = Some((tx, self.process_batch_transaction(transaction)));
+        }
 
-        // Mark the transaction as no longer disputed
-        self.transactions.set_disputed(tx.tx, false);
+        metrics::batch_processed(start_time.elapsed());
+        Ok(BatchResult { outcomes: ordered.into_iter().flatten().collect(), buffer: transactions })
+    }
 
-        // Process the chargeback
-        let account = self.accounts.get_or_create_account(tx.client);
-        if !account.chargeback(amount) {
-            warn!(
-                "Failed to process chargeback: client={}, tx={}, amount={}",
-                tx.client, tx.tx, amount
-            );
-            // Restore dispute status since we couldn't process the chargeback
-            self.transactions.set_disputed(tx.tx, true);
-        } else {
-            info!("Account {} locked due to chargeback", tx.client);
+    /// Reorder a batch so each client's transactions are next to each other,
+    /// keeping their order within the client, with the clients in the order
+    /// they first appear. Applying the grouped batch leaves every account as
+    /// applying it in order would, and keeps hitting the same account while
+    /// it is in cache. A batch in which clients' deposits or withdrawals share
+    /// an id is left in order.
+    pub(crate) fn group_by_client(&mut self, batch: &mut Vec<Transaction>) {
+        if reuses_tx_id_across_clients(batch) {
+            return;
+        }
+        let batch_clients = batch_clients(batch);
+        let mut ranks: IdMap<u16, usize> = IdMap::default();
+        let mut keyed: Vec<(usize, Transaction)> = Vec::with_capacity(batch.len());
+        for transaction in batch.drain(..) {
+            let client = self.group_client(&transaction, &batch_clients);
+            let next = ranks.len();
+            keyed.push((*ranks.entry(client).or_insert(next), transaction));
         }
+        // Stable, so each client's transactions keep their order
+        keyed.sort_by_key(|(rank, _)| *rank);
+        batch.extend(keyed.into_iter().map(|(_, transaction)| transaction));
+    }
 
-        Ok(())
+    /// Client whose group a transaction of a batch belongs to. Disputes
+    /// trusted to the original client go to that client's group, looked up
+    /// among the batch's deposits and withdrawals in `batch_clients` first, so
+    /// one that comes before its transaction still does and is rejected as
+    /// it would be in order. If the lookup fails, the transaction's own
+    /// client hits the same error.
+    fn group_client(&mut self, transaction: &Transaction, batch_clients: &IdMap<TxId, u16>) -> u16 {
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => transaction.client,
+            _ if self.dispute_client_check == DisputeClientCheck::TrustOriginal => match batch_clients.get(&transaction.tx) {
+                Some(client) => *client,
+                None => self.original_client(transaction.tx).ok().flatten().unwrap_or(transaction.client),
+            },
+            _ => transaction.client,
+        }
     }
 
-    /// Get all client accounts
-    pub fn get_accounts(&self) -> Vec<Account> {
-        self.accounts.get_all_accounts()
+    /// Apply transactions in order, e.g. as they arrive from a message queue,
+    /// returning each one's outcome in the same order. Like a batch, a failing
+    /// transaction is logged and reported without stopping the rest.
+    pub fn apply_all(&mut self, transactions: impl IntoIterator<Item = Transaction>) -> Vec<Result<TransactionOutcome>> {
+        transactions.into_iter().map(|transaction| self.process_batch_transaction(transaction)).collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rust_decimal_macros::dec;
-    use std::collections::HashMap;
-    
-    // Helper function to create a deposit transaction
-    fn create_deposit(client: u16, tx: u32, amount: rust_decimal::Decimal) -> Transaction {
-        Transaction {
-            transaction_type: TransactionType::Deposit,
-            client,
-            tx,
-            amount: Some(amount),
+    /// Process a transaction of a batch, logging a failure instead of stopping the batch
+    fn process_batch_transaction(&mut self, transaction: Transaction) -> Result<TransactionOutcome> {
+        let outcome = self.apply(transaction);
+        if let Err(e) = &outcome {
+            error!("Error processing transaction: {}", e);
+            metrics::transaction_rejected("error");
         }
+        outcome
     }
-    
-    // Helper function to create a withdrawal transaction
-    fn create_withdrawal(client: u16, tx: u32, amount: rust_decimal::Decimal) -> Transaction {
-        Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client,
-            tx,
-            amount: Some(amount),
+
+    /// Move a client's account and referenced transactions into a shard engine.
+    /// The transactions are copied first, so this engine is unchanged on error.
+    /// The shard starts the client's history afresh; its events are appended
+    /// when it is merged back.
+    fn take_shard(&mut self, client: u16, group: &[(usize, Transaction)]) -> Result<PaymentEngine> {
+        let mut shard = PaymentEngine::new();
+        for (_, transaction) in group {
+            self.journal_transaction(client, transaction.tx)?;
         }
-    }
-    
-    // Helper function to create a dispute transaction
-    fn create_dispute(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            transaction_type: TransactionType::Dispute,
-            client,
-            tx,
-            amount: None,
+        // Deposits and withdrawals too, so one reusing a stored id is rejected
+        for (_, transaction) in group {
+            self.transactions.copy_entry_to(transaction.tx, &mut shard.transactions)?;
         }
-    }
-    
-    // Helper function to create a resolve transaction
-    fn create_resolve(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            transaction_type: TransactionType::Resolve,
-            client,
-            tx,
-            amount: None,
+        // Any transaction of the group may let a deferred dispute hold its
+        // funds, or come late enough for an open one to expire
+        let mut referenced: Vec<TxId> = self.account_stats.get(&client).map_or_else(Vec::new, |stats| stats.deferred_disputes.iter().map(|d| d.tx).collect());
+        if self.dispute_expiry.is_some() {
+            referenced.extend(self.account_stats.get(&client).into_iter().flat_map(|stats| stats.held_transactions.iter().copied()));
+        }
+        for tx in referenced {
+            self.journal_transaction(client, tx)?;
+            self.transactions.copy_entry_to(tx, &mut shard.transactions)?;
+        }
+        shard.purge_policy = self.purge_policy;
+        shard.limits = self.limits;
+        shard.withdrawal_fee = self.withdrawal_fee;
+        shard.allow_partial_disputes = self.allow_partial_disputes;
+        shard.allow_withdrawal_disputes = self.allow_withdrawal_disputes;
+        shard.max_deferred_disputes = self.max_deferred_disputes;
+        shard.allow_admin_transactions = self.allow_admin_transactions;
+        shard.create_accounts_on_reference = self.create_accounts_on_reference;
+        shard.dispute_client_check = self.dispute_client_check;
+        shard.dispute_expiry = self.dispute_expiry;
+        shard.base_currency = self.base_currency;
+        shard.event_handler = self.event_handler.clone();
+        #[cfg(test)]
+        {
+            shard.panic_after_tx = self.panic_after_tx;
         }
+        if let Some(count) = self.withdrawal_counts.remove(&client) {
+            shard.withdrawal_counts.insert(client, count);
+        }
+        if let Some(stats) = self.account_stats.remove(&client) {
+            shard.account_stats.insert(client, stats);
+        }
+        if let Some(account) = self.accounts.remove_account(client) {
+            shard.accounts.insert(account);
+        }
+        if self.history.is_some() {
+            shard.history = Some(IdMap::default());
+        }
+        if self.changed_clients.is_some() {
+            shard.changed_clients = Some(IdSet::default());
+        }
+        if self.untouched.remove(&client) {
+            shard.untouched.insert(client);
+        }
+        Ok(shard)
     }
-    
-    // Helper function to create a chargeback transaction
-    fn create_chargeback(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client,
-            tx,
-            amount: None,
+
+    /// Merge a shard's account and the transactions its client owns back in
+    fn merge_shard(&mut self, client: u16, mut shard: PaymentEngine) {
+        if let Some(account) = shard.accounts.remove_account(client) {
+            self.accounts.insert(account);
         }
+        if let (Some(history), Some(mut shard_history)) = (self.history.as_mut(), shard.history) {
+            if let Some(events) = shard_history.remove(&client) {
+                history.entry(client).or_default().extend(events);
+            }
+        }
+        self.rejected_transactions += shard.rejected_transactions;
+        self.counters.add(shard.counters);
+        self.latest_timestamp = self.latest_timestamp.max(shard.latest_timestamp);
+        self.max_tx_id = self.max_tx_id.max(shard.max_tx_id);
+        if let (Some(changed), Some(shard_changed)) = (self.changed_clients.as_mut(), shard.changed_clients) {
+            changed.extend(shard_changed);
+        }
+        self.untouched.extend(shard.untouched);
+        self.withdrawal_counts.extend(shard.withdrawal_counts);
+        self.account_stats.extend(shard.account_stats);
+        for (client, count) in shard.limit_rejections {
+            *self.limit_rejections.entry(client).or_default() += count;
+        }
+        for (client, fee) in shard.fees {
+            *self.fees.entry(client).or_default() += fee;
+        }
+        self.transactions.merge_client_entries(client, shard.transactions);
     }
-    
-    #[tokio::test]
-    async fn test_deposit() {
-        let mut engine = PaymentEngine::new();
-        
-        let tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(tx).await.unwrap();
-        
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].client, 1);
-        assert_eq!(accounts[0].available, dec!(100));
+
+    /// Process a single transaction
+    pub async fn process_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        self.apply(transaction).map(|_| ())
+    }
+
+    /// Process a single transaction and report whether it was applied or why
+    /// it was rejected, see [`apply`](Self::apply). Kept async for callers
+    /// that process transactions alongside other async work.
+    pub async fn process_transaction_with_outcome(&mut self, transaction: Transaction) -> Result<TransactionOutcome> {
+        self.apply(transaction)
+    }
+
+    /// Apply a single transaction and report whether it was applied or why it
+    /// was rejected. Errors are returned for malformed transactions, e.g. a
+    /// deposit without an amount.
+    ///
+    /// This is the synchronous core every other way of processing goes
+    /// through. It does no I/O of its own, unless the engine was built with a
+    /// transaction store that spills to disk.
+    pub fn apply(&mut self, mut transaction: Transaction) -> Result<TransactionOutcome> {
+        if transaction.currency.is_some() && transaction.currency == self.base_currency {
+            transaction.currency = None;
+        }
+        if self.dispute_client_check == DisputeClientCheck::TrustOriginal
+            && !matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal)
+        {
+            self.trust_original_client(&mut transaction);
+        }
+        self.journal_transaction(transaction.client, transaction.tx)?;
+        self.latest_timestamp = self.latest_timestamp.max(transaction.timestamp);
+        // Disputes overdue by now expire first, however the input is batched
+        self.expire_disputes()?;
+        self.max_tx_id = self.max_tx_id.max(Some(transaction.tx));
+        debug!(
+            "Processing transaction: type={:?}, client={}, tx={}, amount={:?}",
+            transaction.transaction_type, transaction.client, transaction.tx, transaction.amount
+        );
+        metrics::transaction_processed(transaction.transaction_type);
+        let (client, tx, transaction_type, amount) =
+            (transaction.client, transaction.tx, transaction.transaction_type, transaction.amount);
+        self.last_rejection = None;
+        // Even a rejected transaction creates its client's account, unless it
+        // only refers to an earlier transaction and that isn't allowed to
+        let unknown = self.accounts.get_account(client).is_none();
+        let phantom = unknown
+            && !self.create_accounts_on_reference
+            && matches!(transaction_type, TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback);
+        let created = unknown && !phantom;
+
+        // Locked-account policy lives here alone; the account's balance methods
+        // ignore the lock. A locked account takes no new deposits, withdrawals
+        // or disputes, but disputes opened before the lock can still be
+        // resolved or charged back, so their held funds aren't stuck.
+        let result = if phantom {
+            warn!("Client {} has no account, ignoring {:?} of transaction {}", client, transaction_type, tx);
+            self.reject("unknown_client");
+            Ok(())
+        } else if self.accounts.get_or_create_account(client).locked
+            && !matches!(transaction_type, TransactionType::Resolve | TransactionType::Chargeback)
+        {
+            warn!("Account {} is locked, ignoring transaction", client);
+            self.reject("account_locked");
+            Ok(())
+        } else {
+            match transaction_type {
+                TransactionType::Deposit => self.handle_deposit(transaction),
+                TransactionType::Withdrawal => self.handle_withdrawal(transaction),
+                TransactionType::Dispute => self.handle_dispute(transaction),
+                TransactionType::Resolve => self.handle_resolve(transaction),
+                TransactionType::Chargeback => self.handle_chargeback(transaction),
+                TransactionType::Amend => self.handle_amend(transaction),
+            }
+        };
+        // Funds the transaction left available may cover deferred disputes
+        let result = match result {
+            Ok(()) if self.last_rejection.is_none() => self.activate_deferred_disputes(client),
+            result => result,
+        };
+
+        let outcome = match (&result, self.last_rejection) {
+            (Err(_), _) => TransactionOutcome::Rejected("error"),
+            (Ok(()), Some(rejection)) => rejection,
+            (Ok(()), None) => TransactionOutcome::Applied,
+        };
+
+        if let Some(reason) = outcome.rejection_reason() {
+            self.rejected_transactions += 1;
+            self.emit(EngineEvent::TransactionRejected { client, tx, reason });
+        }
+        if outcome == TransactionOutcome::Applied {
+            self.counters.applied += 1;
+            self.untouched.remove(&client);
+            match transaction_type {
+                TransactionType::Deposit => self.counters.deposits += 1,
+                TransactionType::Withdrawal => self.counters.withdrawals += 1,
+                _ => {}
+            }
+        }
+        if created || outcome == TransactionOutcome::Applied {
+            self.mark_changed(client);
+        }
+
+        if self.history.is_some() {
+            let (available, held) = self.accounts.get_account(client).map_or((Decimal::ZERO, Decimal::ZERO), |a| (a.available, a.held));
+            let event = AuditEvent {
+                tx,
+                transaction_type,
+                amount,
+                outcome,
+                resulting_available: available,
+                resulting_held: held,
+            };
+            if let Some(history) = self.history.as_mut() {
+                history.entry(client).or_default().push(event);
+            }
+        }
+
+        #[cfg(test)]
+        if self.panic_after_tx == Some(tx) {
+            panic!("Injected panic after transaction {}", tx);
+        }
+
+        result.map(|()| outcome)
+    }
+
+    /// Start saving the state the following transactions change, so they can
+    /// be undone with [`roll_back_journal`](Self::roll_back_journal), e.g.
+    /// after applying a batch panicked partway
+    pub(crate) fn begin_journal(&mut self) {
+        let journal = match self.spare_journal.take() {
+            Some(mut journal) => {
+                journal.rejected_transactions = self.rejected_transactions;
+                journal.counters = self.counters;
+                journal
+            }
+            None => Journal {
+                rejected_transactions: self.rejected_transactions,
+                counters: self.counters,
+                clients: IdMap::default(),
+                tx_ids: IdSet::default(),
+                entries: TransactionStore::new(),
+            },
+        };
+        self.journal = Some(journal);
+    }
+
+    /// Keep the changes made since [`begin_journal`](Self::begin_journal)
+    pub(crate) fn commit_journal(&mut self) {
+        if let Some(mut journal) = self.journal.take() {
+            journal.clients.clear();
+            journal.tx_ids.clear();
+            journal.entries.clear();
+            self.spare_journal = Some(journal);
+        }
+    }
+
+    /// Undo every change made since [`begin_journal`](Self::begin_journal).
+    /// Events already emitted can't be taken back. Fails only if a stored
+    /// transaction can't be read back from disk.
+    pub(crate) fn roll_back_journal(&mut self) -> Result<()> {
+        let Some(journal) = self.journal.take() else { return Ok(()) };
+        let gauges = self.gauge_counts();
+        self.rejected_transactions = journal.rejected_transactions;
+        self.counters = journal.counters;
+        for (client, state) in journal.clients {
+            if let Some(changed) = self.changed_clients.as_mut() {
+                changed.remove(&client);
+            }
+            self.accounts.remove_account(client);
+            if let Some(account) = state.account {
+                self.accounts.insert(account);
+            }
+            restore(&mut self.withdrawal_counts, client, state.withdrawal_count);
+            restore(&mut self.limit_rejections, client, state.limit_rejections);
+            restore(&mut self.fees, client, state.fees);
+            restore(&mut self.account_stats, client, state.stats);
+            if let Some(events) = self.history.as_mut().and_then(|history| history.get_mut(&client)) {
+                events.truncate(state.history_len);
+            }
+            if state.untouched {
+                self.untouched.insert(client);
+            }
+        }
+        let restored = self.transactions.restore_entries(journal.tx_ids, journal.entries);
+        self.recount_gauges(gauges);
+        restored?;
+        Ok(())
+    }
+
+    /// Open disputes and locked accounts, as tracked by the metrics gauges
+    fn gauge_counts(&self) -> (usize, usize) {
+        (self.transactions.disputed_count(), self.counters.locked_accounts)
+    }
+
+    /// Move the metrics gauges by how the counts changed since `before`, for
+    /// changes made without going through the transaction handlers
+    fn recount_gauges(&self, before: (usize, usize)) {
+        let (open_disputes, locked_accounts) = self.gauge_counts();
+        metrics::open_disputes_recounted(before.0, open_disputes);
+        metrics::locked_accounts_recounted(before.1, locked_accounts);
+    }
+
+    /// Save the state of a client and a stored transaction, unless a journaled
+    /// transaction already touched them or no journal is kept
+    fn journal_transaction(&mut self, client: u16, tx: TxId) -> Result<()> {
+        let Some(journal) = self.journal.as_mut() else { return Ok(()) };
+        if journal.tx_ids.insert(tx) {
+            self.transactions.copy_entry_to(tx, &mut journal.entries)?;
+        }
+        if let Entry::Vacant(entry) = journal.clients.entry(client) {
+            entry.insert(ClientState {
+                account: self.accounts.get_account(client).cloned(),
+                withdrawal_count: self.withdrawal_counts.get(&client).copied(),
+                limit_rejections: self.limit_rejections.get(&client).copied(),
+                fees: self.fees.get(&client).copied(),
+                stats: self.account_stats.get(&client).cloned(),
+                history_len: self.history.as_ref().and_then(|history| history.get(&client)).map_or(0, Vec::len),
+                untouched: self.untouched.contains(&client),
+            });
+        }
+        Ok(())
+    }
+
+    /// Client of a stored transaction
+    fn original_client(&mut self, tx: TxId) -> Result<Option<u16>> {
+        Ok(self.transactions.load_transaction(tx)?.map(|original| original.client))
+    }
+
+    /// Move a dispute, resolve or chargeback filed under another client to the
+    /// client of the transaction it refers to, so every check and balance
+    /// change applies to that client. A failed lookup leaves the transaction
+    /// alone; its handler hits the same error and reports it.
+    fn trust_original_client(&mut self, transaction: &mut Transaction) {
+        if let Ok(Some(original)) = self.original_client(transaction.tx) {
+            if original != transaction.client {
+                warn!(
+                    "Client mismatch for {:?}: original={}, row={}, applying to original client",
+                    transaction.transaction_type, original, transaction.client
+                );
+                transaction.client = original;
+            }
+        }
+    }
+
+    /// Record that the current transaction was rejected
+    fn reject(&mut self, reason: &'static str) {
+        metrics::transaction_rejected(reason);
+        self.last_rejection = Some(TransactionOutcome::Rejected(reason));
+    }
+
+    /// Record that the current transaction was rejected for exceeding a limit
+    fn reject_limit(&mut self, client: u16, limit: Limit) {
+        metrics::transaction_rejected(limit.as_str());
+        *self.limit_rejections.entry(client).or_default() += 1;
+        self.last_rejection = Some(TransactionOutcome::LimitExceeded(limit));
+    }
+
+    /// Fail a deposit or withdrawal reusing the id of a stored one, which it
+    /// would otherwise replace as the transaction disputes refer to
+    fn check_unique(&self, tx: &Transaction) -> Result<()> {
+        if self.transactions.contains(tx.tx)? {
+            warn!("Duplicate transaction id: client={}, tx={}", tx.client, tx.tx);
+            return Err(PaymentEngineError::DuplicateTransaction(tx.tx));
+        }
+        Ok(())
+    }
+
+    /// Handle a deposit transaction
+    fn handle_deposit(&mut self, tx: Transaction) -> Result<()> {
+        let amount = tx.amount.ok_or(PaymentEngineError::MissingAmount(tx.tx))?;
+        self.check_unique(&tx)?;
+
+        if amount.is_sign_negative() && !amount.is_zero() {
+            warn!("Negative deposit amount: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject("negative_amount");
+            return Ok(());
+        }
+        if self.limits.max_deposit.is_some_and(|max| amount > max) {
+            warn!("Deposit exceeds limit: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject_limit(tx.client, Limit::MaxDeposit);
+            return Ok(());
+        }
+
+        let account = self.accounts.get_or_create_account(tx.client);
+        if !account.with_currency(tx.currency, |account| account.deposit(amount)) {
+            warn!("Deposit would overflow the balance: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject("balance_overflow");
+            return Ok(());
+        }
+        self.emit(EngineEvent::DepositApplied { client: tx.client, tx: tx.tx, amount });
+
+        // Store transaction for potential future disputes
+        self.transactions.add_transaction(tx);
+        self.transactions.spill_if_needed()?;
+
+        Ok(())
+    }
+
+    /// Handle a withdrawal transaction
+    fn handle_withdrawal(&mut self, tx: Transaction) -> Result<()> {
+        let amount = tx.amount.ok_or(PaymentEngineError::MissingAmount(tx.tx))?;
+        self.check_unique(&tx)?;
+
+        // A negative withdrawal would credit the account
+        if amount.is_sign_negative() && !amount.is_zero() {
+            warn!("Negative withdrawal amount: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject("negative_amount");
+            return Ok(());
+        }
+        if self.limits.max_withdrawal.is_some_and(|max| amount > max) {
+            warn!("Withdrawal exceeds limit: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject_limit(tx.client, Limit::MaxWithdrawal);
+            return Ok(());
+        }
+        if let Some(max) = self.limits.max_withdrawals_per_client {
+            if self.withdrawal_counts.get(&tx.client).copied().unwrap_or(0) >= max {
+                warn!("Withdrawal count limit reached: client={}, tx={}", tx.client, tx.tx);
+                self.reject_limit(tx.client, Limit::MaxWithdrawalsPerClient);
+                return Ok(());
+            }
+        }
+
+        // The fee is charged on top of the withdrawal and must be covered too
+        let fee = self.withdrawal_fee.fee_for(amount);
+        let account = self.accounts.get_or_create_account(tx.client);
+        // No balance can cover a debit too large to represent
+        let debit = amount.checked_add(fee);
+        
+        if !debit.is_some_and(|debit| account.with_currency(tx.currency, |account| account.has_sufficient_funds(debit))) {
+            warn!(
+                "Insufficient funds for withdrawal: client={}, tx={}, amount={}, fee={}",
+                tx.client, tx.tx, amount, fee
+            );
+            self.reject("insufficient_funds");
+            return Ok(());
+        }
+
+        account.with_currency(tx.currency, |account| account.withdraw(amount) && account.charge_fee(fee));
+        if !fee.is_zero() {
+            *self.fees.entry(tx.client).or_default() += fee;
+        }
+        if self.limits.max_withdrawals_per_client.is_some() {
+            *self.withdrawal_counts.entry(tx.client).or_default() += 1;
+        }
+        self.emit(EngineEvent::WithdrawalApplied { client: tx.client, tx: tx.tx, amount });
+        
+        // Store transaction for potential future disputes
+        self.transactions.add_transaction(tx);
+        self.transactions.spill_if_needed()?;
+
+        Ok(())
+    }
+
+    /// Handle an amendment, correcting the amount of an earlier deposit that
+    /// was never disputed. The balance moves by the difference and the stored
+    /// deposit takes the new amount, so a later dispute holds that.
+    fn handle_amend(&mut self, tx: Transaction) -> Result<()> {
+        if !self.allow_admin_transactions {
+            warn!("Admin transactions are not allowed: client={}, tx={}", tx.client, tx.tx);
+            self.reject("admin_not_allowed");
+            return Ok(());
+        }
+        let amount = tx.amount.ok_or(PaymentEngineError::MissingAmount(tx.tx))?;
+        if amount.is_sign_negative() && !amount.is_zero() {
+            warn!("Negative amendment amount: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject("negative_amount");
+            return Ok(());
+        }
+
+        let orig_tx = match self.transactions.load_transaction(tx.tx)?.cloned() {
+            Some(t) => t,
+            None if self.transactions.is_finalized(tx.tx) => {
+                warn!("Transaction already finalized for amendment: tx={}", tx.tx);
+                self.reject("transaction_finalized");
+                return Ok(());
+            }
+            None => {
+                warn!("Transaction not found for amendment: tx={}", tx.tx);
+                self.reject("transaction_not_found");
+                return Ok(());
+            }
+        };
+        if orig_tx.client != tx.client {
+            warn!("Client mismatch for amendment: original={}, amendment={}", orig_tx.client, tx.client);
+            self.reject("client_mismatch");
+            return Ok(());
+        }
+        if tx.currency.is_some() && tx.currency != orig_tx.currency {
+            warn!("Currency mismatch for amendment: tx={}, currency={:?}", tx.tx, tx.currency);
+            self.reject("currency_mismatch");
+            return Ok(());
+        }
+        if orig_tx.transaction_type != TransactionType::Deposit {
+            warn!("Cannot amend non-deposit transaction: tx={}, type={:?}", tx.tx, orig_tx.transaction_type);
+            self.reject("not_amendable");
+            return Ok(());
+        }
+        // Once disputed, even if since resolved, the old amount has been held
+        // and released or charged back, so it stays
+        if self.transactions.transaction_state(tx.tx) != Some(TransactionState::Processed) {
+            warn!("Cannot amend a disputed transaction: tx={}", tx.tx);
+            self.reject("transaction_disputed");
+            return Ok(());
+        }
+        if self.limits.max_deposit.is_some_and(|max| amount > max) {
+            warn!("Amended deposit exceeds limit: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+            self.reject_limit(tx.client, Limit::MaxDeposit);
+            return Ok(());
+        }
+
+        let previous = orig_tx.amount.ok_or(PaymentEngineError::MissingAmount(tx.tx))?;
+        let delta = amount - previous;
+        let account = self.accounts.get_or_create_account(tx.client);
+        if !account.with_currency(orig_tx.currency, |account| account.amend_deposit(delta)) {
+            warn!("Amendment not covered by the balance: client={}, tx={}, change={}", tx.client, tx.tx, delta);
+            self.reject(if delta.is_sign_negative() { "insufficient_funds" } else { "balance_overflow" });
+            return Ok(());
+        }
+        self.emit(EngineEvent::DepositAmended { client: tx.client, tx: tx.tx, amount, previous });
+        self.transactions.add_transaction(Transaction { amount: Some(amount), ..orig_tx });
+        self.transactions.spill_if_needed()?;
+
+        Ok(())
+    }
+
+    /// Handle a dispute transaction
+    fn handle_dispute(&mut self, tx: Transaction) -> Result<()> {
+        // Get the original transaction
+        let orig_tx = match self.transactions.load_transaction(tx.tx)?.cloned() {
+            Some(t) => t,
+            None if self.transactions.is_finalized(tx.tx) => {
+                warn!("Transaction already finalized for dispute: tx={}", tx.tx);
+                self.reject("transaction_finalized");
+                return Ok(());
+            }
+            None => {
+                warn!("Transaction not found for dispute: tx={}", tx.tx);
+                self.reject("transaction_not_found");
+                return Ok(());
+            }
+        };
+
+        // Ensure the client matches
+        if orig_tx.client != tx.client {
+            warn!(
+                "Client mismatch for dispute: original={}, dispute={}",
+                orig_tx.client, tx.client
+            );
+            self.reject("client_mismatch");
+            return Ok(());
+        }
+
+        // A currency given on the dispute must match the original transaction's
+        if tx.currency.is_some() && tx.currency != orig_tx.currency {
+            warn!("Currency mismatch for dispute: tx={}, currency={:?}", tx.tx, tx.currency);
+            self.reject("currency_mismatch");
+            return Ok(());
+        }
+
+        // Ensure it's a transaction that can be disputed: a deposit, or a
+        // withdrawal if withdrawal disputes are allowed
+        let withdrawal = orig_tx.transaction_type == TransactionType::Withdrawal;
+        if orig_tx.transaction_type != TransactionType::Deposit && !(withdrawal && self.allow_withdrawal_disputes) {
+            warn!(
+                "Cannot dispute non-deposit transaction: tx={}, type={:?}",
+                tx.tx, orig_tx.transaction_type
+            );
+            if withdrawal {
+                self.counters.withdrawal_disputes_rejected += 1;
+            }
+            self.reject("not_disputable");
+            return Ok(());
+        }
+
+        // Only the part of the original amount not yet under dispute can be disputed
+        let disputed = self.transactions.disputed_amount(tx.tx);
+        let remaining = orig_tx.amount.ok_or(PaymentEngineError::MissingAmount(tx.tx))? - disputed;
+        let partial = self.allow_partial_disputes && tx.amount.is_some();
+
+        // Ensure it's not already disputed, in full unless partial disputes are
+        // allowed, nor waiting to be
+        if self.is_deferred(tx.client, tx.tx) || (!disputed.is_zero() && (!self.allow_partial_disputes || remaining <= Decimal::ZERO)) {
+            warn!("Transaction already disputed: tx={}", tx.tx);
+            self.reject("already_disputed");
+            return Ok(());
+        }
+
+        // Dispute the amount given, or the rest of the transaction without one
+        let amount = match tx.amount {
+            Some(amount) if partial => amount,
+            _ => remaining,
+        };
+        if partial && (amount <= Decimal::ZERO || amount > remaining) {
+            warn!(
+                "Invalid dispute amount: tx={}, amount={}, undisputed={}",
+                tx.tx, amount, remaining
+            );
+            self.reject("invalid_dispute_amount");
+            return Ok(());
+        }
+
+        // Disputing more of a transaction already under dispute opens no new dispute
+        if let Some(max) = self.limits.max_open_disputes_per_client {
+            let open = self.account_stats.get(&tx.client).map_or(0, |stats| stats.held_transactions.len());
+            if disputed.is_zero() && open >= max as usize {
+                warn!("Open dispute limit reached: client={}, tx={}, open={}", tx.client, tx.tx, open);
+                self.reject_limit(tx.client, Limit::MaxOpenDisputesPerClient);
+                return Ok(());
+            }
+        }
+
+        // Mark the amount as disputed and hold the funds of a deposit; a
+        // withdrawal's funds are already gone
+        let mut effects = vec![Effect::Disputed(disputed + amount)];
+        if !withdrawal {
+            effects.push(Effect::Funds(FundsMove::Hold(amount)));
+        }
+        if self.apply_effects(tx.client, tx.tx, orig_tx.currency, &effects) {
+            self.record_dispute_opened(tx.client, tx.tx, tx.timestamp);
+            self.emit(EngineEvent::DisputeOpened { client: tx.client, tx: tx.tx, amount });
+        } else if self.defer_dispute(tx.client, tx.tx, amount) {
+            info!("Deferred dispute until its funds are available: client={}, tx={}, amount={}", tx.client, tx.tx, amount);
+        } else {
+            warn!(
+                "Failed to hold funds for dispute: client={}, tx={}, amount={}",
+                tx.client, tx.tx, amount
+            );
+            self.reject("hold_failed");
+        }
+
+        Ok(())
+    }
+
+    /// Count a dispute whose funds were just held, starting its expiry clock at `timestamp`
+    fn record_dispute_opened(&mut self, client: u16, tx: TxId, timestamp: Option<u64>) {
+        let stats = self.account_stats.entry(client).or_default();
+        stats.disputes_opened += 1;
+        stats.held_transactions.insert(tx);
+        if let (Some(timestamp), Some(_)) = (timestamp, self.dispute_expiry) {
+            self.transactions.set_disputed_since(tx, timestamp);
+        }
+        metrics::dispute_opened();
+    }
+
+    /// Whether a dispute of the client's transaction `tx` is deferred
+    fn is_deferred(&self, client: u16, tx: TxId) -> bool {
+        self.account_stats.get(&client).is_some_and(|stats| stats.deferred_disputes.iter().any(|deferred| deferred.tx == tx))
+    }
+
+    /// Queue a dispute whose funds couldn't be held, unless deferring is off
+    /// or the client's queue is full
+    fn defer_dispute(&mut self, client: u16, tx: TxId, amount: Decimal) -> bool {
+        let Some(max) = self.max_deferred_disputes else { return false };
+        let stats = self.account_stats.entry(client).or_default();
+        if stats.deferred_disputes.len() >= max {
+            return false;
+        }
+        stats.deferred_disputes.push(DeferredDispute { tx, amount });
+        self.emit(EngineEvent::DisputeDeferred { client, tx, amount });
+        true
+    }
+
+    /// Hold the funds of the client's deferred disputes, oldest first, for as
+    /// long as its available balance covers them. A locked account keeps its
+    /// disputes deferred, as it takes no new ones.
+    fn activate_deferred_disputes(&mut self, client: u16) -> Result<()> {
+        while let Some(&DeferredDispute { tx, amount }) =
+            self.account_stats.get(&client).and_then(|stats| stats.deferred_disputes.first())
+        {
+            if self.accounts.get_account(client).is_some_and(|account| account.locked) {
+                return Ok(());
+            }
+            // The part left undisputed may have shrunk, e.g. by an amendment
+            let orig_tx = self.transactions.load_transaction(tx)?.cloned();
+            let disputed = self.transactions.disputed_amount(tx);
+            let covered = orig_tx.as_ref().and_then(|orig_tx| orig_tx.amount).is_some_and(|total| total - disputed >= amount);
+            self.journal_transaction(client, tx)?;
+            let effects = [Effect::Disputed(disputed + amount), Effect::Funds(FundsMove::Hold(amount))];
+            match orig_tx {
+                Some(orig_tx) if covered => {
+                    if !self.apply_effects(client, tx, orig_tx.currency, &effects) {
+                        return Ok(());
+                    }
+                    info!("Deferred dispute activated: client={}, tx={}, amount={}", client, tx, amount);
+                    self.record_dispute_opened(client, tx, self.latest_timestamp);
+                    self.emit(EngineEvent::DisputeActivated { client, tx, amount });
+                }
+                _ => warn!("Dropping deferred dispute of a transaction no longer disputable: client={}, tx={}", client, tx),
+            }
+            if let Some(stats) = self.account_stats.get_mut(&client) {
+                stats.deferred_disputes.remove(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a resolve transaction
+    fn handle_resolve(&mut self, tx: Transaction) -> Result<()> {
+        // Get the original transaction
+        let orig_tx = match self.transactions.load_transaction(tx.tx)?.cloned() {
+            Some(t) => t,
+            None if self.transactions.is_finalized(tx.tx) => {
+                warn!("Transaction already finalized for resolve: tx={}", tx.tx);
+                self.reject("transaction_finalized");
+                return Ok(());
+            }
+            None => {
+                warn!("Transaction not found for resolve: tx={}", tx.tx);
+                self.reject("transaction_not_found");
+                return Ok(());
+            }
+        };
+
+        // Ensure the client matches
+        if orig_tx.client != tx.client {
+            warn!(
+                "Client mismatch for resolve: original={}, resolve={}",
+                orig_tx.client, tx.client
+            );
+            self.reject("client_mismatch");
+            return Ok(());
+        }
+
+        // A currency given on the resolve must match the original transaction's
+        if tx.currency.is_some() && tx.currency != orig_tx.currency {
+            warn!("Currency mismatch for resolve: tx={}, currency={:?}", tx.tx, tx.currency);
+            self.reject("currency_mismatch");
+            return Ok(());
+        }
+
+        // Ensure the transaction is disputed
+        if !self.transactions.is_disputed(tx.tx) {
+            warn!("Transaction not under dispute for resolve: tx={}", tx.tx);
+            self.reject(if self.is_deferred(tx.client, tx.tx) { "dispute_deferred" } else { "not_disputed" });
+            return Ok(());
+        }
+
+        // Only the disputed part of the transaction is released or charged back
+        let amount = self.transactions.disputed_amount(tx.tx);
+
+        // Mark the transaction as no longer disputed and release the funds
+        // held for a deposit; a disputed withdrawal just stands
+        let mut effects = vec![Effect::Disputed(Decimal::ZERO)];
+        if orig_tx.transaction_type != TransactionType::Withdrawal {
+            effects.push(Effect::Funds(FundsMove::Release(amount)));
+        }
+        effects.push(Effect::Resolved);
+        if self.purge_policy == PurgePolicy::AfterResolveOrChargeback {
+            effects.push(Effect::Finalize);
+        }
+        if !self.apply_effects(tx.client, tx.tx, orig_tx.currency, &effects) {
+            warn!(
+                "Failed to release funds for resolve: client={}, tx={}, amount={}",
+                tx.client, tx.tx, amount
+            );
+            self.reject("release_failed");
+        } else {
+            let stats = self.account_stats.entry(tx.client).or_default();
+            stats.disputes_resolved += 1;
+            stats.held_transactions.remove(&tx.tx);
+            metrics::dispute_closed();
+            self.emit(EngineEvent::DisputeResolved { client: tx.client, tx: tx.tx, amount });
+        }
+
+        Ok(())
+    }
+
+    /// Handle a chargeback transaction
+    fn handle_chargeback(&mut self, tx: Transaction) -> Result<()> {
+        // Get the original transaction
+        let orig_tx = match self.transactions.load_transaction(tx.tx)?.cloned() {
+            Some(t) => t,
+            None if self.transactions.is_finalized(tx.tx) => {
+                warn!("Transaction already finalized for chargeback: tx={}", tx.tx);
+                self.reject("transaction_finalized");
+                return Ok(());
+            }
+            None => {
+                warn!("Transaction not found for chargeback: tx={}", tx.tx);
+                self.reject("transaction_not_found");
+                return Ok(());
+            }
+        };
+
+        // Ensure the client matches
+        if orig_tx.client != tx.client {
+            warn!(
+                "Client mismatch for chargeback: original={}, chargeback={}",
+                orig_tx.client, tx.client
+            );
+            self.reject("client_mismatch");
+            return Ok(());
+        }
+
+        // A currency given on the chargeback must match the original transaction's
+        if tx.currency.is_some() && tx.currency != orig_tx.currency {
+            warn!("Currency mismatch for chargeback: tx={}, currency={:?}", tx.tx, tx.currency);
+            self.reject("currency_mismatch");
+            return Ok(());
+        }
+
+        // Ensure the transaction is disputed
+        if !self.transactions.is_disputed(tx.tx) {
+            warn!("Transaction not under dispute for chargeback: tx={}", tx.tx);
+            self.reject(if self.is_deferred(tx.client, tx.tx) { "dispute_deferred" } else { "not_disputed" });
+            return Ok(());
+        }
+
+        // Only the disputed part of the transaction is released or charged back
+        let amount = self.transactions.disputed_amount(tx.tx);
+
+        // Mark the transaction as no longer disputed and process the
+        // chargeback: a deposit's held funds are removed, while a
+        // withdrawal's funds are credited back to the client. The account is
+        // locked; one that is already locked keeps the reason of its first lock.
+        let funds = match orig_tx.transaction_type {
+            TransactionType::Withdrawal => FundsMove::Credit(amount),
+            _ => FundsMove::Chargeback(amount),
+        };
+        let mut effects = vec![
+            Effect::Disputed(Decimal::ZERO),
+            Effect::Funds(funds),
+            Effect::Lock(LockReason::Chargeback(tx.tx)),
+            Effect::ChargedBack,
+        ];
+        if self.purge_policy != PurgePolicy::Never {
+            effects.push(Effect::Finalize);
+        }
+        let newly_locked = !self.accounts.get_account(tx.client).is_some_and(|account| account.locked);
+        if !self.apply_effects(tx.client, tx.tx, orig_tx.currency, &effects) {
+            warn!(
+                "Failed to process chargeback: client={}, tx={}, amount={}",
+                tx.client, tx.tx, amount
+            );
+            self.reject("chargeback_failed");
+        } else {
+            let stats = self.account_stats.entry(tx.client).or_default();
+            stats.disputes_charged_back += 1;
+            stats.held_transactions.remove(&tx.tx);
+            metrics::dispute_closed();
+            self.emit(EngineEvent::ChargebackApplied { client: tx.client, tx: tx.tx, amount });
+            if newly_locked {
+                info!("Account {} locked due to chargeback", tx.client);
+                metrics::account_locked();
+                self.emit(EngineEvent::AccountLocked { client: tx.client });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the disputes that have been open for longer than the dispute
+    /// expiry as of the latest timestamp seen, oldest first, releasing their
+    /// held funds like a resolve would. Returns how many expired.
+    ///
+    /// Nothing expires without a dispute expiry. Fails only if a disputed
+    /// transaction can't be read back from disk.
+    pub fn expire_disputes(&mut self) -> Result<usize> {
+        let (Some(expiry), Some(now)) = (self.dispute_expiry, self.latest_timestamp) else { return Ok(0) };
+        let mut expired = 0;
+        for tx_id in self.transactions.take_disputes_opened_before(now.saturating_sub(expiry.as_secs())) {
+            let Some(orig_tx) = self.transactions.load_transaction(tx_id)?.cloned() else { continue };
+            self.journal_transaction(orig_tx.client, tx_id)?;
+            let amount = self.transactions.disputed_amount(tx_id);
+            let mut effects = vec![Effect::Disputed(Decimal::ZERO)];
+            if orig_tx.transaction_type != TransactionType::Withdrawal {
+                effects.push(Effect::Funds(FundsMove::Release(amount)));
+            }
+            effects.push(Effect::Resolved);
+            if self.purge_policy == PurgePolicy::AfterResolveOrChargeback {
+                effects.push(Effect::Finalize);
+            }
+            if !self.apply_effects(orig_tx.client, tx_id, orig_tx.currency, &effects) {
+                warn!("Failed to release funds of expired dispute: client={}, tx={}, amount={}", orig_tx.client, tx_id, amount);
+                continue;
+            }
+            info!("Dispute expired: client={}, tx={}, amount={}", orig_tx.client, tx_id, amount);
+            let stats = self.account_stats.entry(orig_tx.client).or_default();
+            stats.disputes_expired += 1;
+            stats.held_transactions.remove(&tx_id);
+            metrics::dispute_closed();
+            self.emit(EngineEvent::DisputeExpired { client: orig_tx.client, tx: tx_id, amount });
+            self.mark_changed(orig_tx.client);
+            self.activate_deferred_disputes(orig_tx.client)?;
+            expired += 1;
+        }
+        Ok(expired)
+    }
+
+    /// Make the state changes of the current transaction `tx` of `client`,
+    /// all of them or none.
+    ///
+    /// The funds moves are checked first, in order, on a copy of the
+    /// client's balance in `currency`. If the balance can't cover one of them,
+    /// nothing is changed and `false` is returned. Otherwise the new balance
+    /// is written back and the other effects, which can't fail, are made.
+    fn apply_effects(&mut self, client: u16, tx: TxId, currency: Option<Currency>, effects: &[Effect]) -> bool {
+        let account = self.accounts.get_or_create_account(client);
+        let mut balance = account.with_currency(currency, |account| Account {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            chargeback_total: account.chargeback_total,
+            ..Account::new(client)
+        });
+        let covered = effects.iter().all(|effect| match effect {
+            Effect::Funds(funds) => funds.apply(&mut balance),
+            _ => true,
+        });
+        if !covered {
+            return false;
+        }
+        account.with_currency(currency, |account| {
+            account.available = balance.available;
+            account.held = balance.held;
+            account.total = balance.total;
+            account.chargeback_total = balance.chargeback_total;
+        });
+
+        for effect in effects {
+            match *effect {
+                Effect::Funds(_) => {}
+                Effect::Disputed(amount) => self.transactions.set_disputed_amount(tx, amount),
+                Effect::Resolved => self.transactions.mark_resolved(tx),
+                Effect::ChargedBack => self.transactions.mark_charged_back(tx),
+                Effect::Lock(reason) => {
+                    let account = self.accounts.get_or_create_account(client);
+                    if !account.locked {
+                        account.locked = true;
+                        account.lock_reason = Some(reason);
+                        self.counters.locked_accounts += 1;
+                    }
+                }
+                Effect::Finalize => self.transactions.finalize(tx),
+            }
+        }
+        true
+    }
+
+    /// Stored transaction with the given id, if it is held in memory.
+    /// Only deposits and withdrawals are stored.
+    pub fn get_transaction(&self, tx_id: TxId) -> Option<&Transaction> {
+        self.transactions.get_transaction(tx_id)
+    }
+
+    /// Dispute lifecycle state of a stored transaction, see [`TransactionStore::transaction_state`]
+    pub fn transaction_state(&self, tx_id: TxId) -> Option<TransactionState> {
+        self.transactions.transaction_state(tx_id)
+    }
+
+    /// A client's stored transactions held in memory, with their dispute
+    /// lifecycle states, in transaction id order
+    pub fn client_transactions(&self, client: u16) -> Vec<(&Transaction, TransactionState)> {
+        let mut transactions: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|transaction| transaction.client == client)
+            .filter_map(|transaction| Some((transaction, self.transactions.transaction_state(transaction.tx)?)))
+            .collect();
+        transactions.sort_unstable_by_key(|(transaction, _)| transaction.tx);
+        transactions
+    }
+
+    /// Number of clients with an account
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Number of stored transactions held in memory. Only deposits and
+    /// withdrawals are stored; purged and spilled ones aren't counted.
+    pub fn stored_transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Number of transactions currently under dispute
+    pub fn open_dispute_count(&self) -> usize {
+        self.transactions.disputed_count()
+    }
+
+    /// Get all client accounts
+    pub fn get_accounts(&self) -> Vec<Account> {
+        self.accounts.get_all_accounts()
+    }
+
+    /// Get a single client's account, if the client has been seen
+    pub fn get_account(&self, client: u16) -> Option<&Account> {
+        self.accounts.get_account(client)
+    }
+
+    /// Start from account balances carried over from a previous run, see
+    /// [`AccountStore::load_accounts`]. Locked accounts stay locked.
+    pub fn load_accounts(&mut self, accounts: Vec<Account>) -> Result<(), PaymentEngineError> {
+        let locked = accounts.iter().filter(|account| account.locked).count();
+        let clients: Vec<u16> = accounts.iter().map(|account| account.client).collect();
+        self.accounts.load_accounts(accounts)?;
+        self.counters.locked_accounts += locked;
+        self.untouched.extend(clients);
+        Ok(())
+    }
+
+    /// Clear all accounts, stored transactions and per-client counters so the
+    /// engine can process unrelated input. Configuration such as limits, fees,
+    /// the purge policy and the event handler is kept, and so is allocated capacity.
+    pub fn reset(&mut self) {
+        let gauges = self.gauge_counts();
+        self.accounts.clear();
+        self.transactions.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        self.last_rejection = None;
+        self.rejected_transactions = 0;
+        self.counters = Counters::default();
+        self.latest_timestamp = None;
+        self.max_tx_id = None;
+        if let Some(changed) = self.changed_clients.as_mut() {
+            changed.clear();
+        }
+        self.untouched.clear();
+        self.withdrawal_counts.clear();
+        self.limit_rejections.clear();
+        self.fees.clear();
+        self.account_stats.clear();
+        self.recount_gauges(gauges);
+    }
+
+    /// Combine an engine that processed a disjoint set of clients into this one.
+    ///
+    /// Accounts, stored transactions and per-client counters are moved over
+    /// as they are; only the rejection counts are added up. Fails, leaving this
+    /// engine unchanged, if a client is in both engines, if a transaction id is
+    /// in both with a different transaction or dispute state, or if either
+    /// engine has spilled transactions to disk. The configuration of this engine is kept.
+    pub fn merge(&mut self, other: PaymentEngine) -> Result<()> {
+        if self.transactions.has_spilled() || other.transactions.has_spilled() {
+            return Err(PaymentEngineError::MergeConflict("transactions were spilled to disk".to_string()));
+        }
+        let ours = self.clients();
+        if let Some(client) = other.clients().intersection(&ours).min() {
+            return Err(PaymentEngineError::MergeConflict(format!("client {} is in both engines", client)));
+        }
+        if let Some(tx) = self.transactions.find_conflict(&other.transactions) {
+            return Err(PaymentEngineError::MergeConflict(format!(
+                "transaction {} is in both engines with different state",
+                tx
+            )));
+        }
+
+        if let Some(changed) = self.changed_clients.as_mut() {
+            changed.extend(other.accounts.clients());
+        }
+        self.untouched.extend(other.untouched);
+        self.accounts.absorb(other.accounts);
+        self.transactions.absorb(other.transactions);
+        if let (Some(history), Some(other_history)) = (self.history.as_mut(), other.history) {
+            history.extend(other_history);
+        }
+        self.rejected_transactions += other.rejected_transactions;
+        self.counters.add(other.counters);
+        self.latest_timestamp = self.latest_timestamp.max(other.latest_timestamp);
+        self.max_tx_id = self.max_tx_id.max(other.max_tx_id);
+        self.withdrawal_counts.extend(other.withdrawal_counts);
+        self.limit_rejections.extend(other.limit_rejections);
+        self.fees.extend(other.fees);
+        self.account_stats.extend(other.account_stats);
+        Ok(())
+    }
+
+    /// Every client with an account or any per-client state
+    pub(crate) fn clients(&self) -> IdSet<u16> {
+        let mut clients: IdSet<u16> = self.accounts.clients().collect();
+        clients.extend(self.withdrawal_counts.keys());
+        clients.extend(self.limit_rejections.keys());
+        clients.extend(self.fees.keys());
+        clients.extend(self.account_stats.keys());
+        if let Some(history) = &self.history {
+            clients.extend(history.keys());
+        }
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+    
+    #[tokio::test]
+    async fn test_deposit() {
+        let mut engine = PaymentEngine::new();
+        
+        let tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(tx).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client, 1);
+        assert_eq!(accounts[0].available, dec!(100));
+        assert_eq!(accounts[0].total, dec!(100));
+    }
+    
+    #[tokio::test]
+    async fn test_withdrawal() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit first
+        let deposit_tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Then withdraw
+        let withdraw_tx = Transaction::withdrawal(1, 2, dec!(30));
+        engine.process_transaction(withdraw_tx).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(70));
+        assert_eq!(accounts[0].total, dec!(70));
+    }
+    
+    #[test]
+    fn test_negative_and_overflowing_amounts_rejected() {
+        let mut engine = PaymentEngine::new();
+        assert_eq!(engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap(), TransactionOutcome::Applied);
+
+        // A negative withdrawal would otherwise credit the account
+        assert_eq!(engine.apply(Transaction { amount: Some(dec!(-5)), ..Transaction::deposit(1, 2, dec!(5)) }).unwrap(), TransactionOutcome::Rejected("negative_amount"));
+        assert_eq!(engine.apply(Transaction { amount: Some(dec!(-5)), ..Transaction::withdrawal(1, 3, dec!(5)) }).unwrap(), TransactionOutcome::Rejected("negative_amount"));
+        assert!(engine.get_transaction(2).is_none());
+
+        assert_eq!(engine.apply(Transaction::deposit(2, 4, Decimal::MAX)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.apply(Transaction::deposit(2, 5, Decimal::MAX)).unwrap(), TransactionOutcome::Rejected("balance_overflow"));
+        assert_eq!(engine.get_account(1).unwrap().total, dec!(10));
+        assert_eq!(engine.get_account(2).unwrap().total, Decimal::MAX);
+
+        engine.set_withdrawal_fee(WithdrawalFee::Flat(dec!(1)));
+        assert_eq!(engine.apply(Transaction::withdrawal(2, 6, Decimal::MAX)).unwrap(), TransactionOutcome::Rejected("insufficient_funds"));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_funds_withdrawal() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit first
+        let deposit_tx = Transaction::deposit(1, 1, dec!(50));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Try to withdraw more than available
+        let withdraw_tx = Transaction::withdrawal(1, 2, dec!(75));
+        engine.process_transaction(withdraw_tx).await.unwrap();
+        
+        // Balance should remain unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(50));
+        assert_eq!(accounts[0].total, dec!(50));
+    }
+    
+    #[tokio::test]
+    async fn test_dispute() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        let deposit_tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Dispute the deposit
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine.process_transaction(dispute_tx).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(0));
+        assert_eq!(accounts[0].held, dec!(100));
+        assert_eq!(accounts[0].total, dec!(100));
+    }
+    
+    #[tokio::test]
+    async fn test_resolve() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        let deposit_tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Dispute
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine.process_transaction(dispute_tx).await.unwrap();
+        
+        // Resolve
+        let resolve_tx = Transaction::resolve(1, 1);
+        engine.process_transaction(resolve_tx).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(100));
+        assert_eq!(accounts[0].held, dec!(0));
+        assert_eq!(accounts[0].total, dec!(100));
+    }
+    
+    #[tokio::test]
+    async fn test_transaction_lookups_follow_dispute_lifecycle() {
+        let mut engine = PaymentEngine::new();
+        assert_eq!(engine.stored_transaction_count(), 0);
+        assert_eq!(engine.transaction_state(1), None);
+
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        let stored = engine.get_transaction(1).unwrap();
+        assert_eq!((stored.client, stored.amount), (1, Some(dec!(100))));
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Processed));
+
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Disputed(dec!(100))));
+
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Resolved));
+
+        // Disputes and resolves are not stored themselves
+        assert_eq!(engine.stored_transaction_count(), 1);
+
+        // The state survives a round trip through a parallel batch shard
+        let batch = vec![Transaction::deposit(2, 2, dec!(5)), Transaction::dispute(2, 2), Transaction::chargeback(2, 2)];
+        engine.process_transaction_batch_parallel(batch).await.unwrap();
+        assert_eq!(engine.transaction_state(2), Some(TransactionState::ChargedBack));
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Resolved));
+        assert_eq!(engine.stored_transaction_count(), 2);
+
+        // Each client only sees its own transactions, in id order
+        engine.process_transaction(Transaction::deposit(1, 5, dec!(10))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 3, dec!(10))).await.unwrap();
+        let client_transactions: Vec<_> =
+            engine.client_transactions(1).into_iter().map(|(transaction, state)| (transaction.tx, state)).collect();
+        assert_eq!(
+            client_transactions,
+            [(1, TransactionState::Resolved), (3, TransactionState::Processed), (5, TransactionState::Processed)]
+        );
+        assert_eq!(engine.client_transactions(2).len(), 1);
+        assert!(engine.client_transactions(3).is_empty());
+
+        // Purged transactions are only known as finalized
+        engine.set_purge_policy(PurgePolicy::AfterResolveOrChargeback);
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Finalized));
+        assert!(engine.get_transaction(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed_disputes_leave_no_entries() {
+        let mut engine = PaymentEngine::new();
+        for tx in 1..=100 {
+            engine.process_transaction(Transaction::deposit(tx as u16, tx, dec!(10))).await.unwrap();
+            engine.process_transaction(Transaction::dispute(tx as u16, tx)).await.unwrap();
+        }
+        assert_eq!(engine.open_dispute_count(), 100);
+
+        // Rejected resolves and chargebacks of undisputed transactions add nothing
+        for tx in 1..=100 {
+            let close = if tx % 2 == 0 { Transaction::resolve(tx as u16, tx) } else { Transaction::chargeback(tx as u16, tx) };
+            engine.process_transaction(close).await.unwrap();
+            engine.process_transaction(Transaction::resolve(tx as u16, tx)).await.unwrap();
+        }
+        assert_eq!(engine.open_dispute_count(), 0);
+        assert_eq!(engine.stored_transaction_count(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_chargeback() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        let deposit_tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Dispute
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine.process_transaction(dispute_tx).await.unwrap();
+        
+        // Chargeback
+        let chargeback_tx = Transaction::chargeback(1, 1);
+        engine.process_transaction(chargeback_tx).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(0));
+        assert_eq!(accounts[0].held, dec!(0));
+        assert_eq!(accounts[0].total, dec!(0));
+        assert!(accounts[0].locked);
+        assert_eq!(accounts[0].lock_reason, Some(LockReason::Chargeback(1)));
+    }
+
+    #[tokio::test]
+    async fn test_missing_amount_is_an_error() {
+        let mut engine = PaymentEngine::new();
+        let deposit = Transaction { amount: None, ..Transaction::deposit(1, 1, dec!(1)) };
+        let result = engine.process_transaction(deposit).await;
+        assert!(matches!(result, Err(PaymentEngineError::MissingAmount(1))));
+
+        let withdrawal = Transaction { amount: None, ..Transaction::withdrawal(1, 2, dec!(1)) };
+        let result = engine.process_transaction(withdrawal).await;
+        assert!(matches!(result, Err(PaymentEngineError::MissingAmount(2))));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_transaction_is_an_error() {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(5))).await.unwrap();
+        let result = engine.process_transaction(Transaction::deposit(1, 1, dec!(7))).await;
+        assert!(matches!(result, Err(PaymentEngineError::DuplicateTransaction(1))));
+
+        // The dispute holds the amount of the deposit that was applied
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(5), dec!(5), false));
+
+        // Withdrawals can't reuse an id either, not even another client's
+        engine.process_transaction(Transaction::deposit(2, 2, dec!(10))).await.unwrap();
+        let result = engine.process_transaction(Transaction::withdrawal(2, 1, dec!(3))).await;
+        assert!(matches!(result, Err(PaymentEngineError::DuplicateTransaction(1))));
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(2, 2, dec!(3))).await;
+        assert!(matches!(outcome, Err(PaymentEngineError::DuplicateTransaction(2))));
+        assert_eq!(balances(&engine, 2), (dec!(10), dec!(0), dec!(10), false));
+        assert_eq!(engine.rejected_transactions(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_lock_reason_absent_without_chargeback() {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        // Not under dispute any more, so this chargeback fails
+        engine.process_transaction(Transaction::chargeback(1, 1)).await.unwrap();
+
+        let account = engine.get_account(1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.lock_reason, None);
+        assert!(!serde_json::to_string(account).unwrap().contains("lock_reason"));
+    }
+    
+    #[tokio::test]
+    async fn test_locked_account() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        let deposit_tx = Transaction::deposit(1, 1, dec!(100));
+        engine.process_transaction(deposit_tx).await.unwrap();
+        
+        // Dispute and chargeback to lock the account
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        engine.process_transaction(Transaction::chargeback(1, 1)).await.unwrap();
+        
+        // Try another deposit after account is locked
+        let new_deposit_tx = Transaction::deposit(1, 2, dec!(50));
+        engine.process_transaction(new_deposit_tx).await.unwrap();
+        
+        // Balance should remain unchanged since account is locked
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(0));
+        assert_eq!(accounts[0].total, dec!(0));
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_locked_account_policy_per_transaction_type() {
+        let mut engine = PaymentEngine::new();
+        for (tx, amount) in [(1, dec!(100)), (2, dec!(50)), (3, dec!(30)), (4, dec!(20))] {
+            engine.process_transaction(Transaction::deposit(1, tx, amount)).await.unwrap();
+        }
+        for tx in [2, 3, 1] {
+            engine.process_transaction(Transaction::dispute(1, tx)).await.unwrap();
+        }
+        engine.process_transaction(Transaction::chargeback(1, 1)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(20), dec!(80), dec!(100), true));
+
+        // New activity is rejected
+        let rejected = [Transaction::deposit(1, 5, dec!(10)), Transaction::withdrawal(1, 6, dec!(10)), Transaction::dispute(1, 4)];
+        for transaction in rejected {
+            let outcome = engine.process_transaction_with_outcome(transaction).await.unwrap();
+            assert_eq!(outcome, TransactionOutcome::Rejected("account_locked"));
+        }
+        assert_eq!(balances(&engine, 1), (dec!(20), dec!(80), dec!(100), true));
+
+        // Disputes opened before the lock can still be closed
+        let outcome = engine.process_transaction_with_outcome(Transaction::resolve(1, 2)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(balances(&engine, 1), (dec!(70), dec!(30), dec!(100), true));
+        let outcome = engine.process_transaction_with_outcome(Transaction::chargeback(1, 3)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(balances(&engine, 1), (dec!(70), dec!(0), dec!(70), true));
+
+        // The account keeps the reason of its first lock
+        assert_eq!(engine.get_account(1).unwrap().lock_reason, Some(LockReason::Chargeback(1)));
+        assert_eq!(engine.get_account_stats(1).unwrap().disputes_charged_back, 2);
+        assert_eq!(engine.rejected_transactions(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_clients() {
+        let mut engine = PaymentEngine::new();
+        
+        // Client 1 transactions
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 2, dec!(20))).await.unwrap();
+        
+        // Client 2 transactions
+        engine.process_transaction(Transaction::deposit(2, 3, dec!(200))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(2, 4, dec!(50))).await.unwrap();
+        
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 2);
+        
+        // Find client accounts (they might be in any order)
+        let mut client_balances = HashMap::new();
+        for account in accounts {
+            client_balances.insert(account.client, (account.available, account.total));
+        }
+        
+        assert_eq!(client_balances.get(&1), Some(&(dec!(80), dec!(80))));
+        assert_eq!(client_balances.get(&2), Some(&(dec!(150), dec!(150))));
+    }
+    
+    #[tokio::test]
+    async fn test_dispute_non_existent_tx() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        
+        // Dispute a non-existent transaction
+        engine.process_transaction(Transaction::dispute(1, 999)).await.unwrap();
+        
+        // Balance should remain unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(100));
+        assert_eq!(accounts[0].held, dec!(0));
+        assert_eq!(accounts[0].total, dec!(100));
+    }
+    
+    #[tokio::test]
+    async fn test_resolve_without_dispute() {
+        let mut engine = PaymentEngine::new();
+        
+        // Deposit
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        
+        // Resolve without dispute
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        
+        // Balance should remain unchanged
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(100));
+        assert_eq!(accounts[0].held, dec!(0));
         assert_eq!(accounts[0].total, dec!(100));
     }
-    
+    
+    #[tokio::test]
+    async fn test_client_mismatch() {
+        let mut engine = PaymentEngine::new();
+        
+        // Client 1 deposit
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        
+        // Client 2 tries to dispute client 1's transaction
+        engine.process_transaction(Transaction::dispute(2, 1)).await.unwrap();
+        
+        // Balance should remain unchanged
+        let accounts = engine.get_accounts();
+        let client1_account = accounts.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(client1_account.available, dec!(100));
+        assert_eq!(client1_account.held, dec!(0));
+        assert_eq!(client1_account.total, dec!(100));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_failing_transaction_mid_batch_is_reported() {
+        let batch = || {
+            vec![
+                Transaction::deposit(1, 1, dec!(100)),
+                Transaction::deposit(2, 2, dec!(50)),
+                Transaction { amount: None, ..Transaction::deposit(1, 3, dec!(1)) },
+                Transaction::withdrawal(1, 4, dec!(30)),
+                Transaction::withdrawal(2, 5, dec!(80)),
+                Transaction::deposit(2, 6, dec!(5)),
+            ]
+        };
+
+        for parallel in [false, true] {
+            let mut engine = PaymentEngine::new();
+            let transactions = batch();
+            let (pointer, capacity) = (transactions.as_ptr(), transactions.capacity());
+            let result = if parallel {
+                engine.process_transaction_batch_parallel(transactions).await.unwrap()
+            } else {
+                engine.process_transaction_batch(transactions).await
+            };
+
+            // Everything after the failing deposit is still applied, and reported in order
+            let ids: Vec<TxId> = result.outcomes.iter().map(|(tx, _)| *tx).collect();
+            assert_eq!(ids, [1, 2, 3, 4, 5, 6]);
+            assert_eq!((result.applied(), result.rejected()), (4, 1));
+            let errors: Vec<_> = result.errors().collect();
+            assert!(matches!(errors[..], [(3, PaymentEngineError::MissingAmount(3))]));
+            assert!(matches!(result.outcomes[4].1, Ok(TransactionOutcome::Rejected("insufficient_funds"))));
+            assert_eq!(balances(&engine, 1), (dec!(70), dec!(0), dec!(70), false));
+            assert_eq!(balances(&engine, 2), (dec!(55), dec!(0), dec!(55), false));
+            assert_eq!(engine.rejected_transactions(), 2);
+
+            // The emptied vector comes back for the next batch
+            let buffer = result.into_buffer();
+            assert!(buffer.is_empty());
+            assert_eq!((buffer.as_ptr(), buffer.capacity()), (pointer, capacity));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispute_client_check_modes() {
+        for check in [DisputeClientCheck::Strict, DisputeClientCheck::TrustOriginal] {
+            let mut engine = PaymentEngine::new();
+            engine.set_dispute_client_check(check);
+            engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+            engine.process_transaction(Transaction::deposit(1, 2, dec!(50))).await.unwrap();
+            engine.process_transaction(Transaction::deposit(2, 3, dec!(10))).await.unwrap();
+
+            // Client 2, e.g. the merchant, files the dispute of client 1's deposits
+            let outcome = engine.process_transaction_with_outcome(Transaction::dispute(2, 1)).await.unwrap();
+            engine.process_transaction(Transaction::dispute(2, 2)).await.unwrap();
+
+            match check {
+                DisputeClientCheck::Strict => {
+                    assert_eq!(outcome, TransactionOutcome::Rejected("client_mismatch"));
+                    assert_eq!(balances(&engine, 1), (dec!(150), dec!(0), dec!(150), false));
+                    assert_eq!(balances(&engine, 2), (dec!(10), dec!(0), dec!(10), false));
+                    assert_eq!(engine.rejected_transactions(), 2);
+                }
+                DisputeClientCheck::TrustOriginal => {
+                    assert_eq!(outcome, TransactionOutcome::Applied);
+                    assert_eq!(balances(&engine, 1), (dec!(0), dec!(150), dec!(150), false));
+                    assert_eq!(balances(&engine, 2), (dec!(10), dec!(0), dec!(10), false));
+                    assert_eq!(engine.get_account_stats(1).unwrap().disputes_opened, 2);
+                    assert!(engine.get_account_stats(2).is_none());
+
+                    // Resolve and chargeback follow the same rule
+                    engine.process_transaction(Transaction::resolve(2, 1)).await.unwrap();
+                    engine.process_transaction(Transaction::chargeback(2, 2)).await.unwrap();
+                    assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), true));
+                    assert_eq!(engine.get_account(1).unwrap().lock_reason, Some(LockReason::Chargeback(2)));
+                    assert_eq!(balances(&engine, 2), (dec!(10), dec!(0), dec!(10), false));
+                    assert_eq!(engine.rejected_transactions(), 0);
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_trusted_disputes_in_parallel_batches() {
+        let transactions = [
+            Transaction::deposit(1, 1, dec!(100)),
+            Transaction::deposit(2, 2, dec!(20)),
+            Transaction::dispute(2, 1),
+            Transaction::deposit(3, 3, dec!(30)),
+            Transaction::dispute(3, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::resolve(3, 1),
+        ];
+        let mut sequential = PaymentEngine::new();
+        let mut parallel = PaymentEngine::new();
+        sequential.set_dispute_client_check(DisputeClientCheck::TrustOriginal);
+        parallel.set_dispute_client_check(DisputeClientCheck::TrustOriginal);
+        for chunk in transactions.chunks(3) {
+            sequential.process_transaction_batch(chunk.to_vec()).await;
+            parallel.process_transaction_batch_parallel(chunk.to_vec()).await.unwrap();
+        }
+
+        for client in 1..=3 {
+            assert_eq!(balances(&parallel, client), balances(&sequential, client));
+        }
+        assert_eq!(balances(&parallel, 1), (dec!(100), dec!(0), dec!(100), false));
+        assert_eq!(balances(&parallel, 2), (dec!(0), dec!(0), dec!(0), true));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parallel_batch_matches_sequential() {
+        // Interleaved clients, with disputes spanning batches and a cross-client dispute
+        let mut transactions = Vec::new();
+        let mut tx_id = 1;
+        for round in 0..20u32 {
+            for client in 1..=5u16 {
+                transactions.push(Transaction::deposit(client, tx_id, dec!(10) + rust_decimal::Decimal::from(round)));
+                tx_id += 1;
+                transactions.push(Transaction::withdrawal(client, tx_id, dec!(3)));
+                tx_id += 1;
+            }
+        }
+        transactions.push(Transaction::dispute(1, 1));
+        transactions.push(Transaction::dispute(2, 2 * 5 + 1));
+        transactions.push(Transaction::resolve(1, 1));
+        transactions.push(Transaction::dispute(3, 5));
+        transactions.push(Transaction::chargeback(3, 5));
+        transactions.push(Transaction::deposit(3, tx_id, dec!(100)));
+        transactions.push(Transaction::dispute(4, 1));
+        transactions.push(Transaction::dispute(5, 9));
+        transactions.push(Transaction::withdrawal(5, tx_id + 1, dec!(1000)));
+        // Reused ids, of the same client and of another one, in earlier batches and the same one
+        transactions.push(Transaction::deposit(2, 3, dec!(50)));
+        transactions.push(Transaction::deposit(4, 1, dec!(7)));
+        transactions.push(Transaction::deposit(1, tx_id + 2, dec!(5)));
+        transactions.push(Transaction::withdrawal(2, tx_id + 2, dec!(1)));
+
+        let mut sequential = PaymentEngine::new();
+        let mut parallel = PaymentEngine::new();
+        sequential.set_record_history(true);
+        parallel.set_record_history(true);
+        for chunk in transactions.chunks(7) {
+            sequential.process_transaction_batch(chunk.to_vec()).await;
+            parallel.process_transaction_batch_parallel(chunk.to_vec()).await.unwrap();
+        }
+
+        let mut expected = sequential.get_accounts();
+        let mut actual = parallel.get_accounts();
+        expected.sort_by_key(|a| a.client);
+        actual.sort_by_key(|a| a.client);
+
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!(e.client, a.client);
+            assert_eq!(e.available, a.available);
+            assert_eq!(e.held, a.held);
+            assert_eq!(e.total, a.total);
+            assert_eq!(e.locked, a.locked);
+            assert_eq!(sequential.account_history(e.client), parallel.account_history(a.client));
+        }
+        assert!(actual.iter().any(|a| a.locked));
+        assert!(actual.iter().any(|a| a.held > dec!(0)));
+        assert!(sequential.rejected_transactions() > 0);
+        assert_eq!(sequential.rejected_transactions(), parallel.rejected_transactions());
+    }
+
+    #[tokio::test]
+    async fn test_limits_reject_before_mutating() {
+        let mut engine = PaymentEngine::new();
+        engine.set_limits(Limits {
+            max_deposit: Some(dec!(100)),
+            max_withdrawal: Some(dec!(30)),
+            max_withdrawals_per_client: Some(2),
+            ..Default::default()
+        });
+
+        let outcome = engine.process_transaction_with_outcome(Transaction::deposit(1, 1, dec!(100.0001))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::LimitExceeded(Limit::MaxDeposit));
+        assert_eq!(outcome.rejection_reason(), Some("max_deposit_exceeded"));
+        let outcome = engine.process_transaction_with_outcome(Transaction::deposit(1, 2, dec!(100))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(1, 3, dec!(31))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::LimitExceeded(Limit::MaxWithdrawal));
+        for tx in [4, 5] {
+            let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(1, tx, dec!(30))).await.unwrap();
+            assert_eq!(outcome, TransactionOutcome::Applied);
+        }
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(1, 6, dec!(1))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::LimitExceeded(Limit::MaxWithdrawalsPerClient));
+
+        // Another client has its own withdrawal count; rejected withdrawals don't count
+        engine.process_transaction(Transaction::deposit(2, 7, dec!(10))).await.unwrap();
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(2, 8, dec!(20))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("insufficient_funds"));
+        for tx in [9, 10] {
+            let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(2, tx, dec!(5))).await.unwrap();
+            assert_eq!(outcome, TransactionOutcome::Applied);
+        }
+
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(40));
+        assert_eq!(engine.limit_rejections(), BTreeMap::from([(1, 3)]));
+        assert_eq!(engine.rejected_transactions(), 4);
+    }
+
+    #[test]
+    fn test_max_open_disputes_per_client() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::new();
+        let sink = events.clone();
+        engine.set_event_handler(Box::new(move |event| sink.lock().unwrap().push(event)));
+        engine.set_limits(Limits { max_open_disputes_per_client: Some(2), ..Default::default() });
+        engine.set_allow_partial_disputes(true);
+        for tx in 1..=4 {
+            engine.apply(Transaction::deposit(1, tx, dec!(10))).unwrap();
+        }
+        engine.apply(Transaction::deposit(2, 5, dec!(10))).unwrap();
+
+        let partial = |tx, amount| Transaction { amount: Some(amount), ..Transaction::dispute(1, tx) };
+        assert_eq!(engine.apply(partial(1, dec!(4))).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.apply(Transaction::dispute(1, 2)).unwrap(), TransactionOutcome::Applied);
+        let outcome = engine.apply(Transaction::dispute(1, 3)).unwrap();
+        assert_eq!(outcome, TransactionOutcome::LimitExceeded(Limit::MaxOpenDisputesPerClient));
+        assert_eq!(outcome.rejection_reason(), Some("max_open_disputes_per_client_exceeded"));
+        assert_eq!(engine.transaction_state(3), Some(TransactionState::Processed));
+
+        // Disputing more of a transaction already under dispute still works
+        assert_eq!(engine.apply(partial(1, dec!(6))).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Disputed(dec!(10))));
+
+        // Other clients have their own count
+        assert_eq!(engine.apply(Transaction::dispute(2, 5)).unwrap(), TransactionOutcome::Applied);
+
+        // Resolving frees a slot
+        assert_eq!(engine.apply(Transaction::resolve(1, 1)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.apply(Transaction::dispute(1, 3)).unwrap(), TransactionOutcome::Applied);
+        let outcome = engine.apply(Transaction::dispute(1, 4)).unwrap();
+        assert_eq!(outcome, TransactionOutcome::LimitExceeded(Limit::MaxOpenDisputesPerClient));
+
+        // So does a chargeback
+        assert_eq!(engine.apply(Transaction::chargeback(1, 2)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.apply(Transaction::resolve(1, 3)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.get_account(1).unwrap().held, dec!(0));
+
+        assert_eq!(engine.limit_rejections(), BTreeMap::from([(1, 2)]));
+        let rejected: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                EngineEvent::TransactionRejected { tx, reason, .. } => Some((*tx, *reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rejected, [(3, "max_open_disputes_per_client_exceeded"), (4, "max_open_disputes_per_client_exceeded")]);
+    }
+
+    #[test]
+    fn test_deferred_dispute_activates_when_funds_arrive() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::new();
+        let sink = events.clone();
+        engine.set_event_handler(Box::new(move |event| sink.lock().unwrap().push(event)));
+        engine.set_max_deferred_disputes(Some(2));
+        engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(Transaction::deposit(1, 2, dec!(20))).unwrap();
+        engine.apply(Transaction::withdrawal(1, 3, dec!(15))).unwrap();
+        events.lock().unwrap().clear();
+
+        // Only 5 is left available once the first dispute holds its 10
+        assert_eq!(engine.apply(Transaction::dispute(1, 1)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.apply(Transaction::dispute(1, 2)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.get_account_stats(1).unwrap().deferred_disputes, [DeferredDispute { tx: 2, amount: dec!(20) }]);
+        assert_eq!(engine.transaction_state(2), Some(TransactionState::Processed));
+        assert_eq!(engine.apply(Transaction::resolve(1, 2)).unwrap(), TransactionOutcome::Rejected("dispute_deferred"));
+        assert_eq!(engine.apply(Transaction::dispute(1, 2)).unwrap(), TransactionOutcome::Rejected("already_disputed"));
+
+        engine.apply(Transaction::deposit(1, 4, dec!(15))).unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(0), dec!(30), dec!(30)));
+        let stats = engine.get_account_stats(1).unwrap();
+        assert_eq!((stats.disputes_opened, stats.deferred_disputes.len()), (2, 0));
+        assert_eq!(stats.held_transactions, std::collections::BTreeSet::from([1, 2]));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                EngineEvent::DisputeOpened { client: 1, tx: 1, amount: dec!(10) },
+                EngineEvent::DisputeDeferred { client: 1, tx: 2, amount: dec!(20) },
+                EngineEvent::TransactionRejected { client: 1, tx: 2, reason: "dispute_deferred" },
+                EngineEvent::TransactionRejected { client: 1, tx: 2, reason: "already_disputed" },
+                EngineEvent::DepositApplied { client: 1, tx: 4, amount: dec!(15) },
+                EngineEvent::DisputeActivated { client: 1, tx: 2, amount: dec!(20) },
+            ]
+        );
+        assert_eq!(engine.apply(Transaction::resolve(1, 2)).unwrap(), TransactionOutcome::Applied);
+
+        // Off by default, the dispute is dropped
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(Transaction::withdrawal(1, 2, dec!(5))).unwrap();
+        assert_eq!(engine.apply(Transaction::dispute(1, 1)).unwrap(), TransactionOutcome::Rejected("hold_failed"));
+    }
+
+    #[tokio::test]
+    async fn test_deferred_disputes_are_bounded_and_rolled_back() {
+        let transactions = vec![
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(1, 2, dec!(10)),
+            Transaction::deposit(1, 3, dec!(10)),
+            Transaction::withdrawal(1, 4, dec!(25)),
+            // The first dispute is deferred, the second finds the queue full
+            // and the deposit covers the first
+            Transaction::dispute(1, 2),
+            Transaction::dispute(1, 3),
+            Transaction::deposit(1, 5, dec!(5)),
+        ];
+        let mut engine = PaymentEngine::new();
+        engine.set_max_deferred_disputes(Some(1));
+        let outcomes: Vec<_> = engine.apply_all(transactions.clone()).into_iter().map(Result::unwrap).collect();
+        assert_eq!(outcomes[5], TransactionOutcome::Rejected("hold_failed"));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!((account.available, account.held), (dec!(0), dec!(10)));
+        assert_eq!(engine.transaction_state(2), Some(TransactionState::Disputed(dec!(10))));
+
+        // Activating a deferred dispute is undone with the batch
+        let mut engine = PaymentEngine::new();
+        engine.set_max_deferred_disputes(Some(1));
+        engine.apply_all(transactions[..6].to_vec());
+        engine.begin_journal();
+        engine.apply(transactions[6].clone()).unwrap();
+        engine.roll_back_journal().unwrap();
+        assert_eq!(engine.get_account(1).unwrap().held, dec!(0));
+        assert_eq!(engine.get_account_stats(1).unwrap().deferred_disputes.len(), 1);
+        assert_eq!(engine.transaction_state(2), Some(TransactionState::Processed));
+
+        // Parallel batches activate it the same way, across batches
+        let mut parallel = PaymentEngine::new();
+        parallel.set_max_deferred_disputes(Some(1));
+        for chunk in transactions.chunks(3) {
+            parallel.process_transaction_batch_parallel(chunk.to_vec()).await.unwrap();
+        }
+        let account = parallel.get_account(1).unwrap();
+        assert_eq!((account.available, account.held), (dec!(0), dec!(10)));
+        assert_eq!(parallel.transaction_state(2), Some(TransactionState::Disputed(dec!(10))));
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_fee_modes() {
+        let cases = [
+            (WithdrawalFee::Flat(dec!(1)), dec!(89)),
+            (WithdrawalFee::Percent(dec!(2.5)), dec!(89.75)),
+            (WithdrawalFee::FlatPlusPercent(dec!(1), dec!(2.5)), dec!(88.75)),
+            (WithdrawalFee::None, dec!(90)),
+        ];
+        for (fee, expected_available) in cases {
+            let mut engine = PaymentEngine::new();
+            engine.set_withdrawal_fee(fee);
+            engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+            engine.process_transaction(Transaction::withdrawal(1, 2, dec!(10))).await.unwrap();
+
+            let account = engine.get_account(1).unwrap();
+            assert_eq!(account.available, expected_available, "{:?}", fee);
+            assert_eq!(account.total, expected_available, "{:?}", fee);
+            let charged = engine.fees().get(&1).copied().unwrap_or_default();
+            assert_eq!(charged, dec!(90) - expected_available, "{:?}", fee);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_fee_insufficient_funds_boundary() {
+        let mut engine = PaymentEngine::new();
+        engine.set_withdrawal_fee(WithdrawalFee::Flat(dec!(0.5)));
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(10))).await.unwrap();
+
+        // The balance covers the amount but not the fee
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(1, 2, dec!(9.6))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("insufficient_funds"));
+        assert!(engine.fees().is_empty());
+
+        // Exactly covers amount plus fee
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(1, 3, dec!(9.5))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(0));
+        assert_eq!(engine.fees(), BTreeMap::from([(1, dec!(0.5))]));
+    }
+
+    #[tokio::test]
+    async fn test_loaded_locked_account_stays_locked() {
+        let mut engine = PaymentEngine::new();
+        engine
+            .load_accounts(vec![
+                Account { client: 1, available: dec!(10), held: dec!(0), total: dec!(10), locked: true, ..Default::default() },
+                Account { client: 2, available: dec!(10), held: dec!(0), total: dec!(10), locked: false, ..Default::default() },
+            ])
+            .unwrap();
+
+        let outcome = engine.process_transaction_with_outcome(Transaction::deposit(1, 1, dec!(5))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("account_locked"));
+        let outcome = engine.process_transaction_with_outcome(Transaction::withdrawal(2, 2, dec!(4))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(10));
+        assert_eq!(engine.get_account(2).unwrap().available, dec!(6));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_evicted_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TransactionStore::with_spill(100, dir.path()).unwrap();
+        let mut engine = PaymentEngine::with_transaction_store(store);
+
+        for tx in 1..=350 {
+            engine.process_transaction(Transaction::deposit((tx % 3 + 1) as u16, tx, dec!(1))).await.unwrap();
+        }
+
+        // tx 3 (client 1) and tx 4 (client 2) were evicted to disk long ago
+        engine.process_transaction(Transaction::dispute(1, 3)).await.unwrap();
+        engine.process_transaction(Transaction::dispute(2, 4)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(1, 3)).await.unwrap();
+        engine.process_transaction(Transaction::chargeback(2, 4)).await.unwrap();
+
+        let accounts = engine.get_accounts();
+        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
+        let client2 = accounts.iter().find(|a| a.client == 2).unwrap();
+
+        assert_eq!(client1.available, dec!(116));
+        assert_eq!(client1.held, dec!(0));
+        assert!(!client1.locked);
+
+        assert_eq!(client2.available, dec!(116));
+        assert_eq!(client2.held, dec!(0));
+        assert_eq!(client2.total, dec!(116));
+        assert!(client2.locked);
+    }
+
+    #[tokio::test]
+    async fn test_redispute_of_purged_chargeback_rejected() {
+        let mut engine = PaymentEngine::new();
+        engine.set_purge_policy(PurgePolicy::AfterChargeback);
+
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::deposit(1, 2, dec!(50))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        engine.process_transaction(Transaction::chargeback(1, 1)).await.unwrap();
+
+        assert!(engine.transactions.is_finalized(1));
+        assert!(engine.transactions.get_transaction(1).is_none());
+
+        // The chargeback locked the account, so a re-dispute stops there;
+        // a resolve or chargeback of the purged transaction gets past the
+        // lock and is rejected as finalized, not unknown
+        let outcome = engine.process_transaction_with_outcome(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("account_locked"));
+        for tx in [Transaction::resolve(1, 1), Transaction::chargeback(1, 1)] {
+            let outcome = engine.process_transaction_with_outcome(tx).await.unwrap();
+            assert_eq!(outcome, TransactionOutcome::Rejected("transaction_finalized"));
+        }
+        assert!(engine.transactions.is_finalized(1));
+        assert!(!engine.transactions.is_disputed(1));
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(50));
+        assert_eq!(accounts[0].held, dec!(0));
+        assert_eq!(accounts[0].total, dec!(50));
+        assert!(accounts[0].locked);
+    }
+
+    #[tokio::test]
+    async fn test_redispute_of_purged_resolve_rejected() {
+        let mut engine = PaymentEngine::new();
+        engine.set_purge_policy(PurgePolicy::AfterResolveOrChargeback);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        assert!(engine.transactions.get_transaction(1).is_none());
+
+        let outcome = engine.process_transaction_with_outcome(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("transaction_finalized"));
+        let outcome = engine.process_transaction_with_outcome(Transaction::dispute(1, 2)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("transaction_not_found"));
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), false));
+    }
+
+    #[tokio::test]
+    async fn test_purging_does_not_change_balances() {
+        let config = crate::generator::GeneratorConfig {
+            transactions: 5000,
+            clients: 25,
+            dispute_probability: 0.1,
+            seed: 11,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        let transactions: Vec<Transaction> = reader.deserialize().map(|r| r.unwrap()).collect();
+
+        let mut results = Vec::new();
+        for policy in [PurgePolicy::Never, PurgePolicy::AfterChargeback, PurgePolicy::AfterResolveOrChargeback] {
+            let mut engine = PaymentEngine::new();
+            engine.set_purge_policy(policy);
+            engine.process_transaction_batch(transactions.clone()).await;
+            let mut accounts = engine.get_accounts();
+            accounts.sort_by_key(|a| a.client);
+            results.push(accounts);
+        }
+
+        for accounts in &results[1..] {
+            assert_eq!(accounts.len(), results[0].len());
+            for (e, a) in results[0].iter().zip(accounts.iter()) {
+                assert_eq!((e.client, e.available, e.held, e.total, e.locked), (a.client, a.available, a.held, a.total, a.locked));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_clears_state_but_keeps_configuration() {
+        let mut engine = PaymentEngine::new();
+        engine.set_limits(Limits { max_deposit: Some(dec!(50)), ..Default::default() });
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(10))).await.unwrap();
+        engine.process_transaction(Transaction::deposit(1, 2, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+
+        engine.reset();
+        assert!(engine.get_accounts().is_empty());
+        assert_eq!(engine.stored_transaction_count(), 0);
+        assert_eq!(engine.transaction_state(1), None);
+        assert_eq!(engine.rejected_transactions(), 0);
+        assert!(engine.limit_rejections().is_empty());
+        assert!(engine.get_account_stats(1).is_none());
+
+        // Transaction ids can be reused and the limit still applies
+        engine.process_transaction(Transaction::deposit(2, 1, dec!(100))).await.unwrap();
+        assert!(engine.get_accounts().iter().all(|a| a.total.is_zero()));
+        assert_eq!(engine.rejected_transactions(), 1);
+    }
+
+    #[test]
+    fn test_effects_failing_at_second_step_change_nothing() {
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap();
+        let state = |engine: &PaymentEngine| {
+            let account = engine.get_account(1).unwrap();
+            (account.available, account.held, account.total, account.locked, engine.transaction_state(1))
+        };
+        let unchanged = (dec!(10), dec!(0), dec!(10), false, Some(TransactionState::Processed));
+
+        // The hold could be made, but releasing more than it held can't
+        let effects = [
+            Effect::Disputed(dec!(5)),
+            Effect::Funds(FundsMove::Hold(dec!(5))),
+            Effect::Funds(FundsMove::Release(dec!(6))),
+            Effect::Lock(LockReason::Chargeback(1)),
+            Effect::Finalize,
+        ];
+        assert!(!engine.apply_effects(1, 1, None, &effects));
+        assert_eq!(state(&engine), unchanged);
+
+        // A second currency's balance isn't left behind either
+        let eur: Currency = "EUR".parse().unwrap();
+        let effects = [Effect::Funds(FundsMove::Credit(dec!(3))), Effect::Funds(FundsMove::Hold(dec!(4)))];
+        assert!(!engine.apply_effects(1, 1, Some(eur), &effects));
+        assert!(engine.get_account(1).unwrap().other_currencies.is_empty());
+        assert_eq!(state(&engine), unchanged);
+
+        let effects = [Effect::Disputed(dec!(5)), Effect::Funds(FundsMove::Hold(dec!(5))), Effect::Funds(FundsMove::Release(dec!(1)))];
+        assert!(engine.apply_effects(1, 1, None, &effects));
+        assert_eq!(state(&engine), (dec!(6), dec!(4), dec!(10), false, Some(TransactionState::Disputed(dec!(5)))));
+    }
+
+    #[test]
+    fn test_dispute_that_cannot_hold_leaves_transaction_undisputed() {
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(Transaction::withdrawal(1, 2, dec!(8))).unwrap();
+
+        assert_eq!(engine.apply(Transaction::dispute(1, 1)).unwrap(), TransactionOutcome::Rejected("hold_failed"));
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Processed));
+        assert!(engine.get_account_stats(1).is_none());
+        assert_eq!(engine.apply(Transaction::chargeback(1, 1)).unwrap(), TransactionOutcome::Rejected("not_disputed"));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.locked), (dec!(2), dec!(0), false));
+    }
+
+    #[test]
+    fn test_roll_back_journal_restores_state() {
+        let mut engine = PaymentEngine::new();
+        engine.set_record_history(true);
+        engine.set_limits(Limits { max_withdrawals_per_client: Some(1), ..Default::default() });
+        engine.apply(Transaction::deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(Transaction::deposit(2, 2, dec!(5))).unwrap();
+        let before = |engine: &PaymentEngine| {
+            let account = engine.get_account(1).unwrap();
+            (account.available, account.held, engine.transaction_state(1), engine.stored_transaction_count(), engine.account_history(1).len())
+        };
+        let saved = before(&engine);
+
+        engine.begin_journal();
+        engine.apply(Transaction::dispute(1, 1)).unwrap();
+        engine.apply(Transaction::withdrawal(2, 3, dec!(1))).unwrap();
+        engine.apply(Transaction::withdrawal(2, 4, dec!(1))).unwrap();
+        engine.apply(Transaction::deposit(3, 5, dec!(7))).unwrap();
+        assert_eq!(engine.rejected_transactions(), 1);
+        engine.roll_back_journal().unwrap();
+
+        assert_eq!(before(&engine), saved);
+        assert_eq!(engine.get_account(2).unwrap().available, dec!(5));
+        assert!(engine.get_account(3).is_none() && engine.transaction_state(5).is_none());
+        assert_eq!((engine.rejected_transactions(), engine.limit_rejections().len()), (0, 0));
+
+        // The withdrawal count was restored too, so one more is allowed
+        engine.apply(Transaction::withdrawal(2, 3, dec!(1))).unwrap();
+        assert_eq!(engine.get_account(2).unwrap().available, dec!(4));
+    }
+
+    #[test]
+    fn test_counts_track_mixed_transactions() {
+        // The same outcomes and counts whether or not the stores are pre-sized,
+        // even for hints too large to allocate
+        for mut engine in [PaymentEngine::new(), PaymentEngine::with_capacity(16, 1024), PaymentEngine::with_capacity(usize::MAX, usize::MAX)] {
+            let steps = [
+                (Transaction::deposit(1, 1, dec!(100)), TransactionOutcome::Applied, (1, 1, 0)),
+                (Transaction::deposit(2, 2, dec!(50)), TransactionOutcome::Applied, (2, 2, 0)),
+                (Transaction::deposit(1, 3, dec!(40)), TransactionOutcome::Applied, (2, 3, 0)),
+                (Transaction::withdrawal(1, 4, dec!(30)), TransactionOutcome::Applied, (2, 4, 0)),
+                (Transaction::dispute(1, 1), TransactionOutcome::Applied, (2, 4, 1)),
+                (Transaction::dispute(2, 2), TransactionOutcome::Applied, (2, 4, 2)),
+                (Transaction::dispute(2, 2), TransactionOutcome::Rejected("already_disputed"), (2, 4, 2)),
+                (Transaction::resolve(1, 1), TransactionOutcome::Applied, (2, 4, 1)),
+                (Transaction::chargeback(2, 2), TransactionOutcome::Applied, (2, 4, 0)),
+                (Transaction::deposit(2, 5, dec!(5)), TransactionOutcome::Rejected("account_locked"), (2, 4, 0)),
+                (Transaction::deposit(3, 6, dec!(1)), TransactionOutcome::Applied, (3, 5, 0)),
+            ];
+            for (transaction, outcome, counts) in steps {
+                let tx = transaction.tx;
+                assert_eq!(engine.apply(transaction).unwrap(), outcome, "tx {}", tx);
+                assert_eq!((engine.account_count(), engine.stored_transaction_count(), engine.open_dispute_count()), counts, "tx {}", tx);
+            }
+            engine.reset();
+            assert_eq!((engine.account_count(), engine.stored_transaction_count(), engine.open_dispute_count()), (0, 0, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_disjoint_shards_matches_single_engine() {
+        let config = crate::generator::GeneratorConfig {
+            transactions: 5000,
+            clients: 20,
+            dispute_probability: 0.1,
+            seed: 5,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        let transactions: Vec<Transaction> = reader.deserialize().map(|r| r.unwrap()).collect();
+
+        let mut single = PaymentEngine::new();
+        single.process_transaction_batch(transactions.clone()).await;
+
+        let (odd, even): (Vec<_>, Vec<_>) = transactions.iter().cloned().partition(|t| t.client % 2 == 1);
+        let mut merged = PaymentEngine::new();
+        let mut other = PaymentEngine::new();
+        merged.process_transaction_batch(odd).await;
+        other.process_transaction_batch(even).await;
+        merged.merge(other).unwrap();
+
+        let mut expected = single.get_accounts();
+        let mut actual = merged.get_accounts();
+        expected.sort_by_key(|a| a.client);
+        actual.sort_by_key(|a| a.client);
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!((e.client, e.available, e.held, e.total, e.locked), (a.client, a.available, a.held, a.total, a.locked));
+            assert_eq!(single.get_account_stats(e.client), merged.get_account_stats(e.client));
+        }
+        assert_eq!(single.stored_transaction_count(), merged.stored_transaction_count());
+        assert_eq!(single.rejected_transactions(), merged.rejected_transactions());
+        for transaction in &transactions {
+            assert_eq!(single.transaction_state(transaction.tx), merged.transaction_state(transaction.tx));
+        }
+    }
+
+    #[test]
+    fn test_max_tx_id_includes_rejected_transactions() {
+        let mut engine = PaymentEngine::new();
+        assert_eq!(engine.max_tx_id(), None);
+        engine.apply(Transaction::deposit(1, 10, dec!(5))).unwrap();
+        assert_eq!(engine.apply(Transaction::withdrawal(1, 30, dec!(50))).unwrap(), TransactionOutcome::Rejected("insufficient_funds"));
+        engine.apply(Transaction::deposit(1, 20, dec!(5))).unwrap();
+        assert_eq!(engine.max_tx_id(), Some(30));
+
+        let mut other = PaymentEngine::new();
+        other.apply(Transaction::dispute(2, 70)).unwrap();
+        engine.merge(other).unwrap();
+        assert_eq!(engine.max_tx_id(), Some(70));
+        engine.reset();
+        assert_eq!(engine.max_tx_id(), None);
+    }
+
+    #[test]
+    fn test_group_by_client_keeps_order_within_client() {
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(3, 1, dec!(5.0))).unwrap();
+        let mut batch = vec![
+            Transaction::deposit(2, 10, dec!(1.0)),
+            Transaction::deposit(1, 11, dec!(2.0)),
+            Transaction::withdrawal(2, 12, dec!(0.5)),
+            Transaction::dispute(3, 11),
+            Transaction::dispute(1, 11),
+            Transaction::deposit(3, 13, dec!(1.0)),
+        ];
+        let grouped = |batch: &[Transaction]| batch.iter().map(|t| (t.client, t.tx)).collect::<Vec<_>>();
+
+        engine.group_by_client(&mut batch);
+        assert_eq!(grouped(&batch), [(2, 10), (2, 12), (1, 11), (1, 11), (3, 11), (3, 13)]);
+
+        // Trusted disputes go with the transaction they refer to, whether it
+        // is stored or in the batch, even before it
+        engine.set_dispute_client_check(DisputeClientCheck::TrustOriginal);
+        batch.insert(0, Transaction::dispute(2, 1));
+        batch.insert(0, Transaction::resolve(1, 13));
+        engine.group_by_client(&mut batch);
+        assert_eq!(grouped(&batch), [(1, 13), (2, 1), (3, 13), (2, 10), (2, 12), (1, 11), (1, 11), (3, 11)]);
+    }
+
+    #[test]
+    fn test_stats_follow_rollbacks_and_merges() {
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(1, 1, dec!(10.0))).unwrap();
+        engine.apply(Transaction::dispute(1, 1)).unwrap();
+        engine.begin_journal();
+        engine.apply(Transaction::chargeback(1, 1)).unwrap();
+        assert_eq!((engine.stats().accounts_locked, engine.stats().disputes_open), (1, 0));
+        engine.roll_back_journal().unwrap();
+        let stats = engine.stats();
+        assert_eq!((stats.transactions_applied, stats.deposits_applied, stats.disputes_open, stats.accounts_locked), (2, 1, 1, 0));
+
+        let mut other = PaymentEngine::new();
+        other.load_accounts(vec![Account { locked: true, ..Account::new(2) }]).unwrap();
+        other.apply(Transaction::deposit(3, 2, dec!(5.0))).unwrap();
+        other.apply(Transaction::withdrawal(3, 3, dec!(50.0))).unwrap();
+        engine.merge(other).unwrap();
+        assert_eq!(
+            engine.stats(),
+            EngineStats {
+                transactions_applied: 3,
+                deposits_applied: 2,
+                withdrawals_applied: 0,
+                disputes_open: 1,
+                accounts_locked: 1,
+                transactions_rejected: 1,
+            }
+        );
+        engine.reset();
+        assert_eq!(engine.stats(), EngineStats::default());
+    }
+
+    #[test]
+    fn test_stats_display_groups_thousands() {
+        let stats = EngineStats {
+            transactions_applied: 42_100,
+            deposits_applied: 40_000,
+            withdrawals_applied: 2_000,
+            disputes_open: 15,
+            accounts_locked: 3,
+            transactions_rejected: 1_234_567,
+        };
+        assert_eq!(stats.to_string(), "42,100 tx applied (40,000 deposits, 2,000 withdrawals), 15 disputes open, 3 accounts locked, 1,234,567 rejects");
+    }
+
+    #[test]
+    fn test_changed_clients_are_taken_once_and_rolled_back() {
+        let mut engine = PaymentEngine::new();
+        engine.apply(Transaction::deposit(1, 1, dec!(10.0))).unwrap();
+        assert!(engine.take_changed_clients().is_empty());
+
+        engine.set_track_changes(true);
+        engine.apply(Transaction::deposit(2, 2, dec!(5.0))).unwrap();
+        engine.apply(Transaction::withdrawal(1, 3, dec!(4.0))).unwrap();
+        assert_eq!(engine.take_changed_clients(), vec![1, 2]);
+        assert!(engine.take_changed_clients().is_empty());
+
+        // A rejected transaction changes nothing for an existing account
+        engine.apply(Transaction::withdrawal(2, 4, dec!(50.0))).unwrap();
+        assert!(engine.take_changed_clients().is_empty());
+
+        engine.begin_journal();
+        engine.apply(Transaction::deposit(3, 5, dec!(1.0))).unwrap();
+        engine.roll_back_journal().unwrap();
+        assert!(engine.take_changed_clients().is_empty());
+    }
+
+    #[test]
+    fn test_references_to_unknown_clients_create_no_account_unless_allowed() {
+        let references = [Transaction::dispute(99, 1), Transaction::resolve(99, 1), Transaction::chargeback(99, 1)];
+        let mut engine = PaymentEngine::new();
+        for transaction in references.clone() {
+            assert_eq!(engine.apply(transaction).unwrap(), TransactionOutcome::Rejected("transaction_not_found"));
+        }
+        assert_eq!(engine.get_account(99).map(|account| account.total), Some(Decimal::ZERO));
+
+        let mut engine = PaymentEngine::new();
+        engine.set_create_accounts_on_reference(false);
+        engine.set_track_changes(true);
+        for transaction in references {
+            assert_eq!(engine.apply(transaction).unwrap(), TransactionOutcome::Rejected("unknown_client"));
+        }
+        assert!(engine.get_account(99).is_none());
+        assert!(engine.take_changed_clients().is_empty());
+        assert_eq!(engine.rejected_transactions(), 3);
+
+        // Deposits and withdrawals still open accounts, and references to them apply
+        engine.apply(Transaction::withdrawal(98, 2, dec!(1.0))).unwrap();
+        assert!(engine.get_account(98).is_some());
+        engine.apply(Transaction::deposit(99, 3, dec!(5.0))).unwrap();
+        assert_eq!(engine.apply(Transaction::dispute(99, 3)).unwrap(), TransactionOutcome::Applied);
+        assert_eq!(engine.get_account(99).map(|account| account.held), Some(dec!(5.0)));
+    }
+
+    #[test]
+    fn test_loaded_accounts_stay_untouched_until_a_transaction_applies() {
+        let mut engine = PaymentEngine::new();
+        let loaded = |client| Account { available: dec!(5.0), total: dec!(5.0), ..Account::new(client) };
+        engine.load_accounts(vec![loaded(1), loaded(2)]).unwrap();
+        assert!(engine.is_untouched(1) && engine.is_untouched(2));
+
+        // A rejected withdrawal leaves the account as it was loaded
+        engine.apply(Transaction::withdrawal(1, 1, dec!(50.0))).unwrap();
+        assert!(engine.is_untouched(1));
+        engine.apply(Transaction::deposit(1, 2, dec!(1.0))).unwrap();
+        assert!(!engine.is_untouched(1));
+        assert!(engine.is_untouched(2));
+
+        engine.begin_journal();
+        engine.apply(Transaction::deposit(2, 3, dec!(1.0))).unwrap();
+        assert!(!engine.is_untouched(2));
+        engine.roll_back_journal().unwrap();
+        assert!(engine.is_untouched(2));
+
+        // A client created by a transaction was never loaded
+        engine.apply(Transaction::deposit(3, 4, dec!(1.0))).unwrap();
+        assert!(!engine.is_untouched(3));
+    }
+
     #[tokio::test]
-    async fn test_withdrawal() {
+    async fn test_merge_rejects_overlapping_clients_and_transactions() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit first
-        let deposit_tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Then withdraw
-        let withdraw_tx = create_withdrawal(1, 2, dec!(30));
-        engine.process_transaction(withdraw_tx).await.unwrap();
-        
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(10))).await.unwrap();
+
+        let mut same_client = PaymentEngine::new();
+        same_client.process_transaction(Transaction::deposit(1, 2, dec!(5))).await.unwrap();
+        let err = engine.merge(same_client).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::MergeConflict(_)));
+        assert_eq!(err.to_string(), "Cannot merge engines: client 1 is in both engines");
+
+        let mut same_tx = PaymentEngine::new();
+        same_tx.process_transaction(Transaction::deposit(2, 1, dec!(5))).await.unwrap();
+        let err = engine.merge(same_tx).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot merge engines: transaction 1 is in both engines with different state");
+
+        // A failed merge leaves the engine as it was
         let accounts = engine.get_accounts();
         assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(70));
-        assert_eq!(accounts[0].total, dec!(70));
+        assert_eq!(accounts[0].available, dec!(10));
     }
-    
+
     #[tokio::test]
-    async fn test_insufficient_funds_withdrawal() {
+    async fn test_account_history_for_dispute_and_resolve() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit first
-        let deposit_tx = create_deposit(1, 1, dec!(50));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Try to withdraw more than available
-        let withdraw_tx = create_withdrawal(1, 2, dec!(75));
-        engine.process_transaction(withdraw_tx).await.unwrap();
-        
-        // Balance should remain unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(50));
-        assert_eq!(accounts[0].total, dec!(50));
+        engine.set_record_history(true);
+
+        for tx in [
+            Transaction::deposit(1, 1, dec!(100)),
+            Transaction::deposit(2, 2, dec!(50)),
+            Transaction::deposit(1, 3, dec!(20)),
+            Transaction::dispute(1, 1),
+            Transaction::withdrawal(1, 4, dec!(30)),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+            Transaction::resolve(1, 1),
+        ] {
+            engine.process_transaction(tx).await.unwrap();
+        }
+
+        let events: Vec<_> = engine
+            .account_history(1)
+            .iter()
+            .map(|e| (e.tx, e.transaction_type, e.outcome, e.resulting_available, e.resulting_held))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, TransactionType::Deposit, TransactionOutcome::Applied, dec!(100), dec!(0)),
+                (3, TransactionType::Deposit, TransactionOutcome::Applied, dec!(120), dec!(0)),
+                (1, TransactionType::Dispute, TransactionOutcome::Applied, dec!(20), dec!(100)),
+                (4, TransactionType::Withdrawal, TransactionOutcome::Rejected("insufficient_funds"), dec!(20), dec!(100)),
+                (1, TransactionType::Dispute, TransactionOutcome::Rejected("already_disputed"), dec!(20), dec!(100)),
+                (1, TransactionType::Resolve, TransactionOutcome::Applied, dec!(120), dec!(0)),
+                (1, TransactionType::Resolve, TransactionOutcome::Rejected("not_disputed"), dec!(120), dec!(0)),
+            ]
+        );
+        assert_eq!(engine.account_history(1)[0].amount, Some(dec!(100)));
+        assert_eq!(engine.account_history(2).len(), 1);
+        assert!(engine.account_history(3).is_empty());
+
+        // Nothing is recorded by default
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(1))).await.unwrap();
+        assert!(engine.account_history(1).is_empty());
     }
-    
+
     #[tokio::test]
-    async fn test_dispute() {
+    async fn test_events_for_dispute_and_chargeback() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        let deposit_tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Dispute the deposit
-        let dispute_tx = create_dispute(1, 1);
-        engine.process_transaction(dispute_tx).await.unwrap();
-        
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(0));
-        assert_eq!(accounts[0].held, dec!(100));
-        assert_eq!(accounts[0].total, dec!(100));
+        let sink = events.clone();
+        engine.set_event_handler(Box::new(move |event| sink.lock().unwrap().push(event)));
+
+        for tx in [
+            Transaction::deposit(1, 1, dec!(100)),
+            Transaction::withdrawal(1, 2, dec!(30)),
+            Transaction::withdrawal(1, 3, dec!(500)),
+            Transaction::deposit(1, 4, dec!(50)),
+            Transaction::dispute(1, 1),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::deposit(1, 5, dec!(5)),
+        ] {
+            engine.process_transaction(tx).await.unwrap();
+        }
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                EngineEvent::DepositApplied { client: 1, tx: 1, amount: dec!(100) },
+                EngineEvent::WithdrawalApplied { client: 1, tx: 2, amount: dec!(30) },
+                EngineEvent::TransactionRejected { client: 1, tx: 3, reason: "insufficient_funds" },
+                EngineEvent::DepositApplied { client: 1, tx: 4, amount: dec!(50) },
+                EngineEvent::DisputeOpened { client: 1, tx: 1, amount: dec!(100) },
+                EngineEvent::TransactionRejected { client: 1, tx: 1, reason: "already_disputed" },
+                EngineEvent::ChargebackApplied { client: 1, tx: 1, amount: dec!(100) },
+                EngineEvent::AccountLocked { client: 1 },
+                EngineEvent::TransactionRejected { client: 1, tx: 5, reason: "account_locked" },
+            ]
+        );
     }
-    
+
     #[tokio::test]
-    async fn test_resolve() {
+    async fn test_dispute_expires_after_later_timestamp() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        let deposit_tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Dispute
-        let dispute_tx = create_dispute(1, 1);
-        engine.process_transaction(dispute_tx).await.unwrap();
-        
-        // Resolve
-        let resolve_tx = create_resolve(1, 1);
-        engine.process_transaction(resolve_tx).await.unwrap();
-        
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(100));
-        assert_eq!(accounts[0].held, dec!(0));
-        assert_eq!(accounts[0].total, dec!(100));
+        let sink = events.clone();
+        engine.set_event_handler(Box::new(move |event| sink.lock().unwrap().push(event)));
+        engine.set_dispute_expiry(Some(Duration::from_secs(100)));
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(50)).at(1_000)).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1).at(1_000)).await.unwrap();
+
+        // Open for exactly the expiry isn't older than it
+        engine.process_transaction(Transaction::deposit(2, 2, dec!(5)).at(1_100)).await.unwrap();
+        assert_eq!(engine.expire_disputes().unwrap(), 0);
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(50), dec!(50), false));
+
+        // The next transaction expires it before it applies
+        engine.process_transaction(Transaction::deposit(2, 3, dec!(5)).at(1_101)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(50), dec!(0), dec!(50), false));
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::Resolved));
+        assert_eq!(engine.expired_dispute_count(), 1);
+        assert!(events.lock().unwrap().contains(&EngineEvent::DisputeExpired { client: 1, tx: 1, amount: dec!(50) }));
+
+        // Nothing is left for a late resolve, or for a sweep
+        let outcome = engine.process_transaction_with_outcome(Transaction::resolve(1, 1).at(1_102)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("not_disputed"));
+        assert_eq!(engine.expire_disputes().unwrap(), 0);
     }
-    
+
     #[tokio::test]
-    async fn test_chargeback() {
+    async fn test_chargeback_just_before_expiry_wins() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        let deposit_tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Dispute
-        let dispute_tx = create_dispute(1, 1);
-        engine.process_transaction(dispute_tx).await.unwrap();
-        
-        // Chargeback
-        let chargeback_tx = create_chargeback(1, 1);
-        engine.process_transaction(chargeback_tx).await.unwrap();
-        
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(0));
-        assert_eq!(accounts[0].held, dec!(0));
-        assert_eq!(accounts[0].total, dec!(0));
-        assert!(accounts[0].locked);
+        engine.set_dispute_expiry(Some(Duration::from_secs(100)));
+        for tx in [
+            Transaction::deposit(1, 1, dec!(50)).at(1_000),
+            Transaction::dispute(1, 1).at(1_000),
+            Transaction::chargeback(1, 1).at(1_100),
+            Transaction::deposit(2, 2, dec!(5)).at(5_000),
+        ] {
+            engine.process_transaction(tx).await.unwrap();
+        }
+        assert_eq!(engine.expire_disputes().unwrap(), 0);
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(0), dec!(0), true));
+        assert_eq!(engine.transaction_state(1), Some(TransactionState::ChargedBack));
+
+        // Neither do disputes without a timestamp, or redisputes timed afresh, expire early
+        engine.process_transaction(Transaction::deposit(2, 3, dec!(5))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(2, 3)).await.unwrap();
+        engine.process_transaction(Transaction::dispute(2, 2).at(5_000)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(2, 2).at(5_010)).await.unwrap();
+        engine.process_transaction(Transaction::dispute(2, 2).at(5_090)).await.unwrap();
+        engine.process_transaction(Transaction::deposit(3, 4, dec!(1)).at(5_150)).await.unwrap();
+        assert_eq!(engine.expire_disputes().unwrap(), 0);
+        assert_eq!(balances(&engine, 2), (dec!(0), dec!(10), dec!(10), false));
     }
-    
+
+    fn create_partial_dispute(client: u16, tx: TxId, amount: Decimal) -> Transaction {
+        Transaction { amount: Some(amount), ..Transaction::dispute(client, tx) }
+    }
+
+    fn balances(engine: &PaymentEngine, client: u16) -> (Decimal, Decimal, Decimal, bool) {
+        let account = engine.get_account(client).unwrap();
+        (account.available, account.held, account.total, account.locked)
+    }
+
     #[tokio::test]
-    async fn test_locked_account() {
+    async fn test_withdrawal_dispute_rejected_by_default() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        let deposit_tx = create_deposit(1, 1, dec!(100));
-        engine.process_transaction(deposit_tx).await.unwrap();
-        
-        // Dispute and chargeback to lock the account
-        engine.process_transaction(create_dispute(1, 1)).await.unwrap();
-        engine.process_transaction(create_chargeback(1, 1)).await.unwrap();
-        
-        // Try another deposit after account is locked
-        let new_deposit_tx = create_deposit(1, 2, dec!(50));
-        engine.process_transaction(new_deposit_tx).await.unwrap();
-        
-        // Balance should remain unchanged since account is locked
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(0));
-        assert_eq!(accounts[0].total, dec!(0));
-        assert!(accounts[0].locked);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 2, dec!(40))).await.unwrap();
+
+        let outcome = engine.process_transaction_with_outcome(Transaction::dispute(1, 2)).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("not_disputable"));
+        assert_eq!(balances(&engine, 1), (dec!(60), dec!(0), dec!(60), false));
+        assert_eq!(engine.withdrawal_disputes_rejected(), 1);
+
+        // Other rejected disputes, of an unknown tx or of a deposit that can't
+        // be held, aren't counted with them
+        engine.process_transaction(Transaction::dispute(1, 9)).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(engine.withdrawal_disputes_rejected(), 1);
+        assert_eq!(engine.rejected_transactions(), 3);
     }
 
     #[tokio::test]
-    async fn test_multiple_clients() {
+    async fn test_withdrawal_dispute_then_chargeback_credits_funds() {
         let mut engine = PaymentEngine::new();
-        
-        // Client 1 transactions
-        engine.process_transaction(create_deposit(1, 1, dec!(100))).await.unwrap();
-        engine.process_transaction(create_withdrawal(1, 2, dec!(20))).await.unwrap();
-        
-        // Client 2 transactions
-        engine.process_transaction(create_deposit(2, 3, dec!(200))).await.unwrap();
-        engine.process_transaction(create_withdrawal(2, 4, dec!(50))).await.unwrap();
-        
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 2);
-        
-        // Find client accounts (they might be in any order)
-        let mut client_balances = HashMap::new();
-        for account in accounts {
-            client_balances.insert(account.client, (account.available, account.total));
+        engine.set_allow_withdrawal_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 2, dec!(40))).await.unwrap();
+
+        // Nothing is held for a disputed withdrawal
+        engine.process_transaction(Transaction::dispute(1, 2)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(60), dec!(0), dec!(60), false));
+        assert!(engine.transactions.is_disputed(2));
+
+        engine.process_transaction(Transaction::chargeback(1, 2)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), true));
+        assert_eq!(engine.rejected_transactions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_dispute_then_resolve() {
+        let mut engine = PaymentEngine::new();
+        engine.set_allow_withdrawal_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 2, dec!(40))).await.unwrap();
+
+        engine.process_transaction(Transaction::dispute(1, 2)).await.unwrap();
+        engine.process_transaction(Transaction::resolve(1, 2)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(60), dec!(0), dec!(60), false));
+        assert!(!engine.transactions.is_disputed(2));
+        assert_eq!(engine.rejected_transactions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_amendments_move_the_balance_by_the_difference() {
+        let mut engine = PaymentEngine::new();
+        engine.set_allow_admin_transactions(true);
+        engine.process_transaction(Transaction::deposit(1, 5, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 6, dec!(20))).await.unwrap();
+
+        // Downward, then upward
+        let outcome = engine.process_transaction_with_outcome(Transaction::amend(1, 5, dec!(90.0))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(balances(&engine, 1), (dec!(70), dec!(0), dec!(70), false));
+        engine.process_transaction(Transaction::amend(1, 5, dec!(120))).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), false));
+        assert_eq!(engine.get_transaction(5).unwrap().amount, Some(dec!(120)));
+        assert_eq!(engine.get_account(1).unwrap().deposited_total, dec!(120));
+
+        // A decrease the available funds can't cover is rejected
+        let outcome = engine.process_transaction_with_outcome(Transaction::amend(1, 5, dec!(10))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("insufficient_funds"));
+        assert_eq!(engine.get_transaction(5).unwrap().amount, Some(dec!(120)));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_after_amendment_holds_the_amended_amount() {
+        let mut engine = PaymentEngine::new();
+        engine.set_allow_admin_transactions(true);
+        engine.process_transaction(Transaction::deposit(1, 5, dec!(100))).await.unwrap();
+        engine.process_transaction(Transaction::amend(1, 5, dec!(90))).await.unwrap();
+
+        engine.process_transaction(Transaction::dispute(1, 5)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(90), dec!(90), false));
+        engine.process_transaction(Transaction::chargeback(1, 5)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(0), dec!(0), true));
+        assert_eq!(engine.get_account(1).unwrap().chargeback_total, dec!(90));
+    }
+
+    #[tokio::test]
+    async fn test_amendment_rejections() {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+        let amend = |engine: &mut PaymentEngine, transaction| engine.apply(transaction).unwrap();
+        assert_eq!(amend(&mut engine, Transaction::amend(1, 1, dec!(90))), TransactionOutcome::Rejected("admin_not_allowed"));
+
+        engine.set_allow_admin_transactions(true);
+        engine.process_transaction(Transaction::deposit(1, 2, dec!(50))).await.unwrap();
+        engine.process_transaction(Transaction::withdrawal(1, 3, dec!(10))).await.unwrap();
+        engine.process_transaction(Transaction::deposit(2, 4, dec!(10))).await.unwrap();
+        engine.process_transaction(Transaction::dispute(1, 2)).await.unwrap();
+        for (transaction, reason) in [
+            (Transaction::amend(1, 99, dec!(1)), "transaction_not_found"),
+            (Transaction::amend(1, 4, dec!(1)), "client_mismatch"),
+            (Transaction::amend(1, 3, dec!(1)), "not_amendable"),
+            (Transaction::amend(1, 2, dec!(40)), "transaction_disputed"),
+        ] {
+            assert_eq!(amend(&mut engine, transaction), TransactionOutcome::Rejected(reason));
         }
-        
-        assert_eq!(client_balances.get(&1), Some(&(dec!(80), dec!(80))));
-        assert_eq!(client_balances.get(&2), Some(&(dec!(150), dec!(150))));
+        let negative = Transaction { amount: Some(dec!(-1)), ..Transaction::amend(1, 1, dec!(1)) };
+        assert_eq!(amend(&mut engine, negative), TransactionOutcome::Rejected("negative_amount"));
+
+        // Still rejected once the dispute is resolved
+        engine.process_transaction(Transaction::resolve(1, 2)).await.unwrap();
+        assert_eq!(amend(&mut engine, Transaction::amend(1, 2, dec!(40))), TransactionOutcome::Rejected("transaction_disputed"));
+        assert_eq!(balances(&engine, 1), (dec!(140), dec!(0), dec!(140), false));
     }
-    
+
     #[tokio::test]
-    async fn test_dispute_non_existent_tx() {
+    async fn test_partial_dispute_then_resolve() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        engine.process_transaction(create_deposit(1, 1, dec!(100))).await.unwrap();
-        
-        // Dispute a non-existent transaction
-        engine.process_transaction(create_dispute(1, 999)).await.unwrap();
-        
-        // Balance should remain unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(100));
-        assert_eq!(accounts[0].held, dec!(0));
-        assert_eq!(accounts[0].total, dec!(100));
+        engine.set_allow_partial_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+
+        engine.process_transaction(create_partial_dispute(1, 1, dec!(30))).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(70), dec!(30), dec!(100), false));
+        assert_eq!(engine.transactions.disputed_amount(1), dec!(30));
+
+        // A resolve amount is ignored; the disputed part is released
+        engine.process_transaction(Transaction { amount: Some(dec!(5)), ..Transaction::resolve(1, 1) }).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), false));
+        assert!(!engine.transactions.is_disputed(1));
     }
-    
+
     #[tokio::test]
-    async fn test_resolve_without_dispute() {
+    async fn test_partial_dispute_then_chargeback() {
         let mut engine = PaymentEngine::new();
-        
-        // Deposit
-        engine.process_transaction(create_deposit(1, 1, dec!(100))).await.unwrap();
-        
-        // Resolve without dispute
-        engine.process_transaction(create_resolve(1, 1)).await.unwrap();
-        
-        // Balance should remain unchanged
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 1);
-        assert_eq!(accounts[0].available, dec!(100));
-        assert_eq!(accounts[0].held, dec!(0));
-        assert_eq!(accounts[0].total, dec!(100));
+        engine.set_allow_partial_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+
+        engine.process_transaction(create_partial_dispute(1, 1, dec!(30))).await.unwrap();
+        engine.process_transaction(Transaction::chargeback(1, 1)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(70), dec!(0), dec!(70), true));
+        assert_eq!(engine.rejected_transactions(), 0);
     }
-    
+
     #[tokio::test]
-    async fn test_client_mismatch() {
+    async fn test_partial_dispute_over_amount_rejected() {
         let mut engine = PaymentEngine::new();
-        
-        // Client 1 deposit
-        engine.process_transaction(create_deposit(1, 1, dec!(100))).await.unwrap();
-        
-        // Client 2 tries to dispute client 1's transaction
-        engine.process_transaction(create_dispute(2, 1)).await.unwrap();
-        
-        // Balance should remain unchanged
-        let accounts = engine.get_accounts();
-        let client1_account = accounts.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(client1_account.available, dec!(100));
-        assert_eq!(client1_account.held, dec!(0));
-        assert_eq!(client1_account.total, dec!(100));
+        engine.set_allow_partial_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+
+        for amount in [dec!(100.01), dec!(0), dec!(-5)] {
+            let outcome = engine.process_transaction_with_outcome(create_partial_dispute(1, 1, amount)).await.unwrap();
+            assert_eq!(outcome, TransactionOutcome::Rejected("invalid_dispute_amount"));
+        }
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), false));
+        assert!(!engine.transactions.is_disputed(1));
+    }
+
+    #[tokio::test]
+    async fn test_sequential_partial_disputes() {
+        let mut engine = PaymentEngine::new();
+        engine.set_allow_partial_disputes(true);
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+
+        engine.process_transaction(create_partial_dispute(1, 1, dec!(30))).await.unwrap();
+        engine.process_transaction(create_partial_dispute(1, 1, dec!(50))).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(20), dec!(80), dec!(100), false));
+
+        // Only 20 is left to dispute
+        let outcome = engine.process_transaction_with_outcome(create_partial_dispute(1, 1, dec!(25))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("invalid_dispute_amount"));
+
+        // Without an amount the rest is disputed, after which nothing is left
+        engine.process_transaction(Transaction::dispute(1, 1)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(100), dec!(100), false));
+        let outcome = engine.process_transaction_with_outcome(create_partial_dispute(1, 1, dec!(1))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("already_disputed"));
+
+        engine.process_transaction(Transaction::resolve(1, 1)).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(100), dec!(0), dec!(100), false));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_amount_ignored_unless_partial_disputes_allowed() {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(100))).await.unwrap();
+
+        engine.process_transaction(create_partial_dispute(1, 1, dec!(30))).await.unwrap();
+        assert_eq!(balances(&engine, 1), (dec!(0), dec!(100), dec!(100), false));
+        let outcome = engine.process_transaction_with_outcome(create_partial_dispute(1, 1, dec!(30))).await.unwrap();
+        assert_eq!(outcome, TransactionOutcome::Rejected("already_disputed"));
     }
-}
\ No newline at end of file
+}
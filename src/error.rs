@@ -1,5 +1,76 @@
+use crate::models::TxId;
+use rust_decimal::Decimal;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// Longest prefix of a line kept in a [`PaymentEngineError::ParseError`], in characters
+pub const MAX_PARSE_ERROR_CONTENT: usize = 200;
+
+/// Result type of the library's fallible functions
+pub type Result<T, E = PaymentEngineError> = std::result::Result<T, E>;
+
+/// Why a set of [`ProcessingOptions`](crate::ProcessingOptions) was rejected
+/// by [`validate`](crate::ProcessingOptions::validate)
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OptionsError {
+    #[error("{0} must be at least 1")]
+    Zero(&'static str),
+
+    #[error("auto batch sizes need 1 <= min <= max, got min {min} and max {max}")]
+    AutoBatchSizes { min: usize, max: usize },
+
+    #[error("snapshot_every must be at least 1 record")]
+    ZeroSnapshotRecords,
+
+    #[error("snapshot_every must be longer than zero")]
+    ZeroSnapshotInterval,
+
+    #[error("snapshot_path_template must not be empty")]
+    EmptySnapshotPathTemplate,
+
+    #[error("end_marker must not be empty")]
+    EmptyEndMarker,
+
+    #[error("sample rate must be above 0 and at most 1")]
+    SampleRate,
+
+    #[error("reconcile_tolerance must not be negative")]
+    NegativeReconcileTolerance,
+
+    #[error("delimiter {0:?} is not allowed")]
+    Delimiter(char),
+
+    #[error("decimal_separator must be '.' or ',', not {0:?}")]
+    DecimalSeparator(char),
+
+    #[error("thousands_separator must be ',', '_' or ' ', not {0:?}")]
+    ThousandsSeparator(char),
+
+    #[error("{0} and {1} must differ")]
+    SameSeparator(&'static str, &'static str),
+
+    #[error("{0} can't be combined with {1}")]
+    Conflict(&'static str, &'static str),
+
+    #[error("{0} requires {1}")]
+    Requires(&'static str, &'static str),
+
+    #[error("dispute_expiry must be longer than zero")]
+    ZeroDisputeExpiry,
+
+    #[error("output_columns {0}")]
+    OutputColumns(String),
+
+    #[error("fixed_width {0}")]
+    FixedWidth(String),
+
+    #[error("per-client output template {template:?} {reason}")]
+    ClientTemplate { template: String, reason: &'static str },
+}
+
 #[derive(Error, Debug)]
 pub enum PaymentEngineError {
     #[error("Failed to read file: {0}")]
@@ -9,14 +80,147 @@ pub enum PaymentEngineError {
     CsvError(#[from] csv::Error),
     
     #[error("Missing amount for transaction {0}")]
-    MissingAmount(u32),
+    MissingAmount(TxId),
+
+    #[error("Transaction {0} was already applied; deposits and withdrawals need unique ids")]
+    DuplicateTransaction(TxId),
+
+    #[error("Line exceeds maximum length of {0} bytes")]
+    LineTooLong(usize),
+
+    #[error("Invalid processing options: {0}")]
+    InvalidOptions(OptionsError),
+
+    #[error("Invalid generator config: {0}")]
+    InvalidGeneratorConfig(String),
+
+    #[error("Invalid account for client {0}: {1}")]
+    InvalidAccount(u16, String),
+
+    #[error("Failed to open {path:?}: {source}")]
+    OpenFile { path: PathBuf, source: io::Error },
+
+    #[error("Invalid CSV line format: {0}")]
+    InvalidLineFormat(String),
+
+    #[error("Invalid transaction type: {0}")]
+    InvalidTransactionType(String),
+
+    #[error("Invalid {field}: {value}")]
+    InvalidId { field: &'static str, value: String },
+
+    #[error("Invalid {field}: {value} is larger than the maximum of {max}")]
+    IdOverflow { field: &'static str, value: String, max: u64 },
+
+    #[error("Invalid amount: {0}")]
+    InvalidAmount(String),
+
+    #[error("Amount {0} has more than 4 decimal places")]
+    ExcessPrecision(Decimal),
+
+    #[error("Amount {0} is not positive")]
+    NonPositiveAmount(Decimal),
+
+    #[error("Unexpected amount for transaction {0}; disputes only have one when partial disputes are allowed")]
+    UnexpectedAmount(TxId),
+
+    #[error("Invalid currency: {0} (expected a three-letter code)")]
+    InvalidCurrency(String),
+
+    #[error("Invalid timestamp: {0} (expected seconds since the Unix epoch)")]
+    InvalidTimestamp(String),
+
+    #[error("Failed to parse transaction on line {line} ({content:?}): {source}")]
+    ParseError { line: usize, content: String, source: Box<PaymentEngineError> },
+
+    #[error("Invalid account on row {row}: {source}")]
+    InvalidAccountRow { row: usize, source: csv::Error },
+
+    #[error("Invalid WAL record: {0}")]
+    WalRecord(#[from] serde_json::Error),
+
+    #[error("Processing task failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+
+    #[error("Cannot merge engines: {0}")]
+    MergeConflict(String),
+
+    #[error("Shared payment engine task has stopped")]
+    EngineStopped,
+
+    #[error("Failed to bind {addr}: {source}")]
+    Bind { addr: SocketAddr, source: io::Error },
+
+    #[error("Failed to bind socket {path:?}: {source}")]
+    BindSocket { path: PathBuf, source: io::Error },
+
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] ::parquet::errors::ParquetError),
+
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[cfg(feature = "remote")]
+    #[error("Invalid input URL: {0}")]
+    InvalidUrl(String),
+
+    #[cfg(feature = "remote")]
+    #[error("Failed to fetch {url}: {source}")]
+    Fetch { url: String, source: reqwest::Error },
+
+    #[cfg(feature = "metrics")]
+    #[error("Failed to install metrics exporter: {0}")]
+    MetricsExporter(#[from] metrics_exporter_prometheus::BuildError),
+}
+
+// Not `#[from]`, which would also make the options error the source and
+// repeat its message in error chains
+impl From<OptionsError> for PaymentEngineError {
+    fn from(error: OptionsError) -> Self {
+        PaymentEngineError::InvalidOptions(error)
+    }
+}
+
+impl PaymentEngineError {
+    /// Wrap an error from parsing `content`, found on the given line of the
+    /// input file. The content is truncated to [`MAX_PARSE_ERROR_CONTENT`] characters.
+    pub fn parse_error(line: usize, content: &str, source: PaymentEngineError) -> Self {
+        let content = match content.char_indices().nth(MAX_PARSE_ERROR_CONTENT) {
+            Some((end, _)) => format!("{}...", &content[..end]),
+            None => content.to_string(),
+        };
+        PaymentEngineError::ParseError { line, content, source: Box::new(source) }
+    }
+
+    /// Input column a parse error was found in, if it is about a single field
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            PaymentEngineError::InvalidTransactionType(_) => Some("type"),
+            PaymentEngineError::InvalidId { field, .. } | PaymentEngineError::IdOverflow { field, .. } => Some(field),
+            PaymentEngineError::InvalidAmount(_)
+            | PaymentEngineError::ExcessPrecision(_)
+            | PaymentEngineError::NonPositiveAmount(_)
+            | PaymentEngineError::MissingAmount(_)
+            | PaymentEngineError::UnexpectedAmount(_) => Some("amount"),
+            PaymentEngineError::InvalidCurrency(_) => Some("currency"),
+            PaymentEngineError::InvalidTimestamp(_) => Some("timestamp"),
+            PaymentEngineError::ParseError { source, .. } => source.field(),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rust_decimal_macros::dec;
     use std::error::Error;
-    use std::io;
     
     #[test]
     fn test_file_read_error() {
@@ -28,10 +232,7 @@ mod tests {
         
         // Test From trait implementation
         let error_from: PaymentEngineError = io::Error::new(io::ErrorKind::NotFound, "file not found").into();
-        match error_from {
-            PaymentEngineError::FileReadError(_) => assert!(true),
-            _ => panic!("Wrong error variant"),
-        }
+        assert!(matches!(error_from, PaymentEngineError::FileReadError(_)), "Wrong error variant");
     }
     
     #[test]
@@ -50,10 +251,7 @@ mod tests {
         let csv_error = reader.into_deserialize::<(String, u16, u32)>().next().unwrap().unwrap_err();
         let error_from: PaymentEngineError = csv_error.into();
         
-        match error_from {
-            PaymentEngineError::CsvError(_) => assert!(true),
-            _ => panic!("Wrong error variant"),
-        }
+        assert!(matches!(error_from, PaymentEngineError::CsvError(_)), "Wrong error variant");
     }
     
     #[test]
@@ -64,4 +262,161 @@ mod tests {
         assert!(error.to_string().contains("Missing amount for transaction 12345"));
         assert!(error.source().is_none()); // No source for this error type
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_invalid_options() {
+        let error = PaymentEngineError::from(OptionsError::Zero("batch_size"));
+        assert_eq!(error.to_string(), "Invalid processing options: batch_size must be at least 1");
+        assert!(error.source().is_none());
+
+        let error = OptionsError::AutoBatchSizes { min: 20, max: 10 };
+        assert_eq!(error.to_string(), "auto batch sizes need 1 <= min <= max, got min 20 and max 10");
+        let error = OptionsError::ClientTemplate { template: "out/{id}/a.csv".to_string(), reason: "may only have {id} in the file name" };
+        assert_eq!(error.to_string(), "per-client output template \"out/{id}/a.csv\" may only have {id} in the file name");
+    }
+
+    #[test]
+    fn test_invalid_account() {
+        let error = PaymentEngineError::InvalidAccount(7, "duplicate client".to_string());
+        assert_eq!(error.to_string(), "Invalid account for client 7: duplicate client");
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let error = PaymentEngineError::MergeConflict("client 3 is in both engines".to_string());
+        assert_eq!(error.to_string(), "Cannot merge engines: client 3 is in both engines");
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let error = PaymentEngineError::LineTooLong(4096);
+
+        assert_eq!(error.to_string(), "Line exceeds maximum length of 4096 bytes");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_open_file() {
+        let error = PaymentEngineError::OpenFile {
+            path: PathBuf::from("missing.csv"),
+            source: io::Error::new(io::ErrorKind::NotFound, "file not found"),
+        };
+
+        assert_eq!(error.to_string(), "Failed to open \"missing.csv\": file not found");
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = [
+            (PaymentEngineError::InvalidLineFormat("deposit,1".to_string()), "Invalid CSV line format: deposit,1"),
+            (PaymentEngineError::InvalidTransactionType("transfer".to_string()), "Invalid transaction type: transfer"),
+            (PaymentEngineError::InvalidId { field: "client", value: "abc".to_string() }, "Invalid client: abc"),
+            (PaymentEngineError::InvalidAmount("1.2.3".to_string()), "Invalid amount: 1.2.3"),
+            (PaymentEngineError::ExcessPrecision(dec!(0.12345)), "Amount 0.12345 has more than 4 decimal places"),
+            (PaymentEngineError::NonPositiveAmount(dec!(-1.5)), "Amount -1.5 is not positive"),
+            (
+                PaymentEngineError::UnexpectedAmount(9),
+                "Unexpected amount for transaction 9; disputes only have one when partial disputes are allowed",
+            ),
+            (PaymentEngineError::InvalidCurrency("EURO".to_string()), "Invalid currency: EURO (expected a three-letter code)"),
+            (PaymentEngineError::InvalidTimestamp("noon".to_string()), "Invalid timestamp: noon (expected seconds since the Unix epoch)"),
+        ];
+        for (error, message) in cases {
+            assert_eq!(error.to_string(), message);
+            assert!(error.source().is_none());
+        }
+
+        let error = PaymentEngineError::parse_error(
+            3,
+            "transfer,1,2,3",
+            PaymentEngineError::InvalidTransactionType("transfer".to_string()),
+        );
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse transaction on line 3 (\"transfer,1,2,3\"): Invalid transaction type: transfer"
+        );
+        assert!(error.source().is_some());
+        assert_eq!(error.field(), Some("type"));
+    }
+
+    #[test]
+    fn test_parse_error_truncates_content() {
+        let line = format!("deposit,1,1,{}", "é".repeat(300));
+        let error = PaymentEngineError::parse_error(2, &line, PaymentEngineError::InvalidAmount("é".repeat(300)));
+
+        let PaymentEngineError::ParseError { content, .. } = &error else { panic!("Wrong error variant") };
+        assert_eq!(content.chars().count(), MAX_PARSE_ERROR_CONTENT + 3);
+        assert!(content.starts_with("deposit,1,1,é") && content.ends_with("é..."));
+        assert_eq!(error.field(), Some("amount"));
+    }
+
+    #[test]
+    fn test_invalid_account_row() {
+        let reader = csv::Reader::from_reader("client\nbad".as_bytes());
+        let csv_error = reader.into_deserialize::<(u16,)>().next().unwrap().unwrap_err();
+        let error = PaymentEngineError::InvalidAccountRow { row: 1, source: csv_error };
+
+        assert!(error.to_string().starts_with("Invalid account on row 1: "));
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_wal_record() {
+        let json_error = serde_json::from_str::<u32>("{").unwrap_err();
+        let error: PaymentEngineError = json_error.into();
+
+        assert!(matches!(error, PaymentEngineError::WalRecord(_)));
+        assert!(error.to_string().starts_with("Invalid WAL record: "));
+    }
+
+    #[tokio::test]
+    async fn test_task_failed() {
+        let join_error = tokio::spawn(async { panic!("boom") }).await.unwrap_err();
+        let error: PaymentEngineError = join_error.into();
+
+        assert!(matches!(error, PaymentEngineError::TaskFailed(_)));
+        assert!(error.to_string().starts_with("Processing task failed: "));
+    }
+
+    #[test]
+    fn test_engine_stopped() {
+        assert_eq!(PaymentEngineError::EngineStopped.to_string(), "Shared payment engine task has stopped");
+    }
+
+    #[test]
+    fn test_bind() {
+        let error = PaymentEngineError::Bind {
+            addr: ([127, 0, 0, 1], 8080).into(),
+            source: io::Error::new(io::ErrorKind::AddrInUse, "address in use"),
+        };
+
+        assert_eq!(error.to_string(), "Failed to bind 127.0.0.1:8080: address in use");
+
+        let error = PaymentEngineError::BindSocket {
+            path: PathBuf::from("engine.sock"),
+            source: io::Error::new(io::ErrorKind::AddrInUse, "address in use"),
+        };
+        assert_eq!(error.to_string(), "Failed to bind socket \"engine.sock\": address in use");
+        assert!(error.source().is_some());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite() {
+        let error: PaymentEngineError = rusqlite::Error::InvalidQuery.into();
+        assert!(matches!(error, PaymentEngineError::Sqlite(_)));
+        assert!(error.to_string().starts_with("SQLite error: "));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_and_arrow() {
+        let error: PaymentEngineError = ::parquet::errors::ParquetError::EOF("no footer".to_string()).into();
+        assert!(matches!(error, PaymentEngineError::Parquet(_)));
+        assert!(error.to_string().starts_with("Parquet error: "));
+
+        let error: PaymentEngineError = arrow_schema::ArrowError::SchemaError("no amount column".to_string()).into();
+        assert_eq!(error.to_string(), "Arrow error: Schema error: no amount column");
+    }
+}
@@ -0,0 +1,33 @@
+use crate::models::TxId;
+use rust_decimal::Decimal;
+use std::sync::Arc;
+
+/// Notable things the engine does, emitted at the points where it logs
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    DepositApplied { client: u16, tx: TxId, amount: Decimal },
+    WithdrawalApplied { client: u16, tx: TxId, amount: Decimal },
+    /// An earlier deposit's amount was corrected from `previous` to `amount`
+    DepositAmended { client: u16, tx: TxId, amount: Decimal, previous: Decimal },
+    /// Funds of a disputed deposit were moved from available to held
+    DisputeOpened { client: u16, tx: TxId, amount: Decimal },
+    /// A dispute was queued until the available funds cover its amount
+    DisputeDeferred { client: u16, tx: TxId, amount: Decimal },
+    /// A deferred dispute's funds were moved from available to held
+    DisputeActivated { client: u16, tx: TxId, amount: Decimal },
+    /// Held funds of a resolved dispute were released
+    DisputeResolved { client: u16, tx: TxId, amount: Decimal },
+    /// Held funds of a dispute left open past the dispute expiry were released
+    DisputeExpired { client: u16, tx: TxId, amount: Decimal },
+    /// Held funds were removed by a chargeback; always followed by `AccountLocked`
+    ChargebackApplied { client: u16, tx: TxId, amount: Decimal },
+    AccountLocked { client: u16 },
+    /// The transaction was not applied; the reason matches the rejection metric label
+    TransactionRejected { client: u16, tx: TxId, reason: &'static str },
+}
+
+/// Callback receiving engine events.
+///
+/// Shared with the per-client tasks of parallel batches, so it must be `Sync`,
+/// and events of different clients may then arrive interleaved.
+pub type EventHandler = Arc<dyn Fn(EngineEvent) + Send + Sync>;
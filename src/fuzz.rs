@@ -0,0 +1,107 @@
+use crate::engine::PaymentEngine;
+use crate::error::{PaymentEngineError, Result};
+use crate::input::slice_lines;
+use crate::models::Account;
+use crate::processor::{parse_line, LineFormat, ProcessingOptions};
+use tracing::error;
+
+/// Process CSV content held in memory with the default options and return
+/// the accounts in client order, for fuzzing the parser and engine together.
+///
+/// The bytes go through the same line splitting, header detection and parser
+/// as a file, and are applied to a fresh engine without a runtime. Lines that
+/// can't be read or parsed and transactions the engine fails on are skipped,
+/// as in a normal run. An account whose total isn't its available plus held
+/// balance afterwards is returned as an error. This must not panic on any
+/// input; a panic is a bug.
+pub fn fuzz_process(input: &[u8]) -> Result<Vec<Account>> {
+    let options = ProcessingOptions::default();
+    let format = LineFormat::new(&options, false);
+    let mut lines = slice_lines(input, options.max_line_length, options.encoding).peekable();
+    let header_lines = match lines.peek() {
+        Some(Ok(first_line)) if format.is_header(first_line) => 1,
+        _ => 0,
+    };
+
+    let mut engine = PaymentEngine::new();
+    for (i, line) in lines.enumerate().skip(header_lines) {
+        let transaction = match line.and_then(|line| parse_line(i + 1, &line, format)) {
+            Ok(transaction) => transaction,
+            Err(_) => continue,
+        };
+        if let Err(e) = engine.apply(transaction) {
+            error!("Error processing transaction: {}", e);
+        }
+    }
+
+    let mut accounts = engine.get_accounts();
+    for account in &accounts {
+        account.check_balances().map_err(|e| PaymentEngineError::InvalidAccount(account.client, e))?;
+    }
+    accounts.sort_by_key(|account| account.client);
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::path::Path;
+
+    #[test]
+    fn test_matches_file_processing() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndeposit,1,3,2.0\ndispute,1,3,\ndeposit,2,4,1.5";
+        let accounts = fuzz_process(input).unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!((accounts[0].available, accounts[0].held, accounts[0].total), (dec!(6.0), dec!(2.0), dec!(8.0)));
+        assert_eq!(accounts[1].available, dec!(1.5));
+
+        // Without a header the first line is a transaction
+        let accounts = fuzz_process(b"deposit,1,1,10.0\n").unwrap();
+        assert_eq!(accounts[0].available, dec!(10.0));
+        assert!(fuzz_process(b"").unwrap().is_empty());
+    }
+
+    /// Every file in the fuzz corpus and fuzz/regressions is processed without
+    /// panicking; the regressions once crashed or misbehaved
+    #[test]
+    fn test_corpus_and_regressions_do_not_panic() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz");
+        let mut count = 0;
+        for dir in ["corpus/fuzz_process", "regressions"] {
+            for entry in std::fs::read_dir(root.join(dir)).unwrap() {
+                let path = entry.unwrap().path();
+                let input = std::fs::read(&path).unwrap();
+                assert!(fuzz_process(&input).is_ok(), "{:?}", path);
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_hostile_inputs() {
+        let giant_line = format!("type,client,tx,amount\ndeposit,1,1,{}\ndeposit,1,2,1.0\n", "9".repeat(1_000_000));
+        assert_eq!(fuzz_process(giant_line.as_bytes()).unwrap()[0].available, dec!(1.0));
+
+        // As many fields as fit in the default line length limit
+        let many_fields = format!("deposit,1,1,1.0{}\n", ",".repeat(4_000));
+        assert_eq!(fuzz_process(many_fields.as_bytes()).unwrap()[0].available, dec!(1.0));
+
+        // Negative amounts used to debit on deposit and credit on withdrawal
+        let accounts = fuzz_process(b"deposit,1,1,-100.0\nwithdrawal,1,2,-50.0\n").unwrap();
+        assert_eq!((accounts[0].available, accounts[0].total), (dec!(0), dec!(0)));
+
+        let inputs: [&[u8]; 5] = [
+            b"\xff\xfe\x00garbage\n\x80,1,1,1",
+            b"deposit,1,1,-5.0\nwithdrawal,1,2,-5.0\ndispute,1,1,\nchargeback,1,1,",
+            b"deposit,1,1,79228162514264337593543950335\ndeposit,1,2,79228162514264337593543950335",
+            b"deposit,1,1,1e30\ndeposit,65536,2,1\ndeposit,1,4294967296,1",
+            b"deposit,1,1,1.0\ndeposit,1,1,1.0\ndispute,1,1,\ndispute,1,1,\nresolve,2,1,\nchargeback,1,1",
+        ];
+        for input in inputs {
+            assert!(fuzz_process(input).is_ok(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+}
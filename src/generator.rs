@@ -0,0 +1,299 @@
+use crate::error::{PaymentEngineError, Result};
+use crate::models::TxId;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+
+// Number of recent undisputed deposits remembered per client as dispute targets
+const DISPUTE_CANDIDATES_PER_CLIENT: usize = 16;
+
+/// Distribution used to draw transaction amounts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmountDistribution {
+    /// Uniformly distributed between `min` and `max`
+    Uniform { min: f64, max: f64 },
+    /// Exponentially distributed with the given mean (many small, few large amounts)
+    Exponential { mean: f64 },
+}
+
+/// Parameters for synthetic transaction generation
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    /// Number of transactions to generate
+    pub transactions: usize,
+    /// Number of distinct clients (ids 1..=clients)
+    pub clients: u16,
+    /// Share of deposits among deposits and withdrawals (0.0 - 1.0)
+    pub deposit_ratio: f64,
+    /// Probability that a step disputes an earlier deposit. Open disputes are
+    /// closed at the same rate, by a resolve or a chargeback.
+    pub dispute_probability: f64,
+    /// Probability that a closed dispute ends in a chargeback rather than a resolve
+    pub chargeback_probability: f64,
+    /// Distribution of deposit and withdrawal amounts
+    pub amount_distribution: AmountDistribution,
+    /// RNG seed; the same seed and config always produce the same output
+    pub seed: u64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            transactions: 10_000,
+            clients: 1000,
+            deposit_ratio: 0.65,
+            dispute_probability: 0.05,
+            chargeback_probability: 0.4,
+            amount_distribution: AmountDistribution::Uniform { min: 1.0, max: 10_000.0 },
+            seed: 0,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// Check that the config can be generated from: at least one client,
+    /// probabilities within 0 to 1, and finite, non-negative amount bounds
+    /// with `min` at most `max`, or a finite, positive mean
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(PaymentEngineError::InvalidGeneratorConfig(message));
+        if self.clients == 0 {
+            return invalid("needs at least one client".to_string());
+        }
+        for (name, probability) in [
+            ("deposit_ratio", self.deposit_ratio),
+            ("dispute_probability", self.dispute_probability),
+            ("chargeback_probability", self.chargeback_probability),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                return invalid(format!("{} must be between 0 and 1, got {}", name, probability));
+            }
+        }
+        match self.amount_distribution {
+            AmountDistribution::Uniform { min, .. } if !min.is_finite() || min < 0.0 => {
+                invalid(format!("min_amount must be a finite, non-negative amount, got {}", min))
+            }
+            AmountDistribution::Uniform { max, .. } if !max.is_finite() => invalid(format!("max_amount must be finite, got {}", max)),
+            AmountDistribution::Uniform { min, max } if min > max => {
+                invalid(format!("min_amount {} must not be greater than max_amount {}", min, max))
+            }
+            AmountDistribution::Exponential { mean } if !mean.is_finite() || mean <= 0.0 => {
+                invalid(format!("mean_amount must be a finite, positive amount, got {}", mean))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An open dispute waiting for a resolve or chargeback
+struct OpenDispute {
+    client: u16,
+    tx: TxId,
+}
+
+/// Write `config.transactions` synthetic transactions as CSV to a writer.
+///
+/// Rows are streamed as they are generated. Disputes always reference an
+/// earlier deposit of the same client, and resolves and chargebacks always
+/// reference an open dispute, so the output exercises the full engine.
+pub fn generate_transactions<W: Write>(config: &GeneratorConfig, writer: W) -> Result<()> {
+    config.validate()?;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut deposits: HashMap<u16, VecDeque<TxId>> = HashMap::new();
+    let mut open_disputes: VecDeque<OpenDispute> = VecDeque::new();
+    let mut next_tx: TxId = 1;
+
+    for _ in 0..config.transactions {
+        // Close the oldest open dispute
+        let closing = if !open_disputes.is_empty() && rng.random_bool(config.dispute_probability) {
+            open_disputes.pop_front()
+        } else {
+            None
+        };
+        if let Some(dispute) = closing {
+            let kind = if rng.random_bool(config.chargeback_probability) {
+                "chargeback"
+            } else {
+                "resolve"
+            };
+            write_row(&mut writer, kind, dispute.client, dispute.tx, None)?;
+            continue;
+        }
+
+        let client = rng.random_range(1..=config.clients);
+
+        // Dispute one of the client's earlier deposits
+        let disputed = match deposits.get_mut(&client) {
+            Some(candidates) if !candidates.is_empty() && rng.random_bool(config.dispute_probability) => {
+                let index = rng.random_range(0..candidates.len());
+                candidates.remove(index)
+            }
+            _ => None,
+        };
+        if let Some(tx) = disputed {
+            write_row(&mut writer, "dispute", client, tx, None)?;
+            open_disputes.push_back(OpenDispute { client, tx });
+            continue;
+        }
+
+        let tx = next_tx;
+        next_tx += 1;
+        let amount = draw_amount(&mut rng, config.amount_distribution);
+
+        if rng.random_bool(config.deposit_ratio) {
+            write_row(&mut writer, "deposit", client, tx, Some(amount))?;
+            let candidates = deposits.entry(client).or_default();
+            if candidates.len() >= DISPUTE_CANDIDATES_PER_CLIENT {
+                candidates.pop_front();
+            }
+            candidates.push_back(tx);
+        } else {
+            write_row(&mut writer, "withdrawal", client, tx, Some(amount))?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Write a single transaction row
+fn write_row<W: Write>(
+    writer: &mut csv::Writer<W>,
+    kind: &str,
+    client: u16,
+    tx: TxId,
+    amount: Option<Decimal>,
+) -> Result<()> {
+    let amount = amount.map(|a| a.to_string()).unwrap_or_default();
+    writer.write_record([kind, &client.to_string(), &tx.to_string(), &amount])?;
+    Ok(())
+}
+
+/// Draw a positive amount with 4 decimal places
+fn draw_amount(rng: &mut ChaCha8Rng, distribution: AmountDistribution) -> Decimal {
+    let value = match distribution {
+        AmountDistribution::Uniform { min, max } => rng.random_range(min..=max),
+        AmountDistribution::Exponential { mean } => -mean * (1.0 - rng.random::<f64>()).ln(),
+    };
+    // Work in ten-thousandths so the output is exact and never zero
+    let units = ((value * 10_000.0).round() as i64).max(1);
+    Decimal::new(units, 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::models::{Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+    use std::collections::HashSet;
+
+    fn generate(config: &GeneratorConfig) -> String {
+        let mut output = Vec::new();
+        generate_transactions(config, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_generator_needs_clients() {
+        let config = GeneratorConfig { clients: 0, ..Default::default() };
+        let result = generate_transactions(&config, Vec::new());
+        assert!(matches!(result, Err(PaymentEngineError::InvalidGeneratorConfig(_))));
+    }
+
+    #[test]
+    fn test_generator_rejects_invalid_config() {
+        let uniform = |min, max| AmountDistribution::Uniform { min, max };
+        for (config, field) in [
+            (GeneratorConfig { deposit_ratio: 1.5, ..Default::default() }, "deposit_ratio"),
+            (GeneratorConfig { deposit_ratio: f64::NAN, ..Default::default() }, "deposit_ratio"),
+            (GeneratorConfig { dispute_probability: -0.1, ..Default::default() }, "dispute_probability"),
+            (GeneratorConfig { chargeback_probability: 2.0, ..Default::default() }, "chargeback_probability"),
+            (GeneratorConfig { amount_distribution: uniform(10.0, 1.0), ..Default::default() }, "min_amount 10"),
+            (GeneratorConfig { amount_distribution: uniform(-1.0, 1.0), ..Default::default() }, "min_amount"),
+            (GeneratorConfig { amount_distribution: uniform(f64::NAN, 1.0), ..Default::default() }, "min_amount"),
+            (GeneratorConfig { amount_distribution: uniform(1.0, f64::INFINITY), ..Default::default() }, "max_amount"),
+            (GeneratorConfig { amount_distribution: AmountDistribution::Exponential { mean: 0.0 }, ..Default::default() }, "mean_amount"),
+        ] {
+            match generate_transactions(&config, Vec::new()) {
+                Err(PaymentEngineError::InvalidGeneratorConfig(message)) => assert!(message.starts_with(field), "{}", message),
+                other => panic!("Expected {} to be rejected, got {:?}", field, other),
+            }
+        }
+
+        // The bounds themselves are allowed
+        let config = GeneratorConfig { transactions: 10, deposit_ratio: 1.0, dispute_probability: 0.0, ..Default::default() };
+        generate_transactions(&GeneratorConfig { amount_distribution: uniform(5.0, 5.0), ..config }, Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let config = GeneratorConfig {
+            transactions: 2000,
+            clients: 20,
+            seed: 42,
+            ..Default::default()
+        };
+
+        let first = generate(&config);
+        let second = generate(&config);
+        assert_eq!(first, second);
+        assert_eq!(first.lines().count(), 2001);
+
+        let other_seed = generate(&GeneratorConfig { seed: 43, ..config });
+        assert_ne!(first, other_seed);
+    }
+
+    #[tokio::test]
+    async fn test_generated_file_is_referentially_valid() {
+        let config = GeneratorConfig {
+            transactions: 5000,
+            clients: 10,
+            dispute_probability: 0.1,
+            amount_distribution: AmountDistribution::Exponential { mean: 50.0 },
+            seed: 7,
+            ..Default::default()
+        };
+        let csv = generate(&config);
+
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let transactions: Vec<Transaction> = reader.deserialize().map(|r| r.unwrap()).collect();
+
+        // Disputes reference earlier deposits of the same client; closes reference open disputes
+        let mut deposits = HashMap::new();
+        let mut open = HashSet::new();
+        let mut disputes = 0;
+        for tx in &transactions {
+            match tx.transaction_type {
+                TransactionType::Deposit => {
+                    deposits.insert(tx.tx, tx.client);
+                }
+                TransactionType::Withdrawal => {}
+                TransactionType::Dispute => {
+                    assert_eq!(deposits.get(&tx.tx), Some(&tx.client));
+                    assert!(open.insert(tx.tx));
+                    disputes += 1;
+                }
+                _ => assert!(open.remove(&tx.tx)),
+            }
+        }
+        assert!(disputes > 0);
+
+        // Process it and check account invariants
+        let mut engine = PaymentEngine::new();
+        for tx in transactions {
+            engine.process_transaction(tx).await.unwrap();
+        }
+        for account in engine.get_accounts() {
+            assert_eq!(account.total, account.available + account.held);
+            assert!(account.available >= dec!(0));
+            assert!(account.held >= dec!(0));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::engine::PaymentEngine;
+use crate::error::Result;
+use crate::models::{decimal_string, TransactionOutcome, TransactionType, TxId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::info;
+
+/// One entry of a client's audit trail, recorded as each transaction is
+/// applied or rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub tx: TxId,
+    pub transaction_type: TransactionType,
+    pub amount: Option<Decimal>,
+    pub outcome: TransactionOutcome,
+    /// Available funds after the transaction
+    pub resulting_available: Decimal,
+    /// Held funds after the transaction
+    pub resulting_held: Decimal,
+}
+
+/// CSV row for an audit event
+#[derive(Serialize)]
+struct AuditRow<'a> {
+    client: u16,
+    tx: TxId,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    #[serde(with = "decimal_string::option")]
+    amount: Option<Decimal>,
+    outcome: &'static str,
+    reason: &'a str,
+    #[serde(with = "decimal_string")]
+    available: Decimal,
+    #[serde(with = "decimal_string")]
+    held: Decimal,
+    #[serde(with = "decimal_string")]
+    total: Decimal,
+}
+
+impl<'a> AuditRow<'a> {
+    fn new(client: u16, event: &'a AuditEvent) -> Self {
+        let (outcome, reason) = match event.outcome.rejection_reason() {
+            None => ("applied", ""),
+            Some(reason) => ("rejected", reason),
+        };
+        Self {
+            client,
+            tx: event.tx,
+            transaction_type: event.transaction_type,
+            amount: event.amount,
+            outcome,
+            reason,
+            available: event.resulting_available.round_dp(4),
+            held: event.resulting_held.round_dp(4),
+            total: (event.resulting_available + event.resulting_held).round_dp(4),
+        }
+    }
+}
+
+/// How a dry run writes its trace of transaction outcomes
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// One human-readable line per transaction
+    #[default]
+    Text,
+    /// CSV rows in the audit trail layout
+    Csv,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!("Invalid trace format: {} (expected text or csv)", s)),
+        }
+    }
+}
+
+/// Write one client's recorded audit trail as CSV, with the header before
+/// the first event; nothing is written for a client without events
+pub(crate) fn write_client_history<W: Write>(engine: &PaymentEngine, client: u16, output: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for event in engine.account_history(client) {
+        writer.serialize(AuditRow::new(client, event))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the recorded audit trail to `dir`.
+///
+/// With `combined` all clients go to `history.csv` in client order, otherwise
+/// each client gets its own `client_<id>.csv`. Clients without events are skipped.
+pub fn write_history(engine: &PaymentEngine, dir: &Path, combined: bool) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let mut clients: Vec<u16> = engine.get_accounts().iter().map(|a| a.client).collect();
+    clients.sort_unstable();
+
+    let mut combined_writer = if combined {
+        Some(csv::Writer::from_path(dir.join("history.csv"))?)
+    } else {
+        None
+    };
+
+    let mut files = 0;
+    for client in clients {
+        let history = engine.account_history(client);
+        if history.is_empty() {
+            continue;
+        }
+
+        let mut client_writer;
+        let writer = match combined_writer.as_mut() {
+            Some(writer) => writer,
+            None => {
+                client_writer = csv::Writer::from_path(dir.join(format!("client_{}.csv", client)))?;
+                files += 1;
+                &mut client_writer
+            }
+        };
+        for event in history {
+            writer.serialize(AuditRow::new(client, event))?;
+        }
+        writer.flush()?;
+    }
+
+    if let Some(mut writer) = combined_writer {
+        writer.flush()?;
+        files = 1;
+    }
+
+    info!("Wrote account history to {} file(s) in {:?}", files, dir);
+
+    Ok(())
+}
+
+/// Write the recorded audit trail as a trace of each transaction's outcome
+/// and the client's balances after it, clients in order.
+pub fn write_trace<W: Write>(engine: &PaymentEngine, format: TraceFormat, output: W) -> Result<()> {
+    let mut clients: Vec<u16> = engine.get_accounts().iter().map(|a| a.client).collect();
+    clients.sort_unstable();
+    let rows = clients
+        .into_iter()
+        .flat_map(|client| engine.account_history(client).iter().map(move |event| AuditRow::new(client, event)));
+
+    match format {
+        TraceFormat::Text => {
+            let mut output = std::io::BufWriter::new(output);
+            for row in rows {
+                write!(output, "client {}, tx {}, {}", row.client, row.tx, row.transaction_type)?;
+                if let Some(amount) = row.amount {
+                    write!(output, " {}", amount)?;
+                }
+                match row.outcome {
+                    "applied" => write!(output, ": applied")?,
+                    outcome => write!(output, ": {} ({})", outcome, row.reason)?,
+                }
+                writeln!(output, "; available {}, held {}, total {}", row.available, row.held, row.total)?;
+            }
+            output.flush()?;
+        }
+        TraceFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Transaction;
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_write_history_per_client_and_combined() {
+        let mut engine = PaymentEngine::new();
+        engine.set_record_history(true);
+        let batch = vec![
+            Transaction::deposit(2, 1, dec!(10)),
+            Transaction::withdrawal(2, 2, dec!(20)),
+            Transaction::deposit(1, 3, dec!(1.5)),
+        ];
+        engine.process_transaction_batch(batch).await;
+
+        let dir = tempdir().unwrap();
+        write_history(&engine, dir.path(), false).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("client_2.csv")).unwrap(),
+            "client,tx,type,amount,outcome,reason,available,held,total\n\
+             2,1,deposit,10,applied,,10,0,10\n\
+             2,2,withdrawal,20,rejected,insufficient_funds,10,0,10\n"
+        );
+        assert!(dir.path().join("client_1.csv").exists());
+
+        write_history(&engine, dir.path(), true).unwrap();
+        let combined = fs::read_to_string(dir.path().join("history.csv")).unwrap();
+        let clients: Vec<&str> = combined.lines().skip(1).map(|l| &l[..1]).collect();
+        assert_eq!(clients, ["1", "2", "2"]);
+    }
+}
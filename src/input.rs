@@ -0,0 +1,630 @@
+use crate::error::{PaymentEngineError, Result};
+use futures::future::BoxFuture;
+use memmap2::Mmap;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use tracing::{debug, info};
+
+// How long to wait for a followed regular file to grow after reaching its end
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the input file is read
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InputMode {
+    /// Buffered asynchronous reads
+    #[default]
+    Streaming,
+    /// Memory-map the file and split lines over the mapped bytes.
+    /// Falls back to streaming for pipes and other non-regular files.
+    Mmap,
+}
+
+impl FromStr for InputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "streaming" => Ok(Self::Streaming),
+            "mmap" => Ok(Self::Mmap),
+            _ => Err(format!("Invalid input mode: {}", s)),
+        }
+    }
+}
+
+/// Character encoding of the input file
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InputEncoding {
+    /// UTF-8; lines with invalid sequences are rejected as read errors
+    #[default]
+    Utf8Strict,
+    /// UTF-8; invalid sequences are replaced with U+FFFD and the line is still parsed
+    Utf8Lossy,
+    /// Windows-1252, transcoded to UTF-8
+    Windows1252,
+}
+
+impl FromStr for InputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8-strict" => Ok(Self::Utf8Strict),
+            "utf8-lossy" => Ok(Self::Utf8Lossy),
+            "windows-1252" => Ok(Self::Windows1252),
+            _ => Err(format!("Invalid input encoding: {}", s)),
+        }
+    }
+}
+
+/// How the fields of an input line are laid out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Fields separated by the delimiter, after an optional header line
+    #[default]
+    Delimited,
+    /// Fields at fixed byte offsets, without a header line
+    FixedWidth(FixedWidthSpec),
+}
+
+/// Byte range of a field in a fixed-width record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "(usize, usize)")]
+pub struct FixedWidthField {
+    pub offset: usize,
+    pub width: usize,
+}
+
+impl FixedWidthField {
+    pub const fn new(offset: usize, width: usize) -> Self {
+        Self { offset, width }
+    }
+
+    /// Offset just past the field
+    pub fn end(&self) -> usize {
+        self.offset.saturating_add(self.width)
+    }
+}
+
+impl From<(usize, usize)> for FixedWidthField {
+    fn from((offset, width): (usize, usize)) -> Self {
+        Self::new(offset, width)
+    }
+}
+
+/// Layout of fixed-width records, such as those exported by mainframes.
+///
+/// The default is a 10 byte type, 6 byte client, 10 byte tx and 14 byte
+/// amount with 4 implied decimal places, in that order. In a config file
+/// each field is an `[offset, width]` pair, and missing keys keep their
+/// default:
+///
+/// ```toml
+/// [fixed_width]
+/// amount = [26, 12]
+/// implied_decimal = false
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FixedWidthSpec {
+    #[serde(rename = "type")]
+    pub transaction_type: FixedWidthField,
+    pub client: FixedWidthField,
+    pub tx: FixedWidthField,
+    pub amount: FixedWidthField,
+    /// The amount is a whole number of ten-thousandths, e.g. `00000001234500`
+    /// for 123.45, rather than a number with a decimal separator
+    pub implied_decimal: bool,
+}
+
+impl Default for FixedWidthSpec {
+    fn default() -> Self {
+        Self {
+            transaction_type: FixedWidthField::new(0, 10),
+            client: FixedWidthField::new(10, 6),
+            tx: FixedWidthField::new(16, 10),
+            amount: FixedWidthField::new(26, 14),
+            implied_decimal: true,
+        }
+    }
+}
+
+impl FixedWidthSpec {
+    /// The fields by name, in the order they appear in a CSV line
+    pub fn fields(&self) -> [(&'static str, FixedWidthField); 4] {
+        [("type", self.transaction_type), ("client", self.client), ("tx", self.tx), ("amount", self.amount)]
+    }
+}
+
+impl FromStr for FixedWidthSpec {
+    type Err = String;
+
+    /// Parse `default`, or comma-separated changes to the default layout
+    /// such as `amount=26:12,implied_decimal=false`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = Self::default();
+        if s == "default" {
+            return Ok(spec);
+        }
+        for entry in s.split(',') {
+            let invalid = || format!("Invalid fixed-width field: {} (expected name=offset:width or implied_decimal=true|false)", entry);
+            let (name, value) = entry.split_once('=').ok_or_else(invalid)?;
+            if name.trim() == "implied_decimal" {
+                spec.implied_decimal = value.trim().parse().map_err(|_| invalid())?;
+                continue;
+            }
+            let (offset, width) = value.split_once(':').ok_or_else(invalid)?;
+            let field = FixedWidthField::new(offset.trim().parse().map_err(|_| invalid())?, width.trim().parse().map_err(|_| invalid())?);
+            match name.trim() {
+                "type" => spec.transaction_type = field,
+                "client" => spec.client = field,
+                "tx" => spec.tx = field,
+                "amount" => spec.amount = field,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// Result of reading a single line. Lines only need to be copied when they
+/// are transcoded or contain replaced sequences.
+pub(crate) type LineResult<'a> = Result<Cow<'a, str>, PaymentEngineError>;
+
+/// Reads lines into a reusable buffer instead of allocating a String per line
+pub(crate) struct LineReader<R> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+    max_line_length: usize,
+    encoding: InputEncoding,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    /// Create a reader that rejects lines longer than `max_line_length` bytes (0 = unlimited)
+    pub(crate) fn new(reader: BufReader<R>, max_line_length: usize, encoding: InputEncoding) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(256),
+            max_line_length,
+            encoding,
+        }
+    }
+
+    /// Read the next line without its `\n` or `\r\n` terminator.
+    /// In strict mode, lines that aren't valid UTF-8 are reported as errors, like `AsyncBufReadExt::lines`.
+    /// The rest of an oversized line is skipped without being buffered.
+    pub(crate) async fn next_line(&mut self) -> Option<LineResult<'_>> {
+        self.buf.clear();
+        let mut read_any = false;
+        let mut too_long = false;
+
+        loop {
+            let available = match self.reader.fill_buf().await {
+                Ok(available) => available,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if available.is_empty() {
+                break;
+            }
+            read_any = true;
+
+            let (len, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            // Allow room for the terminator; the exact length is checked after stripping it
+            if !too_long && self.max_line_length > 0 && self.buf.len() + len > self.max_line_length + 2 {
+                too_long = true;
+                self.buf.clear();
+            }
+            if !too_long {
+                self.buf.extend_from_slice(&available[..len]);
+            }
+            self.reader.consume(len);
+
+            if done {
+                break;
+            }
+        }
+
+        if !read_any {
+            return None;
+        }
+        if too_long {
+            return Some(Err(PaymentEngineError::LineTooLong(self.max_line_length)));
+        }
+        Some(decode_line(&self.buf, self.max_line_length, self.encoding))
+    }
+}
+
+/// Splits a memory-mapped file into lines
+pub(crate) struct MmapLines {
+    // None for an empty file, which can't be mapped
+    mmap: Option<Mmap>,
+    pos: usize,
+    max_line_length: usize,
+    encoding: InputEncoding,
+}
+
+impl MmapLines {
+    pub(crate) fn open(file: &std::fs::File, max_line_length: usize, encoding: InputEncoding) -> io::Result<Self> {
+        let mmap = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            // SAFETY: the mapping is read-only; as with any mmap, the file must
+            // not be truncated by another process while it is being processed
+            Some(unsafe { Mmap::map(file)? })
+        };
+        Ok(Self { mmap, pos: 0, max_line_length, encoding })
+    }
+
+    /// Return the next line without its `\n` or `\r\n` terminator
+    pub(crate) fn next_line(&mut self) -> Option<LineResult<'_>> {
+        let data: &[u8] = self.mmap.as_deref().unwrap_or_default();
+        let raw = next_raw_line(data, &mut self.pos)?;
+        Some(decode_line(raw, self.max_line_length, self.encoding))
+    }
+}
+
+/// Split input held in memory into lines, like a memory-mapped file
+#[cfg(feature = "fuzz")]
+pub(crate) fn slice_lines(data: &[u8], max_line_length: usize, encoding: InputEncoding) -> impl Iterator<Item = LineResult<'_>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || next_raw_line(data, &mut pos).map(|raw| decode_line(raw, max_line_length, encoding)))
+}
+
+/// Return the line of `data` starting at `pos`, with its terminator, and
+/// move `pos` past it
+fn next_raw_line<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    if *pos >= data.len() {
+        return None;
+    }
+    let rest = &data[*pos..];
+    let end = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+    *pos += end;
+    Some(&rest[..end])
+}
+
+/// Strip the line terminator, enforce the length limit and decode the line
+fn decode_line(raw: &[u8], max_line_length: usize, encoding: InputEncoding) -> LineResult<'_> {
+    let mut line = raw;
+    if let Some(rest) = line.strip_suffix(b"\n") {
+        line = rest.strip_suffix(b"\r").unwrap_or(rest);
+    }
+    if max_line_length > 0 && line.len() > max_line_length {
+        return Err(PaymentEngineError::LineTooLong(max_line_length));
+    }
+    match encoding {
+        InputEncoding::Utf8Strict => std::str::from_utf8(line).map(Cow::Borrowed).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8").into()
+        }),
+        InputEncoding::Utf8Lossy => Ok(String::from_utf8_lossy(line)),
+        InputEncoding::Windows1252 => Ok(encoding_rs::WINDOWS_1252.decode_without_bom_handling(line).0),
+    }
+}
+
+/// Picks up reading where its input ended instead of reporting EOF. For a
+/// FIFO, whose EOF only means the producer closed its end, the path is opened
+/// again, waiting for the next producer; a regular file is read again after
+/// a short wait, like `tail -f`. Whatever stops the stream has to come from
+/// the lines, such as an end marker, or from dropping the reader.
+pub(crate) struct FollowReader<R> {
+    reader: Option<R>,
+    reopen: Box<dyn FnMut(R) -> BoxFuture<'static, io::Result<R>> + Send>,
+    reopening: Option<BoxFuture<'static, io::Result<R>>>,
+}
+
+impl<R> FollowReader<R> {
+    /// Follow `reader`, calling `reopen` with it for the reader to go on with
+    /// each time it reaches EOF
+    pub(crate) fn new(reader: R, reopen: impl FnMut(R) -> BoxFuture<'static, io::Result<R>> + Send + 'static) -> Self {
+        Self { reader: Some(reader), reopen: Box::new(reopen), reopening: None }
+    }
+}
+
+impl FollowReader<File> {
+    /// Follow the opened file at `path`
+    async fn open(file: File, path: &Path) -> io::Result<Self> {
+        if file.metadata().await?.is_file() {
+            return Ok(Self::new(file, |file| {
+                Box::pin(async move {
+                    tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+                    Ok(file)
+                })
+            }));
+        }
+        let path = path.to_path_buf();
+        Ok(Self::new(file, move |closed| {
+            drop(closed);
+            let path = path.clone();
+            Box::pin(async move {
+                debug!("Producer closed {:?}, waiting for the next one", path);
+                File::open(&path).await
+            })
+        }))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FollowReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(reopening) = &mut this.reopening {
+                let reader = ready!(reopening.as_mut().poll(cx))?;
+                this.reopening = None;
+                this.reader = Some(reader);
+            }
+            let reader = this.reader.as_mut().expect("follow reader without an input");
+            let filled = buf.filled().len();
+            ready!(Pin::new(reader).poll_read(cx, buf))?;
+            if buf.filled().len() > filled || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            // EOF; a partial line read so far stays in the line reader's buffer
+            let reader = this.reader.take().expect("follow reader without an input");
+            this.reopening = Some((this.reopen)(reader));
+        }
+    }
+}
+
+/// Source of input lines for the processing pipeline
+pub(crate) enum LineSource {
+    Stream(LineReader<File>),
+    Mmap(MmapLines),
+    /// Input read past EOF until the end marker line, which isn't returned.
+    /// `ended` keeps it ended once the marker was seen.
+    Follow { lines: LineReader<FollowReader<File>>, end_marker: String, ended: bool },
+    /// Body of a remote object, read as it arrives
+    #[cfg(feature = "remote")]
+    Remote(LineReader<std::pin::Pin<Box<dyn AsyncRead + Send>>>),
+}
+
+impl LineSource {
+    /// Open the input file in the requested mode, rejecting lines longer than
+    /// `max_line_length` bytes (0 = unlimited)
+    pub(crate) async fn open(
+        file_path: &Path,
+        mode: InputMode,
+        max_line_length: usize,
+        encoding: InputEncoding,
+    ) -> Result<Self> {
+        let file = File::open(file_path)
+            .await
+            .map_err(|source| PaymentEngineError::OpenFile { path: file_path.to_path_buf(), source })?;
+
+        if mode == InputMode::Mmap {
+            let file = file.into_std().await;
+            if file.metadata()?.is_file() {
+                return Ok(Self::Mmap(MmapLines::open(&file, max_line_length, encoding)?));
+            }
+            info!("{:?} is not a regular file, falling back to streaming input", file_path);
+            let file = File::from_std(file);
+            return Ok(Self::Stream(LineReader::new(BufReader::new(file), max_line_length, encoding)));
+        }
+
+        Ok(Self::Stream(LineReader::new(BufReader::new(file), max_line_length, encoding)))
+    }
+
+    /// Open the input file to be followed past EOF until a line equal to
+    /// `end_marker`, see [`FollowReader`]
+    pub(crate) async fn follow(
+        file_path: &Path,
+        max_line_length: usize,
+        encoding: InputEncoding,
+        end_marker: &str,
+    ) -> Result<Self> {
+        let file = File::open(file_path)
+            .await
+            .map_err(|source| PaymentEngineError::OpenFile { path: file_path.to_path_buf(), source })?;
+        let reader = FollowReader::open(file, file_path).await?;
+        let lines = LineReader::new(BufReader::new(reader), max_line_length, encoding);
+        Ok(Self::Follow { lines, end_marker: end_marker.to_string(), ended: false })
+    }
+
+    /// Read the next line without its terminator
+    pub(crate) async fn next_line(&mut self) -> Option<LineResult<'_>> {
+        match self {
+            Self::Stream(reader) => reader.next_line().await,
+            Self::Mmap(lines) => lines.next_line(),
+            Self::Follow { ended: true, .. } => None,
+            Self::Follow { lines, end_marker, ended } => match lines.next_line().await? {
+                Ok(line) if line == end_marker.as_str() => {
+                    *ended = true;
+                    None
+                }
+                line => Some(line),
+            },
+            #[cfg(feature = "remote")]
+            Self::Remote(reader) => reader.next_line().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    const INPUT: &[u8] = b"type,client,tx,amount\r\ndeposit,1,1,1.0\n\nwithdrawal,1,2,0.5\r\nbad\xff,1\nresolve,1,1";
+
+    async fn collect(source: &mut LineSource) -> Vec<Result<String, String>> {
+        let mut lines = Vec::new();
+        while let Some(line) = source.next_line().await {
+            lines.push(line.map(Cow::into_owned).map_err(|e| e.to_string()));
+        }
+        lines
+    }
+
+    fn expected() -> Vec<Result<String, String>> {
+        vec![
+            Ok("type,client,tx,amount".to_string()),
+            Ok("deposit,1,1,1.0".to_string()),
+            Ok(String::new()),
+            Ok("withdrawal,1,2,0.5".to_string()),
+            Err("Failed to read file: stream did not contain valid UTF-8".to_string()),
+            // A final line without a trailing newline is still returned
+            Ok("resolve,1,1".to_string()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_line_reader_reuses_buffer_and_strips_terminators() {
+        let mut lines = LineReader::new(BufReader::new(INPUT), 0, InputEncoding::Utf8Strict);
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "type,client,tx,amount");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "deposit,1,1,1.0");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "withdrawal,1,2,0.5");
+        assert!(matches!(lines.next_line().await, Some(Err(PaymentEngineError::FileReadError(_)))));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "resolve,1,1");
+        assert!(lines.next_line().await.is_none());
+        assert!(lines.buf.capacity() >= 256);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_and_mmap_produce_same_lines() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("input.csv");
+        write(&file_path, INPUT).unwrap();
+
+        let mut streaming = LineSource::open(&file_path, InputMode::Streaming, 0, InputEncoding::Utf8Strict).await.unwrap();
+        let mut mmap = LineSource::open(&file_path, InputMode::Mmap, 0, InputEncoding::Utf8Strict).await.unwrap();
+        assert!(matches!(mmap, LineSource::Mmap(_)));
+
+        assert_eq!(collect(&mut streaming).await, expected());
+        assert_eq!(collect(&mut mmap).await, expected());
+    }
+
+    #[tokio::test]
+    async fn test_mmap_empty_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("empty.csv");
+        write(&file_path, b"").unwrap();
+
+        let mut mmap = LineSource::open(&file_path, InputMode::Mmap, 0, InputEncoding::Utf8Strict).await.unwrap();
+        assert!(mmap.next_line().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_line_is_skipped() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("long.csv");
+        let long_line = "x".repeat(100_000);
+        write(&file_path, format!("deposit,1,1,1.0\n{}\ndeposit,1,2,2.0\r\n1234567890\n12345678901", long_line)).unwrap();
+
+        for mode in [InputMode::Streaming, InputMode::Mmap] {
+            let mut source = LineSource::open(&file_path, mode, 10, InputEncoding::Utf8Strict).await.unwrap();
+            let too_long = Err("Line exceeds maximum length of 10 bytes".to_string());
+            assert_eq!(
+                collect(&mut source).await,
+                vec![
+                    Err("Line exceeds maximum length of 10 bytes".to_string()),
+                    too_long.clone(),
+                    Err("Line exceeds maximum length of 10 bytes".to_string()),
+                    Ok("1234567890".to_string()),
+                    too_long,
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_line_reader_does_not_buffer_oversized_line() {
+        let input = format!("{}\nok\n", "y".repeat(1_000_000));
+        let mut lines = LineReader::new(BufReader::new(input.as_bytes()), 64, InputEncoding::Utf8Strict);
+
+        assert!(matches!(lines.next_line().await, Some(Err(PaymentEngineError::LineTooLong(64)))));
+        assert!(lines.buf.capacity() < 1_000_000);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_lossy_and_windows_1252_decoding() {
+        // Windows-1252 curly quotes around a note column
+        let input: &[u8] = b"deposit,1,1,1.0,\x93note\x94\nok\n";
+
+        let mut lossy = LineReader::new(BufReader::new(input), 0, InputEncoding::Utf8Lossy);
+        assert_eq!(lossy.next_line().await.unwrap().unwrap(), "deposit,1,1,1.0,\u{FFFD}note\u{FFFD}");
+        assert!(matches!(lossy.next_line().await.unwrap().unwrap(), Cow::Borrowed("ok")));
+
+        let mut cp1252 = LineReader::new(BufReader::new(input), 0, InputEncoding::Windows1252);
+        assert_eq!(cp1252.next_line().await.unwrap().unwrap(), "deposit,1,1,1.0,\u{201C}note\u{201D}");
+    }
+
+    #[tokio::test]
+    async fn test_follow_reader_buffers_lines_across_reconnects() {
+        use tokio::io::AsyncWriteExt;
+
+        // Each producer writes its chunk and closes its end, splitting lines
+        // and even a \r\n terminator between connections
+        let chunks: [&[u8]; 4] = [b"deposit,1,1,1", b".5\r", b"\ndeposit,1,2,2.0\nwithdrawal,", b"1,3,0.5\n"];
+        let mut connections = Vec::new();
+        for chunk in chunks {
+            let (mut producer, consumer) = tokio::io::duplex(64);
+            producer.write_all(chunk).await.unwrap();
+            connections.push(consumer);
+        }
+        let mut connections = connections.into_iter();
+        let reopens = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let first = connections.next().unwrap();
+        let counter = reopens.clone();
+        let reader = FollowReader::new(first, move |_closed| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let next = connections.next();
+            Box::pin(async move { next.ok_or_else(|| io::Error::other("no more producers")) })
+        });
+        let mut lines = LineReader::new(BufReader::new(reader), 0, InputEncoding::Utf8Strict);
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "deposit,1,1,1.5");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "deposit,1,2,2.0");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "withdrawal,1,3,0.5");
+        assert_eq!(reopens.load(std::sync::atomic::Ordering::Relaxed), 3);
+        // Without another producer the input can't go on
+        assert!(matches!(lines.next_line().await, Some(Err(PaymentEngineError::FileReadError(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_follow_regular_file_until_end_marker() {
+        use std::io::Write as _;
+
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("growing.csv");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,1.0\ndepo").unwrap();
+        let mut source = LineSource::follow(&file_path, 0, InputEncoding::Utf8Strict, "#EOF").await.unwrap();
+        assert_eq!(source.next_line().await.unwrap().unwrap(), "type,client,tx,amount");
+        assert_eq!(source.next_line().await.unwrap().unwrap(), "deposit,1,1,1.0");
+
+        // The rest is appended after the reader has reached the end
+        let appender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut file = std::fs::OpenOptions::new().append(true).open(file_path).unwrap();
+            file.write_all(b"sit,1,2,2.0\n#EOF\ndeposit,1,3,3.0\n").unwrap();
+        });
+        assert_eq!(collect(&mut source).await, vec![Ok("deposit,1,2,2.0".to_string())]);
+        appender.await.unwrap();
+        assert!(source.next_line().await.is_none());
+    }
+
+    #[test]
+    fn test_input_encoding_from_str() {
+        assert_eq!("utf8-strict".parse::<InputEncoding>(), Ok(InputEncoding::Utf8Strict));
+        assert_eq!("utf8-lossy".parse::<InputEncoding>(), Ok(InputEncoding::Utf8Lossy));
+        assert_eq!("windows-1252".parse::<InputEncoding>(), Ok(InputEncoding::Windows1252));
+        assert!("latin1".parse::<InputEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_input_mode_from_str() {
+        assert_eq!("streaming".parse::<InputMode>(), Ok(InputMode::Streaming));
+        assert_eq!("mmap".parse::<InputMode>(), Ok(InputMode::Mmap));
+        assert!("direct".parse::<InputMode>().is_err());
+    }
+}
@@ -1,10 +1,62 @@
 pub mod engine;
 pub mod models;
+pub mod clock;
+pub mod concurrent;
+#[cfg(all(feature = "daemon", unix))]
+pub mod daemon;
+pub mod diff;
 pub mod error;
+pub mod events;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod generator;
+pub mod history;
+mod input;
+pub mod manifest;
+pub mod metrics;
+pub mod multi_file;
+pub mod seen;
+pub mod shared;
+pub mod snapshot;
+pub mod spill;
+pub mod wal;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 mod processor;
+pub mod replay;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "http")]
+mod server;
 
 // Re-export main processing functions for convenience
-pub use processor::{process_transactions, process_transactions_with_options, ProcessingOptions};
+pub use input::{FixedWidthField, FixedWidthSpec, InputEncoding, InputFormat, InputMode};
+pub use processor::{
+    process_transactions, process_transactions_with_options, summarize_transactions, BatchSizeMode, ChangeStream, ClientFilter, ClientSample,
+    ClosedAccountHandling, FileSummary, OutputColumn, OutputColumns, OutputMode, Preset, ProcessingOptions, ProcessingOptionsBuilder,
+    ProcessingReport, SampleEstimate, StopAfter, TransactionHook, Verbosity,
+};
+pub use engine::{BatchResult, EngineStats, PaymentEngine};
+pub use error::{OptionsError, PaymentEngineError};
+pub use models::{Account, AccountDelta, Transaction, TransactionOutcome, TransactionType, TxId};
+pub use events::EngineEvent;
+pub use history::write_history;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use manifest::RunManifest;
+pub use multi_file::process_transaction_files_parallel;
+pub use shared::SharedPaymentEngine;
+pub use snapshot::SnapshotEvery;
+pub use wal::replay_wal;
+#[cfg(feature = "sqlite")]
+pub use sqlite::write_accounts_to_sqlite;
+#[cfg(feature = "parquet")]
+pub use self::parquet::process_transactions_parquet;
+#[cfg(feature = "remote")]
+pub use remote::process_transactions_from_url;
 
 #[cfg(test)]
 mod tests {
@@ -37,9 +89,10 @@ mod tests {
         write(&file_path, csv_content).unwrap();
         
         // Process the transactions with a small batch size for testing
-        let options = ProcessingOptions {
-            batch_size: 5,  // Use a small batch size for testing
-        };
+        let options = ProcessingOptions::builder()
+            .batch_size(5)  // Use a small batch size for testing
+            .build()
+            .unwrap();
         process_transactions_with_options(Path::new(&file_path), options).await.unwrap();
         
         // Note: Since process_transactions writes to stdout, we can't easily capture
@@ -67,9 +120,35 @@ mod tests {
         
         // Process should complete without panic even with errors
         // Using a custom batch size to test the batch processing with errors
-        let options = ProcessingOptions {
-            batch_size: 2,  // Small batch size to test error handling in batches
-        };
+        let options = ProcessingOptions::builder()
+            .batch_size(2)  // Small batch size to test error handling in batches
+            .build()
+            .unwrap();
         process_transactions_with_options(Path::new(&file_path), options).await.unwrap();
     }
+    #[test]
+    fn test_embedded_engine_without_processor() {
+        use rust_decimal_macros::dec;
+
+        // Only items re-exported at the crate root, as a downstream consumer would use
+        let mut engine = PaymentEngine::new();
+
+        let outcome = engine.apply(Transaction::deposit(1, 1, dec!(100))).unwrap();
+        assert_eq!(outcome, TransactionOutcome::Applied);
+
+        let outcomes = engine.apply_all([
+            Transaction::withdrawal(1, 2, dec!(30)),
+            Transaction::withdrawal(1, 3, dec!(500)),
+            Transaction { amount: None, ..Transaction::deposit(1, 4, dec!(1)) },
+            Transaction::dispute(1, 1),
+        ]);
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!(outcomes[0].as_ref().unwrap(), &TransactionOutcome::Applied);
+        assert_eq!(outcomes[1].as_ref().unwrap(), &TransactionOutcome::Rejected("insufficient_funds"));
+        assert!(matches!(outcomes[2], Err(PaymentEngineError::MissingAmount(4))));
+        assert_eq!(outcomes[3].as_ref().unwrap(), &TransactionOutcome::Rejected("hold_failed"));
+
+        let account: &Account = engine.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(70), dec!(0), dec!(70)));
+    }
 }
\ No newline at end of file
@@ -1,44 +1,390 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
 use std::fs;
+use std::io::BufWriter;
 use chrono::Local;
+use rust_decimal::Decimal;
+use tracing::{error, warn};
 use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
 
-use payment_engine::{process_transactions_with_options, ProcessingOptions};
+use payment_engine::diff::{diff_accounts, read_accounts_from_path};
+use payment_engine::generator::{generate_transactions, AmountDistribution, GeneratorConfig};
+use payment_engine::replay::replay_client;
+use payment_engine::{
+    process_transaction_files_parallel, process_transactions_with_options, summarize_transactions, InputEncoding, InputMode, PaymentEngineError,
+    ProcessingOptions, StopAfter, TxId, Verbosity,
+};
+
+mod config;
+use config::Settings;
 
 #[derive(Parser, Debug)]
 #[command(about = "A payment transaction processor")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input CSV file with transactions, or with the `remote` feature an
+    /// http://, https:// or s3:// URL. Several files need --parallel-files.
+    #[arg(name = "FILE", required = true, num_args = 1..)]
+    input_files: Vec<PathBuf>,
+
+    /// Process the input files, whose clients must not overlap, this many at
+    /// a time, each on its own engine, and write the merged accounts
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    parallel_files: Option<u16>,
+
+    /// TOML config file with defaults for the options below; flags given on
+    /// the command line take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a synthetic transactions CSV for testing and benchmarking
+    Generate(GenerateArgs),
+
+    /// Print statistics about an input file without applying its transactions
+    Summarize(SummarizeArgs),
+
+    /// Compare two account output files and report the clients that differ
+    Diff(DiffArgs),
+
+    /// Rebuild one client's account and stored transactions from an input,
+    /// up to a transaction or line
+    Replay(ReplayArgs),
+
+    /// Keep an engine running and apply transactions sent over a Unix socket
+    #[cfg(all(feature = "daemon", unix))]
+    Daemon(DaemonArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// Number of transactions to generate
+    #[arg(long, default_value = "10000")]
+    transactions: usize,
+
+    /// Number of distinct clients
+    #[arg(long, default_value = "1000")]
+    clients: u16,
+
+    /// Share of deposits among deposits and withdrawals
+    #[arg(long, default_value = "0.65")]
+    deposit_ratio: f64,
+
+    /// Probability that a transaction disputes an earlier deposit
+    #[arg(long, default_value = "0.05")]
+    dispute_probability: f64,
+
+    /// Probability that a dispute ends in a chargeback rather than a resolve
+    #[arg(long, default_value = "0.4")]
+    chargeback_probability: f64,
+
+    /// Draw amounts from an exponential distribution with this mean
+    /// instead of uniformly between --min-amount and --max-amount
+    #[arg(long)]
+    mean_amount: Option<f64>,
+
+    /// Minimum amount for uniformly distributed amounts
+    #[arg(long, default_value = "1.0")]
+    min_amount: f64,
+
+    /// Maximum amount for uniformly distributed amounts
+    #[arg(long, default_value = "10000.0")]
+    max_amount: f64,
+
+    /// RNG seed for reproducible output
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Output file (defaults to stdout)
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SummarizeArgs {
     /// Input CSV file with transactions
     #[arg(name = "FILE")]
     input_file: PathBuf,
 
-    /// Log directory (defaults to logs/)
-    #[arg(long, default_value = "logs")]
-    log_dir: PathBuf,
-    
-    /// Batch size for processing transactions (default: 1000)
-    #[arg(long, default_value = "1000")]
-    batch_size: usize,
+    /// Output format: table or json
+    #[arg(long, default_value = "table")]
+    format: SummaryFormat,
+
+    /// How to read the input file: streaming or mmap
+    #[arg(long, default_value = "streaming")]
+    input_mode: InputMode,
+
+    /// Input encoding: utf8-strict, utf8-lossy or windows-1252
+    #[arg(long, default_value = "utf8-strict")]
+    encoding: InputEncoding,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Accounts CSV from the earlier run
+    #[arg(name = "OLD")]
+    old: PathBuf,
+
+    /// Accounts CSV from the later run
+    #[arg(name = "NEW")]
+    new: PathBuf,
+
+    /// Ignore balance differences up to this amount
+    #[arg(long, default_value = "0")]
+    tolerance: Decimal,
 }
 
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Input CSV file with transactions
+    #[arg(name = "FILE")]
+    input_file: PathBuf,
+
+    /// Client whose state to rebuild
+    #[arg(long)]
+    client: u16,
+
+    /// Stop after the first record with this transaction id
+    #[arg(long, conflicts_with = "until_line")]
+    until_tx: Option<TxId>,
+
+    /// Stop after this input line, counting the header as line 1
+    #[arg(long)]
+    until_line: Option<usize>,
+
+    /// TOML config file of the run being replayed, for its engine rules
+    /// such as limits, fees and dispute policies
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[cfg(all(feature = "daemon", unix))]
+#[derive(clap::Args, Debug)]
+struct DaemonArgs {
+    /// Path of the Unix socket to listen on
+    #[arg(name = "SOCKET")]
+    socket: PathBuf,
+
+    /// Write the final accounts CSV here on shutdown
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// TOML config file with the engine rules, such as limits, fees and
+    /// dispute policies
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+/// Output format of the `summarize` subcommand
+#[derive(Debug, Clone, Copy)]
+enum SummaryFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(SummaryFormat::Table),
+            "json" => Ok(SummaryFormat::Json),
+            _ => Err(format!("Invalid summary format: {} (expected table or json)", s)),
+        }
+    }
+}
+
+/// Build the processing options, exiting with a usage error if they are
+/// invalid, as clap does for a malformed flag
+fn to_options(settings: &Settings) -> Result<ProcessingOptions> {
+    match settings.to_options() {
+        Err(e) => match e.downcast_ref::<PaymentEngineError>() {
+            Some(PaymentEngineError::InvalidOptions(invalid)) => Args::command().error(ErrorKind::ValueValidation, invalid).exit(),
+            _ => Err(e),
+        },
+        options => options,
+    }
+}
+
+/// Run the `summarize` subcommand
+async fn run_summarize(args: SummarizeArgs) -> Result<()> {
+    let options = ProcessingOptions::builder().input_mode(args.input_mode).encoding(args.encoding).build()?;
+    let summary = summarize_transactions(&args.input_file, &options).await?;
+    match args.format {
+        SummaryFormat::Table => println!("{}", summary),
+        SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+    }
+    Ok(())
+}
+
+/// Run the `diff` subcommand, returning whether any differences were found
+fn run_diff(args: DiffArgs) -> Result<bool> {
+    let old = read_accounts_from_path(&args.old)?;
+    let new = read_accounts_from_path(&args.new)?;
+    let diff = diff_accounts(&old, &new, args.tolerance);
+    print!("{}", diff);
+    Ok(!diff.is_empty())
+}
+
+/// Run the `replay` subcommand
+async fn run_replay(args: ReplayArgs) -> Result<()> {
+    let options = match &args.config {
+        Some(path) => to_options(&Settings::load(path)?.0)?,
+        None => ProcessingOptions::default(),
+    };
+    let stop_after = match (args.until_tx, args.until_line) {
+        (Some(tx), _) => Some(StopAfter::Tx(tx)),
+        (None, Some(line)) => Some(StopAfter::Line(line)),
+        (None, None) => None,
+    };
+    let replay = replay_client(&args.input_file, args.client, stop_after, options).await?;
+    if stop_after.is_some() && !replay.report().stop_reached {
+        eprintln!("Warning: the input ended before the cut point; replayed all of it");
+    }
+    print!("{}", replay);
+    Ok(())
+}
+
+/// Run the `daemon` subcommand until it receives `SHUTDOWN` or Ctrl-C
+#[cfg(all(feature = "daemon", unix))]
+async fn run_daemon(args: DaemonArgs) -> Result<()> {
+    let options = match &args.config {
+        Some(path) => to_options(&Settings::load(path)?.0)?,
+        None => ProcessingOptions::default(),
+    };
+    let daemon = payment_engine::daemon::Daemon::bind(&args.socket, options)?;
+    let shutdown = daemon.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown();
+        }
+    });
+    daemon.run(args.output.as_deref()).await?;
+    Ok(())
+}
+
+/// Run the `generate` subcommand
+fn run_generate(args: GenerateArgs) -> Result<()> {
+    let GenerateArgs {
+        transactions,
+        clients,
+        deposit_ratio,
+        dispute_probability,
+        chargeback_probability,
+        mean_amount,
+        min_amount,
+        max_amount,
+        seed,
+        output,
+    } = args;
+
+    let amount_distribution = match mean_amount {
+        Some(mean) => AmountDistribution::Exponential { mean },
+        None => AmountDistribution::Uniform { min: min_amount, max: max_amount },
+    };
+    let config = GeneratorConfig {
+        transactions,
+        clients,
+        deposit_ratio,
+        dispute_probability,
+        chargeback_probability,
+        amount_distribution,
+        seed,
+    };
+
+    // Before an output file is created for it
+    config.validate()?;
+    match output {
+        Some(path) => generate_transactions(&config, BufWriter::new(fs::File::create(path)?))?,
+        None => generate_transactions(&config, BufWriter::new(std::io::stdout().lock()))?,
+    }
+    Ok(())
+}
+
+/// Exit status for runs that completed with more skipped or rejected lines
+/// than `--max-errors` allows. Fatal errors exit with status 1, and usage
+/// errors, invalid options included, with clap's 2.
+const EXIT_TOO_MANY_ERRORS: u8 = 4;
+
+/// Exit status for runs whose final balances don't match `--expected`
+const EXIT_RECONCILIATION_FAILED: u8 = 3;
+
+/// Exit status of `diff` when the account files differ
+const EXIT_DIFFERENCES: u8 = 2;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     // Parse command line arguments
     let args = Args::parse();
+
+    match args.command {
+        Some(Command::Generate(generate)) => {
+            run_generate(generate)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Summarize(summarize)) => {
+            run_summarize(summarize).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Diff(diff)) => {
+            let differs = run_diff(diff)?;
+            return Ok(if differs { ExitCode::from(EXIT_DIFFERENCES) } else { ExitCode::SUCCESS });
+        }
+        Some(Command::Replay(replay)) => {
+            run_replay(replay).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(all(feature = "daemon", unix))]
+        Some(Command::Daemon(daemon)) => {
+            run_daemon(daemon).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
+    if args.input_files.len() > 1 && args.parallel_files.is_none() {
+        Args::command().error(ErrorKind::ArgumentConflict, "several input files need --parallel-files").exit();
+    }
+    let input_files = args.input_files;
+    let input_file = input_files.first().cloned().ok_or_else(|| anyhow::anyhow!("Missing input file"))?;
+
+    // Command line flags take precedence over the config file
+    let (settings, unknown_keys) = match &args.config {
+        Some(path) => {
+            let (config, unknown_keys) = Settings::load(path)?;
+            (args.settings.merge(config), unknown_keys)
+        }
+        None => (args.settings, Vec::new()),
+    };
+    let options = to_options(&settings)?;
+    let quiet = options.verbosity == Verbosity::Quiet;
+    if !unknown_keys.is_empty() && !quiet {
+        eprintln!("Warning: ignoring unknown config keys: {}", unknown_keys.join(", "));
+    }
+    let log_dir = settings.log_dir();
     
     // Create logs directory if it doesn't exist
-    if !args.log_dir.exists() {
-        fs::create_dir_all(&args.log_dir)?;
+    if !log_dir.exists() {
+        fs::create_dir_all(&log_dir)?;
     }
     
     // Generate log filename with current datetime
     let datetime = Local::now().format("%Y%m%d_%H%M%S");
-    let log_file = args.log_dir.join(format!("payment_engine_{}.log", datetime));
+    let log_file = log_dir.join(format!("payment_engine_{}.log", datetime));
     
     // Initialize logging to a file
-    let file_appender = tracing_appender::rolling::never(&args.log_dir, log_file.file_name().unwrap_or_default());
+    let file_appender = tracing_appender::rolling::never(&log_dir, log_file.file_name().unwrap_or_default());
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     
     registry()
@@ -49,14 +395,55 @@ async fn main() -> Result<()> {
         )
         .with(EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into()))
         .init();
+
+    if !unknown_keys.is_empty() {
+        warn!("Ignoring unknown config keys: {}", unknown_keys.join(", "));
+    }
     
-    // Configure processing options
-    let options = ProcessingOptions {
-        batch_size: args.batch_size,
-    };
+    // Expose metrics for scraping while the run is in progress
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = settings.metrics_addr() {
+        payment_engine::metrics::install_prometheus_exporter(addr)?;
+    }
     
     // Process the transactions and output results
-    process_transactions_with_options(&args.input_file, options).await?;
+    #[cfg(feature = "remote")]
+    let report = match (args.parallel_files, input_file.to_str().filter(|input| payment_engine::remote::is_remote_url(input))) {
+        (Some(concurrency), _) => process_transaction_files_parallel(&input_files, options, concurrency.into()).await,
+        (None, Some(url)) => payment_engine::process_transactions_from_url(url, options).await,
+        (None, None) => process_transactions_with_options(&input_file, options).await,
+    };
+    #[cfg(not(feature = "remote"))]
+    let report = match args.parallel_files {
+        Some(concurrency) => process_transaction_files_parallel(&input_files, options, concurrency.into()).await,
+        None => process_transactions_with_options(&input_file, options).await,
+    };
+
+    // A quiet run only reports a failure in its exit status and the log
+    let report = match report {
+        Ok(report) => report,
+        Err(e) if quiet => {
+            error!("Processing failed: {}", e);
+            return Ok(ExitCode::FAILURE);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let errors = report.error_count();
+    if settings.max_errors.is_some_and(|max| errors > max) {
+        error!("{} lines were skipped or rejected, more than --max-errors allows", errors);
+        if !quiet {
+            eprintln!("Error: {} lines were skipped or rejected, more than --max-errors allows", errors);
+        }
+        return Ok(ExitCode::from(EXIT_TOO_MANY_ERRORS));
+    }
+    if let Some(mismatches) = report.reconciliation_mismatches.filter(|&mismatches| mismatches > 0) {
+        error!("{} clients differ from the expected balances", mismatches);
+        if !quiet {
+            eprintln!("Error: {} clients differ from the expected balances", mismatches);
+        }
+        return Ok(ExitCode::from(EXIT_RECONCILIATION_FAILED));
+    }
     
-    Ok(())
-}
\ No newline at end of file
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,599 @@
+use crate::clock::Clock;
+use crate::error::{PaymentEngineError, Result};
+use crate::history::TraceFormat;
+use crate::input::{InputEncoding, InputFormat, InputMode};
+use crate::models::{decimal_string, AmountPrecision, Currency, DisputeClientCheck, PurgePolicy, TransactionType, TxId, WithdrawalFee};
+use crate::processor::{
+    BatchSizeMode, ChangeStream, ClientFilter, ClosedAccountHandling, OutputMode, ProcessingOptions, ProcessingReport, StopAfter, Verbosity,
+};
+use crate::snapshot::SnapshotEvery;
+use chrono::SecondsFormat;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// Machine-readable description of a run, written as JSON to
+/// [`ProcessingOptions::manifest_path`] when the run ends, whether it
+/// succeeded or failed, for orchestration to pick up as a single artifact
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunManifest {
+    /// Version of the engine that made the run
+    pub version: String,
+    pub environment: ManifestEnvironment,
+    pub input: ManifestInput,
+    pub options: ManifestOptions,
+    /// Start and end of the run, RFC 3339 in UTC
+    pub started_at: String,
+    pub finished_at: String,
+    /// Whether the run completed; the counts are all zero if it didn't
+    pub succeeded: bool,
+    /// Why the run failed
+    pub error: Option<String>,
+    pub counts: ManifestCounts,
+    pub errors: ManifestErrors,
+    /// The accounts CSV written to stdout or the output file; `None` for
+    /// failed runs and partitioned, per-client or dry-run output
+    pub output: Option<ManifestOutput>,
+}
+
+/// Where the run happened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEnvironment {
+    pub os: String,
+    pub arch: String,
+    /// Command line of the process, program name first
+    pub args: Vec<String>,
+}
+
+/// The file or URL the transactions were read from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestInput {
+    pub path: String,
+    /// Size in bytes, if the input is a local file
+    pub size: Option<u64>,
+}
+
+/// The run's [`ProcessingOptions`], under their own names. Enums are written
+/// as their command-line values, currency codes and decimals as strings and
+/// durations in seconds; options that are code, such as the transaction
+/// hook, only show whether they were set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestOptions {
+    /// Transactions per batch, `None` with an automatic batch size
+    pub batch_size: Option<usize>,
+    pub auto_batch_size: Option<ManifestAutoBatchSize>,
+    pub pipeline_depth: usize,
+    pub max_tps: Option<u32>,
+    pub wal_path: Option<PathBuf>,
+    pub seen_tx_ids_path: Option<PathBuf>,
+    pub parallel_batches: bool,
+    pub group_by_client_in_batch: bool,
+    pub max_in_memory_transactions: Option<usize>,
+    pub spill_dir: Option<PathBuf>,
+    pub purge_finalized: String,
+    pub limits: ManifestLimits,
+    pub multi_currency: bool,
+    pub base_currency: Currency,
+    pub amount_precision: String,
+    pub delimiter: char,
+    pub decimal_separator: char,
+    pub lenient_amounts: bool,
+    pub thousands_separator: char,
+    pub allow_partial_disputes: bool,
+    pub allow_withdrawal_disputes: bool,
+    pub max_deferred_disputes: Option<usize>,
+    pub allow_admin_transactions: bool,
+    pub create_accounts_on_reference: bool,
+    pub dispute_client_check: String,
+    pub timestamps: bool,
+    pub dispute_expiry_secs: Option<u64>,
+    /// As `--withdrawal-fee` takes it, e.g. `0.5+1.5%`
+    pub withdrawal_fee: String,
+    pub fee_summary: bool,
+    pub timing_summary: bool,
+    pub verbosity: String,
+    pub extended_output: bool,
+    /// As `--columns` takes them, e.g. `client,total=balance`
+    pub output_columns: Option<String>,
+    pub output_partitions: Option<usize>,
+    pub output_dir: Option<PathBuf>,
+    /// Output file, `None` for stdout or per-client output
+    pub output_file: Option<PathBuf>,
+    /// Path template of per-client output
+    pub output_per_client: Option<String>,
+    pub compress_output: bool,
+    pub exclude_closed: bool,
+    pub closed_accounts_file: Option<PathBuf>,
+    pub emit_untouched_accounts: bool,
+    pub expected_transactions: Option<usize>,
+    pub expected_clients: Option<usize>,
+    pub input_mode: String,
+    pub encoding: String,
+    pub input_format: String,
+    pub follow: bool,
+    pub end_marker: String,
+    pub max_line_length: usize,
+    pub tolerate_repeated_headers: bool,
+    pub skip_records: usize,
+    pub max_records: Option<usize>,
+    pub stop_after_tx: Option<TxId>,
+    pub stop_after_line: Option<usize>,
+    /// As `--clients` takes it, e.g. `1,5,100-200`, or `predicate` for a function
+    pub client_filter: Option<String>,
+    pub sample_rate: Option<f64>,
+    pub sample_seed: Option<u64>,
+    pub skip_locked_fast_path: bool,
+    pub transaction_hook: bool,
+    pub dry_run: Option<String>,
+    /// A record count, or a duration such as `30s`
+    pub snapshot_every: Option<String>,
+    pub snapshot_path_template: String,
+    pub record_history: bool,
+    pub history_out: Option<PathBuf>,
+    pub history_combined: bool,
+    pub manifest_path: Option<PathBuf>,
+    pub initial_accounts: Option<PathBuf>,
+    pub expected_accounts: Option<PathBuf>,
+    #[serde(with = "decimal_string")]
+    pub reconcile_tolerance: Decimal,
+    pub event_sender: bool,
+    /// File the changes are streamed to, or `writer` or `channel`
+    pub change_stream: Option<String>,
+    /// Always `None` without the `http` feature
+    pub serve_addr: Option<SocketAddr>,
+    /// Always `None` without the `sqlite` feature
+    pub sqlite_path: Option<PathBuf>,
+}
+
+/// Bounds of an automatic batch size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestAutoBatchSize {
+    pub min: usize,
+    pub max: usize,
+    pub target_batch_ms: u64,
+}
+
+/// The run's risk limits, `None` where unset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestLimits {
+    #[serde(with = "decimal_string::option")]
+    pub max_deposit: Option<Decimal>,
+    #[serde(with = "decimal_string::option")]
+    pub max_withdrawal: Option<Decimal>,
+    pub max_withdrawals_per_client: Option<u32>,
+    pub max_open_disputes_per_client: Option<u32>,
+}
+
+impl From<&ProcessingOptions> for ManifestOptions {
+    fn from(options: &ProcessingOptions) -> Self {
+        let (batch_size, auto_batch_size) = match options.batch_size_mode {
+            BatchSizeMode::Fixed(size) => (Some(size), None),
+            BatchSizeMode::Auto { min, max, target_batch_ms } => (None, Some(ManifestAutoBatchSize { min, max, target_batch_ms })),
+        };
+        let (output_file, output_per_client) = match &options.output_mode {
+            OutputMode::Stdout => (None, None),
+            OutputMode::PerClientDir(template) => (None, Some(template.clone())),
+            OutputMode::File(path) => (Some(path.clone()), None),
+        };
+        let (exclude_closed, closed_accounts_file) = match &options.closed_account_handling {
+            ClosedAccountHandling::IncludeInOutput => (false, None),
+            ClosedAccountHandling::SeparateFile(path) => (false, Some(path.clone())),
+            ClosedAccountHandling::Exclude => (true, None),
+        };
+        let limits = options.limits;
+        Self {
+            batch_size,
+            auto_batch_size,
+            pipeline_depth: options.pipeline_depth,
+            max_tps: options.max_tps,
+            wal_path: options.wal_path.clone(),
+            seen_tx_ids_path: options.seen_tx_ids_path.clone(),
+            parallel_batches: options.parallel_batches,
+            group_by_client_in_batch: options.group_by_client_in_batch,
+            max_in_memory_transactions: options.max_in_memory_transactions,
+            spill_dir: options.spill_dir.clone(),
+            purge_finalized: match options.purge_finalized {
+                PurgePolicy::Never => "never",
+                PurgePolicy::AfterChargeback => "after-chargeback",
+                PurgePolicy::AfterResolveOrChargeback => "after-resolve-or-chargeback",
+            }
+            .to_string(),
+            limits: ManifestLimits {
+                max_deposit: limits.max_deposit,
+                max_withdrawal: limits.max_withdrawal,
+                max_withdrawals_per_client: limits.max_withdrawals_per_client,
+                max_open_disputes_per_client: limits.max_open_disputes_per_client,
+            },
+            multi_currency: options.multi_currency,
+            base_currency: options.base_currency,
+            amount_precision: match options.amount_precision {
+                AmountPrecision::Reject => "reject",
+                AmountPrecision::Truncate => "truncate",
+                AmountPrecision::RoundHalfEven => "round-half-even",
+            }
+            .to_string(),
+            delimiter: options.delimiter,
+            decimal_separator: options.decimal_separator,
+            lenient_amounts: options.lenient_amounts,
+            thousands_separator: options.thousands_separator,
+            allow_partial_disputes: options.allow_partial_disputes,
+            allow_withdrawal_disputes: options.allow_withdrawal_disputes,
+            max_deferred_disputes: options.max_deferred_disputes,
+            allow_admin_transactions: options.allow_admin_transactions,
+            create_accounts_on_reference: options.create_accounts_on_reference,
+            dispute_client_check: match options.dispute_client_check {
+                DisputeClientCheck::Strict => "strict",
+                DisputeClientCheck::TrustOriginal => "trust-original",
+            }
+            .to_string(),
+            timestamps: options.timestamps,
+            dispute_expiry_secs: options.dispute_expiry.map(|expiry| expiry.as_secs()),
+            withdrawal_fee: match options.withdrawal_fee {
+                WithdrawalFee::None => "none".to_string(),
+                WithdrawalFee::Flat(flat) => flat.to_string(),
+                WithdrawalFee::Percent(percent) => format!("{}%", percent),
+                WithdrawalFee::FlatPlusPercent(flat, percent) => format!("{}+{}%", flat, percent),
+            },
+            fee_summary: options.fee_summary,
+            timing_summary: options.timing_summary,
+            verbosity: match options.verbosity {
+                Verbosity::Quiet => "quiet",
+                Verbosity::Normal => "normal",
+                Verbosity::Verbose => "verbose",
+            }
+            .to_string(),
+            extended_output: options.extended_output,
+            output_columns: options.output_columns.as_ref().map(|columns| {
+                let specs: Vec<String> = columns
+                    .0
+                    .iter()
+                    .map(|(column, header)| match column.as_str() {
+                        name if name == header => name.to_string(),
+                        name => format!("{}={}", name, header),
+                    })
+                    .collect();
+                specs.join(",")
+            }),
+            output_partitions: options.output_partitions,
+            output_dir: options.output_dir.clone(),
+            output_file,
+            output_per_client,
+            compress_output: options.compress_output,
+            exclude_closed,
+            closed_accounts_file,
+            emit_untouched_accounts: options.emit_untouched_accounts,
+            expected_transactions: options.expected_transactions,
+            expected_clients: options.expected_clients,
+            input_mode: match options.input_mode {
+                InputMode::Streaming => "streaming",
+                InputMode::Mmap => "mmap",
+            }
+            .to_string(),
+            encoding: match options.encoding {
+                InputEncoding::Utf8Strict => "utf8-strict",
+                InputEncoding::Utf8Lossy => "utf8-lossy",
+                InputEncoding::Windows1252 => "windows-1252",
+            }
+            .to_string(),
+            input_format: match options.input_format {
+                InputFormat::Delimited => "delimited",
+                InputFormat::FixedWidth(_) => "fixed-width",
+            }
+            .to_string(),
+            follow: options.follow,
+            end_marker: options.end_marker.clone(),
+            max_line_length: options.max_line_length,
+            tolerate_repeated_headers: options.tolerate_repeated_headers,
+            skip_records: options.skip_records,
+            max_records: options.max_records,
+            stop_after_tx: match options.stop_after {
+                Some(StopAfter::Tx(tx)) => Some(tx),
+                _ => None,
+            },
+            stop_after_line: match options.stop_after {
+                Some(StopAfter::Line(line)) => Some(line),
+                _ => None,
+            },
+            client_filter: options.client_filter.as_ref().map(|filter| match filter {
+                ClientFilter::Set(clients) => clients.iter().map(u16::to_string).collect::<Vec<_>>().join(","),
+                ClientFilter::Range(range) => format!("{}-{}", range.start(), range.end()),
+                ClientFilter::Predicate(_) => "predicate".to_string(),
+            }),
+            sample_rate: options.sample.map(|sample| sample.rate),
+            sample_seed: options.sample.map(|sample| sample.seed),
+            skip_locked_fast_path: options.skip_locked_fast_path,
+            transaction_hook: options.transaction_hook.is_some(),
+            dry_run: options.dry_run.map(|format| {
+                match format {
+                    TraceFormat::Text => "text",
+                    TraceFormat::Csv => "csv",
+                }
+                .to_string()
+            }),
+            snapshot_every: options.snapshot_every.map(|every| match every {
+                SnapshotEvery::Records(records) => records.to_string(),
+                SnapshotEvery::Duration(duration) => format!("{}s", duration.as_secs()),
+            }),
+            snapshot_path_template: options.snapshot_path_template.clone(),
+            record_history: options.record_history,
+            history_out: options.history_out.clone(),
+            history_combined: options.history_combined,
+            manifest_path: options.manifest_path.clone(),
+            initial_accounts: options.initial_accounts.clone(),
+            expected_accounts: options.expected_accounts.clone(),
+            reconcile_tolerance: options.reconcile_tolerance,
+            event_sender: options.event_sender.is_some(),
+            change_stream: options.change_stream.as_ref().map(|stream| match stream {
+                ChangeStream::File(path) => path.display().to_string(),
+                ChangeStream::Writer(_) => "writer".to_string(),
+                ChangeStream::Channel(_) => "channel".to_string(),
+            }),
+            #[cfg(feature = "http")]
+            serve_addr: options.serve_addr,
+            #[cfg(not(feature = "http"))]
+            serve_addr: None,
+            #[cfg(feature = "sqlite")]
+            sqlite_path: options.sqlite_path.clone(),
+            #[cfg(not(feature = "sqlite"))]
+            sqlite_path: None,
+        }
+    }
+}
+
+/// What happened to the input lines, from the run's [`ProcessingReport`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestCounts {
+    pub lines_read: usize,
+    pub transactions_processed: usize,
+    /// Transactions handed to the engine, per type
+    pub transactions_by_type: BTreeMap<TransactionType, usize>,
+    pub records_skipped: usize,
+    pub records_filtered: usize,
+    pub duplicates: usize,
+    pub chargebacks: usize,
+    pub withdrawal_disputes_rejected: usize,
+    /// Highest tx id seen, for allocating new ids above it; see
+    /// [`ProcessingReport::max_tx_id`]
+    pub max_tx_id: Option<TxId>,
+}
+
+/// Lines skipped or rejected, from the run's [`ProcessingReport`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestErrors {
+    pub parse_errors: usize,
+    pub read_errors: usize,
+    pub oversized_lines: usize,
+    pub transactions_rejected: usize,
+    /// All of the above, as in [`ProcessingReport::error_count`]
+    pub total: usize,
+}
+
+/// The accounts CSV a run wrote
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestOutput {
+    /// Output file, or `None` for stdout
+    pub path: Option<PathBuf>,
+    #[serde(flatten)]
+    pub digest: OutputDigest,
+}
+
+/// Account rows and content hash of an accounts CSV
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputDigest {
+    /// Rows after the header
+    pub rows: usize,
+    /// Hex SHA-256 of the CSV as written, before any compression
+    pub sha256: String,
+}
+
+/// Writer hashing and counting the lines of everything written through it
+pub(crate) struct DigestWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    lines: usize,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, hasher: Sha256::new(), lines: 0 }
+    }
+
+    /// The digest of the CSV written so far, header included
+    pub(crate) fn finish(self) -> OutputDigest {
+        let sha256 = self.hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        OutputDigest { rows: self.lines.saturating_sub(1), sha256 }
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.lines += buf[..written].iter().filter(|&&byte| byte == b'\n').count();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl RunManifest {
+    /// Manifest of a run of `input` starting now, before its outcome is known
+    fn start(input: &str, size: Option<u64>, options: &ProcessingOptions) -> Self {
+        let now = timestamp(&*options.clock);
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            environment: ManifestEnvironment {
+                os: std::env::consts::OS.to_string(),
+                arch: std::env::consts::ARCH.to_string(),
+                args: std::env::args().collect(),
+            },
+            input: ManifestInput { path: input.to_string(), size },
+            options: ManifestOptions::from(options),
+            started_at: now.clone(),
+            finished_at: now,
+            succeeded: false,
+            error: None,
+            counts: ManifestCounts::default(),
+            errors: ManifestErrors::default(),
+            output: None,
+        }
+    }
+
+    /// Fill in the outcome of the run, writing to `output_mode`
+    fn finish(mut self, result: &Result<ProcessingReport>, output_mode: &OutputMode, clock: &dyn Clock) -> Self {
+        self.finished_at = timestamp(clock);
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return self;
+            }
+        };
+        self.succeeded = true;
+        self.counts = ManifestCounts {
+            lines_read: report.lines_read,
+            transactions_processed: report.transactions_processed,
+            transactions_by_type: report.transactions_by_type.clone(),
+            records_skipped: report.records_skipped,
+            records_filtered: report.records_filtered,
+            duplicates: report.duplicates,
+            chargebacks: report.chargebacks,
+            withdrawal_disputes_rejected: report.withdrawal_disputes_rejected,
+            max_tx_id: report.max_tx_id,
+        };
+        self.errors = ManifestErrors {
+            parse_errors: report.parse_errors,
+            read_errors: report.read_errors,
+            oversized_lines: report.oversized_lines,
+            transactions_rejected: report.transactions_rejected,
+            total: report.error_count(),
+        };
+        self.output = report.output_digest.clone().map(|digest| ManifestOutput {
+            path: match output_mode {
+                OutputMode::File(path) => Some(path.clone()),
+                _ => None,
+            },
+            digest,
+        });
+        self
+    }
+
+    /// Write the manifest as pretty-printed JSON, through a temporary file
+    /// renamed into place so a reader never sees a partial manifest
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        std::fs::write(&tmp_path, json).map_err(|source| PaymentEngineError::OpenFile { path: tmp_path.clone(), source })?;
+        std::fs::rename(&tmp_path, path)?;
+        info!("Wrote run manifest to {:?}", path);
+        Ok(())
+    }
+
+    /// Read a manifest written by [`write`](Self::write)
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = std::fs::read(path).map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+fn timestamp(clock: &dyn Clock) -> String {
+    clock.now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Await `run`, a run of `input`, and write its manifest if
+/// `options.manifest_path` is set. A manifest that can't be written fails a
+/// successful run; a failed run keeps its own error.
+pub(crate) async fn with_manifest(
+    input: &str,
+    size: Option<u64>,
+    options: &ProcessingOptions,
+    run: impl Future<Output = Result<ProcessingReport>>,
+) -> Result<ProcessingReport> {
+    let Some(path) = options.manifest_path.clone() else {
+        return run.await;
+    };
+    let manifest = RunManifest::start(input, size, options);
+    let (output_mode, clock) = (options.output_mode.clone(), options.clock.clone());
+    let result = run.await;
+    let written = manifest.finish(&result, &output_mode, &*clock).write(&path);
+    match (result, written) {
+        (Ok(report), written) => written.map(|_| report),
+        (Err(e), Err(write_error)) => {
+            error!("Failed to write the manifest of the failed run: {}", write_error);
+            Err(e)
+        }
+        (Err(e), Ok(())) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Limits;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    #[test]
+    fn test_options_written_as_json_object() {
+        let options = ProcessingOptions::builder()
+            .batch_size(500)
+            .multi_currency(true)
+            .base_currency("eur".parse().unwrap())
+            .limits(Limits { max_deposit: Some(dec!(1000.50)), max_withdrawals_per_client: Some(3), ..Default::default() })
+            .withdrawal_fee("0.25+1.5%".parse().unwrap())
+            .purge_finalized(PurgePolicy::AfterChargeback)
+            .timestamps(true)
+            .dispute_expiry(Duration::from_secs(86_400))
+            .client_filter(ClientFilter::from(10..=20))
+            .transaction_hook(Some)
+            .build()
+            .unwrap();
+        let manifest = RunManifest::start("input.csv", None, &options);
+        let json = serde_json::to_string(&manifest).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let written = &value["options"];
+        assert_eq!(written["batch_size"], 500);
+        assert_eq!(written["auto_batch_size"], serde_json::Value::Null);
+        assert_eq!(written["base_currency"], "EUR");
+        assert_eq!(written["limits"]["max_deposit"], "1000.50");
+        assert_eq!(written["limits"]["max_withdrawal"], serde_json::Value::Null);
+        assert_eq!(written["limits"]["max_withdrawals_per_client"], 3);
+        assert_eq!(written["withdrawal_fee"], "0.25+1.5%");
+        assert_eq!(written["reconcile_tolerance"], "0");
+        assert_eq!(written["purge_finalized"], "after-chargeback");
+        assert_eq!(written["dispute_expiry_secs"], 86_400);
+        assert_eq!(written["client_filter"], "10-20");
+        assert_eq!(written["transaction_hook"], true);
+
+        // It reads back as it was written, and the fee as `--withdrawal-fee` takes it
+        let parsed: RunManifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.options.withdrawal_fee.parse::<WithdrawalFee>(), Ok(options.withdrawal_fee));
+    }
+
+    #[test]
+    fn test_digest_writer_counts_rows() {
+        let mut writer = DigestWriter::new(Vec::new());
+        writer.write_all(b"client,available\n1,2.0\n").unwrap();
+        writer.write_all(b"2,3.0\n").unwrap();
+        let DigestWriter { inner, .. } = &writer;
+        let expected: String = Sha256::digest(inner).iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(writer.finish(), OutputDigest { rows: 2, sha256: expected });
+        assert_eq!(
+            DigestWriter::new(io::sink()).finish().sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Operational metrics for the payment engine.
+//!
+//! All functions compile to no-ops unless the `metrics` feature is enabled, so
+//! the engine can call them unconditionally at the points where it logs.
+
+use crate::models::TransactionType;
+use std::time::Duration;
+
+/// Counter of transactions handed to the engine, labelled by type
+pub const TRANSACTIONS_PROCESSED: &str = "payment_engine_transactions_processed_total";
+/// Counter of rejected transactions, labelled by reason
+pub const TRANSACTIONS_REJECTED: &str = "payment_engine_transactions_rejected_total";
+/// Gauge of disputes that have not yet been resolved or charged back
+pub const OPEN_DISPUTES: &str = "payment_engine_open_disputes";
+/// Gauge of accounts locked by a chargeback
+pub const LOCKED_ACCOUNTS: &str = "payment_engine_locked_accounts";
+/// Histogram of batch processing latency in seconds
+pub const BATCH_LATENCY: &str = "payment_engine_batch_latency_seconds";
+
+/// Record that a transaction of the given type was handed to the engine
+pub fn transaction_processed(transaction_type: TransactionType) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(TRANSACTIONS_PROCESSED, "type" => type_label(transaction_type)).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = transaction_type;
+}
+
+/// Record that a transaction was rejected for the given reason
+pub fn transaction_rejected(reason: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(TRANSACTIONS_REJECTED, "reason" => reason).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = reason;
+}
+
+/// Record that a dispute was opened
+pub fn dispute_opened() {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(OPEN_DISPUTES).increment(1.0);
+}
+
+/// Record that a dispute was closed by a resolve or chargeback
+pub fn dispute_closed() {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(OPEN_DISPUTES).decrement(1.0);
+}
+
+/// Record that an account was locked
+pub fn account_locked() {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(LOCKED_ACCOUNTS).increment(1.0);
+}
+
+/// Record that an engine's open disputes went from `before` to `after` other
+/// than through the calls above, e.g. when a batch was rolled back
+pub fn open_disputes_recounted(before: usize, after: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(OPEN_DISPUTES).increment(after as f64 - before as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (before, after);
+}
+
+/// Record that an engine's locked accounts went from `before` to `after`
+/// other than through [`account_locked`], e.g. when a batch was rolled back
+pub fn locked_accounts_recounted(before: usize, after: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(LOCKED_ACCOUNTS).increment(after as f64 - before as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (before, after);
+}
+
+/// Record how long a batch took to process
+pub fn batch_processed(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(BATCH_LATENCY).record(latency.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = latency;
+}
+
+#[cfg(feature = "metrics")]
+fn type_label(transaction_type: TransactionType) -> &'static str {
+    match transaction_type {
+        TransactionType::Deposit => "deposit",
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::Dispute => "dispute",
+        TransactionType::Resolve => "resolve",
+        TransactionType::Chargeback => "chargeback",
+        TransactionType::Amend => "amend",
+    }
+}
+
+/// Install a Prometheus recorder serving `/metrics` on the given address.
+/// The endpoint has no authentication, so bind to localhost unless the
+/// metrics are meant to be scraped from elsewhere.
+#[cfg(feature = "metrics")]
+pub fn install_prometheus_exporter(addr: std::net::SocketAddr) -> crate::error::Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::models::Transaction;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+    use rust_decimal_macros::dec;
+
+    type SnapshotEntry = (metrics_util::CompositeKey, Option<metrics::Unit>, Option<metrics::SharedString>, DebugValue);
+
+    fn counter_value<'a>(
+        snapshot: &'a [SnapshotEntry],
+        kind: MetricKind,
+        name: &str,
+        label: (&str, &str),
+    ) -> Option<&'a DebugValue> {
+        snapshot.iter().find_map(|(key, _, _, value)| {
+            let matches = key.kind() == kind
+                && key.key().name() == name
+                && key.key().labels().any(|l| l.key() == label.0 && l.value() == label.1);
+            matches.then_some(value)
+        })
+    }
+
+    #[test]
+    fn test_metrics_after_fixture_run() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            futures::executor::block_on(async {
+                let mut engine = PaymentEngine::new();
+                let batch = vec![
+                    Transaction::deposit(1, 1, dec!(10)),
+                    Transaction::deposit(2, 2, dec!(5)),
+                    Transaction::withdrawal(1, 3, dec!(50)),
+                    Transaction::dispute(1, 1),
+                    Transaction::dispute(2, 2),
+                    Transaction::chargeback(2, 2),
+                    Transaction::dispute(1, 99),
+                ];
+                engine.process_transaction_batch(batch).await;
+            });
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+
+        assert_eq!(
+            counter_value(&snapshot, MetricKind::Counter, TRANSACTIONS_PROCESSED, ("type", "deposit")),
+            Some(&DebugValue::Counter(2))
+        );
+        assert_eq!(
+            counter_value(&snapshot, MetricKind::Counter, TRANSACTIONS_PROCESSED, ("type", "dispute")),
+            Some(&DebugValue::Counter(3))
+        );
+        assert_eq!(
+            counter_value(&snapshot, MetricKind::Counter, TRANSACTIONS_REJECTED, ("reason", "insufficient_funds")),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            counter_value(&snapshot, MetricKind::Counter, TRANSACTIONS_REJECTED, ("reason", "transaction_not_found")),
+            Some(&DebugValue::Counter(1))
+        );
+
+        let gauge = |name: &str| {
+            snapshot.iter().find_map(|(key, _, _, value)| (key.key().name() == name).then_some(value))
+        };
+        assert_eq!(gauge(OPEN_DISPUTES), Some(&DebugValue::Gauge(1.0.into())));
+        assert_eq!(gauge(LOCKED_ACCOUNTS), Some(&DebugValue::Gauge(1.0.into())));
+        assert!(matches!(gauge(BATCH_LATENCY), Some(DebugValue::Histogram(h)) if h.len() == 1));
+    }
+
+    #[test]
+    fn test_gauges_follow_rollback_and_reset() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let gauges = || {
+            let snapshot = snapshotter.snapshot().into_vec();
+            let gauge = |name: &str| {
+                snapshot.iter().find_map(|(key, _, _, value)| match value {
+                    DebugValue::Gauge(value) if key.key().name() == name => Some(value.into_inner()),
+                    _ => None,
+                })
+            };
+            (gauge(OPEN_DISPUTES), gauge(LOCKED_ACCOUNTS))
+        };
+
+        metrics::with_local_recorder(&recorder, || {
+            let mut engine = PaymentEngine::new();
+            for transaction in [
+                Transaction::deposit(1, 1, dec!(10)),
+                Transaction::deposit(2, 2, dec!(5)),
+                Transaction::dispute(2, 2),
+                Transaction::chargeback(2, 2),
+            ] {
+                engine.apply(transaction).unwrap();
+            }
+            assert_eq!(gauges(), (Some(0.0), Some(1.0)));
+
+            engine.begin_journal();
+            engine.apply(Transaction::dispute(1, 1)).unwrap();
+            assert_eq!(gauges(), (Some(1.0), Some(1.0)));
+            engine.roll_back_journal().unwrap();
+            assert_eq!(gauges(), (Some(0.0), Some(1.0)));
+
+            engine.apply(Transaction::dispute(1, 1)).unwrap();
+            engine.reset();
+            assert_eq!(gauges(), (Some(0.0), Some(0.0)));
+        });
+    }
+}
@@ -1,10 +1,149 @@
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::error::PaymentEngineError;
+use crate::processor::{parse_transaction_fields, LineFormat};
+use crate::spill::SpillStore;
+use rustc_hash::FxBuildHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Serde helpers that write decimals as strings, so JSON consumers that parse
+/// numbers as doubles can't lose precision, and read them from either strings
+/// or numbers. Use with `#[serde(with = "decimal_string")]`, or the `option`
+/// and `map` submodules for `Option<Decimal>` and maps of decimals.
+///
+/// A JSON number arrives as a double, so it is read through the shortest
+/// decimal form that round-trips: `0.1` is read as exactly `0.1`, but
+/// numbers with more than about 15 significant digits may already have been
+/// rounded. Strings are always read exactly.
+pub mod decimal_string {
+    use rust_decimal::Decimal;
+    use serde::de::{self, Unexpected, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+
+    /// A decimal going through the helpers above, for use inside other types
+    struct DecimalString(Decimal);
+
+    impl Serialize for DecimalString {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(&self.0, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for DecimalString {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer).map(DecimalString)
+        }
+    }
+
+    struct DecimalVisitor;
+
+    impl Visitor<'_> for DecimalVisitor {
+        type Value = Decimal;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a decimal number or a string holding one")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+            Decimal::from_str(value)
+                .or_else(|_| Decimal::from_scientific(value))
+                .map_err(|_| E::invalid_value(Unexpected::Str(value), &self))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+            Ok(Decimal::from(value))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+            Ok(Decimal::from(value))
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+            // A double's `Display` is its shortest round-tripping form, never
+            // in exponent notation
+            Decimal::from_str(&value.to_string()).map_err(|_| E::invalid_value(Unexpected::Float(value), &self))
+        }
+    }
+
+    /// For `Option<Decimal>`, with `None` as null or an empty CSV field
+    pub mod option {
+        use super::DecimalString;
+        use rust_decimal::Decimal;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(value: &Option<Decimal>, serializer: S) -> Result<S::Ok, S::Error> {
+            value.map(DecimalString).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+            Ok(Option::<DecimalString>::deserialize(deserializer)?.map(|value| value.0))
+        }
+    }
+
+    /// For maps of decimals, such as amounts per client
+    pub mod map {
+        use super::*;
+
+        pub fn serialize<K, S>(map: &BTreeMap<K, Decimal>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            K: Serialize,
+            S: Serializer,
+        {
+            serializer.collect_map(map.iter().map(|(key, value)| (key, DecimalString(*value))))
+        }
+
+        pub fn deserialize<'de, K, D>(deserializer: D) -> Result<BTreeMap<K, Decimal>, D::Error>
+        where
+            K: Deserialize<'de> + Ord,
+            D: Deserializer<'de>,
+        {
+            let map = BTreeMap::<K, DecimalString>::deserialize(deserializer)?;
+            Ok(map.into_iter().map(|(key, value)| (key, value.0)).collect())
+        }
+    }
+}
+
+/// Transaction id, `u32` as in the specification, or `u64` with the
+/// `wide-tx-ids` feature for inputs whose ids don't fit
+#[cfg(not(feature = "wide-tx-ids"))]
+pub type TxId = u32;
+/// Transaction id, `u32` as in the specification, or `u64` with the
+/// `wide-tx-ids` feature for inputs whose ids don't fit
+#[cfg(feature = "wide-tx-ids")]
+pub type TxId = u64;
+
+/// Largest transaction id of the active width, for error messages
+#[allow(clippy::unnecessary_cast)]
+pub(crate) const MAX_TX_ID: u64 = TxId::MAX as u64;
+
+/// Map keyed by client or transaction id. The keys are small integers from
+/// the input file, so a trivial hasher is much faster than SipHash and
+/// the lack of DoS resistance doesn't matter.
+pub(crate) type IdMap<K, V> = HashMap<K, V, FxBuildHasher>;
+
+/// Set of client or transaction ids, see [`IdMap`]
+pub(crate) type IdSet<K> = HashSet<K, FxBuildHasher>;
 
 /// Transaction types as defined in the specification
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -12,30 +151,463 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Corrects the amount of an earlier, undisputed deposit. An admin
+    /// transaction outside the specification, only applied when allowed.
+    Amend,
+}
+
+/// Lowercase, as in the input's type column
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Amend => "amend",
+        })
+    }
 }
 
 /// Transaction record from the CSV input
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
     pub client: u16,
-    pub tx: u32,
-    #[serde(default)]
+    pub tx: TxId,
+    #[serde(default, with = "decimal_string::option")]
     pub amount: Option<Decimal>,
+    /// Currency of the amount; `None` is the engine's base currency. Only
+    /// read from the input in multi-currency mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// When the transaction happened, in seconds since the Unix epoch. Only
+    /// read from the input with timestamps enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+/// One-line constructors of well-formed transactions in the base currency:
+/// deposits, withdrawals and amendments carry a positive amount, disputes,
+/// resolves and chargebacks none. Malformed transactions, as the engine must reject them,
+/// can still be written as struct literals.
+impl Transaction {
+    pub fn deposit(client: u16, tx: TxId, amount: Decimal) -> Self {
+        Self::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    pub fn withdrawal(client: u16, tx: TxId, amount: Decimal) -> Self {
+        Self::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    pub fn dispute(client: u16, tx: TxId) -> Self {
+        Self::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    pub fn resolve(client: u16, tx: TxId) -> Self {
+        Self::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    pub fn chargeback(client: u16, tx: TxId) -> Self {
+        Self::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    pub fn amend(client: u16, tx: TxId, amount: Decimal) -> Self {
+        Self::new(TransactionType::Amend, client, tx, Some(amount))
+    }
+
+    /// The same transaction in `currency` rather than the base currency
+    pub fn in_currency(self, currency: Currency) -> Self {
+        Self { currency: Some(currency), ..self }
+    }
+
+    /// The same transaction stamped with the time it happened
+    pub fn at(self, timestamp: u64) -> Self {
+        Self { timestamp: Some(timestamp), ..self }
+    }
+
+    fn new(transaction_type: TransactionType, client: u16, tx: TxId, amount: Option<Decimal>) -> Self {
+        match transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Amend => {
+                debug_assert!(amount.is_some_and(|amount| amount > Decimal::ZERO), "{} amount must be positive", transaction_type)
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                debug_assert!(amount.is_none(), "{} takes no amount", transaction_type)
+            }
+        }
+        Self { transaction_type, client, tx, amount, currency: None, timestamp: None }
+    }
+}
+
+impl Transaction {
+    /// Parse one `type,client,tx[,amount]` line of CSV input, its fields
+    /// separated by `delimiter`, with the parser a run uses, so a producer can
+    /// check lines before sending them. It is stricter than a run's defaults:
+    /// an amount with more than 4 decimal places is an error rather than
+    /// rounded, and deposits, withdrawals and amendments need a positive
+    /// amount rather than leaving the engine to reject a negative one.
+    /// Amounts are plain numbers without thousands separators, and there is no
+    /// currency or timestamp column. A header line is an invalid type.
+    pub fn from_csv_line(line: &str, delimiter: char) -> Result<Self, PaymentEngineError> {
+        let transaction = parse_transaction_fields(line, LineFormat::strict(delimiter))?;
+        match transaction.amount {
+            Some(amount) if amount <= Decimal::ZERO => Err(PaymentEngineError::NonPositiveAmount(amount)),
+            _ => Ok(transaction),
+        }
+    }
+}
+
+/// Three-letter currency code such as `USD`, stored uppercase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters are ever stored
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
 }
 
-/// Account state for a client
-#[derive(Debug, Default, Clone, Serialize)]
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                Ok(Self([a.to_ascii_uppercase(), b.to_ascii_uppercase(), c.to_ascii_uppercase()]))
+            }
+            _ => Err(format!("Invalid currency: {} (expected a three-letter code)", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.as_str().to_string()
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the engine did with a transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionOutcome {
+    /// The transaction was applied
+    Applied,
+    /// The transaction was rejected; the reason matches the rejection metric label
+    Rejected(&'static str),
+    /// The transaction was rejected because it would exceed a configured limit
+    LimitExceeded(Limit),
+}
+
+impl TransactionOutcome {
+    /// Why the transaction was rejected, or `None` if it was applied
+    pub fn rejection_reason(&self) -> Option<&'static str> {
+        match self {
+            TransactionOutcome::Applied => None,
+            TransactionOutcome::Rejected(reason) => Some(reason),
+            TransactionOutcome::LimitExceeded(limit) => Some(limit.as_str()),
+        }
+    }
+}
+
+/// A configured limit a transaction can exceed, see [`Limits`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    MaxDeposit,
+    MaxWithdrawal,
+    MaxWithdrawalsPerClient,
+    MaxOpenDisputesPerClient,
+}
+
+impl Limit {
+    /// Rejection reason, as used for metrics and the audit trail
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Limit::MaxDeposit => "max_deposit_exceeded",
+            Limit::MaxWithdrawal => "max_withdrawal_exceeded",
+            Limit::MaxWithdrawalsPerClient => "max_withdrawals_per_client_exceeded",
+            Limit::MaxOpenDisputesPerClient => "max_open_disputes_per_client_exceeded",
+        }
+    }
+}
+
+/// Risk limits checked before deposits, withdrawals and disputes are applied.
+/// Every limit is optional and unset by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Largest amount a single deposit may have
+    pub max_deposit: Option<Decimal>,
+    /// Largest amount a single withdrawal may have
+    pub max_withdrawal: Option<Decimal>,
+    /// Most withdrawals applied per client in one run
+    pub max_withdrawals_per_client: Option<u32>,
+    /// Most transactions of a client under dispute at the same time
+    pub max_open_disputes_per_client: Option<u32>,
+}
+
+/// Account state for a client.
+///
+/// Deserializing checks that `total == available + held`, in the base
+/// currency and every other one, and rejects the account otherwise.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedAccount")]
 pub struct Account {
     pub client: u16,
+    #[serde(with = "decimal_string")]
     pub available: Decimal,
+    #[serde(with = "decimal_string")]
     pub held: Decimal,
+    #[serde(with = "decimal_string")]
     pub total: Decimal,
     pub locked: bool,
+    /// Balances in currencies other than the base currency, whose balance is
+    /// `available`, `held` and `total`. Only used in multi-currency mode.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub other_currencies: BTreeMap<Currency, Balance>,
+    /// What locked the account, if it is locked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<LockReason>,
+    /// Funds removed from the base balance by chargebacks of deposits
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub chargeback_total: Decimal,
+    /// Sum of the deposits applied to the base balance. Chargebacks don't
+    /// reduce it; they are counted in `chargeback_total` instead.
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub deposited_total: Decimal,
+    /// Sum of the withdrawals applied to the base balance, without fees.
+    /// Chargebacks of withdrawals don't reduce it.
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub withdrawn_total: Decimal,
+}
+
+/// An account as read, before its balances are checked
+#[derive(Deserialize)]
+struct UncheckedAccount {
+    client: u16,
+    #[serde(with = "decimal_string")]
+    available: Decimal,
+    #[serde(with = "decimal_string")]
+    held: Decimal,
+    #[serde(with = "decimal_string")]
+    total: Decimal,
+    locked: bool,
+    #[serde(default)]
+    other_currencies: BTreeMap<Currency, Balance>,
+    #[serde(default)]
+    lock_reason: Option<LockReason>,
+    #[serde(default, with = "decimal_string")]
+    chargeback_total: Decimal,
+    #[serde(default, with = "decimal_string")]
+    deposited_total: Decimal,
+    #[serde(default, with = "decimal_string")]
+    withdrawn_total: Decimal,
+}
+
+impl TryFrom<UncheckedAccount> for Account {
+    type Error = String;
+
+    fn try_from(unchecked: UncheckedAccount) -> Result<Self, Self::Error> {
+        let UncheckedAccount {
+            client,
+            available,
+            held,
+            total,
+            locked,
+            other_currencies,
+            lock_reason,
+            chargeback_total,
+            deposited_total,
+            withdrawn_total,
+        } = unchecked;
+        let account = Account {
+            client,
+            available,
+            held,
+            total,
+            locked,
+            other_currencies,
+            lock_reason,
+            chargeback_total,
+            deposited_total,
+            withdrawn_total,
+        };
+        account.check_balances()?;
+        Ok(account)
+    }
+}
+
+/// Dispute activity of one client
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountStats {
+    /// Disputes applied; rejected disputes are not counted
+    pub disputes_opened: u32,
+    pub disputes_resolved: u32,
+    pub disputes_charged_back: u32,
+    /// Disputes resolved by the engine for being left open too long
+    pub disputes_expired: u32,
+    /// Transactions currently under dispute, whose funds are held
+    pub held_transactions: BTreeSet<TxId>,
+    /// Disputes waiting, oldest first, for the available funds to hold them;
+    /// see [`PaymentEngine::set_max_deferred_disputes`](crate::PaymentEngine::set_max_deferred_disputes)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deferred_disputes: Vec<DeferredDispute>,
+}
+
+/// A dispute accepted while the client's available funds couldn't cover it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeferredDispute {
+    pub tx: TxId,
+    /// Amount to hold once the funds are there
+    #[serde(with = "decimal_string")]
+    pub amount: Decimal,
+}
+
+/// Why an account was locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LockReason {
+    /// Charged back by the transaction with this id
+    Chargeback(TxId),
+}
+
+impl FromStr for LockReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("chargeback", tx)) => tx
+                .parse()
+                .map(LockReason::Chargeback)
+                .map_err(|_| format!("Invalid lock reason: {} (expected chargeback:<tx>)", s)),
+            _ => Err(format!("Invalid lock reason: {} (expected chargeback:<tx>)", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for LockReason {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<LockReason> for String {
+    fn from(reason: LockReason) -> Self {
+        reason.to_string()
+    }
+}
+
+impl std::fmt::Display for LockReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockReason::Chargeback(tx) => write!(f, "chargeback:{}", tx),
+        }
+    }
+}
+
+/// An account's balances after a batch that changed them, as streamed to
+/// [`ProcessingOptions::change_stream`](crate::ProcessingOptions::change_stream)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountDelta {
+    /// Sequence number of the batch within the run, starting at 1
+    pub batch: u64,
+    pub client: u16,
+    #[serde(with = "decimal_string")]
+    pub available: Decimal,
+    #[serde(with = "decimal_string")]
+    pub held: Decimal,
+    #[serde(with = "decimal_string")]
+    pub total: Decimal,
+    pub locked: bool,
+    /// Balances in other currencies, only in multi-currency mode
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub other_currencies: BTreeMap<Currency, Balance>,
+}
+
+impl AccountDelta {
+    pub fn new(batch: u64, account: &Account) -> Self {
+        Self {
+            batch,
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            other_currencies: account.other_currencies.clone(),
+        }
+    }
+}
+
+/// Funds an account holds in one currency
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    #[serde(with = "decimal_string")]
+    pub available: Decimal,
+    #[serde(with = "decimal_string")]
+    pub held: Decimal,
+    #[serde(with = "decimal_string")]
+    pub total: Decimal,
+    /// Funds removed from this balance by chargebacks of deposits
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub chargeback_total: Decimal,
+    /// Sum of the deposits applied to this balance
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub deposited_total: Decimal,
+    /// Sum of the withdrawals applied to this balance, without fees
+    #[serde(default, with = "decimal_string", skip_serializing_if = "Decimal::is_zero")]
+    pub withdrawn_total: Decimal,
 }
 
 impl Account {
+    /// Create an account with the given balances, e.g. to load state kept
+    /// elsewhere. The total is computed; negative balances are rejected.
+    pub fn with_balances(client: u16, available: Decimal, held: Decimal, locked: bool) -> Result<Self, PaymentEngineError> {
+        if available < Decimal::ZERO || held < Decimal::ZERO {
+            return Err(PaymentEngineError::InvalidAccount(
+                client,
+                format!("negative balance: available {}, held {}", available, held),
+            ));
+        }
+        Ok(Self { client, available, held, total: available + held, locked, ..Self::default() })
+    }
+
+    /// Check that `total == available + held` in every currency
+    pub fn check_balances(&self) -> Result<(), String> {
+        let balances = std::iter::once((None, self.available, self.held, self.total)).chain(
+            self.other_currencies.iter().map(|(currency, b)| (Some(currency), b.available, b.held, b.total)),
+        );
+        for (currency, available, held, total) in balances {
+            if total != available + held {
+                let currency = currency.map_or_else(String::new, |currency| format!(" in {}", currency));
+                return Err(format!("total {} is not available {} + held {}{}", total, available, held, currency));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the account is closed: locked with nothing left in it, in
+    /// any currency, as after the chargeback of its last funds
+    pub fn is_closed(&self) -> bool {
+        self.locked && self.total.is_zero() && self.other_currencies.values().all(|balance| balance.total.is_zero())
+    }
+
     pub fn new(client_id: u16) -> Self {
         Self {
             client: client_id,
@@ -43,27 +615,100 @@ impl Account {
             held: dec!(0),
             total: dec!(0),
             locked: false,
+            other_currencies: BTreeMap::new(),
+            lock_reason: None,
+            chargeback_total: dec!(0),
+            deposited_total: dec!(0),
+            withdrawn_total: dec!(0),
+        }
+    }
+
+    /// Run `f` against the balance in `currency`, or the base balance for `None`.
+    ///
+    /// The balance is swapped into `available`, `held` and `total` for the
+    /// duration of the call, so the single-currency methods below apply to
+    /// every currency and a chargeback in any currency locks the whole account.
+    pub fn with_currency<R>(&mut self, currency: Option<Currency>, f: impl FnOnce(&mut Account) -> R) -> R {
+        let Some(currency) = currency else {
+            return f(self);
+        };
+        let existing = self.other_currencies.remove(&currency);
+        let mut balance = existing.unwrap_or_default();
+        self.swap_balance(&mut balance);
+        let result = f(self);
+        self.swap_balance(&mut balance);
+        // A rejected transaction in a new currency leaves no empty balance behind
+        if existing.is_some() || balance != Balance::default() {
+            self.other_currencies.insert(currency, balance);
         }
+        result
+    }
+
+    fn swap_balance(&mut self, balance: &mut Balance) {
+        std::mem::swap(&mut self.available, &mut balance.available);
+        std::mem::swap(&mut self.held, &mut balance.held);
+        std::mem::swap(&mut self.total, &mut balance.total);
+        std::mem::swap(&mut self.chargeback_total, &mut balance.chargeback_total);
+        std::mem::swap(&mut self.deposited_total, &mut balance.deposited_total);
+        std::mem::swap(&mut self.withdrawn_total, &mut balance.withdrawn_total);
     }
 
+    // The methods below are balance math only and ignore `locked`: which
+    // transactions a locked account still accepts is decided by the engine.
+
     /// Check if account has sufficient funds for a withdrawal
     pub fn has_sufficient_funds(&self, amount: Decimal) -> bool {
-        !self.locked && self.available >= amount
+        self.available >= amount
     }
 
-    /// Deposit funds into the account
+    /// Deposit funds into the account, adding them to `deposited_total`,
+    /// unless its total would overflow
     pub fn deposit(&mut self, amount: Decimal) -> bool {
-        if self.locked {
+        if !self.credit(amount) {
             return false;
         }
-        
-        self.available += amount;
-        self.total += amount;
+        self.deposited_total = self.deposited_total.saturating_add(amount);
         true
     }
 
-    /// Withdraw funds from the account
+    /// Withdraw funds from the account, adding them to `withdrawn_total`
     pub fn withdraw(&mut self, amount: Decimal) -> bool {
+        if !self.debit(amount) {
+            return false;
+        }
+        self.withdrawn_total = self.withdrawn_total.saturating_add(amount);
+        true
+    }
+
+    /// Correct an earlier deposit by `delta`, negative for a smaller amount,
+    /// in the balance and in `deposited_total`. Fails if the available funds
+    /// don't cover a decrease or the total would overflow.
+    pub fn amend_deposit(&mut self, delta: Decimal) -> bool {
+        let applied = if delta.is_sign_negative() { self.debit(-delta) } else { self.credit(delta) };
+        if applied {
+            self.deposited_total = self.deposited_total.saturating_add(delta);
+        }
+        applied
+    }
+
+    /// Take a fee from the available funds; unlike a withdrawal it doesn't
+    /// count toward `withdrawn_total`
+    pub fn charge_fee(&mut self, fee: Decimal) -> bool {
+        self.debit(fee)
+    }
+
+    /// Add to the available funds, unless the total would overflow
+    fn credit(&mut self, amount: Decimal) -> bool {
+        let (Some(available), Some(total)) = (self.available.checked_add(amount), self.total.checked_add(amount)) else {
+            return false;
+        };
+        self.available = available;
+        self.total = total;
+        true
+    }
+
+    /// Take from the available funds, if they cover the amount
+    fn debit(&mut self, amount: Decimal) -> bool {
         if !self.has_sufficient_funds(amount) {
             return false;
         }
@@ -73,93 +718,691 @@ impl Account {
         true
     }
 
-    /// Hold funds for a dispute
-    pub fn hold(&mut self, amount: Decimal) -> bool {
-        if self.locked || self.available < amount {
-            return false;
+    /// Hold funds for a dispute
+    pub fn hold(&mut self, amount: Decimal) -> bool {
+        if self.available < amount {
+            return false;
+        }
+        
+        self.available -= amount;
+        self.held += amount;
+        true
+    }
+
+    /// Release funds from a dispute
+    pub fn release(&mut self, amount: Decimal) -> bool {
+        if self.held < amount {
+            return false;
+        }
+        
+        self.held -= amount;
+        self.available += amount;
+        true
+    }
+
+    /// Remove held funds for a chargeback, adding them to `chargeback_total`.
+    /// Locking the account is up to the engine.
+    pub fn chargeback(&mut self, amount: Decimal) -> bool {
+        if self.held < amount {
+            return false;
+        }
+        
+        self.held -= amount;
+        self.total -= amount;
+        self.chargeback_total += amount;
+        true
+    }
+
+    /// Process a chargeback of a disputed withdrawal, returning the withdrawn
+    /// funds. Unlike a deposit chargeback this adds to the balance, since
+    /// nothing was held for the dispute; it fails if the total would overflow.
+    /// Neither `deposited_total` nor `withdrawn_total` changes.
+    pub fn chargeback_credit(&mut self, amount: Decimal) -> bool {
+        self.credit(amount)
+    }
+}
+
+/// When to drop transactions that can no longer change state from the store
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PurgePolicy {
+    /// Keep every transaction
+    #[default]
+    Never,
+    /// Drop a transaction once it has been charged back
+    AfterChargeback,
+    /// Drop a transaction once its dispute has been resolved or charged back
+    AfterResolveOrChargeback,
+}
+
+/// Fee charged on each withdrawal, deducted from the account along with the
+/// withdrawn amount. Percentages are of the withdrawn amount, e.g. `1.5` for
+/// 1.5%, and fees are rounded to 4 decimal places.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WithdrawalFee {
+    #[default]
+    None,
+    Flat(Decimal),
+    Percent(Decimal),
+    FlatPlusPercent(Decimal, Decimal),
+}
+
+impl WithdrawalFee {
+    /// Fee for withdrawing `amount`
+    pub fn fee_for(&self, amount: Decimal) -> Decimal {
+        let percent = |percent: Decimal| (amount * percent / dec!(100)).round_dp(4);
+        match *self {
+            WithdrawalFee::None => Decimal::ZERO,
+            WithdrawalFee::Flat(flat) => flat,
+            WithdrawalFee::Percent(p) => percent(p),
+            WithdrawalFee::FlatPlusPercent(flat, p) => flat + percent(p),
+        }
+    }
+}
+
+/// Parses `none`, a flat fee like `0.5`, a percentage like `1.5%`, or both like `0.5+1.5%`
+impl FromStr for WithdrawalFee {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid withdrawal fee: {} (expected none, 0.5, 1.5% or 0.5+1.5%)", s);
+        let amount = |s: &str| match Decimal::from_str(s.trim()) {
+            Ok(amount) if !amount.is_sign_negative() => Ok(amount),
+            _ => Err(invalid()),
+        };
+        let percent = |s: &str| s.trim().strip_suffix('%').ok_or_else(invalid).and_then(amount);
+
+        if s == "none" {
+            return Ok(Self::None);
+        }
+        match s.split_once('+') {
+            Some((flat, p)) => Ok(Self::FlatPlusPercent(amount(flat)?, percent(p)?)),
+            None if s.ends_with('%') => Ok(Self::Percent(percent(s)?)),
+            None => Ok(Self::Flat(amount(s)?)),
+        }
+    }
+}
+
+impl FromStr for PurgePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "after-chargeback" => Ok(Self::AfterChargeback),
+            "after-resolve-or-chargeback" => Ok(Self::AfterResolveOrChargeback),
+            _ => Err(format!("Invalid purge policy: {}", s)),
+        }
+    }
+}
+
+/// What to do with a dispute, resolve or chargeback whose client differs from
+/// the client of the transaction it refers to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeClientCheck {
+    /// Reject it
+    #[default]
+    Strict,
+    /// Apply it to the original transaction's client, logging the mismatch
+    TrustOriginal,
+}
+
+impl FromStr for DisputeClientCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "trust-original" => Ok(Self::TrustOriginal),
+            _ => Err(format!("Invalid dispute client check: {} (expected strict or trust-original)", s)),
+        }
+    }
+}
+
+/// Decimal places amounts are kept to, as given by the input spec
+pub const AMOUNT_DECIMAL_PLACES: u32 = 4;
+
+/// How input amounts with more than [`AMOUNT_DECIMAL_PLACES`] decimal places are handled
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AmountPrecision {
+    /// Reject the row
+    Reject,
+    /// Drop the extra digits
+    Truncate,
+    /// Round to the nearest value, ties to even, as the output always did
+    #[default]
+    RoundHalfEven,
+}
+
+impl AmountPrecision {
+    /// Bring an amount to the supported precision, or `None` if it must be rejected
+    pub fn apply(&self, amount: Decimal) -> Option<Decimal> {
+        let strategy = match self {
+            AmountPrecision::Reject if amount != amount.round_dp(AMOUNT_DECIMAL_PLACES) => return None,
+            AmountPrecision::Reject => return Some(amount),
+            AmountPrecision::Truncate => RoundingStrategy::ToZero,
+            AmountPrecision::RoundHalfEven => RoundingStrategy::MidpointNearestEven,
+        };
+        Some(amount.round_dp_with_strategy(AMOUNT_DECIMAL_PLACES, strategy))
+    }
+}
+
+impl FromStr for AmountPrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "truncate" => Ok(Self::Truncate),
+            "round-half-even" => Ok(Self::RoundHalfEven),
+            _ => Err(format!("Invalid amount precision policy: {} (expected reject, truncate or round-half-even)", s)),
+        }
+    }
+}
+
+/// Where a stored transaction is in the dispute lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    /// Applied and never disputed
+    Processed,
+    /// Under dispute, with the amount currently held
+    Disputed(Decimal),
+    /// Last dispute was resolved and the held funds released
+    Resolved,
+    /// Last dispute ended in a chargeback
+    ChargedBack,
+    /// Dropped from the store after its dispute lifecycle ended
+    Finalized,
+}
+
+/// Snake case, without the held amount of a dispute
+impl std::fmt::Display for TransactionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TransactionState::Processed => "processed",
+            TransactionState::Disputed(_) => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged_back",
+            TransactionState::Finalized => "finalized",
+        })
+    }
+}
+
+/// Store for all processed transactions
+#[derive(Debug, Default)]
+pub struct TransactionStore {
+    transactions: IdMap<TxId, Transaction>,
+    /// Amount currently under dispute per transaction; absent when undisputed
+    disputed: IdMap<TxId, Decimal>,
+    /// How the last dispute of a transaction was closed, if it had one
+    closed: IdMap<TxId, TransactionState>,
+    /// Timestamp of the dispute row that opened each timed dispute
+    disputed_since: IdMap<TxId, u64>,
+    /// Timed disputes by opening time. Entries of disputes closed since are
+    /// only dropped when they come up, so they can be put back by a rollback.
+    dispute_queue: BTreeSet<(u64, TxId)>,
+    /// Ids of purged transactions, kept so later references can be rejected as finalized
+    finalized: IdSet<TxId>,
+    /// On-disk tier and the in-memory limit that triggers spilling to it
+    spill: Option<(usize, SpillStore)>,
+}
+
+impl TransactionStore {
+    pub fn new() -> Self {
+        Self {
+            transactions: IdMap::default(),
+            disputed: IdMap::default(),
+            closed: IdMap::default(),
+            disputed_since: IdMap::default(),
+            dispute_queue: BTreeSet::new(),
+            finalized: IdSet::default(),
+            spill: None,
+        }
+    }
+
+    /// Create a store pre-sized for the expected number of transactions. A
+    /// capacity too large to allocate leaves the store to grow on demand.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut store = Self::new();
+        if store.transactions.try_reserve(capacity).is_err() {
+            warn!("Can't pre-size the transaction store for {} transactions, growing it on demand", capacity);
+        }
+        store
+    }
+
+    /// Create a store that spills transactions to segment files in `dir`
+    /// once more than `max_in_memory` are held in memory
+    pub fn with_spill(max_in_memory: usize, dir: &Path) -> io::Result<Self> {
+        Ok(Self {
+            spill: Some((max_in_memory, SpillStore::new(dir)?)),
+            ..Self::new()
+        })
+    }
+
+    pub fn add_transaction(&mut self, tx: Transaction) {
+        self.transactions.insert(tx.tx, tx);
+    }
+
+    /// Whether a transaction with this id is stored, in memory, on disk or
+    /// purged
+    pub fn contains(&self, tx_id: TxId) -> io::Result<bool> {
+        if self.transactions.contains_key(&tx_id) || self.finalized.contains(&tx_id) {
+            return Ok(true);
+        }
+        match &self.spill {
+            Some((_, spill)) => Ok(spill.get(tx_id)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Get a transaction held in memory
+    pub fn get_transaction(&self, tx_id: TxId) -> Option<&Transaction> {
+        self.transactions.get(&tx_id)
+    }
+
+    /// Transactions held in memory, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    /// Get a transaction from memory, falling back to the on-disk tier.
+    /// Transactions found on disk are brought back into memory.
+    pub fn load_transaction(&mut self, tx_id: TxId) -> io::Result<Option<&Transaction>> {
+        if self.finalized.contains(&tx_id) {
+            return Ok(None);
+        }
+        if !self.transactions.contains_key(&tx_id) {
+            let spilled = match &self.spill {
+                Some((_, spill)) => spill.get(tx_id)?,
+                None => None,
+            };
+            match spilled {
+                Some(tx) => self.add_transaction(tx),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.transactions.get(&tx_id))
+    }
+
+    /// Flush the in-memory transactions to disk if they exceed the spill limit.
+    /// Dispute status stays in memory, so it is unaffected by spilling.
+    pub fn spill_if_needed(&mut self) -> io::Result<()> {
+        if let Some((max_in_memory, spill)) = &mut self.spill {
+            if self.transactions.len() > *max_in_memory {
+                let evicted = self.transactions.drain().map(|(_, tx)| tx).collect();
+                spill.write_segment(evicted)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the amount of a transaction under dispute; zero clears the dispute
+    pub fn set_disputed_amount(&mut self, tx_id: TxId, amount: Decimal) {
+        if amount.is_zero() {
+            self.disputed.remove(&tx_id);
+            self.disputed_since.remove(&tx_id);
+        } else {
+            self.disputed.insert(tx_id, amount);
+        }
+    }
+
+    /// Amount of a transaction under dispute, zero if it isn't disputed
+    pub fn disputed_amount(&self, tx_id: TxId) -> Decimal {
+        self.disputed.get(&tx_id).copied().unwrap_or_default()
+    }
+
+    pub fn is_disputed(&self, tx_id: TxId) -> bool {
+        self.disputed.contains_key(&tx_id)
+    }
+
+    /// Number of transactions currently under dispute. Closed disputes leave
+    /// no entry behind.
+    pub fn disputed_count(&self) -> usize {
+        self.disputed.len()
+    }
+
+    /// Record the time the transaction's current dispute was opened, unless
+    /// one was recorded already, e.g. by an earlier partial dispute
+    pub(crate) fn set_disputed_since(&mut self, tx_id: TxId, timestamp: u64) {
+        if let Entry::Vacant(entry) = self.disputed_since.entry(tx_id) {
+            entry.insert(timestamp);
+            self.dispute_queue.insert((timestamp, tx_id));
+        }
+    }
+
+    /// Take the transactions whose timed dispute was opened before `cutoff`
+    /// off the queue, oldest first
+    pub(crate) fn take_disputes_opened_before(&mut self, cutoff: u64) -> Vec<TxId> {
+        let mut opened_before = Vec::new();
+        while let Some(&(timestamp, tx_id)) = self.dispute_queue.first() {
+            if timestamp >= cutoff {
+                break;
+            }
+            self.dispute_queue.pop_first();
+            if self.disputed_since.get(&tx_id) == Some(&timestamp) {
+                opened_before.push(tx_id);
+            }
+        }
+        opened_before
+    }
+
+    /// Record that a dispute of the transaction was resolved
+    pub fn mark_resolved(&mut self, tx_id: TxId) {
+        self.closed.insert(tx_id, TransactionState::Resolved);
+    }
+
+    /// Record that a dispute of the transaction ended in a chargeback
+    pub fn mark_charged_back(&mut self, tx_id: TxId) {
+        self.closed.insert(tx_id, TransactionState::ChargedBack);
+    }
+
+    /// Dispute lifecycle state of a transaction, or `None` if it isn't known.
+    /// Undisputed transactions spilled to disk are not known.
+    pub fn transaction_state(&self, tx_id: TxId) -> Option<TransactionState> {
+        if self.finalized.contains(&tx_id) {
+            return Some(TransactionState::Finalized);
+        }
+        if let Some(amount) = self.disputed.get(&tx_id) {
+            return Some(TransactionState::Disputed(*amount));
+        }
+        if let Some(state) = self.closed.get(&tx_id) {
+            return Some(*state);
+        }
+        self.transactions.contains_key(&tx_id).then_some(TransactionState::Processed)
+    }
+
+    /// Number of transactions held in memory
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Drop a transaction that can no longer change state, remembering only its id
+    pub fn finalize(&mut self, tx_id: TxId) {
+        self.transactions.remove(&tx_id);
+        self.disputed.remove(&tx_id);
+        self.disputed_since.remove(&tx_id);
+        self.closed.remove(&tx_id);
+        self.finalized.insert(tx_id);
+    }
+
+    /// Check if a transaction was purged after being finalized
+    pub fn is_finalized(&self, tx_id: TxId) -> bool {
+        self.finalized.contains(&tx_id)
+    }
+
+    /// Whether any transactions have been spilled to disk
+    pub fn has_spilled(&self) -> bool {
+        self.spill.as_ref().is_some_and(|(_, spill)| spill.segment_count() > 0)
+    }
+
+    /// Remove every transaction and dispute status, keeping allocated capacity
+    /// and the spill limit
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+        self.disputed.clear();
+        self.closed.clear();
+        self.disputed_since.clear();
+        self.dispute_queue.clear();
+        self.finalized.clear();
+        if let Some((_, spill)) = &mut self.spill {
+            spill.clear();
+        }
+    }
+
+    /// Id of a transaction both stores know with a different transaction or
+    /// dispute state, if there is one
+    pub(crate) fn find_conflict(&self, other: &TransactionStore) -> Option<TxId> {
+        let ids = other.transactions.keys().chain(&other.finalized).chain(other.disputed.keys()).chain(other.closed.keys());
+        ids.copied().find(|&tx_id| {
+            let ours = self.transaction_state(tx_id);
+            ours.is_some()
+                && (ours != other.transaction_state(tx_id)
+                    || self.transactions.get(&tx_id) != other.transactions.get(&tx_id))
+        })
+    }
+
+    /// Move every entry of another store into this one, replacing entries with the same id
+    pub(crate) fn absorb(&mut self, other: TransactionStore) {
+        self.transactions.extend(other.transactions);
+        self.disputed.extend(other.disputed);
+        self.closed.extend(other.closed);
+        self.disputed_since.extend(other.disputed_since);
+        self.dispute_queue.extend(other.dispute_queue);
+        self.finalized.extend(other.finalized);
+    }
+
+    /// Copy a stored transaction and its dispute status into another store
+    pub(crate) fn copy_entry_to(&self, tx_id: TxId, other: &mut TransactionStore) -> io::Result<()> {
+        if self.is_finalized(tx_id) {
+            other.finalized.insert(tx_id);
+            return Ok(());
         }
-        
-        self.available -= amount;
-        self.held += amount;
-        true
+        let tx = match (self.transactions.get(&tx_id), &self.spill) {
+            (Some(tx), _) => Some(tx.clone()),
+            (None, Some((_, spill))) => spill.get(tx_id)?,
+            (None, None) => None,
+        };
+        if let Some(tx) = tx {
+            other.add_transaction(tx);
+            other.set_disputed_amount(tx_id, self.disputed_amount(tx_id));
+            if let Some(state) = self.closed.get(&tx_id) {
+                other.closed.insert(tx_id, *state);
+            }
+            if let Some(&timestamp) = self.disputed_since.get(&tx_id) {
+                other.set_disputed_since(tx_id, timestamp);
+            }
+        }
+        Ok(())
     }
 
-    /// Release funds from a dispute
-    pub fn release(&mut self, amount: Decimal) -> bool {
-        if self.locked || self.held < amount {
-            return false;
+    /// Put the entries of `tx_ids` back as they are in `saved`, a store they
+    /// were copied to earlier; ids it has no entry for are removed. A removed
+    /// transaction that was spilled to disk in the meantime can't be deleted
+    /// there, so it is finalized instead.
+    pub(crate) fn restore_entries(&mut self, tx_ids: IdSet<TxId>, saved: TransactionStore) -> io::Result<()> {
+        for tx_id in tx_ids {
+            self.transactions.remove(&tx_id);
+            self.disputed.remove(&tx_id);
+            self.closed.remove(&tx_id);
+            self.disputed_since.remove(&tx_id);
+            self.finalized.remove(&tx_id);
+            let was_stored = saved.transactions.contains_key(&tx_id) || saved.finalized.contains(&tx_id);
+            if let (false, Some((_, spill))) = (was_stored, &self.spill) {
+                if spill.get(tx_id)?.is_some() {
+                    self.finalized.insert(tx_id);
+                }
+            }
         }
-        
-        self.held -= amount;
-        self.available += amount;
-        true
+        self.absorb(saved);
+        Ok(())
     }
 
-    /// Process a chargeback
-    pub fn chargeback(&mut self, amount: Decimal) -> bool {
-        if self.locked || self.held < amount {
-            return false;
+    /// Move entries belonging to a client from another store into this one
+    pub(crate) fn merge_client_entries(&mut self, client_id: u16, other: TransactionStore) {
+        for tx_id in other.finalized {
+            self.finalize(tx_id);
+        }
+        for (tx_id, tx) in other.transactions {
+            if tx.client != client_id {
+                continue;
+            }
+            self.set_disputed_amount(tx_id, other.disputed.get(&tx_id).copied().unwrap_or_default());
+            if let Some(state) = other.closed.get(&tx_id) {
+                self.closed.insert(tx_id, *state);
+            }
+            self.disputed_since.remove(&tx_id);
+            if let Some(&timestamp) = other.disputed_since.get(&tx_id) {
+                self.set_disputed_since(tx_id, timestamp);
+            }
+            self.transactions.insert(tx_id, tx);
         }
-        
-        self.held -= amount;
-        self.total -= amount;
-        self.locked = true;
-        true
     }
 }
 
-/// Store for all processed transactions
-#[derive(Debug, Default)]
-pub struct TransactionStore {
-    transactions: HashMap<u32, Transaction>,
-    disputed: HashMap<u32, bool>,
+/// Where accounts are kept, so account operations can run against either the
+/// single-threaded [`AccountStore`] or a store shared between threads.
+///
+/// A [`ConcurrentAccountStore`](crate::concurrent::ConcurrentAccountStore) is
+/// used through a shared reference, which is cheap to copy into every feeder;
+/// its guards lock the account until they are dropped.
+pub trait AccountStorage {
+    /// Mutable access to one account, held until it is dropped
+    type Guard<'a>: DerefMut<Target = Account>
+    where
+        Self: 'a;
+
+    /// The client's account, created empty if the client hasn't been seen
+    fn get_or_create(&mut self, client_id: u16) -> Self::Guard<'_>;
+
+    /// A copy of the client's account, if the client has been seen
+    fn account(&self, client_id: u16) -> Option<Account>;
+
+    /// Copies of all accounts, in no particular order
+    fn accounts(&self) -> Vec<Account>;
+
+    /// Number of clients with an account
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
-impl TransactionStore {
-    pub fn new() -> Self {
-        Self {
-            transactions: HashMap::new(),
-            disputed: HashMap::new(),
-        }
+impl<S: AccountStorage + ?Sized> AccountStorage for &mut S {
+    type Guard<'a>
+        = S::Guard<'a>
+    where
+        Self: 'a;
+
+    fn get_or_create(&mut self, client_id: u16) -> S::Guard<'_> {
+        (**self).get_or_create(client_id)
     }
 
-    pub fn add_transaction(&mut self, tx: Transaction) {
-        self.transactions.insert(tx.tx, tx);
+    fn account(&self, client_id: u16) -> Option<Account> {
+        (**self).account(client_id)
     }
 
-    pub fn get_transaction(&self, tx_id: u32) -> Option<&Transaction> {
-        self.transactions.get(&tx_id)
+    fn accounts(&self) -> Vec<Account> {
+        (**self).accounts()
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+impl AccountStorage for AccountStore {
+    type Guard<'a> = &'a mut Account;
+
+    fn get_or_create(&mut self, client_id: u16) -> &mut Account {
+        self.get_or_create_account(client_id)
+    }
+
+    fn account(&self, client_id: u16) -> Option<Account> {
+        self.get_account(client_id).cloned()
     }
 
-    pub fn set_disputed(&mut self, tx_id: u32, status: bool) {
-        self.disputed.insert(tx_id, status);
+    fn accounts(&self) -> Vec<Account> {
+        self.get_all_accounts()
     }
 
-    pub fn is_disputed(&self, tx_id: u32) -> bool {
-        self.disputed.get(&tx_id).copied().unwrap_or(false)
+    fn len(&self) -> usize {
+        self.accounts.len()
     }
 }
 
 /// Store for all client accounts
 #[derive(Debug, Default)]
 pub struct AccountStore {
-    accounts: HashMap<u16, Account>,
+    accounts: IdMap<u16, Account>,
 }
 
 impl AccountStore {
     pub fn new() -> Self {
         Self {
-            accounts: HashMap::new(),
+            accounts: IdMap::default(),
+        }
+    }
+
+    /// Create a store pre-sized for the expected number of clients, at most
+    /// one per client id
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            accounts: IdMap::with_capacity_and_hasher(capacity.min(u16::MAX as usize + 1), FxBuildHasher),
         }
     }
 
+    pub fn get_account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
     pub fn get_or_create_account(&mut self, client_id: u16) -> &mut Account {
         self.accounts.entry(client_id).or_insert_with(|| Account::new(client_id))
     }
 
+    /// Take an account out of the store, e.g. to apply transactions to it elsewhere
+    pub(crate) fn remove_account(&mut self, client_id: u16) -> Option<Account> {
+        self.accounts.remove(&client_id)
+    }
+
+    /// Put an account into the store, e.g. when loading accounts in bulk,
+    /// returning the one it replaces for the same client
+    pub fn insert(&mut self, account: Account) -> Option<Account> {
+        self.accounts.insert(account.client, account)
+    }
+
+    /// Ids of all clients with an account
+    pub(crate) fn clients(&self) -> impl Iterator<Item = u16> + '_ {
+        self.accounts.keys().copied()
+    }
+
+    /// Move every account of another store into this one, replacing accounts of the same client
+    pub(crate) fn absorb(&mut self, other: AccountStore) {
+        self.accounts.extend(other.accounts);
+    }
+
+    /// Remove all accounts, keeping allocated capacity
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+    }
+
+    /// Number of clients with an account
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
     pub fn get_all_accounts(&self) -> Vec<Account> {
         self.accounts.values().cloned().collect()
     }
+
+    /// All accounts, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Load accounts carried over from a previous run into an empty store.
+    ///
+    /// Every account must have `total == available + held` and appear once;
+    /// nothing is loaded if any account is invalid.
+    pub fn load_accounts(&mut self, accounts: Vec<Account>) -> Result<(), PaymentEngineError> {
+        let mut loaded = IdMap::with_capacity_and_hasher(accounts.len(), FxBuildHasher);
+        for account in accounts {
+            account.check_balances().map_err(|reason| PaymentEngineError::InvalidAccount(account.client, reason))?;
+            if self.accounts.contains_key(&account.client) || loaded.contains_key(&account.client) {
+                return Err(PaymentEngineError::InvalidAccount(account.client, "duplicate client".to_string()));
+            }
+            loaded.insert(account.client, account);
+        }
+        self.accounts.extend(loaded);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -174,23 +1417,16 @@ mod tests {
         assert_eq!(account.available, dec!(0));
         assert_eq!(account.held, dec!(0));
         assert_eq!(account.total, dec!(0));
-        assert_eq!(account.locked, false);
+        assert!(!account.locked);
     }
 
     #[test]
     fn test_account_deposit() {
         let mut account = Account::new(1);
         
-        let result = account.deposit(dec!(100));
-        assert!(result);
+        account.deposit(dec!(100));
         assert_eq!(account.available, dec!(100));
         assert_eq!(account.total, dec!(100));
-        
-        // Test locked account
-        account.locked = true;
-        let result = account.deposit(dec!(50));
-        assert!(!result);
-        assert_eq!(account.available, dec!(100)); // Unchanged
     }
 
     #[test]
@@ -208,12 +1444,6 @@ mod tests {
         let result = account.withdraw(dec!(80));
         assert!(!result);
         assert_eq!(account.available, dec!(70)); // Unchanged
-        
-        // Locked account
-        account.locked = true;
-        let result = account.withdraw(dec!(10));
-        assert!(!result);
-        assert_eq!(account.available, dec!(70)); // Unchanged
     }
 
     #[test]
@@ -233,13 +1463,6 @@ mod tests {
         assert!(!result);
         assert_eq!(account.available, dec!(70)); // Unchanged
         assert_eq!(account.held, dec!(30)); // Unchanged
-        
-        // Locked account
-        account.locked = true;
-        let result = account.hold(dec!(10));
-        assert!(!result);
-        assert_eq!(account.available, dec!(70)); // Unchanged
-        assert_eq!(account.held, dec!(30)); // Unchanged
     }
 
     #[test]
@@ -260,13 +1483,6 @@ mod tests {
         assert!(!result);
         assert_eq!(account.available, dec!(90)); // Unchanged
         assert_eq!(account.held, dec!(10)); // Unchanged
-        
-        // Locked account
-        account.locked = true;
-        let result = account.release(dec!(5));
-        assert!(!result);
-        assert_eq!(account.available, dec!(90)); // Unchanged
-        assert_eq!(account.held, dec!(10)); // Unchanged
     }
 
     #[test]
@@ -281,13 +1497,90 @@ mod tests {
         assert_eq!(account.available, dec!(70)); // Unchanged
         assert_eq!(account.held, dec!(10));
         assert_eq!(account.total, dec!(80)); // Reduced by chargeback amount
-        assert!(account.locked); // Account is locked
+        assert!(!account.locked); // Locking is the engine's decision
+        assert_eq!(account.chargeback_total, dec!(20));
         
-        // Already locked, further chargebacks fail
-        let result = account.chargeback(dec!(10));
+        // Insufficient held funds
+        let result = account.chargeback(dec!(20));
         assert!(!result);
         assert_eq!(account.held, dec!(10)); // Unchanged
+        assert_eq!(account.chargeback_total, dec!(20));
         assert_eq!(account.total, dec!(80)); // Unchanged
+
+        // Chargebacks in another currency are totalled with its balance
+        let eur: Currency = "EUR".parse().unwrap();
+        assert!(account.with_currency(Some(eur), |account| account.deposit(dec!(5)) && account.hold(dec!(5)) && account.chargeback(dec!(5))));
+        assert_eq!(account.chargeback_total, dec!(20));
+        assert_eq!(account.other_currencies[&eur].chargeback_total, dec!(5));
+    }
+
+    #[test]
+    fn test_account_balance_math_ignores_lock() {
+        let mut account = Account::new(1);
+        account.locked = true;
+
+        account.deposit(dec!(100));
+        assert!(account.withdraw(dec!(10)));
+        assert!(account.hold(dec!(30)));
+        assert!(account.release(dec!(10)));
+        assert!(account.chargeback(dec!(20)));
+        account.chargeback_credit(dec!(5));
+        assert_eq!((account.available, account.held, account.total), (dec!(75), dec!(0), dec!(75)));
+    }
+
+    #[test]
+    fn test_account_chargeback_credit() {
+        let mut account = Account::new(1);
+        account.deposit(dec!(100));
+        account.withdraw(dec!(40));
+
+        // The withdrawn funds come back, the opposite sign of a deposit chargeback
+        assert!(account.chargeback_credit(dec!(40)));
+        assert_eq!(account.available, dec!(100));
+        assert_eq!(account.held, dec!(0));
+        assert_eq!(account.total, dec!(100));
+        assert_eq!((account.deposited_total, account.withdrawn_total), (dec!(100), dec!(40)));
+    }
+
+    #[test]
+    fn test_account_fees_stay_out_of_gross_totals() {
+        let mut account = Account::new(1);
+        account.deposit(dec!(100));
+        assert!(account.withdraw(dec!(40)) && account.charge_fee(dec!(1.5)));
+        assert_eq!(account.available, dec!(58.5));
+        assert_eq!((account.deposited_total, account.withdrawn_total), (dec!(100), dec!(40)));
+    }
+
+    #[test]
+    fn test_account_deposit_overflow_is_refused() {
+        let mut account = Account::new(1);
+        assert!(account.deposit(Decimal::MAX));
+        assert!(!account.deposit(dec!(1)));
+        assert!(!account.chargeback_credit(dec!(1)));
+        assert_eq!((account.available, account.total), (Decimal::MAX, Decimal::MAX));
+    }
+
+    #[test]
+    fn test_transaction_constructors() {
+        let deposit = Transaction::deposit(3, 7, dec!(1.5));
+        assert_eq!((deposit.transaction_type, deposit.client, deposit.tx, deposit.amount), (TransactionType::Deposit, 3, 7, Some(dec!(1.5))));
+        assert_eq!(Transaction::withdrawal(3, 8, dec!(2)).transaction_type, TransactionType::Withdrawal);
+        for (transaction, transaction_type) in [
+            (Transaction::dispute(3, 7), TransactionType::Dispute),
+            (Transaction::resolve(3, 7), TransactionType::Resolve),
+            (Transaction::chargeback(3, 7), TransactionType::Chargeback),
+        ] {
+            assert_eq!((transaction.transaction_type, transaction.tx, transaction.amount, transaction.currency), (transaction_type, 7, None, None));
+        }
+        let eur = "EUR".parse().unwrap();
+        assert_eq!(deposit.in_currency(eur).currency, Some(eur));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "withdrawal amount must be positive")]
+    fn test_transaction_constructor_rejects_negative_amount() {
+        Transaction::withdrawal(1, 1, dec!(-5));
     }
 
     // Tests for TransactionStore
@@ -295,12 +1588,7 @@ mod tests {
     fn test_transaction_store() {
         let mut store = TransactionStore::new();
         
-        let tx = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 1,
-            tx: 123,
-            amount: Some(dec!(100)),
-        };
+        let tx = Transaction::deposit(1, 123, dec!(100));
         
         // Add transaction
         store.add_transaction(tx.clone());
@@ -318,12 +1606,112 @@ mod tests {
         assert!(!store.is_disputed(123));
         
         // Set disputed
-        store.set_disputed(123, true);
+        store.set_disputed_amount(123, dec!(30));
         assert!(store.is_disputed(123));
+        assert_eq!(store.disputed_amount(123), dec!(30));
         
         // Clear disputed
-        store.set_disputed(123, false);
+        store.set_disputed_amount(123, dec!(0));
         assert!(!store.is_disputed(123));
+        assert_eq!(store.disputed_amount(123), dec!(0));
+    }
+
+    #[test]
+    fn test_transaction_store_finalize() {
+        let mut store = TransactionStore::new();
+        store.add_transaction(Transaction::deposit(1, 7, dec!(10)));
+        store.set_disputed_amount(7, dec!(10));
+
+        store.finalize(7);
+        assert!(store.get_transaction(7).is_none());
+        assert!(store.load_transaction(7).unwrap().is_none());
+        assert!(!store.is_disputed(7));
+        assert!(store.is_finalized(7));
+        assert!(!store.is_finalized(8));
+    }
+
+    #[test]
+    fn test_purge_policy_from_str() {
+        assert_eq!("never".parse::<PurgePolicy>(), Ok(PurgePolicy::Never));
+        assert_eq!("after-chargeback".parse::<PurgePolicy>(), Ok(PurgePolicy::AfterChargeback));
+        assert_eq!(
+            "after-resolve-or-chargeback".parse::<PurgePolicy>(),
+            Ok(PurgePolicy::AfterResolveOrChargeback)
+        );
+        assert!("sometimes".parse::<PurgePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_currency_balances() {
+        let eur: Currency = "eur".parse().unwrap();
+        assert_eq!(eur.as_str(), "EUR");
+        assert!("EURO".parse::<Currency>().is_err());
+        assert!("E1R".parse::<Currency>().is_err());
+
+        let mut account = Account::new(1);
+        account.deposit(dec!(10));
+        account.with_currency(Some(eur), |a| a.deposit(dec!(5)));
+        assert!(!account.with_currency(Some(eur), |a| a.withdraw(dec!(6))));
+        assert_eq!(account.total, dec!(10));
+        assert_eq!(
+            account.other_currencies[&eur],
+            Balance { available: dec!(5), held: dec!(0), total: dec!(5), deposited_total: dec!(5), ..Default::default() }
+        );
+
+        // Nothing is recorded for a currency whose only transaction failed
+        assert!(!account.with_currency(Some("GBP".parse().unwrap()), |a| a.withdraw(dec!(1))));
+        assert_eq!(account.other_currencies.len(), 1);
+    }
+
+    #[test]
+    fn test_amount_precision() {
+        let amount = dec!(0.123456789);
+        assert_eq!(AmountPrecision::Reject.apply(amount), None);
+        assert_eq!(AmountPrecision::Truncate.apply(amount), Some(dec!(0.1234)));
+        assert_eq!(AmountPrecision::RoundHalfEven.apply(amount), Some(dec!(0.1235)));
+        assert_eq!(AmountPrecision::RoundHalfEven.apply(dec!(0.00015)), Some(dec!(0.0002)));
+        assert_eq!(AmountPrecision::RoundHalfEven.apply(dec!(0.00025)), Some(dec!(0.0002)));
+
+        // Trailing zeros don't count as extra precision
+        assert_eq!(AmountPrecision::Reject.apply(dec!(1.500000)), Some(dec!(1.5)));
+        assert_eq!("round-half-even".parse::<AmountPrecision>(), Ok(AmountPrecision::RoundHalfEven));
+        assert!("round".parse::<AmountPrecision>().is_err());
+    }
+
+    // Timing comparison of the id hasher against SipHash on 5M generated
+    // transactions. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_id_map_vs_std_hash_map() {
+        use std::time::Instant;
+
+        let config = crate::generator::GeneratorConfig {
+            transactions: 5_000_000,
+            clients: 10_000,
+            seed: 1,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+        let transactions: Vec<Transaction> = csv::Reader::from_reader(csv.as_slice())
+            .deserialize()
+            .map(|r| r.unwrap())
+            .collect();
+
+        fn run<S: std::hash::BuildHasher>(mut map: HashMap<TxId, Transaction, S>, transactions: &[Transaction]) -> f64 {
+            let start = Instant::now();
+            for tx in transactions {
+                map.insert(tx.tx, tx.clone());
+            }
+            let found = transactions.iter().filter(|tx| map.contains_key(&tx.tx)).count();
+            assert_eq!(found, transactions.len());
+            start.elapsed().as_secs_f64()
+        }
+
+        let sip = run(HashMap::new(), &transactions);
+        let fx = run(IdMap::default(), &transactions);
+        let fx_presized = run(IdMap::with_capacity_and_hasher(transactions.len(), FxBuildHasher), &transactions);
+        println!("SipHash: {:.3}s, FxHash: {:.3}s, FxHash pre-sized: {:.3}s", sip, fx, fx_presized);
     }
 
     // Tests for AccountStore
@@ -348,4 +1736,310 @@ mod tests {
         assert_eq!(accounts[0].client, 1);
         assert_eq!(accounts[0].available, dec!(100));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_withdrawal_fee() {
+        assert_eq!(WithdrawalFee::None.fee_for(dec!(100)), dec!(0));
+        assert_eq!(WithdrawalFee::Flat(dec!(0.5)).fee_for(dec!(100)), dec!(0.5));
+        assert_eq!(WithdrawalFee::Percent(dec!(1.5)).fee_for(dec!(100)), dec!(1.5));
+        assert_eq!(WithdrawalFee::Percent(dec!(1)).fee_for(dec!(0.12345)), dec!(0.0012));
+        assert_eq!(WithdrawalFee::FlatPlusPercent(dec!(0.25), dec!(2)).fee_for(dec!(10)), dec!(0.45));
+
+        assert_eq!("none".parse(), Ok(WithdrawalFee::None));
+        assert_eq!("0.5".parse(), Ok(WithdrawalFee::Flat(dec!(0.5))));
+        assert_eq!("1.5%".parse(), Ok(WithdrawalFee::Percent(dec!(1.5))));
+        assert_eq!("0.5 + 1.5%".parse(), Ok(WithdrawalFee::FlatPlusPercent(dec!(0.5), dec!(1.5))));
+        assert!("-1".parse::<WithdrawalFee>().is_err());
+        assert!("0.5+1.5".parse::<WithdrawalFee>().is_err());
+        assert!("lots".parse::<WithdrawalFee>().is_err());
+    }
+
+    #[test]
+    fn test_account_with_balances() {
+        let account = Account::with_balances(3, dec!(10.5), dec!(2), true).unwrap();
+        assert_eq!((account.client, account.available, account.held, account.total), (3, dec!(10.5), dec!(2), dec!(12.5)));
+        assert!(account.locked);
+        assert!(account.check_balances().is_ok());
+
+        let err = Account::with_balances(3, dec!(-1), dec!(2), false).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidAccount(3, _)));
+        assert!(Account::with_balances(3, dec!(1), dec!(-0.0001), false).is_err());
+    }
+
+    #[test]
+    fn test_account_csv_and_json_round_trip() {
+        let mut account = Account::with_balances(7, dec!(12.25), dec!(3), true).unwrap();
+        account.lock_reason = Some(LockReason::Chargeback(4));
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(&account).unwrap();
+        let csv = writer.into_inner().unwrap();
+        let read: Account = csv::Reader::from_reader(csv.as_slice()).deserialize().next().unwrap().unwrap();
+        assert_eq!((read.client, read.available, read.held, read.total), (7, dec!(12.25), dec!(3), dec!(15.25)));
+        assert!(read.locked);
+        assert_eq!(read.lock_reason, Some(LockReason::Chargeback(4)));
+
+        let eur: Currency = "EUR".parse().unwrap();
+        account.other_currencies.insert(eur, Balance { available: dec!(1), held: dec!(0.5), total: dec!(1.5), ..Default::default() });
+        let read: Account = serde_json::from_str(&serde_json::to_string(&account).unwrap()).unwrap();
+        assert_eq!(read.total, dec!(15.25));
+        assert_eq!(read.other_currencies, account.other_currencies);
+        assert_eq!(read.lock_reason, account.lock_reason);
+    }
+
+    #[test]
+    fn test_decimals_round_trip_through_json_as_strings() {
+        for value in [dec!(92233720368.5477), dec!(0.0001), dec!(0.1), dec!(-3.50), dec!(0)] {
+            let account = Account::with_balances(1, value.abs(), value.abs(), false).unwrap();
+            let json = serde_json::to_value(&account).unwrap();
+            assert_eq!(json["available"], value.abs().to_string());
+            let read: Account = serde_json::from_value(json).unwrap();
+            assert_eq!(read.available, value.abs());
+            assert_eq!(read.available.scale(), value.scale());
+
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.serialize(&account).unwrap();
+            let csv = writer.into_inner().unwrap();
+            let read: Account = csv::Reader::from_reader(csv.as_slice()).deserialize().next().unwrap().unwrap();
+            assert_eq!(read.total, account.total);
+
+            let transaction = Transaction::deposit(1, 2, value.abs().max(dec!(0.0001)));
+            let json = serde_json::to_string(&transaction).unwrap();
+            assert!(json.contains(&format!(r#""amount":"{}""#, transaction.amount.unwrap())), "{}", json);
+            assert_eq!(serde_json::from_str::<Transaction>(&json).unwrap(), transaction);
+        }
+
+        // Numbers are accepted on input, read through their shortest form
+        for (json, amount) in [
+            ("92233720368.5477", dec!(92233720368.5477)),
+            ("0.0001", dec!(0.0001)),
+            ("0.1", dec!(0.1)),
+            ("12", dec!(12)),
+            ("-7", dec!(-7)),
+            (r#""1e-4""#, dec!(0.0001)),
+            ("null", Decimal::ZERO),
+        ] {
+            let transaction: Transaction =
+                serde_json::from_str(&format!(r#"{{"type":"deposit","client":1,"tx":2,"amount":{}}}"#, json)).unwrap();
+            assert_eq!(transaction.amount.unwrap_or_default(), amount, "{}", json);
+        }
+        let err = serde_json::from_str::<Transaction>(r#"{"type":"deposit","client":1,"tx":2,"amount":"ten"}"#).unwrap_err();
+        assert!(err.to_string().contains("a decimal number or a string holding one"), "{}", err);
+
+        // Maps of decimals are written as strings too
+        let report = crate::ProcessingReport { fees_by_client: BTreeMap::from([(3, dec!(0.0001))]), fees_collected: dec!(0.0001), ..Default::default() };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["fees_by_client"]["3"], "0.0001");
+        assert_eq!(json["fees_collected"], "0.0001");
+    }
+
+    #[test]
+    fn test_inconsistent_account_is_rejected_on_load() {
+        let csv = "client,available,held,total,locked\n1,10,5,16,false\n";
+        let err = csv::Reader::from_reader(csv.as_bytes()).deserialize::<Account>().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("total 16 is not available 10 + held 5"), "{}", err);
+
+        let json = r#"{"client":1,"available":"1","held":"0","total":"1","locked":false,
+            "other_currencies":{"EUR":{"available":"1","held":"1","total":"1"}}}"#;
+        let err = serde_json::from_str::<Account>(json).unwrap_err();
+        assert!(err.to_string().contains("in EUR"), "{}", err);
+    }
+
+    #[test]
+    fn test_account_store_insert() {
+        let mut store = AccountStore::new();
+        assert!(store.insert(Account::with_balances(1, dec!(5), dec!(0), false).unwrap()).is_none());
+        let replaced = store.insert(Account::with_balances(1, dec!(8), dec!(0), false).unwrap()).unwrap();
+        assert_eq!(replaced.total, dec!(5));
+        assert_eq!(store.get_account(1).unwrap().total, dec!(8));
+    }
+
+    #[test]
+    fn test_account_store_load_accounts() {
+        let account = |client, available, held, total| Account { client, available, held, total, locked: client == 2, ..Default::default() };
+        let mut store = AccountStore::new();
+
+        let err = store
+            .load_accounts(vec![account(1, dec!(10), dec!(5), dec!(15)), account(2, dec!(1), dec!(1), dec!(3))])
+            .unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidAccount(2, _)));
+        assert!(store.get_account(1).is_none());
+
+        let err = store
+            .load_accounts(vec![account(1, dec!(10), dec!(0), dec!(10)), account(1, dec!(1), dec!(0), dec!(1))])
+            .unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidAccount(1, _)));
+
+        store
+            .load_accounts(vec![account(1, dec!(10), dec!(5), dec!(15)), account(2, dec!(0), dec!(0), dec!(0))])
+            .unwrap();
+        assert_eq!(store.get_account(1).unwrap().held, dec!(5));
+        assert!(store.get_account(2).unwrap().locked);
+    }
+
+    #[test]
+    fn test_transaction_store_spill() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = TransactionStore::with_spill(10, dir.path()).unwrap();
+
+        for i in 1..=25 {
+            store.add_transaction(Transaction::deposit(1, i, dec!(1) * rust_decimal::Decimal::from(i)));
+            store.spill_if_needed().unwrap();
+        }
+
+        // Evicted transactions are only reachable through the disk tier
+        assert!(store.get_transaction(1).is_none());
+        store.set_disputed_amount(1, dec!(1));
+        let tx = store.load_transaction(1).unwrap().unwrap();
+        assert_eq!(tx.amount, Some(dec!(1)));
+        assert!(store.is_disputed(1));
+        assert!(store.get_transaction(1).is_some());
+        assert!(store.load_transaction(999).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_from_csv_line_deposit() {
+        let line = "deposit,1,1,100.50";
+        let tx = Transaction::from_csv_line(line, ',').unwrap();
+        
+        assert_eq!(tx.transaction_type, TransactionType::Deposit);
+        assert_eq!(tx.client, 1);
+        assert_eq!(tx.tx, 1);
+        assert_eq!(tx.amount, Some(dec!(100.50)));
+    }
+    
+    #[test]
+    fn test_from_csv_line_withdrawal() {
+        let line = "withdrawal,2,5,20.75";
+        let tx = Transaction::from_csv_line(line, ',').unwrap();
+        
+        assert_eq!(tx.transaction_type, TransactionType::Withdrawal);
+        assert_eq!(tx.client, 2);
+        assert_eq!(tx.tx, 5);
+        assert_eq!(tx.amount, Some(dec!(20.75)));
+    }
+    
+    #[test]
+    fn test_from_csv_line_dispute() {
+        let line = "dispute,1,10,";
+        let tx = Transaction::from_csv_line(line, ',').unwrap();
+        
+        assert_eq!(tx.transaction_type, TransactionType::Dispute);
+        assert_eq!(tx.client, 1);
+        assert_eq!(tx.tx, 10);
+        assert_eq!(tx.amount, None);
+    }
+    
+    #[test]
+    fn test_from_csv_line_resolve() {
+        let line = "resolve,3,15";
+        let tx = Transaction::from_csv_line(line, ',').unwrap();
+        
+        assert_eq!(tx.transaction_type, TransactionType::Resolve);
+        assert_eq!(tx.client, 3);
+        assert_eq!(tx.tx, 15);
+        assert_eq!(tx.amount, None);
+    }
+    
+    #[test]
+    fn test_from_csv_line_chargeback() {
+        let line = "chargeback,4,20";
+        let tx = Transaction::from_csv_line(line, ',').unwrap();
+        
+        assert_eq!(tx.transaction_type, TransactionType::Chargeback);
+        assert_eq!(tx.client, 4);
+        assert_eq!(tx.tx, 20);
+        assert_eq!(tx.amount, None);
+    }
+    
+    #[test]
+    fn test_from_csv_line_invalid_type() {
+        let line = "unknown,1,1,100";
+        let result = Transaction::from_csv_line(line, ',');
+        assert!(matches!(result, Err(PaymentEngineError::InvalidTransactionType(kind)) if kind == "unknown"));
+    }
+    
+    #[test]
+    fn test_from_csv_line_invalid_format() {
+        let line = "deposit,1";
+        let result = Transaction::from_csv_line(line, ',');
+        assert!(matches!(result, Err(PaymentEngineError::InvalidLineFormat(_))));
+    }
+    
+    #[test]
+    fn test_from_csv_line_invalid_client() {
+        let line = "deposit,abc,1,100";
+        let result = Transaction::from_csv_line(line, ',');
+        assert!(matches!(result, Err(PaymentEngineError::InvalidId { field: "client", .. })));
+    }
+    
+    #[test]
+    fn test_from_csv_line_invalid_tx() {
+        let line = "deposit,1,abc,100";
+        let result = Transaction::from_csv_line(line, ',');
+        assert!(matches!(result, Err(PaymentEngineError::InvalidId { field: "tx", .. })));
+    }
+
+    #[test]
+    fn test_from_csv_line_tx_overflow() {
+        let max = TxId::MAX.to_string();
+        assert_eq!(Transaction::from_csv_line(&format!("deposit,1,{},1.0", max), ',').unwrap().tx, TxId::MAX);
+
+        let over = (u128::from(TxId::MAX) + 1).to_string();
+        let error = Transaction::from_csv_line(&format!("deposit,1,{},1.0", over), ',').unwrap_err();
+        assert!(matches!(&error, PaymentEngineError::IdOverflow { field: "tx", max: m, .. } if *m == MAX_TX_ID));
+        assert_eq!(error.to_string(), format!("Invalid tx: {} is larger than the maximum of {}", over, max));
+        assert_eq!(error.field(), Some("tx"));
+
+        let error = Transaction::from_csv_line("deposit,70000,1,1.0", ',').unwrap_err();
+        assert!(matches!(error, PaymentEngineError::IdOverflow { field: "client", max: 65535, .. }));
+        assert!(matches!(Transaction::from_csv_line("deposit,1,-1,1.0", ','), Err(PaymentEngineError::InvalidId { field: "tx", .. })));
+    }
+
+    #[cfg(not(feature = "wide-tx-ids"))]
+    #[test]
+    fn test_from_csv_line_rejects_wide_tx() {
+        let error = Transaction::from_csv_line("deposit,1,5000000000,1.0", ',').unwrap_err();
+        assert_eq!(error.to_string(), "Invalid tx: 5000000000 is larger than the maximum of 4294967295");
+    }
+
+    #[cfg(feature = "wide-tx-ids")]
+    #[test]
+    fn test_from_csv_line_accepts_wide_tx() {
+        assert_eq!(Transaction::from_csv_line("deposit,1,5000000000,1.0", ',').unwrap().tx, 5_000_000_000);
+    }
+    
+    #[test]
+    fn test_from_csv_line_invalid_amount() {
+        let line = "deposit,1,1,abc";
+        let result = Transaction::from_csv_line(line, ',');
+        assert!(matches!(result, Err(PaymentEngineError::InvalidAmount(amount)) if amount == "abc"));
+    }
+    
+    #[test]
+    fn test_from_csv_line_whitespace_and_extra_fields() {
+        let tx = Transaction::from_csv_line(" deposit , 7 , 42 , 1.5 , extra", ',').unwrap();
+
+        assert_eq!(tx.transaction_type, TransactionType::Deposit);
+        assert_eq!(tx.client, 7);
+        assert_eq!(tx.tx, 42);
+        assert_eq!(tx.amount, Some(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_from_csv_line_strict_validations() {
+        let parse = |line| Transaction::from_csv_line(line, ',');
+        assert!(matches!(parse("deposit,1,1,-2.5"), Err(PaymentEngineError::NonPositiveAmount(a)) if a == dec!(-2.5)));
+        assert!(matches!(parse("withdrawal,1,1,0"), Err(PaymentEngineError::NonPositiveAmount(_))));
+        assert!(matches!(parse("amend,1,1,-0.0001"), Err(PaymentEngineError::NonPositiveAmount(_))));
+        assert!(matches!(parse("deposit,1,1,1.23456"), Err(PaymentEngineError::ExcessPrecision(a)) if a == dec!(1.23456)));
+        assert!(matches!(parse("deposit,1,1,"), Err(PaymentEngineError::MissingAmount(1))));
+        assert!(matches!(parse("dispute,1,1,5.0"), Err(PaymentEngineError::UnexpectedAmount(1))));
+        assert!(matches!(parse("Deposit,1,1,1.0"), Err(PaymentEngineError::InvalidTransactionType(_))));
+        assert!(matches!(parse("type,client,tx,amount"), Err(PaymentEngineError::InvalidTransactionType(_))));
+        assert_eq!(parse("deposit,1,1,-2.5").unwrap_err().field(), Some("amount"));
+
+        assert_eq!(Transaction::from_csv_line("amend;3;9;1.2345", ';').unwrap(), Transaction::amend(3, 9, dec!(1.2345)));
+        assert!(matches!(Transaction::from_csv_line("deposit;1;2;1,000.00", ';'), Err(PaymentEngineError::InvalidAmount(_))));
+    }
+}
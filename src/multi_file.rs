@@ -0,0 +1,194 @@
+use crate::diff::read_accounts_from_path;
+use crate::engine::PaymentEngine;
+use crate::error::{OptionsError, PaymentEngineError, Result};
+use crate::manifest::with_manifest;
+use crate::processor::{create_engine, process_records, write_run_outputs, LiveRun, ProcessingOptions, ProcessingReport, RecordSource};
+use futures::{StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Process several CSV files whose clients don't overlap, e.g. one file per
+/// region, each on its own task with its own engine, at most `concurrency`
+/// at a time. The engines are merged in the order of `paths` and the
+/// accounts written once, as
+/// [`process_transactions_with_options`](crate::process_transactions_with_options)
+/// does for one file.
+///
+/// Fails without writing any accounts if a client appears in more than one
+/// file. Options that carry state from one record to the whole input, such
+/// as a WAL, initial accounts or snapshots, can't be combined with it. The
+/// report adds up the counts of every file; its phase times are summed over
+/// the files, while `elapsed` is the wall-clock time of the whole run.
+pub async fn process_transaction_files_parallel(paths: &[PathBuf], options: ProcessingOptions, concurrency: usize) -> Result<ProcessingReport> {
+    options.validate()?;
+    check_options(&options, concurrency)?;
+    info!("Processing {} files, {} at a time, with batch size: {:?}", paths.len(), concurrency, options.batch_size_mode);
+    let input = paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(",");
+    let size = paths.iter().map(|path| std::fs::metadata(path).ok().map(|metadata| metadata.len())).sum();
+    with_manifest(&input, size, &options.clone(), async {
+        let start_time = options.clock.monotonic();
+        let expected = match options.expected_accounts.as_deref() {
+            Some(path) => Some((path, read_accounts_from_path(path)?)),
+            None => None,
+        };
+
+        let runs: Vec<(PaymentEngine, ProcessingReport)> = futures::stream::iter(paths.iter().cloned())
+            .map(|path| tokio::spawn(process_file(path, options.clone())))
+            .buffered(concurrency)
+            .map(|joined| joined?)
+            .try_collect()
+            .await?;
+        let (engine, mut report) = merge_runs(paths, runs, &options)?;
+
+        let duration = options.clock.elapsed(start_time);
+        report.elapsed = duration;
+        write_run_outputs(&engine, report, duration, expected, &options)
+    })
+    .await
+}
+
+/// Reject the options that need the whole input in one engine
+fn check_options(options: &ProcessingOptions, concurrency: usize) -> Result<(), OptionsError> {
+    if concurrency == 0 {
+        return Err(OptionsError::Zero("parallel_files"));
+    }
+    let conflicts = [
+        ("wal_path", options.wal_path.is_some()),
+        ("seen_tx_ids_path", options.seen_tx_ids_path.is_some()),
+        ("initial_accounts", options.initial_accounts.is_some()),
+        ("snapshot_every", options.snapshot_every.is_some()),
+        ("change_stream", options.change_stream.is_some()),
+        ("sample", options.sample.is_some()),
+        ("follow", options.follow),
+        ("dry_run", options.dry_run.is_some()),
+        #[cfg(feature = "http")]
+        ("serve_addr", options.serve_addr.is_some()),
+    ];
+    match conflicts.into_iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(OptionsError::Conflict("parallel_files", name)),
+        None => Ok(()),
+    }
+}
+
+/// Apply one file on an engine of its own
+async fn process_file(path: PathBuf, options: ProcessingOptions) -> Result<(PaymentEngine, ProcessingReport)> {
+    let run = LiveRun::new(create_engine(&options)?);
+    let source = RecordSource::open_lines(&path, &options).await?;
+    let report = process_records(source, &run, &options).await?;
+    info!("Processed {:?}: {} transactions", path, report.transactions_processed);
+    Ok((run.engine.into_inner(), report))
+}
+
+/// Merge the engines and reports of the files, failing on the lowest client
+/// found in two files
+fn merge_runs(paths: &[PathBuf], runs: Vec<(PaymentEngine, ProcessingReport)>, options: &ProcessingOptions) -> Result<(PaymentEngine, ProcessingReport)> {
+    let mut owners: HashMap<u16, usize> = HashMap::new();
+    let mut shared = Vec::new();
+    for (index, (engine, _)) in runs.iter().enumerate() {
+        for client in engine.clients() {
+            match owners.get(&client) {
+                Some(&owner) => shared.push((client, owner, index)),
+                None => {
+                    owners.insert(client, index);
+                }
+            }
+        }
+    }
+    if let Some((client, first, second)) = shared.into_iter().min() {
+        return Err(PaymentEngineError::MergeConflict(format!("client {} is in both {:?} and {:?}", client, paths[first], paths[second])));
+    }
+
+    let mut merged = create_engine(options)?;
+    let mut report = ProcessingReport::default();
+    for (engine, file_report) in runs {
+        merged.merge(engine)?;
+        report.absorb(file_report);
+    }
+    Ok((merged, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::OutputMode;
+    use std::fs::{read_to_string, write};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    // Split a CSV by client into `parts` files, client `c` going to part `c % parts`
+    fn split_by_client(input: &Path, dir: &Path, parts: u16) -> Vec<PathBuf> {
+        let content = read_to_string(input).unwrap();
+        let mut lines = content.lines();
+        let header = lines.next().unwrap();
+        let mut files = vec![format!("{}\n", header); parts as usize];
+        for line in lines {
+            let client: u16 = line.split(',').nth(1).unwrap().trim().parse().unwrap();
+            files[(client % parts) as usize].push_str(&format!("{}\n", line));
+        }
+        files
+            .into_iter()
+            .enumerate()
+            .map(|(part, content)| {
+                let path = dir.join(format!("part_{}.csv", part));
+                write(&path, content).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    // Output rows after the header, sorted
+    fn sorted_rows(path: &Path) -> Vec<String> {
+        let mut rows: Vec<String> = read_to_string(path).unwrap().lines().map(str::to_string).collect();
+        rows[1..].sort();
+        rows
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_split_files_match_sequential_run() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("all.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 3_000, clients: 30, seed: 9, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&input).unwrap()).unwrap();
+        let parts = split_by_client(&input, dir.path(), 3);
+        let output = |name: &str| dir.path().join(name);
+        let options = |name: &str| ProcessingOptions::builder().batch_size(64).output_mode(OutputMode::File(output(name))).build().unwrap();
+
+        let sequential = crate::process_transactions_with_options(&input, options("sequential.csv")).await.unwrap();
+        for concurrency in [1, 2, 3] {
+            let parallel = process_transaction_files_parallel(&parts, options("parallel.csv"), concurrency).await.unwrap();
+            assert_eq!(sorted_rows(&output("parallel.csv")), sorted_rows(&output("sequential.csv")), "{} at a time", concurrency);
+            let without_output = |report: &ProcessingReport| ProcessingReport { output_digest: None, ..report.clone().without_timings() };
+            assert_eq!(without_output(&parallel), without_output(&sequential));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_in_two_files_is_an_error() {
+        let dir = tempdir().unwrap();
+        let (east, west, output) = (dir.path().join("east.csv"), dir.path().join("west.csv"), dir.path().join("out.csv"));
+        write(&east, "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,7,2,1.0\n").unwrap();
+        write(&west, "type,client,tx,amount\ndeposit,2,3,5.0\nwithdrawal,7,4,1.0\n").unwrap();
+        let options = ProcessingOptions::builder().output_mode(OutputMode::File(output.clone())).build().unwrap();
+
+        let result = process_transaction_files_parallel(&[east.clone(), west.clone()], options, 2).await;
+        match result {
+            Err(PaymentEngineError::MergeConflict(message)) => {
+                assert_eq!(message, format!("client 7 is in both {:?} and {:?}", east, west));
+            }
+            other => panic!("Expected a merge conflict, got {:?}", other),
+        }
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn test_options_needing_one_engine_are_rejected() {
+        let paths = [PathBuf::from("a.csv")];
+        let result = process_transaction_files_parallel(&paths, ProcessingOptions::default(), 0).await;
+        assert!(matches!(result, Err(PaymentEngineError::InvalidOptions(OptionsError::Zero("parallel_files")))));
+
+        let options = ProcessingOptions::builder().wal_path(PathBuf::from("run.wal")).build().unwrap();
+        let result = process_transaction_files_parallel(&paths, options, 2).await;
+        assert!(matches!(result, Err(PaymentEngineError::InvalidOptions(OptionsError::Conflict("parallel_files", "wal_path")))));
+    }
+}
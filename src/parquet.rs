@@ -0,0 +1,350 @@
+use crate::error::{PaymentEngineError, Result};
+use crate::manifest::with_manifest;
+use crate::models::{AmountPrecision, Transaction, TxId, MAX_TX_ID};
+use crate::processor::{check_amount, parse_transaction_type, process_source, ProcessingOptions, ProcessingReport, RecordSource};
+use ::parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use ::parquet::arrow::ProjectionMask;
+use arrow_array::cast::AsArray;
+use arrow_array::types::Int64Type;
+use arrow_array::{Array, Decimal128Array, Int64Array, RecordBatch, StringArray};
+use arrow_cast::{can_cast_types, cast};
+use arrow_schema::{ArrowError, DataType};
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::path::Path;
+use tracing::info;
+
+// Columns read from the file; any others are skipped
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Largest scale of a `Decimal`; decimal columns with a larger scale are read as strings
+const MAX_DECIMAL_SCALE: i8 = 28;
+
+/// Process transactions from a Parquet file with `type`, `client`, `tx` and
+/// `amount` columns and output account balances, like
+/// [`process_transactions_with_options`](crate::process_transactions_with_options).
+///
+/// The file is read one record batch of the initial batch size at a time, so
+/// memory stays bounded by the batch rather than the row group or file. Ids
+/// may be any integer column, and amounts a decimal or string column.
+pub async fn process_transactions_parquet(file_path: &Path, options: ProcessingOptions) -> Result<ProcessingReport> {
+    options.validate()?;
+    info!("Processing Parquet transactions from: {:?} with batch size: {:?}", file_path, options.batch_size_mode);
+    let size = std::fs::metadata(file_path).ok().map(|metadata| metadata.len());
+    with_manifest(&file_path.display().to_string(), size, &options.clone(), async {
+        let rows = ParquetRows::open(file_path, &options)?;
+        process_source(RecordSource::Parquet(Box::new(rows)), options).await
+    })
+    .await
+}
+
+/// Rows of a Parquet file, converted to transactions one at a time
+pub(crate) struct ParquetRows {
+    reader: ParquetRecordBatchReader,
+    columns: Option<Columns>,
+    row: usize,
+    amount_precision: AmountPrecision,
+    partial_disputes: bool,
+}
+
+impl ParquetRows {
+    /// Open a Parquet file, checking that it has the transaction columns
+    pub(crate) fn open(file_path: &Path, options: &ProcessingOptions) -> Result<Self> {
+        let file = File::open(file_path)
+            .map_err(|source| PaymentEngineError::OpenFile { path: file_path.to_path_buf(), source })?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
+
+        let schema = builder.schema().clone();
+        let mut indices = Vec::with_capacity(COLUMNS.len());
+        for name in COLUMNS {
+            let index = schema.index_of(name)?;
+            let data_type = schema.field(index).data_type();
+            let target = if name == "type" || name == "amount" { DataType::Utf8 } else { DataType::Int64 };
+            if !can_cast_types(data_type, &target) {
+                return Err(ArrowError::SchemaError(format!("column {} has unsupported type {}", name, data_type)).into());
+            }
+            indices.push(index);
+        }
+        let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+        let reader = builder.with_projection(mask).with_batch_size(options.batch_size_mode.initial_size()).build()?;
+
+        Ok(Self {
+            reader,
+            columns: None,
+            row: 0,
+            amount_precision: options.amount_precision,
+            partial_disputes: options.allow_partial_disputes,
+        })
+    }
+
+    /// Convert the next row, the `row_number`th of the file. The outer error
+    /// is a record batch that could not be read, the inner one a row that
+    /// could not be converted.
+    pub(crate) fn next_record(&mut self, row_number: usize) -> Option<Result<Result<Transaction>>> {
+        loop {
+            if let Some(columns) = self.columns.as_ref().filter(|columns| self.row < columns.len) {
+                let row = self.row;
+                self.row += 1;
+                let transaction = columns
+                    .transaction(row, self.amount_precision, self.partial_disputes)
+                    .map_err(|e| PaymentEngineError::parse_error(row_number, &columns.render(row), e));
+                return Some(Ok(transaction));
+            }
+
+            let batch = match self.reader.next()? {
+                Ok(batch) => batch,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match Columns::new(&batch) {
+                Ok(columns) => {
+                    self.columns = Some(columns);
+                    self.row = 0;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Amount column: exact decimals with their scale, or anything else read as strings
+enum Amounts {
+    Decimal(Decimal128Array, u32),
+    Text(StringArray),
+}
+
+/// The columns of a record batch, cast to the types rows are converted from
+struct Columns {
+    kind: StringArray,
+    client: Int64Array,
+    tx: Int64Array,
+    amount: Amounts,
+    len: usize,
+}
+
+impl Columns {
+    fn new(batch: &RecordBatch) -> Result<Self> {
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| ArrowError::SchemaError(format!("missing column {}", name)))
+        };
+        let kind = cast(column("type")?, &DataType::Utf8)?.as_string::<i32>().clone();
+        let client = cast(column("client")?, &DataType::Int64)?.as_primitive::<Int64Type>().clone();
+        let tx = cast(column("tx")?, &DataType::Int64)?.as_primitive::<Int64Type>().clone();
+        let amount = column("amount")?;
+        let amount = match amount.data_type() {
+            DataType::Decimal128(_, scale) if (0..=MAX_DECIMAL_SCALE).contains(scale) => {
+                Amounts::Decimal(amount.as_primitive().clone(), *scale as u32)
+            }
+            _ => Amounts::Text(cast(amount, &DataType::Utf8)?.as_string::<i32>().clone()),
+        };
+        Ok(Self { kind, client, tx, amount, len: batch.num_rows() })
+    }
+
+    /// Convert a row into a transaction, with the same rules as a CSV line
+    fn transaction(&self, row: usize, amount_precision: AmountPrecision, partial_disputes: bool) -> Result<Transaction> {
+        let transaction_type = parse_transaction_type(self.kind_value(row))?;
+        let client: u16 = id("client", &self.client, row, u16::MAX.into())?;
+        let tx: TxId = id("tx", &self.tx, row, MAX_TX_ID)?;
+
+        let amount = match &self.amount {
+            Amounts::Decimal(amounts, _) if amounts.is_null(row) => None,
+            Amounts::Decimal(amounts, scale) => Some(
+                Decimal::try_from_i128_with_scale(amounts.value(row), *scale)
+                    .map_err(|_| PaymentEngineError::InvalidAmount(self.amount_value(row)))?,
+            ),
+            Amounts::Text(amounts) if amounts.is_null(row) || amounts.value(row).trim().is_empty() => None,
+            Amounts::Text(amounts) => {
+                let amount = amounts.value(row).trim();
+                Some(amount.parse().map_err(|_| PaymentEngineError::InvalidAmount(amount.to_string()))?)
+            }
+        };
+        let amount = match amount {
+            Some(amount) => Some(amount_precision.apply(amount).ok_or(PaymentEngineError::ExcessPrecision(amount))?),
+            None => None,
+        };
+        check_amount(transaction_type, tx, amount, partial_disputes)?;
+
+        Ok(Transaction { transaction_type, client, tx, amount, currency: None, timestamp: None })
+    }
+
+    fn kind_value(&self, row: usize) -> &str {
+        if self.kind.is_null(row) {
+            ""
+        } else {
+            self.kind.value(row).trim()
+        }
+    }
+
+    fn amount_value(&self, row: usize) -> String {
+        match &self.amount {
+            Amounts::Decimal(amounts, _) if amounts.is_valid(row) => amounts.value_as_string(row),
+            Amounts::Text(amounts) if amounts.is_valid(row) => amounts.value(row).to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// A row in CSV form, for error messages
+    fn render(&self, row: usize) -> String {
+        let id = |column: &Int64Array| if column.is_valid(row) { column.value(row).to_string() } else { String::new() };
+        format!("{},{},{},{}", self.kind_value(row), id(&self.client), id(&self.tx), self.amount_value(row))
+    }
+}
+
+/// Read an id column, rejecting nulls and negative values, and values above
+/// `max`, the largest of the id type, as the CSV parser does
+fn id<T: TryFrom<i64>>(field: &'static str, column: &Int64Array, row: usize, max: u64) -> Result<T> {
+    if !column.is_valid(row) {
+        return Err(PaymentEngineError::InvalidId { field, value: "null".to_string() });
+    }
+    let value = column.value(row);
+    T::try_from(value).map_err(|_| match value {
+        1.. => PaymentEngineError::IdOverflow { field, value: value.to_string(), max },
+        _ => PaymentEngineError::InvalidId { field, value: value.to_string() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::processor::{process_live_run, process_records, BatchSizeMode, LiveRun};
+    use ::parquet::arrow::ArrowWriter;
+    use ::parquet::file::properties::WriterProperties;
+    use arrow_array::{ArrayRef, UInt16Array, UInt32Array};
+    use arrow_schema::{Field, Schema};
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    const ROWS: [(&str, u16, u32, Option<&str>); 7] = [
+        ("deposit", 1, 1, Some("100.5")),
+        ("deposit", 2, 2, Some("200.25")),
+        ("withdrawal", 1, 3, Some("50.1234")),
+        ("dispute", 2, 2, None),
+        ("withdrawal", 2, 4, Some("1.5")),
+        ("deposit", 3, 5, Some("10")),
+        ("chargeback", 2, 2, None),
+    ];
+
+    // Write a Parquet file with small row groups, the amount column as given
+    fn write_parquet(path: &Path, columns: Vec<(&str, ArrayRef)>) {
+        let fields: Vec<Field> = columns.iter().map(|(name, array)| Field::new(*name, array.data_type().clone(), true)).collect();
+        let schema = Arc::new(Schema::new(fields));
+        let batch = RecordBatch::try_new(schema.clone(), columns.into_iter().map(|(_, array)| array).collect()).unwrap();
+        let properties = WriterProperties::builder().set_max_row_group_size(3).build();
+        let mut writer = ArrowWriter::try_new(File::create(path).unwrap(), schema, Some(properties)).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+    }
+
+    fn id_columns() -> Vec<(&'static str, ArrayRef)> {
+        vec![
+            ("type", Arc::new(StringArray::from_iter_values(ROWS.iter().map(|r| r.0)))),
+            ("client", Arc::new(UInt16Array::from_iter_values(ROWS.iter().map(|r| r.1)))),
+            ("tx", Arc::new(UInt32Array::from_iter_values(ROWS.iter().map(|r| r.2)))),
+        ]
+    }
+
+    async fn run_parquet(path: &Path, options: &ProcessingOptions) -> (ProcessingReport, PaymentEngine) {
+        let run = LiveRun::new(PaymentEngine::new());
+        let rows = ParquetRows::open(path, options).unwrap();
+        let report = process_records(RecordSource::Parquet(Box::new(rows)), &run, options).await.unwrap();
+        (report, run.engine.into_inner())
+    }
+
+    fn balances(engine: &PaymentEngine) -> Vec<(u16, Decimal, Decimal, Decimal, bool)> {
+        let mut accounts: Vec<_> =
+            engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+        accounts.sort_by_key(|a| a.0);
+        accounts
+    }
+
+    #[tokio::test]
+    async fn test_parquet_matches_csv() {
+        let dir = tempdir().unwrap();
+        let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(2), ..Default::default() };
+
+        let csv_path = dir.path().join("transactions.csv");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for (kind, client, tx, amount) in ROWS {
+            csv.push_str(&format!("{},{},{},{}\n", kind, client, tx, amount.unwrap_or_default()));
+        }
+        std::fs::write(&csv_path, csv).unwrap();
+        let run = LiveRun::new(PaymentEngine::new());
+        let csv_report = process_live_run(&csv_path, &run, &options).await.unwrap().without_timings();
+        let expected = balances(&run.engine.into_inner());
+
+        // Exact decimal amounts
+        let decimal_path = dir.path().join("decimal.parquet");
+        let amounts = ROWS.iter().map(|r| r.3.map(|a| {
+            let mut amount = a.parse::<Decimal>().unwrap();
+            amount.rescale(4);
+            amount.mantissa()
+        }));
+        let amounts = Decimal128Array::from_iter(amounts).with_precision_and_scale(18, 4).unwrap();
+        let mut columns = id_columns();
+        columns.push(("amount", Arc::new(amounts)));
+        write_parquet(&decimal_path, columns);
+
+        // String amounts
+        let string_path = dir.path().join("string.parquet");
+        let mut columns = id_columns();
+        columns.push(("amount", Arc::new(StringArray::from_iter(ROWS.iter().map(|r| r.3)))));
+        write_parquet(&string_path, columns);
+
+        for path in [&decimal_path, &string_path] {
+            let (report, engine) = run_parquet(path, &options).await;
+            assert_eq!(report.without_timings(), csv_report);
+            assert_eq!(balances(&engine), expected);
+        }
+        assert_eq!(expected[0], (1, dec!(50.3766), dec!(0), dec!(50.3766), false));
+        assert!(expected[1].4);
+    }
+
+    #[tokio::test]
+    async fn test_parquet_row_errors_name_row_and_field() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("bad.parquet");
+        write_parquet(
+            &path,
+            vec![
+                ("type", Arc::new(StringArray::from(vec!["deposit", "deposit", "deposit", "refund"]))),
+                ("client", Arc::new(Int64Array::from(vec![1, 70000, 1, 1]))),
+                ("tx", Arc::new(Int64Array::from(vec![1, 2, 3, 4]))),
+                ("amount", Arc::new(StringArray::from(vec!["1.0", "2.0", "0.12345", "4.0"]))),
+            ],
+        );
+
+        let options = ProcessingOptions { amount_precision: AmountPrecision::Reject, ..Default::default() };
+        let mut rows = ParquetRows::open(&path, &options).unwrap();
+        assert_eq!(rows.next_record(1).unwrap().unwrap().unwrap().amount, Some(dec!(1.0)));
+
+        let err = rows.next_record(2).unwrap().unwrap().unwrap_err();
+        assert_eq!(err.field(), Some("client"));
+        assert_eq!(err.to_string(), "Failed to parse transaction on line 2 (\"deposit,70000,2,2.0\"): Invalid client: 70000 is larger than the maximum of 65535");
+        assert_eq!(rows.next_record(3).unwrap().unwrap().unwrap_err().field(), Some("amount"));
+        assert_eq!(rows.next_record(4).unwrap().unwrap().unwrap_err().field(), Some("type"));
+        assert!(rows.next_record(5).is_none());
+
+        let (report, engine) = run_parquet(&path, &options).await;
+        assert_eq!((report.lines_read, report.parse_errors), (4, 3));
+        assert_eq!(balances(&engine), [(1, dec!(1), dec!(0), dec!(1), false)]);
+    }
+
+    #[test]
+    fn test_parquet_requires_transaction_columns() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("no_amount.parquet");
+        let columns = id_columns();
+        write_parquet(&path, columns);
+
+        let err = ParquetRows::open(&path, &ProcessingOptions::default()).err().unwrap();
+        assert!(matches!(err, PaymentEngineError::Arrow(_)));
+        assert!(err.to_string().contains("amount"));
+        assert!(matches!(
+            ParquetRows::open(&dir.path().join("missing.parquet"), &ProcessingOptions::default()),
+            Err(PaymentEngineError::OpenFile { .. })
+        ));
+    }
+}
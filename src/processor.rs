@@ -1,410 +1,6376 @@
+use crate::diff::{diff_accounts, read_accounts_from_path, AccountsDiff};
 use crate::engine::PaymentEngine;
-use crate::models::Transaction;
-use anyhow::Result;
-use csv::Writer;
-use futures::stream::StreamExt;
-use std::path::Path;
-use std::time::Instant;
-use std::io::Write;
-use tokio::fs::File;
-use tokio::io::{AsyncRead, BufReader};
-use tokio_stream::wrappers::LinesStream;
-use tracing::{error, info};
+use crate::error::{OptionsError, PaymentEngineError, Result};
+use crate::events::EngineEvent;
+use crate::history::TraceFormat;
+use crate::clock::{Clock, SystemClock};
+use crate::input::{FixedWidthField, FixedWidthSpec, InputEncoding, InputFormat, InputMode, LineSource};
+use crate::manifest::{with_manifest, DigestWriter, OutputDigest};
+use crate::models::{
+    decimal_string, Account, AccountDelta, AccountStats, AccountStore, AmountPrecision, Balance, Currency, DisputeClientCheck, IdMap, IdSet, Limits, PurgePolicy, Transaction, TransactionStore, TransactionType,
+    TxId, WithdrawalFee, MAX_TX_ID,
+};
+use crate::seen::SeenTransactions;
+use crate::snapshot::{SnapshotEvery, SnapshotWriter};
+use crate::wal::{replay_wal, RecoveredTransactions, WalOutcome, WalWriter};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use futures::FutureExt;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::num::{IntErrorKind, ParseIntError};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use std::io::{BufWriter, Write};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::panic::AssertUnwindSafe;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
 
 // Default batch size for transaction processing
 const DEFAULT_BATCH_SIZE: usize = 1000;
 
-/// Processing options for transaction handling
-pub struct ProcessingOptions {
-    /// Batch size for processing transactions
-    pub batch_size: usize,
+// Default maximum length of an input line in bytes
+const DEFAULT_MAX_LINE_LENGTH: usize = 4096;
+
+// Default number of parsed batches queued ahead of the engine
+const DEFAULT_PIPELINE_DEPTH: usize = 2;
+
+// Fixed batches smaller than this are read and applied in turn even with a
+// pipeline depth, as handing each one to the engine task costs more than
+// reading ahead saves
+const MIN_PIPELINED_BATCH_SIZE: usize = 16;
+
+// One in this many records is timed to split reading time between reading
+// and parsing lines, keeping the timing overhead off most records
+const TIMING_SAMPLE_INTERVAL: usize = 64;
+
+// Capacity of the buffer account rows are written through
+const OUTPUT_BUFFER_SIZE: usize = 1 << 20;
+
+// Threads writing per-client files, each with one file open at a time
+const PER_CLIENT_WRITERS: usize = 16;
+
+// Default path template of account snapshots
+const DEFAULT_SNAPSHOT_PATH_TEMPLATE: &str = "snapshot_{seq}.csv";
+
+// Default line ending a followed input
+const DEFAULT_END_MARKER: &str = "#EOF";
+
+// Default currency of transactions without one in multi-currency mode
+const DEFAULT_BASE_CURRENCY: &str = "USD";
+
+/// How many transactions go into each batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchSizeMode {
+    /// Every batch holds this many transactions
+    Fixed(usize),
+    /// Start at the default batch size clamped to `min..=max`, then size each
+    /// batch so that applying it takes about `target_batch_ms`, going by the
+    /// apply time of the batches before it
+    Auto { min: usize, max: usize, target_batch_ms: u64 },
 }
 
-impl Default for ProcessingOptions {
+impl Default for BatchSizeMode {
     fn default() -> Self {
+        Self::Fixed(DEFAULT_BATCH_SIZE)
+    }
+}
+
+impl BatchSizeMode {
+    /// Size of the first batch
+    pub fn initial_size(&self) -> usize {
+        match *self {
+            Self::Fixed(size) => size,
+            Self::Auto { min, max, .. } => DEFAULT_BATCH_SIZE.min(max).max(min),
+        }
+    }
+
+    /// Size of the batch after one of `current` that applied `applied`
+    /// transactions in `elapsed`. Auto mode moves toward the size that would
+    /// take the target time at the observed rate, at most halving or doubling
+    /// per batch so a single slow or fast batch doesn't swing it.
+    fn next_size(&self, current: usize, applied: usize, elapsed: Duration) -> usize {
+        let Self::Auto { min, max, target_batch_ms } = *self else { return current };
+        let target = Duration::from_millis(target_batch_ms).as_secs_f64();
+        let ideal = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => (applied as f64 * target / secs) as usize,
+            _ => usize::MAX,
+        };
+        ideal.clamp(current / 2, current.saturating_mul(2)).clamp(min, max)
+    }
+}
+
+/// Which clients' transactions to apply; the others are skipped after parsing
+#[derive(Clone)]
+pub enum ClientFilter {
+    /// Only these clients
+    Set(BTreeSet<u16>),
+    /// Only the clients in this range
+    Range(RangeInclusive<u16>),
+    /// Only the clients this function accepts
+    Predicate(Arc<dyn Fn(u16) -> bool + Send + Sync>),
+}
+
+impl ClientFilter {
+    /// Filter accepting the clients `predicate` returns true for
+    pub fn predicate(predicate: impl Fn(u16) -> bool + Send + Sync + 'static) -> Self {
+        Self::Predicate(Arc::new(predicate))
+    }
+
+    /// Whether the transactions of `client` are applied
+    pub fn matches(&self, client: u16) -> bool {
+        match self {
+            Self::Set(clients) => clients.contains(&client),
+            Self::Range(range) => range.contains(&client),
+            Self::Predicate(predicate) => predicate(client),
+        }
+    }
+}
+
+impl From<u16> for ClientFilter {
+    fn from(client: u16) -> Self {
+        Self::Set(BTreeSet::from([client]))
+    }
+}
+
+impl From<RangeInclusive<u16>> for ClientFilter {
+    fn from(range: RangeInclusive<u16>) -> Self {
+        Self::Range(range)
+    }
+}
+
+impl FromIterator<u16> for ClientFilter {
+    fn from_iter<I: IntoIterator<Item = u16>>(clients: I) -> Self {
+        Self::Set(clients.into_iter().collect())
+    }
+}
+
+impl fmt::Debug for ClientFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set(clients) => f.debug_tuple("Set").field(clients).finish(),
+            Self::Range(range) => f.debug_tuple("Range").field(range).finish(),
+            Self::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+/// Predicates are equal only if they are the same function
+impl PartialEq for ClientFilter {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Set(a), Self::Set(b)) => a == b,
+            (Self::Range(a), Self::Range(b)) => a == b,
+            (Self::Predicate(a), Self::Predicate(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Deterministic sample of the clients, to estimate the load of a full run
+/// from a fraction of it. Whether a client is in the sample depends only on
+/// the seed and the client id, so a client's transactions, disputes
+/// included, are either all applied or all skipped, and runs with the same
+/// seed sample the same clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientSample {
+    /// Fraction of the clients to apply, above 0 and at most 1
+    pub rate: f64,
+    pub seed: u64,
+}
+
+impl ClientSample {
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Self { rate, seed }
+    }
+
+    /// Whether the transactions of `client` are applied
+    pub fn contains(&self, client: u16) -> bool {
+        // SplitMix64 finalizer, spreading neighbouring ids over the whole range
+        let mut z = self.seed ^ u64::from(client).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+/// Totals of a sampled run scaled up by the sample rate, an estimate of
+/// what the full run would have applied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SampleEstimate {
+    pub transactions_processed: usize,
+    #[serde(with = "decimal_string")]
+    pub deposited_total: Decimal,
+    #[serde(with = "decimal_string")]
+    pub withdrawn_total: Decimal,
+    pub apply_time: Duration,
+}
+
+impl SampleEstimate {
+    /// Extrapolate the report of a run that applied `rate` of the clients
+    fn extrapolate(report: &ProcessingReport, rate: f64) -> Self {
+        let scale = |amount: Decimal| Decimal::try_from(rate).ok().and_then(|rate| amount.checked_div(rate)).unwrap_or(Decimal::MAX);
         Self {
-            batch_size: DEFAULT_BATCH_SIZE,
+            transactions_processed: (report.transactions_processed as f64 / rate).round() as usize,
+            deposited_total: scale(report.deposited_total),
+            withdrawn_total: scale(report.withdrawn_total),
+            apply_time: report.apply_time.div_f64(rate),
         }
     }
 }
 
-/// Process transactions from a CSV file and output account balances
-pub async fn process_transactions(file_path: &Path) -> Result<()> {
-    // Use default options
-    process_transactions_with_options(file_path, ProcessingOptions::default()).await
+/// Function every parsed transaction goes through before it is batched: it
+/// returns the transaction to apply in its place, or `None` to drop it, e.g.
+/// to remap client ids after a migration or rescale amounts.
+///
+/// The hook only ever sees one owned transaction and can't reach the batches,
+/// which are built from what it returns. A returned amount with more decimal
+/// places than supported is brought to precision with the run's
+/// `amount_precision`, and the engine validates the rest as it does for
+/// parsed input. With a WAL the hook has to return the same type and tx id
+/// for a transaction on every run: recovery skips the transactions whose type
+/// and tx id were logged, matched against what the hook returns, so a hook
+/// that changed them on the restart would apply a logged transaction twice,
+/// or skip one the interrupted run never applied.
+#[derive(Clone)]
+pub struct TransactionHook(Arc<dyn Fn(Transaction) -> Option<Transaction> + Send + Sync>);
+
+impl TransactionHook {
+    pub fn new(hook: impl Fn(Transaction) -> Option<Transaction> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    /// Run the hook on a transaction
+    pub fn apply(&self, transaction: Transaction) -> Option<Transaction> {
+        (self.0)(transaction)
+    }
 }
 
-/// Process transactions from a CSV file with custom options
-pub async fn process_transactions_with_options(file_path: &Path, options: ProcessingOptions) -> Result<()> {
-    info!("Processing transactions from: {:?} with batch size: {}", file_path, options.batch_size);
-    
-    // Track processing time
-    let start_time = Instant::now();
-    
-    // Create a new payment engine
-    let mut engine = PaymentEngine::new();
-    
-    // Process transactions in streaming fashion
-    process_transactions_stream(file_path, &mut engine, options.batch_size).await?;
-    
-    // Calculate elapsed time
-    let duration = start_time.elapsed();
-    
-    // Write results to stdout (with duration at the top)
-    write_account_balances(&engine, duration)?;
-    
-    Ok(())
+impl fmt::Debug for TransactionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TransactionHook(..)")
+    }
 }
 
-/// Process transactions from a CSV file as a stream
-async fn process_transactions_stream(file_path: &Path, engine: &mut PaymentEngine, batch_size: usize) -> Result<()> {
-    // Open the file
-    let file = File::open(file_path).await?;
-    let reader = BufReader::new(file);
-    
-    // Create a stream of CSV lines
-    let lines_stream = create_csv_line_stream(reader);
-    
-    // Skip the header line
-    let mut lines = lines_stream.skip(1);
-    
-    // Process transactions in batches
-    let mut line_count = 0;
-    let mut batch = Vec::with_capacity(batch_size);
-    
-    while let Some(line_result) = lines.next().await {
-        match line_result {
-            Ok(line) => {
-                line_count += 1;
-                
-                // Parse the transaction
-                match parse_transaction(&line) {
-                    Ok(transaction) => {
-                        // Add to batch
-                        batch.push(transaction);
-                        
-                        // Process batch if it reaches the specified size
-                        if batch.len() >= batch_size {
-                            if let Err(e) = engine.process_transaction_batch(&mut batch).await {
-                                error!("Failed to process transaction batch: {}", e);
-                            }
-                            // Clear the batch for next iterations
-                            batch.clear();
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to parse transaction on line {}: {}", line_count, e);
-                    }
+/// Parses a comma-separated list of clients and inclusive ranges, e.g.
+/// `1,5,100-200`. A single range becomes a [`ClientFilter::Range`], anything
+/// else the [`ClientFilter::Set`] of every listed client.
+impl FromStr for ClientFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_client = |client: &str| client.trim().parse::<u16>().map_err(|_| format!("Invalid client id: {}", client.trim()));
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, end)) => parse_client(start)?..=parse_client(end)?,
+                None => {
+                    let client = parse_client(part)?;
+                    client..=client
                 }
+            };
+            if range.is_empty() {
+                return Err(format!("Invalid client range: {}", part.trim()));
             }
-            Err(e) => {
-                error!("Error reading line {}: {}", line_count + 1, e);
-            }
+            ranges.push(range);
+        }
+        match ranges.as_slice() {
+            [range] if range.start() != range.end() => Ok(Self::Range(range.clone())),
+            _ => Ok(ranges.into_iter().flatten().collect()),
         }
     }
-    
-    // Process any remaining transactions in the last batch
-    if !batch.is_empty() {
-        if let Err(e) = engine.process_transaction_batch(&mut batch).await {
-            error!("Failed to process final transaction batch: {}", e);
+}
+
+/// A column of the account output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColumn {
+    Client,
+    /// Currency of the row's balances; accounts holding several currencies
+    /// get one row per currency whether or not this column is written
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    /// What locked the account, empty if it isn't locked
+    LockReason,
+    DisputesOpened,
+    DisputesResolved,
+    DisputesChargedBack,
+    /// Funds removed from the row's balance by chargebacks of deposits
+    ChargebackTotal,
+    /// Sum of the deposits applied to the row's balance
+    DepositedTotal,
+    /// Sum of the withdrawals applied to the row's balance, without fees
+    WithdrawnTotal,
+    /// Space-separated ids of the transactions currently held
+    HeldTransactions,
+}
+
+impl OutputColumn {
+    /// Every column, in the order of the extended multi-currency layout
+    pub const ALL: [OutputColumn; 14] = [
+        Self::Client,
+        Self::Currency,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+        Self::LockReason,
+        Self::DisputesOpened,
+        Self::DisputesResolved,
+        Self::DisputesChargedBack,
+        Self::ChargebackTotal,
+        Self::DepositedTotal,
+        Self::WithdrawnTotal,
+        Self::HeldTransactions,
+    ];
+
+    /// Name of the column, as written in the default header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Currency => "currency",
+            Self::Available => "available",
+            Self::Held => "held",
+            Self::Total => "total",
+            Self::Locked => "locked",
+            Self::LockReason => "lock_reason",
+            Self::DisputesOpened => "disputes_opened",
+            Self::DisputesResolved => "disputes_resolved",
+            Self::DisputesChargedBack => "disputes_charged_back",
+            Self::ChargebackTotal => "chargeback_total",
+            Self::DepositedTotal => "deposited_total",
+            Self::WithdrawnTotal => "withdrawn_total",
+            Self::HeldTransactions => "held_transactions",
         }
     }
-    
-    info!("Processed {} transactions", line_count);
-    
-    Ok(())
+
+    fn needs_stats(&self) -> bool {
+        matches!(self, Self::DisputesOpened | Self::DisputesResolved | Self::DisputesChargedBack | Self::HeldTransactions)
+    }
 }
 
-/// Create a stream of CSV lines from a reader
-fn create_csv_line_stream<R: AsyncRead + Unpin + 'static>(
-    reader: BufReader<R>,
-) -> impl futures::Stream<Item = Result<String, std::io::Error>> {
-    LinesStream::new(tokio::io::AsyncBufReadExt::lines(reader))
+impl FromStr for OutputColumn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|column| column.as_str() == s)
+            .ok_or_else(|| format!("Invalid output column: {}", s))
+    }
 }
 
-/// Parse a CSV line into a Transaction
-fn parse_transaction(line: &str) -> Result<Transaction> {
-    // Split the line by commas
-    let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
-    
-    // Ensure we have the required fields (type, client, tx, [amount])
-    if parts.len() < 3 {
-        anyhow::bail!("Invalid CSV line format: {}", line);
+/// Columns of the account output in order, each with the name written in the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputColumns(pub Vec<(OutputColumn, String)>);
+
+impl OutputColumns {
+    /// Layout used without explicit columns: `client`, `currency` if any
+    /// account holds a currency other than the base currency, the balances
+    /// and `locked`, then the extended columns if enabled
+    fn layout(multi_currency: bool, extended: bool) -> Self {
+        OutputColumn::ALL
+            .into_iter()
+            .filter(|column| match column {
+                OutputColumn::Currency => multi_currency,
+                OutputColumn::LockReason
+                | OutputColumn::ChargebackTotal
+                | OutputColumn::DepositedTotal
+                | OutputColumn::WithdrawnTotal => extended,
+                column => extended || !column.needs_stats(),
+            })
+            .collect()
+    }
+
+    /// Check that there is at least one column, that no column repeats, and
+    /// that every header name is non-empty and needs no CSV quoting
+    fn validate(&self) -> Result<(), OptionsError> {
+        let invalid = |reason: String| Err(OptionsError::OutputColumns(reason));
+        if self.0.is_empty() {
+            return invalid("must not be empty".to_string());
+        }
+        for (i, (column, header)) in self.0.iter().enumerate() {
+            if self.0[..i].iter().any(|(other, _)| other == column) {
+                return invalid(format!("lists {} more than once", column.as_str()));
+            }
+            if header.is_empty() || header.contains([',', '"', '\n', '\r']) {
+                return invalid(format!("has an invalid header {:?} for {}", header, column.as_str()));
+            }
+        }
+        Ok(())
     }
-    
-    // Parse the CSV fields
-    let transaction_type = match parts[0] {
-        "deposit" => crate::models::TransactionType::Deposit,
-        "withdrawal" => crate::models::TransactionType::Withdrawal,
-        "dispute" => crate::models::TransactionType::Dispute,
-        "resolve" => crate::models::TransactionType::Resolve,
-        "chargeback" => crate::models::TransactionType::Chargeback,
-        _ => anyhow::bail!("Invalid transaction type: {}", parts[0]),
-    };
-    
-    let client: u16 = parts[1].parse()?;
-    let tx: u32 = parts[2].parse()?;
-    
-    // Amount is optional (not present for dispute, resolve, chargeback)
-    let amount = if parts.len() > 3 && !parts[3].is_empty() {
-        Some(parts[3].parse()?)
-    } else {
-        None
-    };
-    
-    Ok(Transaction {
-        transaction_type,
-        client,
-        tx,
-        amount,
-    })
 }
 
-/// Write account balances to stdout as CSV
-fn write_account_balances(engine: &PaymentEngine, duration: std::time::Duration) -> Result<()> {
-    let accounts = engine.get_accounts();
-    
-    // Create a CSV writer to stdout
-    let mut writer = Writer::from_writer(std::io::stdout());
-    
-    // Write the processing time as a comment at the top of the CSV
-    writeln!(
-        std::io::stdout(),
-        "# Processing completed in {:.2?}",
-        duration
-    )?;
-    
-    // Format accounts to ensure 4 decimal places for monetary values
-    for mut account in accounts {
-        // Scale to 4 decimal places
-        account.available = account.available.round_dp(4);
-        account.held = account.held.round_dp(4);
-        account.total = account.total.round_dp(4);
-        
-        // Serialize to CSV
-        writer.serialize(account)?;
+/// Columns with their own names as headers
+impl FromIterator<OutputColumn> for OutputColumns {
+    fn from_iter<I: IntoIterator<Item = OutputColumn>>(columns: I) -> Self {
+        Self(columns.into_iter().map(|column| (column, column.as_str().to_string())).collect())
     }
-    
-    writer.flush()?;
-    
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::models::{TransactionType};
-    use rust_decimal_macros::dec;
-    use tempfile::tempdir;
-    use std::fs::write;
-    
-    #[test]
-    fn test_parse_transaction_deposit() {
-        let line = "deposit,1,1,100.50";
-        let tx = parse_transaction(line).unwrap();
-        
-        assert_eq!(tx.transaction_type, TransactionType::Deposit);
-        assert_eq!(tx.client, 1);
-        assert_eq!(tx.tx, 1);
-        assert_eq!(tx.amount, Some(dec!(100.50)));
+/// Parses a comma-separated list of column names, each optionally renamed in
+/// the header with `=`, e.g. `client,total=balance`
+impl FromStr for OutputColumns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|spec| {
+                let (name, header) = spec.split_once('=').unwrap_or((spec, spec));
+                Ok((name.trim().parse()?, header.trim().to_string()))
+            })
+            .collect::<Result<_, String>>()
+            .map(Self)
     }
-    
-    #[test]
-    fn test_parse_transaction_withdrawal() {
-        let line = "withdrawal,2,5,20.75";
-        let tx = parse_transaction(line).unwrap();
-        
-        assert_eq!(tx.transaction_type, TransactionType::Withdrawal);
-        assert_eq!(tx.client, 2);
-        assert_eq!(tx.tx, 5);
-        assert_eq!(tx.amount, Some(dec!(20.75)));
+}
+
+/// Placeholder in a per-client output path template replaced by the client id
+pub const CLIENT_PLACEHOLDER: &str = "{id}";
+
+/// Where the accounts are written at the end of a run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum OutputMode {
+    /// One CSV on stdout, or the partition files if `output_partitions` is set
+    #[default]
+    Stdout,
+    /// One file per client at this path template, such as
+    /// `out/client_{id}.csv`, with `{id}` replaced by the client id. The
+    /// placeholder must be in the file name, and `..` isn't allowed.
+    PerClientDir(String),
+    /// One CSV at this path instead of stdout, gzip-compressed if the path
+    /// ends in `.gz` or `compress_output` is set
+    File(PathBuf),
+}
+
+/// What the output does with closed accounts, those locked with a total of
+/// zero, see [`Account::is_closed`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum ClosedAccountHandling {
+    /// Write them with the other accounts
+    #[default]
+    IncludeInOutput,
+    /// Write them to this CSV instead, with the same columns as the output
+    SeparateFile(PathBuf),
+    /// Leave them out of the output
+    Exclude,
+}
+
+/// Where the accounts each batch changed are streamed, see
+/// [`ProcessingOptions::change_stream`]. Accounts are written as JSON lines.
+#[derive(Clone)]
+pub enum ChangeStream {
+    /// JSON lines written to this file, replacing it
+    File(PathBuf),
+    /// JSON lines written to this writer, flushed after each batch
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    /// One message per account; sending never blocks processing, and
+    /// messages are dropped once the receiver is closed
+    Channel(UnboundedSender<AccountDelta>),
+}
+
+impl fmt::Debug for ChangeStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Writer(_) => f.write_str("Writer(..)"),
+            Self::Channel(sender) => f.debug_tuple("Channel").field(sender).finish(),
+        }
     }
-    
-    #[test]
-    fn test_parse_transaction_dispute() {
-        let line = "dispute,1,10,";
-        let tx = parse_transaction(line).unwrap();
-        
-        assert_eq!(tx.transaction_type, TransactionType::Dispute);
-        assert_eq!(tx.client, 1);
-        assert_eq!(tx.tx, 10);
-        assert_eq!(tx.amount, None);
+}
+
+impl ChangeStream {
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        Self::Writer(Arc::new(Mutex::new(writer)))
     }
-    
-    #[test]
-    fn test_parse_transaction_resolve() {
-        let line = "resolve,3,15";
-        let tx = parse_transaction(line).unwrap();
-        
-        assert_eq!(tx.transaction_type, TransactionType::Resolve);
-        assert_eq!(tx.client, 3);
-        assert_eq!(tx.tx, 15);
-        assert_eq!(tx.amount, None);
+
+    /// Send the accounts one batch changed
+    fn send(&self, deltas: &[AccountDelta]) -> Result<()> {
+        match self {
+            Self::File(_) => unreachable!("change stream files are opened before the first batch"),
+            Self::Writer(writer) => {
+                let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+                for delta in deltas {
+                    serde_json::to_writer(&mut *writer, delta).map_err(std::io::Error::from)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+            }
+            Self::Channel(sender) => {
+                for delta in deltas {
+                    let _ = sender.send(delta.clone());
+                }
+            }
+        }
+        Ok(())
     }
-    
-    #[test]
-    fn test_parse_transaction_chargeback() {
-        let line = "chargeback,4,20";
-        let tx = parse_transaction(line).unwrap();
-        
-        assert_eq!(tx.transaction_type, TransactionType::Chargeback);
-        assert_eq!(tx.client, 4);
-        assert_eq!(tx.tx, 20);
-        assert_eq!(tx.amount, None);
+}
+
+/// Change stream of a run, with the number of batches applied so far
+struct ChangeSink {
+    stream: ChangeStream,
+    batches: u64,
+}
+
+impl ChangeSink {
+    /// Open a change stream file, so a bad path fails the run before any batch
+    fn open(stream: &ChangeStream) -> Result<Self> {
+        let stream = match stream {
+            ChangeStream::File(path) => {
+                let file = std::fs::File::create(path).map_err(|source| PaymentEngineError::OpenFile { path: path.clone(), source })?;
+                ChangeStream::writer(BufWriter::new(file))
+            }
+            stream => stream.clone(),
+        };
+        Ok(Self { stream, batches: 0 })
     }
-    
-    #[test]
-    fn test_parse_transaction_invalid_type() {
-        let line = "unknown,1,1,100";
-        let result = parse_transaction(line);
-        assert!(result.is_err());
+
+    /// Send the accounts changed since the last batch, as they stand now
+    fn after_batch(&mut self, engine: &mut PaymentEngine) -> Result<()> {
+        self.batches += 1;
+        let clients = engine.take_changed_clients();
+        let deltas: Vec<AccountDelta> =
+            clients.into_iter().filter_map(|client| engine.get_account(client)).map(|account| AccountDelta::new(self.batches, account)).collect();
+        self.stream.send(&deltas)
     }
-    
-    #[test]
-    fn test_parse_transaction_invalid_format() {
-        let line = "deposit,1";
-        let result = parse_transaction(line);
-        assert!(result.is_err());
+}
+
+/// Path of a client's file under a per-client output path template
+pub fn client_output_path(template: &str, client: u16) -> PathBuf {
+    PathBuf::from(template.replace(CLIENT_PLACEHOLDER, &client.to_string()))
+}
+
+/// Check that a per-client template puts every client in its own file in one
+/// directory, which can't be above the template's own
+fn validate_client_template(template: &str) -> Result<(), OptionsError> {
+    let invalid = |reason| Err(OptionsError::ClientTemplate { template: template.to_string(), reason });
+    let path = Path::new(template);
+    if path.components().any(|component| component == std::path::Component::ParentDir) {
+        return invalid("must not contain ..");
     }
-    
-    #[test]
-    fn test_parse_transaction_invalid_client() {
-        let line = "deposit,abc,1,100";
-        let result = parse_transaction(line);
-        assert!(result.is_err());
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !file_name.contains(CLIENT_PLACEHOLDER) {
+        return invalid("needs {id} in the file name");
     }
-    
-    #[test]
-    fn test_parse_transaction_invalid_tx() {
-        let line = "deposit,1,abc,100";
-        let result = parse_transaction(line);
-        assert!(result.is_err());
+    if path.parent().is_some_and(|parent| parent.to_string_lossy().contains(CLIENT_PLACEHOLDER)) {
+        return invalid("may only have {id} in the file name");
     }
-    
-    #[test]
-    fn test_parse_transaction_invalid_amount() {
-        let line = "deposit,1,1,abc";
-        let result = parse_transaction(line);
-        assert!(result.is_err());
+    Ok(())
+}
+
+/// Where a run stops reading its input early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopAfter {
+    /// After the first record with this transaction id, whichever client
+    /// it belongs to and even if it is filtered out or rejected
+    Tx(TxId),
+    /// After this input line, numbered from 1 with the header as line 1
+    Line(usize),
+}
+
+/// How much a run writes to stderr besides the account CSV on stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Nothing; errors only show in the exit status and the log
+    Quiet,
+    /// The processing time and any summaries asked for
+    #[default]
+    Normal,
+    /// Also a progress line per batch and a summary of the run's counts
+    Verbose,
+}
+
+/// Bundle of the policies that decide how strictly the input is read and
+/// which disputes are honoured, applied with [`ProcessingOptionsBuilder::preset`]
+///
+/// | Option                      | `StrictSpec`   | `Lenient`          |
+/// |-----------------------------|----------------|--------------------|
+/// | `amount_precision`          | `Reject`       | `RoundHalfEven`    |
+/// | `lenient_amounts`           | `false`        | `true`             |
+/// | `tolerate_repeated_headers` | `false`        | `true`             |
+/// | `encoding`                  | `Utf8Strict`   | `Utf8Lossy`        |
+/// | `allow_partial_disputes`    | `false`        | `true`             |
+/// | `allow_withdrawal_disputes` | `false`        | `true`             |
+/// | `dispute_client_check`      | `Strict`       | `TrustOriginal`    |
+/// | `create_accounts_on_reference` | `false`     | `true`             |
+///
+/// Without a preset the options are those of `StrictSpec`, except that
+/// excess decimal places are rounded rather than rejected and disputes of a
+/// client without an account create it, as they always have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Only input the spec describes, read exactly as it describes it
+    StrictSpec,
+    /// Whatever can be made sense of
+    Lenient,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::StrictSpec),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(format!("Invalid preset: {} (expected strict or lenient)", s)),
+        }
     }
-    
-    #[tokio::test]
-    async fn test_process_transactions_integration() {
-        // Create a temporary directory
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("test_transactions.csv");
-        
-        // Create a test CSV file
-        let csv_content = "type,client,tx,amount\n\
-                          deposit,1,1,100.0\n\
-                          deposit,2,2,200.0\n\
-                          withdrawal,1,3,50.0\n\
-                          withdrawal,2,4,25.0\n";
-                          
-        write(&file_path, csv_content).unwrap();
-        
-        // Process the file
-        let mut engine = PaymentEngine::new();
-        process_transactions_stream(&file_path, &mut engine, DEFAULT_BATCH_SIZE).await.unwrap();
-        
-        // Check the results
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 2);
-        
-        // Find each client's account
-        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
-        let client2 = accounts.iter().find(|a| a.client == 2).unwrap();
-        
-        assert_eq!(client1.available, dec!(50.0));
-        assert_eq!(client1.total, dec!(50.0));
-        
-        assert_eq!(client2.available, dec!(175.0));
-        assert_eq!(client2.total, dec!(175.0));
+}
+
+/// Processing options for transaction handling.
+///
+/// Build with [`ProcessingOptions::builder`], which validates the options;
+/// fields may be added in any release.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ProcessingOptions {
+    /// Transactions per batch, fixed or adapted to the engine's apply time
+    pub batch_size_mode: BatchSizeMode,
+    /// Parsed batches that may wait for the engine while the next one is read.
+    /// Reading and parsing run on their own task unless this is 0 or the
+    /// batch size is fixed below 16, where the handoff would cost more than
+    /// reading ahead saves.
+    pub pipeline_depth: usize,
+    /// Apply at most this many transactions per second on average, e.g. to
+    /// keep a large backfill from starving other services. Paced per batch,
+    /// so batches still go to the engine at full speed; `None` doesn't pace.
+    pub max_tps: Option<u32>,
+    /// Write-ahead log of applied transactions; an existing log is replayed
+    /// and the transactions it covers are skipped in the input
+    pub wal_path: Option<PathBuf>,
+    /// Roaring bitmap file of the deposit and withdrawal ids applied by
+    /// previous runs. Those transactions are skipped as duplicates, and the ids
+    /// applied by this run are added to the file once it completes.
+    pub seen_tx_ids_path: Option<PathBuf>,
+    /// Apply each batch with one task per client instead of sequentially.
+    /// Ignored when a WAL is configured, since WAL records need a single order.
+    pub parallel_batches: bool,
+    /// Reorder each batch so every client's transactions are applied one
+    /// after the other, keeping their order within the client, for cache
+    /// locality. The balances are the same. Parallel batches are grouped anyway.
+    /// Ignored with a `dispute_expiry`, since when a dispute expires depends
+    /// on the order the other clients' timestamps come in.
+    pub group_by_client_in_batch: bool,
+    /// Spill stored transactions to disk once more than this many are held in
+    /// memory. `None` or 0 keeps the whole store in memory.
+    pub max_in_memory_transactions: Option<usize>,
+    /// Directory for spilled transaction segments (defaults to the system temp dir)
+    pub spill_dir: Option<PathBuf>,
+    /// When to drop resolved or charged-back transactions from the store
+    pub purge_finalized: PurgePolicy,
+    /// Risk limits on deposits and withdrawals; none are set by default
+    pub limits: Limits,
+    /// Read a `currency` column when the header has one and keep a balance
+    /// per currency. Off by default, which ignores any currency column.
+    pub multi_currency: bool,
+    /// Currency of transactions without one in multi-currency mode
+    pub base_currency: Currency,
+    /// How amounts with more than 4 decimal places are handled when parsed
+    pub amount_precision: AmountPrecision,
+    /// Character separating the fields of an input line, e.g. `;` or `\t`
+    pub delimiter: char,
+    /// Decimal separator of input amounts, `.` or `,`. A comma needs a
+    /// delimiter other than a comma.
+    pub decimal_separator: char,
+    /// Accept amounts with `thousands_separator` between groups of three
+    /// digits, such as `+1 234.56`. Off by default. A leading `+` is
+    /// accepted either way.
+    pub lenient_amounts: bool,
+    /// Thousands separator removed from amounts with `lenient_amounts`: `,`,
+    /// `_` or a space. It must differ from the decimal separator, and a
+    /// comma only shows up in an amount with a delimiter other than a comma.
+    pub thousands_separator: char,
+    /// Let a dispute row's amount contest only part of the original
+    /// transaction. Off by default, which disputes the whole transaction.
+    pub allow_partial_disputes: bool,
+    /// Let withdrawals be disputed as well as deposits. A chargeback of a
+    /// disputed withdrawal credits the funds back. Off by default.
+    pub allow_withdrawal_disputes: bool,
+    /// Queue up to this many disputes per client whose funds can't be held
+    /// yet, holding them once the available funds cover them, instead of
+    /// rejecting them. Off by default.
+    pub max_deferred_disputes: Option<usize>,
+    /// Apply admin transactions, amendments correcting an undisputed
+    /// deposit's amount, instead of rejecting them. Off by default.
+    pub allow_admin_transactions: bool,
+    /// Let a dispute, resolve or chargeback of a client without an account
+    /// create an empty account that shows up in the output. Otherwise it is
+    /// rejected as `unknown_client`. On by default, off in the strict preset.
+    pub create_accounts_on_reference: bool,
+    /// How disputes, resolves and chargebacks whose client differs from the
+    /// original transaction's are handled. Strict, rejecting them, by default.
+    pub dispute_client_check: DisputeClientCheck,
+    /// Read each transaction's time, in seconds since the Unix epoch, from
+    /// the column after the amount, or after the currency column when there
+    /// is one. An empty timestamp is allowed. Off by default.
+    pub timestamps: bool,
+    /// Resolve disputes left open for longer than this, releasing the held
+    /// funds, once a later timestamp shows up. Needs `timestamps`; disputes
+    /// without a timestamp never expire.
+    pub dispute_expiry: Option<Duration>,
+    /// Fee charged on each withdrawal, on top of the withdrawn amount
+    pub withdrawal_fee: WithdrawalFee,
+    /// Write each client's fee total as a `#` comment line to stderr
+    pub fee_summary: bool,
+    /// Write the run's time breakdown and throughput as `#` comment lines to stderr
+    pub timing_summary: bool,
+    /// What is written to stderr; stdout only ever gets the accounts
+    pub verbosity: Verbosity,
+    /// Add lock reason and dispute count columns to the account output
+    pub extended_output: bool,
+    /// Write exactly these account columns in this order instead of the
+    /// default layout. Can't be combined with `extended_output`.
+    pub output_columns: Option<OutputColumns>,
+    /// Write the accounts to this many files `accounts_<part>.csv` instead of
+    /// stdout, client `c` going to part `c % partitions`
+    pub output_partitions: Option<usize>,
+    /// Directory for partitioned account files (defaults to the working directory)
+    pub output_dir: Option<PathBuf>,
+    /// Whether the accounts go to stdout, one file or a file per client
+    pub output_mode: OutputMode,
+    /// Gzip the output file whatever its extension
+    pub compress_output: bool,
+    /// Whether closed accounts are written with the others, to a separate
+    /// file or not at all. Applies to every output mode; reconciliation and
+    /// the SQLite output still see them.
+    pub closed_account_handling: ClosedAccountHandling,
+    /// Whether accounts loaded from `initial_accounts` that no transaction
+    /// of this run was applied to are written with their carried-over
+    /// balances, so the output covers every client. Applies to every output
+    /// mode, like `closed_account_handling`; snapshots still see them.
+    pub emit_untouched_accounts: bool,
+    /// Expected number of transactions, used to pre-size the transaction store
+    pub expected_transactions: Option<usize>,
+    /// Expected number of clients, used to pre-size the account store
+    pub expected_clients: Option<usize>,
+    /// How the input file is read
+    pub input_mode: InputMode,
+    /// Character encoding of the input and how invalid UTF-8 is handled
+    pub encoding: InputEncoding,
+    /// Layout of the input lines: delimited by default, or fixed-width
+    /// records without a header. Fixed-width records can't carry a currency
+    /// or timestamp.
+    pub input_format: InputFormat,
+    /// Keep reading the input file past its end, reopening a FIFO whose
+    /// producer closed it, until a line equal to `end_marker`. Can't be
+    /// combined with mmap input.
+    pub follow: bool,
+    /// Line ending a followed input; it isn't processed itself
+    pub end_marker: String,
+    /// Lines longer than this many bytes are rejected and skipped without
+    /// being buffered. 0 disables the limit.
+    pub max_line_length: usize,
+    /// Skip lines repeating the header after the first line, as left by
+    /// concatenating files, instead of rejecting them as unparseable
+    pub tolerate_repeated_headers: bool,
+    /// Skip this many records (data lines after the header) before
+    /// processing, e.g. those a previous run already applied
+    pub skip_records: usize,
+    /// Stop after this many records (data lines after the header), counted
+    /// after the `skip_records` skipped. This is the "take" of `--skip` and
+    /// `--take`; the builder also sets it with
+    /// [`take_records`](ProcessingOptionsBuilder::take_records).
+    pub max_records: Option<usize>,
+    /// Stop reading after this transaction or line, e.g. to replay the input
+    /// up to a point. Checked before `client_filter`, so the cut is the same
+    /// whichever clients are applied.
+    pub stop_after: Option<StopAfter>,
+    /// Only apply the transactions of the clients in this filter, disputes
+    /// included by their own client; the others are skipped after parsing,
+    /// before they reach the engine
+    pub client_filter: Option<ClientFilter>,
+    /// Only apply the transactions of a deterministic sample of the clients,
+    /// checked after `client_filter`, and extrapolate the report's totals
+    /// into [`ProcessingReport::sample_estimate`]
+    pub sample: Option<ClientSample>,
+    /// Drop the deposits and withdrawals of clients already locked as soon as
+    /// their type, client and tx columns are read, without parsing the rest
+    /// of the line, instead of handing them to the engine to reject. Disputes,
+    /// resolves and chargebacks always go through. Only applies to text input.
+    pub skip_locked_fast_path: bool,
+    /// Transform or drop each parsed transaction before it is batched. It
+    /// runs before `client_filter`, which sees the client it returns;
+    /// dropped transactions are counted as filtered.
+    pub transaction_hook: Option<TransactionHook>,
+    /// Write a trace of every transaction's outcome and the client's balances
+    /// after it, in this format, instead of the final balances. Can't be
+    /// combined with options that write other files or persist state.
+    pub dry_run: Option<TraceFormat>,
+    /// Write a snapshot of the account balances between batches this often,
+    /// while processing goes on
+    pub snapshot_every: Option<SnapshotEvery>,
+    /// Path of each snapshot, `{seq}` replaced by its number from 1. Each
+    /// snapshot is written to a temporary file renamed into place.
+    pub snapshot_path_template: String,
+    /// Keep a per-client audit trail of every transaction applied or rejected
+    pub record_history: bool,
+    /// Directory to write the audit trail to after processing. Implies `record_history`.
+    pub history_out: Option<PathBuf>,
+    /// Write the audit trail as one combined file instead of one file per client
+    pub history_combined: bool,
+    /// Write a [`RunManifest`](crate::manifest::RunManifest) describing the
+    /// run to this path as JSON when it ends, whether it succeeds or fails.
+    /// Only runs of a file or URL through the `process_transactions_*`
+    /// functions write one.
+    pub manifest_path: Option<PathBuf>,
+    /// Clock the run's timings, duration-based snapshots and manifest
+    /// timestamps read, the system clock by default
+    pub clock: Arc<dyn Clock>,
+    /// Accounts CSV in the output format to start from, e.g. the previous
+    /// period's closing balances
+    pub initial_accounts: Option<PathBuf>,
+    /// Accounts CSV in the output format to reconcile the final balances
+    /// against, e.g. from the ledger system. The clients that differ are
+    /// written as comment lines to stderr.
+    pub expected_accounts: Option<PathBuf>,
+    /// Largest balance difference from `expected_accounts` that still matches
+    pub reconcile_tolerance: Decimal,
+    /// Channel receiving an event whenever a transaction is applied or rejected.
+    /// Sending never blocks processing; events are dropped once the receiver is closed.
+    pub event_sender: Option<UnboundedSender<EngineEvent>>,
+    /// Stream the accounts each batch changed, as they stand after the batch,
+    /// for change-data-capture. Each carries the batch's sequence number;
+    /// accounts a batch didn't touch aren't sent.
+    pub change_stream: Option<ChangeStream>,
+    /// Serve read-only account queries over HTTP on this address while processing
+    #[cfg(feature = "http")]
+    pub serve_addr: Option<std::net::SocketAddr>,
+    /// SQLite database to write final account balances to, in addition to stdout
+    #[cfg(feature = "sqlite")]
+    pub sqlite_path: Option<PathBuf>,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        Self {
+            batch_size_mode: BatchSizeMode::Fixed(DEFAULT_BATCH_SIZE),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            max_tps: None,
+            wal_path: None,
+            seen_tx_ids_path: None,
+            parallel_batches: false,
+            group_by_client_in_batch: false,
+            max_in_memory_transactions: None,
+            spill_dir: None,
+            purge_finalized: PurgePolicy::Never,
+            limits: Limits::default(),
+            multi_currency: false,
+            base_currency: DEFAULT_BASE_CURRENCY.parse().expect("valid default currency"),
+            amount_precision: AmountPrecision::RoundHalfEven,
+            delimiter: ',',
+            decimal_separator: '.',
+            lenient_amounts: false,
+            thousands_separator: ',',
+            allow_partial_disputes: false,
+            allow_withdrawal_disputes: false,
+            max_deferred_disputes: None,
+            allow_admin_transactions: false,
+            create_accounts_on_reference: true,
+            dispute_client_check: DisputeClientCheck::Strict,
+            withdrawal_fee: WithdrawalFee::None,
+            fee_summary: false,
+            timing_summary: false,
+            verbosity: Verbosity::Normal,
+            extended_output: false,
+            output_columns: None,
+            output_partitions: None,
+            output_mode: OutputMode::Stdout,
+            compress_output: false,
+            closed_account_handling: ClosedAccountHandling::IncludeInOutput,
+            emit_untouched_accounts: true,
+            output_dir: None,
+            expected_transactions: None,
+            expected_clients: None,
+            input_mode: InputMode::Streaming,
+            encoding: InputEncoding::Utf8Strict,
+            input_format: InputFormat::Delimited,
+            follow: false,
+            end_marker: DEFAULT_END_MARKER.to_string(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            tolerate_repeated_headers: false,
+            timestamps: false,
+            dispute_expiry: None,
+            skip_records: 0,
+            max_records: None,
+            stop_after: None,
+            client_filter: None,
+            sample: None,
+            skip_locked_fast_path: false,
+            transaction_hook: None,
+            dry_run: None,
+            snapshot_every: None,
+            snapshot_path_template: DEFAULT_SNAPSHOT_PATH_TEMPLATE.to_string(),
+            record_history: false,
+            history_out: None,
+            history_combined: false,
+            manifest_path: None,
+            clock: Arc::new(SystemClock),
+            initial_accounts: None,
+            expected_accounts: None,
+            reconcile_tolerance: Decimal::ZERO,
+            event_sender: None,
+            change_stream: None,
+            #[cfg(feature = "http")]
+            serve_addr: None,
+            #[cfg(feature = "sqlite")]
+            sqlite_path: None,
+        }
     }
-    
-    #[tokio::test]
-    async fn test_process_transactions_with_dispute() {
-        // Create a temporary directory
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("test_disputes.csv");
-        
-        // Create a test CSV file with disputes
-        let csv_content = "type,client,tx,amount\n\
-                          deposit,1,1,100.0\n\
-                          dispute,1,1,\n\
-                          resolve,1,1,\n\
+}
+
+impl ProcessingOptions {
+    /// Start building options from the defaults
+    pub fn builder() -> ProcessingOptionsBuilder {
+        ProcessingOptionsBuilder::default()
+    }
+
+    /// Start building options from the defaults with a preset applied
+    pub fn preset(preset: Preset) -> ProcessingOptionsBuilder {
+        Self::builder().preset(preset)
+    }
+
+    /// Check for values and combinations that can't be meant, such as a
+    /// batch size of 0 or a dry run that writes a WAL. The builder checks
+    /// this, and so does every run, for options set field by field.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        match self.batch_size_mode {
+            BatchSizeMode::Fixed(0) => return Err(OptionsError::Zero("batch_size")),
+            BatchSizeMode::Auto { min, max, target_batch_ms } => {
+                if min == 0 || min > max {
+                    return Err(OptionsError::AutoBatchSizes { min, max });
+                }
+                if target_batch_ms == 0 {
+                    return Err(OptionsError::Zero("target_batch_ms"));
+                }
+            }
+            BatchSizeMode::Fixed(_) => {}
+        }
+        if self.max_line_length == 0 {
+            return Err(OptionsError::Zero("max_line_length"));
+        }
+        if self.max_tps == Some(0) {
+            return Err(OptionsError::Zero("max_tps"));
+        }
+        if self.max_deferred_disputes == Some(0) {
+            return Err(OptionsError::Zero("max_deferred_disputes"));
+        }
+        if self.dispute_expiry.is_some_and(|expiry| expiry.is_zero()) {
+            return Err(OptionsError::ZeroDisputeExpiry);
+        }
+        if self.dispute_expiry.is_some() && !self.timestamps {
+            return Err(OptionsError::Requires("dispute_expiry", "timestamps"));
+        }
+        if self.output_partitions == Some(0) {
+            return Err(OptionsError::Zero("output_partitions"));
+        }
+        if let OutputMode::PerClientDir(template) = &self.output_mode {
+            validate_client_template(template)?;
+            if self.output_partitions.is_some() {
+                return Err(OptionsError::Conflict("per-client output", "output_partitions"));
+            }
+        }
+        if let OutputMode::File(_) = &self.output_mode {
+            if self.output_partitions.is_some() {
+                return Err(OptionsError::Conflict("output file", "output_partitions"));
+            }
+        } else if self.compress_output {
+            return Err(OptionsError::Requires("compress_output", "an output file"));
+        }
+        let ProcessingOptions { delimiter, decimal_separator, thousands_separator, .. } = *self;
+        if matches!(delimiter, '\n' | '\r' | '"') {
+            return Err(OptionsError::Delimiter(delimiter));
+        }
+        if !matches!(decimal_separator, '.' | ',') {
+            return Err(OptionsError::DecimalSeparator(decimal_separator));
+        }
+        if delimiter == decimal_separator {
+            return Err(OptionsError::SameSeparator("delimiter", "decimal_separator"));
+        }
+        if !matches!(thousands_separator, ',' | '_' | ' ') {
+            return Err(OptionsError::ThousandsSeparator(thousands_separator));
+        }
+        if self.lenient_amounts && thousands_separator == decimal_separator {
+            return Err(OptionsError::SameSeparator("decimal_separator", "thousands_separator"));
+        }
+        if let InputFormat::FixedWidth(spec) = self.input_format {
+            let fields = spec.fields();
+            for (i, (name, field)) in fields.iter().enumerate() {
+                if field.width == 0 {
+                    return Err(OptionsError::FixedWidth(format!("{} field must not be empty", name)));
+                }
+                if let Some((other, _)) = fields[..i].iter().find(|(_, other)| other.offset < field.end() && field.offset < other.end()) {
+                    return Err(OptionsError::FixedWidth(format!("fields {} and {} overlap", other, name)));
+                }
+            }
+            if self.multi_currency {
+                return Err(OptionsError::Conflict("fixed-width input", "multi_currency"));
+            }
+            if self.timestamps {
+                return Err(OptionsError::Conflict("fixed-width input", "timestamps"));
+            }
+        }
+        match self.snapshot_every {
+            Some(SnapshotEvery::Records(0)) => return Err(OptionsError::ZeroSnapshotRecords),
+            Some(SnapshotEvery::Duration(interval)) if interval.is_zero() => {
+                return Err(OptionsError::ZeroSnapshotInterval);
+            }
+            _ => {}
+        }
+        if let Some(columns) = &self.output_columns {
+            columns.validate()?;
+            if self.extended_output {
+                return Err(OptionsError::Conflict("output_columns", "extended_output"));
+            }
+        }
+        if self.reconcile_tolerance.is_sign_negative() {
+            return Err(OptionsError::NegativeReconcileTolerance);
+        }
+        if self.snapshot_path_template.is_empty() {
+            return Err(OptionsError::EmptySnapshotPathTemplate);
+        }
+        if self.sample.is_some_and(|sample| !(sample.rate > 0.0 && sample.rate <= 1.0)) {
+            return Err(OptionsError::SampleRate);
+        }
+        if self.follow && self.end_marker.is_empty() {
+            return Err(OptionsError::EmptyEndMarker);
+        }
+        if self.follow && self.input_mode == InputMode::Mmap {
+            return Err(OptionsError::Conflict("follow", "mmap input"));
+        }
+        if self.skip_locked_fast_path {
+            // Each of these has to see every transaction of a locked client
+            let conflicts = [
+                ("transaction_hook", self.transaction_hook.is_some()),
+                ("dry_run", self.dry_run.is_some()),
+                ("record_history", self.record_history || self.history_out.is_some()),
+            ];
+            if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(OptionsError::Conflict("skip_locked_fast_path", name));
+            }
+        }
+        if self.dry_run.is_some() {
+            let conflicts = [
+                ("snapshot_every", self.snapshot_every.is_some()),
+                ("expected_accounts", self.expected_accounts.is_some()),
+                ("wal_path", self.wal_path.is_some()),
+                ("seen_tx_ids_path", self.seen_tx_ids_path.is_some()),
+                ("output_partitions", self.output_partitions.is_some()),
+                ("output_mode", self.output_mode != OutputMode::Stdout),
+                ("history_out", self.history_out.is_some()),
+                (
+                    "closed_account_handling",
+                    matches!(self.closed_account_handling, ClosedAccountHandling::SeparateFile(_)),
+                ),
+                ("change_stream", matches!(self.change_stream, Some(ChangeStream::File(_)))),
+                #[cfg(feature = "sqlite")]
+                ("sqlite_path", self.sqlite_path.is_some()),
+            ];
+            if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(OptionsError::Conflict("dry_run", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder for [`ProcessingOptions`]
+#[derive(Debug, Clone, Default)]
+pub struct ProcessingOptionsBuilder {
+    options: ProcessingOptions,
+}
+
+impl ProcessingOptionsBuilder {
+    /// Set every option of a [`Preset`]; setters called afterwards override it
+    pub fn preset(self, preset: Preset) -> Self {
+        let strict = preset == Preset::StrictSpec;
+        self.amount_precision(if strict { AmountPrecision::Reject } else { AmountPrecision::RoundHalfEven })
+            .lenient_amounts(!strict)
+            .tolerate_repeated_headers(!strict)
+            .encoding(if strict { InputEncoding::Utf8Strict } else { InputEncoding::Utf8Lossy })
+            .allow_partial_disputes(!strict)
+            .allow_withdrawal_disputes(!strict)
+            .dispute_client_check(if strict { DisputeClientCheck::Strict } else { DisputeClientCheck::TrustOriginal })
+            .create_accounts_on_reference(!strict)
+    }
+
+    /// Number of transactions applied per batch (at least 1)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.options.batch_size_mode = BatchSizeMode::Fixed(batch_size);
+        self
+    }
+
+    /// Fixed batches, or batches sized toward a target apply time
+    pub fn batch_size_mode(mut self, batch_size_mode: BatchSizeMode) -> Self {
+        self.options.batch_size_mode = batch_size_mode;
+        self
+    }
+
+    /// Batches queued between the reader task and the engine (0 = read and apply on one task)
+    pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.options.pipeline_depth = pipeline_depth;
+        self
+    }
+
+    /// Transactions applied per second at most, on average (at least 1)
+    pub fn max_tps(mut self, max_tps: impl Into<Option<u32>>) -> Self {
+        self.options.max_tps = max_tps.into();
+        self
+    }
+
+    pub fn wal_path(mut self, wal_path: impl Into<Option<PathBuf>>) -> Self {
+        self.options.wal_path = wal_path.into();
+        self
+    }
+
+    pub fn seen_tx_ids_path(mut self, seen_tx_ids_path: impl Into<Option<PathBuf>>) -> Self {
+        self.options.seen_tx_ids_path = seen_tx_ids_path.into();
+        self
+    }
+
+    pub fn parallel_batches(mut self, parallel_batches: bool) -> Self {
+        self.options.parallel_batches = parallel_batches;
+        self
+    }
+
+    pub fn group_by_client_in_batch(mut self, group_by_client_in_batch: bool) -> Self {
+        self.options.group_by_client_in_batch = group_by_client_in_batch;
+        self
+    }
+
+    pub fn max_in_memory_transactions(mut self, max: impl Into<Option<usize>>) -> Self {
+        self.options.max_in_memory_transactions = max.into();
+        self
+    }
+
+    pub fn spill_dir(mut self, spill_dir: impl Into<Option<PathBuf>>) -> Self {
+        self.options.spill_dir = spill_dir.into();
+        self
+    }
+
+    pub fn purge_finalized(mut self, purge_finalized: PurgePolicy) -> Self {
+        self.options.purge_finalized = purge_finalized;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    pub fn multi_currency(mut self, multi_currency: bool) -> Self {
+        self.options.multi_currency = multi_currency;
+        self
+    }
+
+    pub fn base_currency(mut self, base_currency: Currency) -> Self {
+        self.options.base_currency = base_currency;
+        self
+    }
+
+    pub fn amount_precision(mut self, amount_precision: AmountPrecision) -> Self {
+        self.options.amount_precision = amount_precision;
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.options.delimiter = delimiter;
+        self
+    }
+
+    pub fn decimal_separator(mut self, decimal_separator: char) -> Self {
+        self.options.decimal_separator = decimal_separator;
+        self
+    }
+
+    pub fn lenient_amounts(mut self, lenient_amounts: bool) -> Self {
+        self.options.lenient_amounts = lenient_amounts;
+        self
+    }
+
+    pub fn thousands_separator(mut self, thousands_separator: char) -> Self {
+        self.options.thousands_separator = thousands_separator;
+        self
+    }
+
+    pub fn allow_partial_disputes(mut self, allow_partial_disputes: bool) -> Self {
+        self.options.allow_partial_disputes = allow_partial_disputes;
+        self
+    }
+
+    pub fn allow_withdrawal_disputes(mut self, allow_withdrawal_disputes: bool) -> Self {
+        self.options.allow_withdrawal_disputes = allow_withdrawal_disputes;
+        self
+    }
+
+    pub fn max_deferred_disputes(mut self, max_deferred_disputes: impl Into<Option<usize>>) -> Self {
+        self.options.max_deferred_disputes = max_deferred_disputes.into();
+        self
+    }
+
+    pub fn allow_admin_transactions(mut self, allow_admin_transactions: bool) -> Self {
+        self.options.allow_admin_transactions = allow_admin_transactions;
+        self
+    }
+
+    pub fn create_accounts_on_reference(mut self, create_accounts_on_reference: bool) -> Self {
+        self.options.create_accounts_on_reference = create_accounts_on_reference;
+        self
+    }
+
+    pub fn dispute_client_check(mut self, dispute_client_check: DisputeClientCheck) -> Self {
+        self.options.dispute_client_check = dispute_client_check;
+        self
+    }
+
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.options.timestamps = timestamps;
+        self
+    }
+
+    pub fn dispute_expiry(mut self, dispute_expiry: impl Into<Option<Duration>>) -> Self {
+        self.options.dispute_expiry = dispute_expiry.into();
+        self
+    }
+
+    pub fn withdrawal_fee(mut self, withdrawal_fee: WithdrawalFee) -> Self {
+        self.options.withdrawal_fee = withdrawal_fee;
+        self
+    }
+
+    pub fn fee_summary(mut self, fee_summary: bool) -> Self {
+        self.options.fee_summary = fee_summary;
+        self
+    }
+
+    pub fn timing_summary(mut self, timing_summary: bool) -> Self {
+        self.options.timing_summary = timing_summary;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.options.verbosity = verbosity;
+        self
+    }
+
+    pub fn extended_output(mut self, extended_output: bool) -> Self {
+        self.options.extended_output = extended_output;
+        self
+    }
+
+    pub fn output_columns(mut self, output_columns: impl Into<Option<OutputColumns>>) -> Self {
+        self.options.output_columns = output_columns.into();
+        self
+    }
+
+    /// Split the account output into this many files (at least 1)
+    pub fn output_partitions(mut self, partitions: impl Into<Option<usize>>) -> Self {
+        self.options.output_partitions = partitions.into();
+        self
+    }
+
+    pub fn output_dir(mut self, output_dir: impl Into<Option<PathBuf>>) -> Self {
+        self.options.output_dir = output_dir.into();
+        self
+    }
+
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.options.output_mode = output_mode;
+        self
+    }
+
+    pub fn compress_output(mut self, compress_output: bool) -> Self {
+        self.options.compress_output = compress_output;
+        self
+    }
+
+    pub fn closed_account_handling(mut self, closed_account_handling: ClosedAccountHandling) -> Self {
+        self.options.closed_account_handling = closed_account_handling;
+        self
+    }
+
+    pub fn emit_untouched_accounts(mut self, emit_untouched_accounts: bool) -> Self {
+        self.options.emit_untouched_accounts = emit_untouched_accounts;
+        self
+    }
+
+    pub fn expected_transactions(mut self, expected: impl Into<Option<usize>>) -> Self {
+        self.options.expected_transactions = expected.into();
+        self
+    }
+
+    pub fn expected_clients(mut self, expected: impl Into<Option<usize>>) -> Self {
+        self.options.expected_clients = expected.into();
+        self
+    }
+
+    pub fn input_mode(mut self, input_mode: InputMode) -> Self {
+        self.options.input_mode = input_mode;
+        self
+    }
+
+    pub fn follow(mut self, follow: bool) -> Self {
+        self.options.follow = follow;
+        self
+    }
+
+    pub fn end_marker(mut self, end_marker: impl Into<String>) -> Self {
+        self.options.end_marker = end_marker.into();
+        self
+    }
+
+    pub fn encoding(mut self, encoding: InputEncoding) -> Self {
+        self.options.encoding = encoding;
+        self
+    }
+
+    pub fn input_format(mut self, input_format: InputFormat) -> Self {
+        self.options.input_format = input_format;
+        self
+    }
+
+    pub fn max_line_length(mut self, max_line_length: usize) -> Self {
+        self.options.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn tolerate_repeated_headers(mut self, tolerate_repeated_headers: bool) -> Self {
+        self.options.tolerate_repeated_headers = tolerate_repeated_headers;
+        self
+    }
+
+    pub fn skip_records(mut self, skip_records: usize) -> Self {
+        self.options.skip_records = skip_records;
+        self
+    }
+
+    pub fn max_records(mut self, max_records: impl Into<Option<usize>>) -> Self {
+        self.options.max_records = max_records.into();
+        self
+    }
+
+    /// Process this many records after the `skip_records` skipped, as
+    /// `--take` does; the same as [`max_records`](Self::max_records)
+    pub fn take_records(self, take_records: impl Into<Option<usize>>) -> Self {
+        self.max_records(take_records)
+    }
+
+    pub fn transaction_hook(mut self, hook: impl Fn(Transaction) -> Option<Transaction> + Send + Sync + 'static) -> Self {
+        self.options.transaction_hook = Some(TransactionHook::new(hook));
+        self
+    }
+
+    pub fn stop_after(mut self, stop_after: impl Into<Option<StopAfter>>) -> Self {
+        self.options.stop_after = stop_after.into();
+        self
+    }
+
+    pub fn client_filter(mut self, client_filter: impl Into<Option<ClientFilter>>) -> Self {
+        self.options.client_filter = client_filter.into();
+        self
+    }
+
+    pub fn sample(mut self, sample: impl Into<Option<ClientSample>>) -> Self {
+        self.options.sample = sample.into();
+        self
+    }
+
+    pub fn skip_locked_fast_path(mut self, skip_locked_fast_path: bool) -> Self {
+        self.options.skip_locked_fast_path = skip_locked_fast_path;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: impl Into<Option<TraceFormat>>) -> Self {
+        self.options.dry_run = dry_run.into();
+        self
+    }
+
+    pub fn snapshot_every(mut self, snapshot_every: impl Into<Option<SnapshotEvery>>) -> Self {
+        self.options.snapshot_every = snapshot_every.into();
+        self
+    }
+
+    pub fn snapshot_path_template(mut self, snapshot_path_template: impl Into<String>) -> Self {
+        self.options.snapshot_path_template = snapshot_path_template.into();
+        self
+    }
+
+    pub fn record_history(mut self, record_history: bool) -> Self {
+        self.options.record_history = record_history;
+        self
+    }
+
+    pub fn history_out(mut self, history_out: impl Into<Option<PathBuf>>) -> Self {
+        self.options.history_out = history_out.into();
+        self
+    }
+
+    pub fn history_combined(mut self, history_combined: bool) -> Self {
+        self.options.history_combined = history_combined;
+        self
+    }
+
+    pub fn manifest_path(mut self, manifest_path: impl Into<Option<PathBuf>>) -> Self {
+        self.options.manifest_path = manifest_path.into();
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options.clock = clock;
+        self
+    }
+
+    pub fn initial_accounts(mut self, initial_accounts: impl Into<Option<PathBuf>>) -> Self {
+        self.options.initial_accounts = initial_accounts.into();
+        self
+    }
+
+    pub fn expected_accounts(mut self, expected_accounts: impl Into<Option<PathBuf>>) -> Self {
+        self.options.expected_accounts = expected_accounts.into();
+        self
+    }
+
+    pub fn reconcile_tolerance(mut self, reconcile_tolerance: Decimal) -> Self {
+        self.options.reconcile_tolerance = reconcile_tolerance;
+        self
+    }
+
+    pub fn event_sender(mut self, event_sender: impl Into<Option<UnboundedSender<EngineEvent>>>) -> Self {
+        self.options.event_sender = event_sender.into();
+        self
+    }
+
+    pub fn change_stream(mut self, change_stream: impl Into<Option<ChangeStream>>) -> Self {
+        self.options.change_stream = change_stream.into();
+        self
+    }
+
+    #[cfg(feature = "http")]
+    pub fn serve_addr(mut self, serve_addr: impl Into<Option<std::net::SocketAddr>>) -> Self {
+        self.options.serve_addr = serve_addr.into();
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_path(mut self, sqlite_path: impl Into<Option<PathBuf>>) -> Self {
+        self.options.sqlite_path = sqlite_path.into();
+        self
+    }
+
+    /// Validate and return the options
+    pub fn build(self) -> Result<ProcessingOptions, PaymentEngineError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// Summary of what happened to the input during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessingReport {
+    /// Data lines read after the header, including rejected ones. Records
+    /// skipped with `skip_records` are not counted.
+    pub lines_read: usize,
+    /// Records read but not applied because of `skip_records`; fewer than
+    /// asked if the input ended first
+    pub records_skipped: usize,
+    /// Transactions of other clients skipped because of `client_filter`
+    pub records_filtered: usize,
+    /// Transactions of clients outside the `sample` skipped
+    pub records_sampled_out: usize,
+    /// Deposits and withdrawals of locked clients dropped before parsing by
+    /// `skip_locked_fast_path`, rather than rejected by the engine
+    pub records_locked_skipped: usize,
+    /// Lines parsed into transactions and handed to the engine
+    pub transactions_processed: usize,
+    /// `transactions_processed` per transaction type
+    pub transactions_by_type: BTreeMap<TransactionType, usize>,
+    /// Deposits and withdrawals skipped because a previous run applied them,
+    /// see [`ProcessingOptions::seen_tx_ids_path`]
+    pub duplicates: usize,
+    /// Lines that could not be parsed as a transaction
+    pub parse_errors: usize,
+    /// Lines that could not be read, e.g. invalid UTF-8
+    pub read_errors: usize,
+    /// Lines rejected for exceeding `max_line_length`
+    pub oversized_lines: usize,
+    /// Header lines after the first line skipped with
+    /// `tolerate_repeated_headers`; not counted in `lines_read`
+    pub repeated_headers: usize,
+    /// Transactions the engine rejected, e.g. for insufficient funds
+    pub transactions_rejected: usize,
+    /// Transactions rejected for exceeding a limit, per client. Also counted
+    /// in `transactions_rejected`.
+    pub limit_rejections: BTreeMap<u16, usize>,
+    /// Withdrawal fees charged, per client
+    #[serde(with = "decimal_string::map")]
+    pub fees_by_client: BTreeMap<u16, Decimal>,
+    /// Withdrawal fees charged across all clients
+    #[serde(with = "decimal_string")]
+    pub fees_collected: Decimal,
+    /// Chargebacks applied, of deposits and withdrawals
+    pub chargebacks: usize,
+    /// Disputes of withdrawals rejected as `not_disputable`, also counted in
+    /// `transactions_rejected`
+    pub withdrawal_disputes_rejected: usize,
+    /// Disputes resolved for being open for longer than `dispute_expiry`
+    pub disputes_expired: usize,
+    /// Sum of the deposits applied to base balances across all clients
+    #[serde(with = "decimal_string")]
+    pub deposited_total: Decimal,
+    /// Sum of the withdrawals applied to base balances across all clients, without fees
+    #[serde(with = "decimal_string")]
+    pub withdrawn_total: Decimal,
+    /// Funds removed from base balances by chargebacks of deposits, per client
+    #[serde(with = "decimal_string::map")]
+    pub chargebacks_by_client: BTreeMap<u16, Decimal>,
+    /// Funds removed from base balances by chargebacks across all clients
+    #[serde(with = "decimal_string")]
+    pub chargeback_total: Decimal,
+    /// Highest tx id of the engine after the run, see
+    /// [`PaymentEngine::max_tx_id`]. Counts every row whose tx field parsed,
+    /// including rejected, filtered, skipped and otherwise malformed rows.
+    pub max_tx_id: Option<TxId>,
+    /// Whether processing stopped early because `max_records` was reached
+    pub max_records_reached: bool,
+    /// Whether processing stopped early because `stop_after` was reached
+    pub stop_reached: bool,
+    /// Clients whose final balances differ from `expected_accounts`, if given
+    pub reconciliation_mismatches: Option<usize>,
+    /// Input lines of the batches rolled back because applying them
+    /// panicked, in input order. Their transactions left no trace in the
+    /// engine, though they are counted as processed.
+    pub failed_batches: Vec<RangeInclusive<usize>>,
+    /// The run's totals scaled up to all the clients, when only a `sample`
+    /// of them was applied
+    pub sample_estimate: Option<SampleEstimate>,
+    /// Rows and hash of the accounts CSV written to stdout or the output
+    /// file, computed when `manifest_path` is set
+    pub output_digest: Option<OutputDigest>,
+    /// Time spent reading input lines. Reading and parsing are timed together
+    /// per batch and split by timing a sample of the records; decoding a
+    /// Parquet row counts as reading.
+    pub read_time: Duration,
+    /// Time spent parsing lines into transactions and sorting them into batches
+    pub parse_time: Duration,
+    /// Time spent applying batches to the engine, including the WAL
+    pub apply_time: Duration,
+    /// Time spent waiting to stay under `max_tps`
+    pub throttle_time: Duration,
+    /// Wall-clock time of the run, from the WAL replay to the last batch
+    /// applied. Writing the output is not included. With a pipeline depth
+    /// above 0 reading overlaps applying, so the phases may add up to more.
+    pub elapsed: Duration,
+}
+
+impl ProcessingReport {
+    /// Lines that were skipped or rejected, for whatever reason
+    pub fn error_count(&self) -> usize {
+        self.parse_errors + self.read_errors + self.oversized_lines + self.transactions_rejected
+    }
+
+    /// Input lines read per second of the run
+    pub fn lines_per_sec(&self) -> f64 {
+        self.lines_read as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Transactions handed to the engine per second of the run, the
+    /// effective rate when paced with `max_tps`
+    pub fn transactions_per_sec(&self) -> f64 {
+        self.transactions_processed as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Add the counts of a run over other input whose clients are disjoint
+    /// from this one's. Phase times add up; `elapsed` is left to the caller,
+    /// as runs in parallel overlap.
+    pub(crate) fn absorb(&mut self, other: ProcessingReport) {
+        self.lines_read += other.lines_read;
+        self.records_skipped += other.records_skipped;
+        self.records_filtered += other.records_filtered;
+        self.records_sampled_out += other.records_sampled_out;
+        self.records_locked_skipped += other.records_locked_skipped;
+        self.transactions_processed += other.transactions_processed;
+        for (transaction_type, count) in other.transactions_by_type {
+            *self.transactions_by_type.entry(transaction_type).or_default() += count;
+        }
+        self.duplicates += other.duplicates;
+        self.parse_errors += other.parse_errors;
+        self.read_errors += other.read_errors;
+        self.oversized_lines += other.oversized_lines;
+        self.repeated_headers += other.repeated_headers;
+        self.transactions_rejected += other.transactions_rejected;
+        self.limit_rejections.extend(other.limit_rejections);
+        self.fees_by_client.extend(other.fees_by_client);
+        self.fees_collected += other.fees_collected;
+        self.chargebacks += other.chargebacks;
+        self.withdrawal_disputes_rejected += other.withdrawal_disputes_rejected;
+        self.disputes_expired += other.disputes_expired;
+        self.deposited_total += other.deposited_total;
+        self.withdrawn_total += other.withdrawn_total;
+        self.chargebacks_by_client.extend(other.chargebacks_by_client);
+        self.chargeback_total += other.chargeback_total;
+        self.max_tx_id = self.max_tx_id.max(other.max_tx_id);
+        self.max_records_reached |= other.max_records_reached;
+        self.stop_reached |= other.stop_reached;
+        self.failed_batches.extend(other.failed_batches);
+        self.read_time += other.read_time;
+        self.parse_time += other.parse_time;
+        self.apply_time += other.apply_time;
+        self.throttle_time += other.throttle_time;
+    }
+
+    /// The report with its timings cleared, for comparing the counts of two runs
+    #[cfg(test)]
+    pub(crate) fn without_timings(self) -> Self {
+        let sample_estimate = self.sample_estimate.map(|estimate| SampleEstimate { apply_time: Duration::ZERO, ..estimate });
+        Self { read_time: Duration::ZERO, parse_time: Duration::ZERO, apply_time: Duration::ZERO, throttle_time: Duration::ZERO, elapsed: Duration::ZERO, sample_estimate, ..self }
+    }
+}
+
+/// Process transactions from a CSV file and output account balances
+pub async fn process_transactions(file_path: &Path) -> Result<ProcessingReport> {
+    // Use default options
+    process_transactions_with_options(file_path, ProcessingOptions::default()).await
+}
+
+/// Process transactions from a CSV file with custom options
+pub async fn process_transactions_with_options(file_path: &Path, options: ProcessingOptions) -> Result<ProcessingReport> {
+    options.validate()?;
+    info!("Processing transactions from: {:?} with batch size: {:?}", file_path, options.batch_size_mode);
+    let size = std::fs::metadata(file_path).ok().map(|metadata| metadata.len());
+    with_manifest(&file_path.display().to_string(), size, &options.clone(), async {
+        let source = RecordSource::open_lines(file_path, &options).await?;
+        process_source(source, options).await
+    })
+    .await
+}
+
+/// Process the records of an opened input and output account balances
+pub(crate) async fn process_source(source: RecordSource, options: ProcessingOptions) -> Result<ProcessingReport> {
+    // Track processing time
+    let start_time = options.clock.monotonic();
+
+    // Read the expected balances first, so a bad file fails before a long run
+    let expected = match options.expected_accounts.as_deref() {
+        Some(path) => Some((path, read_accounts_from_path(path)?)),
+        None => None,
+    };
+    
+    // Create a new payment engine
+    let run = Arc::new(LiveRun::new(create_engine(&options)?));
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = options.serve_addr {
+        let (local_addr, _) = crate::server::spawn_server(addr, run.clone()).await?;
+        info!("Serving account queries on http://{}", local_addr);
+    }
+    
+    // Process transactions in streaming fashion
+    let report = process_records(source, &run, &options).await?;
+    
+    // Calculate elapsed time
+    let duration = options.clock.elapsed(start_time);
+    let engine = run.engine.read().await;
+    write_run_outputs(&engine, report, duration, expected, &options)
+}
+
+/// Write the accounts of a finished run wherever the options send them, along
+/// with the status, reconciliation against the `expected` accounts, the
+/// SQLite copy and the audit trail
+pub(crate) fn write_run_outputs(
+    engine: &PaymentEngine,
+    mut report: ProcessingReport,
+    duration: Duration,
+    expected: Option<(&Path, Vec<Account>)>,
+    options: &ProcessingOptions,
+) -> Result<ProcessingReport> {
+    // A dry run only writes the trace
+    if let Some(format) = options.dry_run {
+        crate::history::write_trace(engine, format, std::io::stdout().lock())?;
+        return Ok(report);
+    }
+
+    // Write the accounts to stdout, an output file, partition files or per-client files, and the status to stderr
+    if let Some(partitions) = options.output_partitions {
+        let dir = options.output_dir.as_deref().unwrap_or(Path::new("."));
+        write_partitioned_accounts(engine, options, dir, partitions)?;
+    }
+    match &options.output_mode {
+        OutputMode::PerClientDir(template) => write_per_client_files(engine, options, template)?,
+        OutputMode::File(path) => report.output_digest = write_accounts_file(engine, options, path)?,
+        OutputMode::Stdout => {}
+    }
+    if let ClosedAccountHandling::SeparateFile(path) = &options.closed_account_handling {
+        write_closed_accounts(engine, options, path)?;
+    }
+    let reconciliation = expected.map(|(expected_path, expected)| {
+        (expected_path, diff_accounts(&expected, &engine.get_accounts(), options.reconcile_tolerance))
+    });
+    report.reconciliation_mismatches = reconciliation.as_ref().map(|(_, diff)| diff.len());
+    let reconciliation = reconciliation.as_ref().map(|(path, diff)| (*path, diff));
+    if let Some(digest) = write_output(engine, &report, duration, reconciliation, options, std::io::stdout().lock(), std::io::stderr().lock())? {
+        report.output_digest = Some(digest);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(sqlite_path) = &options.sqlite_path {
+        crate::sqlite::write_accounts_to_sqlite(engine, sqlite_path)?;
+    }
+
+    if let Some(history_dir) = &options.history_out {
+        crate::history::write_history(engine, history_dir, options.history_combined)?;
+    }
+    
+    Ok(report)
+}
+
+/// Create an engine whose stores match the memory options and capacity hints
+pub(crate) fn create_engine(options: &ProcessingOptions) -> Result<PaymentEngine> {
+    let expected_clients = options.expected_clients.unwrap_or(0);
+    let mut engine = match options.max_in_memory_transactions {
+        Some(limit) if limit > 0 => {
+            let dir = options.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+            info!("Spilling transactions to {:?} beyond {} in memory", dir, limit);
+            PaymentEngine::with_stores(AccountStore::with_capacity(expected_clients), TransactionStore::with_spill(limit, &dir)?)
+        }
+        _ => PaymentEngine::with_capacity(expected_clients, options.expected_transactions.unwrap_or(0)),
+    };
+    if let Some(path) = &options.initial_accounts {
+        let accounts = read_accounts_from_path(path)?;
+        info!("Loading {} initial accounts from {:?}", accounts.len(), path);
+        engine.load_accounts(accounts)?;
+    }
+    engine.set_purge_policy(options.purge_finalized);
+    engine.set_limits(options.limits);
+    engine.set_withdrawal_fee(options.withdrawal_fee);
+    engine.set_allow_partial_disputes(options.allow_partial_disputes);
+    engine.set_allow_withdrawal_disputes(options.allow_withdrawal_disputes);
+    engine.set_max_deferred_disputes(options.max_deferred_disputes);
+    engine.set_allow_admin_transactions(options.allow_admin_transactions);
+    engine.set_create_accounts_on_reference(options.create_accounts_on_reference);
+    engine.set_dispute_client_check(options.dispute_client_check);
+    engine.set_dispute_expiry(options.dispute_expiry);
+    if options.multi_currency {
+        engine.set_base_currency(options.base_currency);
+    }
+    engine.set_record_history(options.record_history || options.history_out.is_some() || options.dry_run.is_some());
+    if let Some(sender) = options.event_sender.clone() {
+        engine.set_event_handler(Box::new(move |event| {
+            let _ = sender.send(event);
+        }));
+    }
+    Ok(engine)
+}
+
+/// Engine and progress of a run, shared with readers such as the query server.
+///
+/// The processor takes the engine's write lock once per batch, so readers see
+/// state as of the last completed batch.
+pub(crate) struct LiveRun {
+    pub(crate) engine: RwLock<PaymentEngine>,
+    pub(crate) report: Mutex<ProcessingReport>,
+    // Times applying batches as if slow and records their sizes
+    #[cfg(test)]
+    hooks: TestHooks,
+}
+
+/// Lets tests simulate a slow engine and see the batch sizes it leads to
+#[cfg(test)]
+#[derive(Default)]
+struct TestHooks {
+    // Moves the run's manual clock forward by this much per transaction
+    // applied, so batch sizes follow exact apply times
+    apply_delay: Option<(Arc<crate::clock::ManualClock>, Duration)>,
+    batch_sizes: Mutex<Vec<usize>>,
+}
+
+impl LiveRun {
+    pub(crate) fn new(engine: PaymentEngine) -> Self {
+        Self {
+            engine: RwLock::new(engine),
+            report: Mutex::new(ProcessingReport::default()),
+            #[cfg(test)]
+            hooks: TestHooks::default(),
+        }
+    }
+
+    /// Make the report so far visible to readers
+    fn publish(&self, report: &ProcessingReport) {
+        if let Ok(mut published) = self.report.lock() {
+            published.clone_from(report);
+        }
+    }
+
+    /// Write a progress line for the batch of `lines` to stderr if verbose
+    fn report_progress(&self, lines: &RangeInclusive<usize>, verbosity: Verbosity) {
+        if verbosity != Verbosity::Verbose {
+            return;
+        }
+        if let Ok(progress) = self.report.lock() {
+            if let Err(e) = write_batch_progress(lines, &progress, std::io::stderr().lock()) {
+                warn!("Failed to write batch progress: {}", e);
+            }
+        }
+    }
+}
+
+/// Process transactions from a CSV file as a stream into an engine owned by the caller
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) async fn process_transactions_stream(
+    file_path: &Path,
+    engine: &mut PaymentEngine,
+    options: &ProcessingOptions,
+) -> Result<ProcessingReport> {
+    let run = LiveRun::new(std::mem::take(engine));
+    let result = process_live_run(file_path, &run, options).await;
+    *engine = run.engine.into_inner();
+    result
+}
+
+/// Process transactions from a CSV file as a stream into a shared engine
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) async fn process_live_run(
+    file_path: &Path,
+    run: &LiveRun,
+    options: &ProcessingOptions,
+) -> Result<ProcessingReport> {
+    let source = RecordSource::open_lines(file_path, options).await?;
+    process_records(source, run, options).await
+}
+
+/// Process the records of an opened input as a stream into a shared engine
+pub(crate) async fn process_records(source: RecordSource, run: &LiveRun, options: &ProcessingOptions) -> Result<ProcessingReport> {
+    let started = options.clock.monotonic();
+
+    // Recover from an existing WAL and open it for appending
+    let mut recovered = RecoveredTransactions::default();
+    let mut wal = match &options.wal_path {
+        Some(wal_path) => {
+            if wal_path.exists() {
+                recovered = replay_wal(&mut *run.engine.write().await, wal_path).await?;
+            }
+            Some(WalWriter::open(wal_path)?)
+        }
+        None => None,
+    };
+
+    // Only count rejections, fees, chargebacks, expiries and gross totals from
+    // this input, not from the WAL replay
+    let (
+        rejected_before,
+        limit_rejections_before,
+        fees_before,
+        chargebacks_before,
+        chargeback_totals_before,
+        withdrawal_disputes_before,
+        expired_before,
+        gross_before,
+    ) = {
+        let engine = run.engine.read().await;
+        (
+            engine.rejected_transactions(),
+            engine.limit_rejections(),
+            engine.fees(),
+            engine.chargeback_count(),
+            engine.chargeback_totals(),
+            engine.withdrawal_disputes_rejected(),
+            engine.expired_dispute_count(),
+            engine.gross_totals(),
+        )
+    };
+
+    let seen = options.seen_tx_ids_path.as_deref().map(SeenTransactions::load).transpose()?;
+    let locked = match options.skip_locked_fast_path {
+        true => Some(Arc::new(LockedClients::of(&*run.engine.read().await))),
+        false => None,
+    };
+    let batch_size_mode = options.batch_size_mode;
+    let batch_size = Arc::new(AtomicUsize::new(batch_size_mode.initial_size()));
+    let mut reader = BatchReader {
+        source,
+        batch_size: batch_size.clone(),
+        skip_records: options.skip_records,
+        max_records: options.max_records,
+        stop_after: options.stop_after,
+        client_filter: options.client_filter.clone(),
+        client_sample: options.sample.map(|sample| (sample, IdMap::default())),
+        locked: locked.clone(),
+        transaction_hook: options.transaction_hook.clone(),
+        amount_precision: options.amount_precision,
+        recovered,
+        seen,
+        sample: PhaseSample::default(),
+        clock: options.clock.clone(),
+        report: ProcessingReport::default(),
+        batch_lines: 0..=0,
+    };
+    let parallel = options.parallel_batches;
+    // Changes are tracked from here, so clients replayed from the WAL aren't sent
+    let changes = options.change_stream.as_ref().map(ChangeSink::open).transpose()?;
+    if changes.is_some() {
+        let mut engine = run.engine.write().await;
+        engine.set_track_changes(true);
+        engine.take_changed_clients();
+    }
+    let mut applied = AppliedSoFar {
+        rejected_before,
+        locked,
+        changes,
+        group_by_client: options.group_by_client_in_batch && !parallel && options.dispute_expiry.is_none(),
+        clock: options.clock.clone(),
+        apply_time: Duration::ZERO,
+        throttle_time: Duration::ZERO,
+        failed_batches: Vec::new(),
+    };
+    let mut snapshots =
+        options.snapshot_every.map(|every| SnapshotWriter::new(every, &options.snapshot_path_template, options.clock.clone()));
+    let mut pacer = options.max_tps.map(Pacer::new);
+
+    // The engine hands each batch's vector back empty, and it is refilled
+    // with the next batch, so a run only allocates one per batch in flight
+    let tiny_batches = matches!(batch_size_mode, BatchSizeMode::Fixed(size) if size < MIN_PIPELINED_BATCH_SIZE);
+    if options.pipeline_depth == 0 || tiny_batches {
+        // Read and apply batches in turn on this task, reusing a single vector
+        let mut buffer = None;
+        while let Some(batch) = reader.next_batch(buffer.take()).await {
+            let (progress, lines) = (reader.report.clone(), reader.batch_lines.clone());
+            let (current, len) = (batch_size.load(Ordering::Relaxed), batch.len());
+            let started = applied.apply_time;
+            buffer = apply_and_publish(run, batch, lines.clone(), wal.as_mut(), parallel, progress, &mut applied).await;
+            run.report_progress(&lines, options.verbosity);
+            if let Some(pacer) = &mut pacer {
+                applied.throttle_time += pacer.pace(len).await;
+            }
+            batch_size.store(batch_size_mode.next_size(current, len, applied.apply_time - started), Ordering::Relaxed);
+            if let Some(snapshots) = &mut snapshots {
+                snapshots.after_batch(run, reader.report.lines_read, options).await;
+            }
+        }
+    } else {
+        // Read and parse on a separate task while the previous batches are
+        // applied. Applied batches' vectors go back to the reader to refill.
+        let (sender, mut receiver) = mpsc::channel(options.pipeline_depth);
+        let (spare_sender, mut spare_receiver) = mpsc::channel(options.pipeline_depth + 1);
+        let reading = tokio::spawn(async move {
+            while let Some(batch) = reader.next_batch(spare_receiver.try_recv().ok()).await {
+                if sender.send((batch, reader.batch_lines.clone(), reader.report.clone())).await.is_err() {
+                    break;
+                }
+            }
+            reader
+        });
+        // Batches already queued keep their size; the reader uses the new
+        // size from the next batch it reads
+        while let Some((batch, lines, progress)) = receiver.recv().await {
+            let (current, len) = (batch_size.load(Ordering::Relaxed), batch.len());
+            let (started, records) = (applied.apply_time, progress.lines_read);
+            let buffer = apply_and_publish(run, batch, lines.clone(), wal.as_mut(), parallel, progress, &mut applied).await;
+            run.report_progress(&lines, options.verbosity);
+            if let Some(pacer) = &mut pacer {
+                applied.throttle_time += pacer.pace(len).await;
+            }
+            if let Some(snapshots) = &mut snapshots {
+                snapshots.after_batch(run, records, options).await;
+            }
+            batch_size.store(batch_size_mode.next_size(current, len, applied.apply_time - started), Ordering::Relaxed);
+            if let Some(buffer) = buffer {
+                let _ = spare_sender.try_send(buffer);
+            }
+        }
+        reader = reading.await?;
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.finish().await;
+    }
+    if let Some(seen) = &reader.seen {
+        seen.save()?;
+    }
+
+    let mut report = reader.report;
+    let stats = {
+        let mut engine = run.engine.write().await;
+        engine.observe_tx_id(report.max_tx_id);
+        report.max_tx_id = engine.max_tx_id();
+        report.transactions_rejected = engine.rejected_transactions() - rejected_before;
+        report.limit_rejections = per_client_since(engine.limit_rejections(), &limit_rejections_before);
+        report.fees_by_client = per_client_since(engine.fees(), &fees_before);
+        report.chargebacks = engine.chargeback_count() - chargebacks_before;
+        report.chargebacks_by_client = per_client_since(engine.chargeback_totals(), &chargeback_totals_before);
+        report.withdrawal_disputes_rejected = engine.withdrawal_disputes_rejected() - withdrawal_disputes_before;
+        report.disputes_expired = engine.expired_dispute_count() - expired_before;
+        let (deposited, withdrawn) = engine.gross_totals();
+        (report.deposited_total, report.withdrawn_total) = (deposited - gross_before.0, withdrawn - gross_before.1);
+        engine.stats()
+    };
+    report.fees_collected = report.fees_by_client.values().sum();
+    report.chargeback_total = report.chargebacks_by_client.values().sum();
+    report.apply_time = applied.apply_time;
+    report.throttle_time = applied.throttle_time;
+    report.failed_batches = applied.failed_batches;
+    report.sample_estimate = options.sample.map(|sample| SampleEstimate::extrapolate(&report, sample.rate));
+    report.elapsed = options.clock.elapsed(started);
+    run.publish(&report);
+    
+    info!("Processed {} transactions", report.lines_read);
+    info!("Processing report: {:?}", report);
+    info!("Engine: {}", stats);
+    if report.oversized_lines > 0 {
+        warn!("Skipped {} lines longer than {} bytes", report.oversized_lines, options.max_line_length);
+    }
+    if report.max_records_reached {
+        warn!("Stopped after reaching the limit of {} records", report.lines_read);
+    }
+    if report.stop_reached {
+        info!("Stopped at {:?} after {} records", options.stop_after, report.lines_read);
+    }
+    if let Some(max_tps) = options.max_tps {
+        info!("Waited {:?} to stay under {} transactions/s, averaging {:.0}", report.throttle_time, max_tps, report.transactions_per_sec());
+    }
+    if !report.failed_batches.is_empty() {
+        warn!("Rolled back {} batches that failed to apply", report.failed_batches.len());
+    }
+    
+    Ok(report)
+}
+
+/// Where the records of a run come from
+pub(crate) enum RecordSource {
+    /// Delimited text lines, usually following a header line
+    Lines {
+        lines: Box<LineSource>,
+        format: LineFormat,
+        /// The first line of a file without a header, returned as the first record
+        first_line: Option<Result<String>>,
+        /// Lines before the first record: 1 for the header, 0 without one
+        header_lines: usize,
+        /// Repeated header lines skipped so far
+        repeated_headers: usize,
+    },
+    /// Rows of a Parquet file
+    #[cfg(feature = "parquet")]
+    Parquet(Box<crate::parquet::ParquetRows>),
+}
+
+impl RecordSource {
+    /// Open a delimited text file and read its first line. If it is a header,
+    /// note whether it has a currency column; otherwise it is kept as the
+    /// first record rather than dropped.
+    pub(crate) async fn open_lines(file_path: &Path, options: &ProcessingOptions) -> Result<Self> {
+        let lines = if options.follow {
+            LineSource::follow(file_path, options.max_line_length, options.encoding, &options.end_marker).await?
+        } else {
+            LineSource::open(file_path, options.input_mode, options.max_line_length, options.encoding).await?
+        };
+        Self::from_lines(lines, &file_path.display().to_string(), options).await
+    }
+
+    /// Read the first line of an opened text input named `input` in
+    /// messages, as [`open_lines`](Self::open_lines) does
+    pub(crate) async fn from_lines(mut lines: LineSource, input: &str, options: &ProcessingOptions) -> Result<Self> {
+        let mut format = LineFormat::new(options, false);
+        let first_line = match lines.next_line().await {
+            Some(Ok(header)) if format.is_header(&header) => {
+                format.currency_column = options.multi_currency && format.has_currency_column(&header);
+                None
+            }
+            Some(first_line) => {
+                if format.fixed_width.is_none() {
+                    warn!("{:?} has no header line, reading its first line as a transaction", input);
+                }
+                // Without a header, a fifth column can only be a currency
+                format.currency_column = options.multi_currency;
+                Some(first_line.map(Cow::into_owned))
+            }
+            None => None,
+        };
+        let header_lines = usize::from(first_line.is_none());
+        Ok(Self::Lines { lines: Box::new(lines), format, first_line, header_lines, repeated_headers: 0 })
+    }
+
+    /// Position of a record in the input as reported in errors: the line
+    /// number for text files, counting the header and any repeated headers
+    /// skipped, and the row number otherwise
+    fn line_number(&self, record: usize) -> usize {
+        match self {
+            Self::Lines { header_lines, repeated_headers, .. } => record + header_lines + repeated_headers,
+            #[cfg(feature = "parquet")]
+            Self::Parquet(_) => record,
+        }
+    }
+
+    /// Repeated header lines skipped so far
+    fn repeated_headers(&self) -> usize {
+        match self {
+            Self::Lines { repeated_headers, .. } => *repeated_headers,
+            #[cfg(feature = "parquet")]
+            Self::Parquet(_) => 0,
+        }
+    }
+
+    /// Read and parse the record at `line_number`, adding the time each took
+    /// to `sample` if given. The outer error is a record that could not be
+    /// read, the inner one a record that could not be parsed. Repeated header
+    /// lines are skipped if the format allows it.
+    async fn next_record(
+        &mut self,
+        mut line_number: usize,
+        sample: Option<&mut PhaseSample>,
+        locked: Option<&LockedClients>,
+    ) -> Option<Result<Record>> {
+        let started = sample.is_some().then(Instant::now);
+        match self {
+            Self::Lines { lines, format, first_line, repeated_headers, .. } => {
+                if let Some(line) = first_line.take() {
+                    return Some(line.map(|line| Record::read(line_number, &line, *format, locked)));
+                }
+                loop {
+                    let line = lines.next_line().await?;
+                    if format.skip_repeated_headers && matches!(&line, Ok(line) if format.is_header(line)) {
+                        debug!("Skipping repeated header on line {}", line_number);
+                        *repeated_headers += 1;
+                        line_number += 1;
+                        continue;
+                    }
+                    let Some((sample, started)) = sample.zip(started) else {
+                        return Some(line.map(|line| Record::read(line_number, &line, *format, locked)));
+                    };
+                    let parsing = Instant::now();
+                    let record = line.map(|line| Record::read(line_number, &line, *format, locked));
+                    sample.read += parsing - started;
+                    sample.parse += parsing.elapsed();
+                    return Some(record);
+                }
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(rows) => {
+                let record = rows.next_record(line_number).map(|record| record.map(Record::from));
+                if let Some((sample, started)) = sample.zip(started) {
+                    sample.read += started.elapsed();
+                }
+                record
+            }
+        }
+    }
+}
+
+/// A record read from a [`RecordSource`]
+enum Record {
+    /// A line or row parsed into a transaction
+    Parsed(Transaction),
+    /// A line or row that failed to parse, with its tx id if that field parsed
+    Malformed { error: PaymentEngineError, tx: Option<TxId> },
+    /// A deposit or withdrawal of a locked client, left unparsed
+    LockedClient { client: u16, tx: TxId },
+}
+
+impl Record {
+    /// Parse the line found at `line_number`, unless it is a deposit or
+    /// withdrawal of a client in `locked`
+    fn read(line_number: usize, line: &str, format: LineFormat, locked: Option<&LockedClients>) -> Self {
+        match locked.and_then(|locked| locked_client_line(line, format, locked)) {
+            Some((client, tx)) => Self::LockedClient { client, tx },
+            None => match parse_line(line_number, line, format) {
+                Ok(transaction) => Self::Parsed(transaction),
+                Err(error) => Self::Malformed { error, tx: tx_field(line, format) },
+            },
+        }
+    }
+
+    /// The record's tx id, if it is known
+    fn tx(&self) -> Option<TxId> {
+        match self {
+            Self::Parsed(transaction) => Some(transaction.tx),
+            Self::Malformed { tx, .. } => *tx,
+            Self::LockedClient { tx, .. } => Some(*tx),
+        }
+    }
+}
+
+impl From<Result<Transaction>> for Record {
+    fn from(parsed: Result<Transaction>) -> Self {
+        match parsed {
+            Ok(transaction) => Self::Parsed(transaction),
+            Err(error) => Self::Malformed { error, tx: None },
+        }
+    }
+}
+
+/// The tx id of a line that failed to parse, if its tx field holds one
+fn tx_field(line: &str, format: LineFormat) -> Option<TxId> {
+    let tx = match format.fixed_width {
+        Some(spec) => line.get(spec.tx.offset..spec.tx.end())?,
+        None => line.split(format.delimiter).nth(2)?,
+    };
+    tx.trim().parse().ok()
+}
+
+/// The client and tx id of a deposit or withdrawal of a locked client,
+/// reading only the line's first three columns. `None` for any other line,
+/// including malformed ones, which are left for parsing to reject.
+fn locked_client_line(line: &str, format: LineFormat, locked: &LockedClients) -> Option<(u16, TxId)> {
+    let (kind, client, tx) = match format.fixed_width {
+        Some(spec) => {
+            let field = |field: FixedWidthField| line.get(field.offset..field.end()).map(str::trim);
+            (field(spec.transaction_type)?, field(spec.client)?, field(spec.tx)?)
+        }
+        None => {
+            let mut fields = line.split(format.delimiter).map(str::trim);
+            (fields.next()?, fields.next()?, fields.next()?)
+        }
+    };
+    if !matches!(kind, "deposit" | "withdrawal") {
+        return None;
+    }
+    let client = client.parse().ok().filter(|&client| locked.contains(client))?;
+    Some((client, tx.parse().ok()?))
+}
+
+/// Set of locked clients, one bit per client id, shared by the reader
+/// skipping their transactions and the batches locking them. Accounts never
+/// unlock, so a reader ahead of the engine only misses clients locked later.
+#[derive(Debug)]
+pub(crate) struct LockedClients(Box<[AtomicU64]>);
+
+impl LockedClients {
+    /// The clients locked in `engine`
+    fn of(engine: &PaymentEngine) -> Self {
+        let locked = Self((0..=u16::MAX / 64).map(|_| AtomicU64::new(0)).collect());
+        for account in engine.get_accounts().iter().filter(|account| account.locked) {
+            locked.insert(account.client);
+        }
+        locked
+    }
+
+    fn insert(&self, client: u16) {
+        self.0[usize::from(client / 64)].fetch_or(1 << (client % 64), Ordering::Relaxed);
+    }
+
+    fn contains(&self, client: u16) -> bool {
+        self.0[usize::from(client / 64)].load(Ordering::Relaxed) & (1 << (client % 64)) != 0
+    }
+}
+
+/// Read and parse times of the records timed so far
+#[derive(Debug, Default)]
+struct PhaseSample {
+    read: Duration,
+    parse: Duration,
+}
+
+impl PhaseSample {
+    /// Split the time spent reading a batch into reading and parsing in the
+    /// proportion of the sampled records
+    fn split(&self, elapsed: Duration) -> (Duration, Duration) {
+        let sampled = (self.read + self.parse).as_secs_f64();
+        if sampled == 0.0 {
+            return (elapsed, Duration::ZERO);
+        }
+        let read = elapsed.mul_f64(self.read.as_secs_f64() / sampled).min(elapsed);
+        (read, elapsed - read)
+    }
+}
+
+/// Reads records and groups the parsed transactions into batches, counting
+/// records that are read, skipped or rejected in its report
+struct BatchReader {
+    source: RecordSource,
+    // Size of the next batch, adjusted after each applied batch in auto mode
+    batch_size: Arc<AtomicUsize>,
+    skip_records: usize,
+    max_records: Option<usize>,
+    stop_after: Option<StopAfter>,
+    client_filter: Option<ClientFilter>,
+    // Whether each client seen so far is in the sample
+    client_sample: Option<(ClientSample, IdMap<u16, bool>)>,
+    // Clients whose deposits and withdrawals are dropped before parsing
+    locked: Option<Arc<LockedClients>>,
+    transaction_hook: Option<TransactionHook>,
+    // Brings the amounts the hook returns to precision
+    amount_precision: AmountPrecision,
+    // Transactions already applied from the WAL, skipped in the input
+    recovered: RecoveredTransactions,
+    // Transactions applied by previous runs, skipped as duplicates
+    seen: Option<SeenTransactions>,
+    // Read and parse times of the sampled records
+    sample: PhaseSample,
+    // Times reading and parsing each batch, split by the sampled times
+    clock: Arc<dyn Clock>,
+    report: ProcessingReport,
+    // Input lines the last batch was read from, including rejected ones
+    batch_lines: RangeInclusive<usize>,
+}
+
+impl BatchReader {
+    /// Read the next batch of up to `batch_size` transactions into `buffer`,
+    /// an empty vector from an earlier batch, or a new one without it.
+    /// Returns `None` once the input is exhausted.
+    async fn next_batch(&mut self, buffer: Option<Vec<Transaction>>) -> Option<Vec<Transaction>> {
+        let started = self.clock.monotonic();
+        let report = &mut self.report;
+        let batch_size = self.batch_size.load(Ordering::Relaxed);
+        let mut batch = buffer.unwrap_or_else(|| Vec::with_capacity(batch_size));
+        debug_assert!(batch.is_empty());
+
+        // Skipped records still have to be read to find the lines after them,
+        // and keep their place in the line numbers of later errors
+        while report.records_skipped < self.skip_records {
+            let line_number = self.source.line_number(report.records_skipped + 1);
+            let Some(record) = self.source.next_record(line_number, None, None).await else { break };
+            report.max_tx_id = report.max_tx_id.max(record.ok().and_then(|record| record.tx()));
+            report.records_skipped += 1;
+        }
+
+        let first_line = self.source.line_number(report.records_skipped + report.lines_read + 1);
+        while batch.len() < batch_size && !report.max_records_reached && !report.stop_reached {
+            let line_number = self.source.line_number(report.records_skipped + report.lines_read + 1);
+            let sample = report.lines_read.is_multiple_of(TIMING_SAMPLE_INTERVAL).then_some(&mut self.sample);
+            // While transactions recovered from the WAL are left, none is dropped
+            // early, so each is taken off the recovered set below and the fast
+            // path resumes once they all are
+            let locked = self.locked.as_deref().filter(|_| self.recovered.is_empty());
+            let Some(record) = self.source.next_record(line_number, sample, locked).await else { break };
+            if self.max_records.is_some_and(|max| report.lines_read >= max) {
+                report.max_records_reached = true;
+                break;
+            }
+            report.lines_read += 1;
+            let tx = record.as_ref().ok().and_then(Record::tx);
+            report.max_tx_id = report.max_tx_id.max(tx);
+            report.stop_reached = match self.stop_after {
+                Some(StopAfter::Tx(stop)) => tx == Some(stop) && !matches!(record, Ok(Record::Malformed { .. })),
+                Some(StopAfter::Line(last)) => line_number >= last,
+                None => false,
+            };
+            let record = match record {
+                Ok(Record::Parsed(transaction)) => Ok(Ok(transaction)),
+                Ok(Record::Malformed { error, .. }) => Ok(Err(error)),
+                Ok(Record::LockedClient { client, tx }) => {
+                    report.records_locked_skipped += 1;
+                    debug!("Skipping transaction {} of locked client {}", tx, client);
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+
+            // The hook sees parsed transactions before the filters below
+            let record = match (record, &self.transaction_hook) {
+                (Ok(Ok(transaction)), Some(hook)) => match hook.apply(transaction) {
+                    Some(transaction) => Ok(with_amount_precision(transaction, self.amount_precision, line_number)),
+                    None => {
+                        report.records_filtered += 1;
+                        continue;
+                    }
+                },
+                (record, _) => record,
+            };
+
+            // Duplicates never reach the WAL, so they are skipped before looking
+            // up the transactions it recovered
+            match record {
+                Ok(Ok(transaction)) if self.client_filter.as_ref().is_some_and(|filter| !filter.matches(transaction.client)) => {
+                    report.records_filtered += 1;
+                }
+                Ok(Ok(transaction))
+                    if self.client_sample.as_mut().is_some_and(|(sample, sampled)| {
+                        !*sampled.entry(transaction.client).or_insert_with(|| sample.contains(transaction.client))
+                    }) =>
+                {
+                    report.records_sampled_out += 1;
+                }
+                Ok(Ok(transaction)) if self.seen.as_ref().is_some_and(|seen| seen.is_duplicate(&transaction)) => {
+                    report.duplicates += 1;
+                    debug!("Skipping transaction {} applied by a previous run", transaction.tx);
+                }
+                Ok(Ok(transaction)) if self.recovered.take(&transaction) => {
+                    // Already applied in a previous run and recovered from the WAL
+                    if let Some(seen) = self.seen.as_mut() {
+                        seen.insert(&transaction);
+                    }
+                }
+                Ok(Ok(transaction)) => {
+                    report.transactions_processed += 1;
+                    *report.transactions_by_type.entry(transaction.transaction_type).or_default() += 1;
+                    if let Some(seen) = self.seen.as_mut() {
+                        seen.insert(&transaction);
+                    }
+                    batch.push(transaction);
+                }
+                Ok(Err(e)) => {
+                    report.parse_errors += 1;
+                    error!("{}", e);
+                }
+                Err(e @ PaymentEngineError::LineTooLong(_)) => {
+                    report.oversized_lines += 1;
+                    error!("Skipping line {}: {}", line_number, e);
+                }
+                Err(e) => {
+                    report.read_errors += 1;
+                    error!("Error reading line {}: {}", line_number, e);
+                }
+            }
+        }
+
+        report.repeated_headers = self.source.repeated_headers();
+        self.batch_lines = first_line..=self.source.line_number(report.records_skipped + report.lines_read);
+        let (read_time, parse_time) = self.sample.split(self.clock.elapsed(started));
+        report.read_time += read_time;
+        report.parse_time += parse_time;
+        (!batch.is_empty()).then_some(batch)
+    }
+}
+
+/// Bring the amount of a transaction returned by a hook to the supported
+/// precision, as parsing does, failing as a parse error of its line
+fn with_amount_precision(mut transaction: Transaction, amount_precision: AmountPrecision, line_number: usize) -> Result<Transaction> {
+    if let Some(amount) = transaction.amount {
+        let amount = amount_precision.apply(amount).ok_or_else(|| {
+            PaymentEngineError::parse_error(line_number, "transaction hook output", PaymentEngineError::ExcessPrecision(amount))
+        })?;
+        transaction.amount = Some(amount);
+    }
+    Ok(transaction)
+}
+
+/// What applying the batches of a run has added up to so far
+struct AppliedSoFar {
+    // Rejections counted by the engine before the run
+    rejected_before: usize,
+    // Clients for the reader to skip, updated with those charged back
+    locked: Option<Arc<LockedClients>>,
+    changes: Option<ChangeSink>,
+    // Whether sequential batches are grouped by client before they are applied
+    group_by_client: bool,
+    // Times applying the batches
+    clock: Arc<dyn Clock>,
+    apply_time: Duration,
+    throttle_time: Duration,
+    failed_batches: Vec<RangeInclusive<usize>>,
+}
+
+/// Token bucket holding a run to `max_tps` transactions per second. A
+/// batch's tokens are taken once it is applied and the run sleeps off any
+/// deficit, so there is no timer per transaction. The bucket starts empty and
+/// fills up to a second's worth while the input is slow. It keeps to the
+/// system clock whatever the run's clock, as it sleeps in real time.
+struct Pacer {
+    max_tps: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Pacer {
+    fn new(max_tps: u32) -> Self {
+        Self { max_tps: f64::from(max_tps), tokens: 0.0, refilled: Instant::now() }
+    }
+
+    /// Take tokens for `transactions` and wait until the bucket is out of
+    /// deficit, returning the time waited
+    async fn pace(&mut self, transactions: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.max_tps;
+        self.tokens = (self.tokens + refill).min(self.max_tps) - transactions as f64;
+        self.refilled = now;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.max_tps)).await;
+        now.elapsed()
+    }
+}
+
+/// Apply a batch read from the input `lines` and publish the reader's
+/// progress along with what was applied so far, adding the batch to
+/// `applied`. Returns the batch's emptied vector unless applying it failed.
+///
+/// A batch that panics is rolled back, so it leaves no half-applied
+/// transaction behind, and its lines are recorded as a failed batch.
+async fn apply_and_publish(
+    run: &LiveRun,
+    mut batch: Vec<Transaction>,
+    lines: RangeInclusive<usize>,
+    wal: Option<&mut WalWriter>,
+    parallel: bool,
+    mut progress: ProcessingReport,
+    applied: &mut AppliedSoFar,
+) -> Option<Vec<Transaction>> {
+    // Only chargebacks lock accounts
+    let charged_back: Vec<u16> = match &applied.locked {
+        Some(_) => batch.iter().filter(|t| t.transaction_type == TransactionType::Chargeback).map(|t| t.client).collect(),
+        None => Vec::new(),
+    };
+    let mut engine = run.engine.write().await;
+    let started = applied.clock.monotonic();
+    if applied.group_by_client {
+        engine.group_by_client(&mut batch);
+    }
+    #[cfg(test)]
+    {
+        run.hooks.batch_sizes.lock().unwrap().push(batch.len());
+        if let Some((clock, per_transaction)) = &run.hooks.apply_delay {
+            clock.advance(*per_transaction * batch.len() as u32);
+        }
+    }
+    engine.begin_journal();
+    let (buffer, panic) = match AssertUnwindSafe(apply_batch(&mut engine, batch, wal, parallel)).catch_unwind().await {
+        Ok(Ok(buffer)) => (Some(buffer), None),
+        Ok(Err(PaymentEngineError::TaskFailed(e))) if e.is_panic() => (None, Some(e.into_panic())),
+        Ok(Err(e)) => {
+            error!("Failed to process transaction batch: {}", e);
+            (None, None)
+        }
+        Err(panic) => (None, Some(panic)),
+    };
+    match panic {
+        Some(panic) => {
+            error!("Applying the batch of lines {}-{} panicked: {}", lines.start(), lines.end(), panic_message(&*panic));
+            if let Err(e) = engine.roll_back_journal() {
+                error!("Failed to roll back the batch of lines {}-{}: {}", lines.start(), lines.end(), e);
+            }
+            applied.failed_batches.push(lines.clone());
+        }
+        None => {
+            // Disputes expire before each transaction; this catches the ones the
+            // next would, such as those of clients another shard moved time past
+            if let Err(e) = engine.expire_disputes() {
+                error!("Failed to expire disputes after the batch of lines {}-{}: {}", lines.start(), lines.end(), e);
+            }
+            engine.commit_journal();
+        }
+    }
+    if let Some(locked) = &applied.locked {
+        for client in charged_back {
+            if engine.get_account(client).is_some_and(|account| account.locked) {
+                locked.insert(client);
+            }
+        }
+    }
+    if let Some(changes) = &mut applied.changes {
+        if let Err(e) = changes.after_batch(&mut engine) {
+            error!("Failed to stream the account changes of lines {}-{}: {}", lines.start(), lines.end(), e);
+        }
+    }
+    applied.apply_time += applied.clock.elapsed(started);
+    progress.apply_time = applied.apply_time;
+    progress.transactions_rejected = engine.rejected_transactions() - applied.rejected_before;
+    progress.failed_batches.clone_from(&applied.failed_batches);
+    drop(engine);
+    run.publish(&progress);
+    buffer
+}
+
+/// Message a panic was raised with, if it was a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown cause", String::as_str),
+    }
+}
+
+/// Per-client totals accumulated since `before` was taken, without clients
+/// that didn't change
+fn per_client_since<V>(after: BTreeMap<u16, V>, before: &BTreeMap<u16, V>) -> BTreeMap<u16, V>
+where
+    V: Copy + PartialEq + Default + std::ops::Sub<Output = V>,
+{
+    after
+        .into_iter()
+        .map(|(client, value)| (client, before.get(&client).map_or(value, |&b| value - b)))
+        .filter(|(_, value)| *value != V::default())
+        .collect()
+}
+
+/// Apply a batch to the engine, logging each transaction to the WAL if one is
+/// open, and return the batch's emptied vector. The batch is logged once it
+/// has been applied, and if applying it panics, every transaction is logged
+/// as failed before the panic goes on, as the batch will be rolled back.
+/// Every transaction of the batch is applied even if writing the WAL fails;
+/// the first WAL error is returned after.
+async fn apply_batch(
+    engine: &mut PaymentEngine,
+    mut batch: Vec<Transaction>,
+    wal: Option<&mut WalWriter>,
+    parallel: bool,
+) -> Result<Vec<Transaction>> {
+    let wal = match wal {
+        Some(wal) => wal,
+        None if parallel => return Ok(engine.process_transaction_batch_parallel(batch).await?.into_buffer()),
+        None => return Ok(engine.process_transaction_batch(batch).await.into_buffer()),
+    };
+
+    let apply_all = || {
+        let apply = |transaction: &Transaction| match engine.apply(transaction.clone()) {
+            Ok(_) => WalOutcome::Applied,
+            Err(e) => {
+                error!("Error processing transaction: {}", e);
+                WalOutcome::Failed
+            }
+        };
+        batch.iter().map(apply).collect::<Vec<_>>()
+    };
+    let outcomes = match std::panic::catch_unwind(AssertUnwindSafe(apply_all)) {
+        Ok(outcomes) => outcomes,
+        Err(panic) => {
+            let logged = batch.iter().try_for_each(|transaction| wal.append(transaction, WalOutcome::Failed));
+            if let Err(e) = logged.and_then(|()| wal.sync()) {
+                error!("Failed to log the failed batch to the WAL: {}", e);
+            }
+            std::panic::resume_unwind(panic);
+        }
+    };
+
+    let mut wal_result = Ok(());
+    for (transaction, outcome) in batch.drain(..).zip(outcomes) {
+        if wal_result.is_ok() {
+            wal_result = wal.append(&transaction, outcome);
+        }
+    }
+
+    // Make the batch durable before the next one starts
+    wal_result.and_then(|()| wal.sync()).map(|()| batch)
+}
+
+/// How the fields of an input line are separated and parsed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LineFormat {
+    delimiter: char,
+    decimal_separator: char,
+    /// Read a currency from the fifth column; an empty currency means the base currency
+    currency_column: bool,
+    amount_precision: AmountPrecision,
+    /// Thousands separator to remove from amounts, along with a leading `+`;
+    /// `None` parses amounts strictly
+    thousands_separator: Option<char>,
+    /// Skip header lines after the first line, e.g. from concatenated files
+    skip_repeated_headers: bool,
+    /// Accept an amount on disputes, resolves and chargebacks
+    partial_disputes: bool,
+    /// Read a timestamp from the column after the amount, or the currency if there is one
+    timestamp_column: bool,
+    /// Slice fields at fixed offsets instead of splitting at the delimiter
+    fixed_width: Option<FixedWidthSpec>,
+}
+
+impl LineFormat {
+    pub(crate) fn new(options: &ProcessingOptions, currency_column: bool) -> Self {
+        Self {
+            delimiter: options.delimiter,
+            decimal_separator: options.decimal_separator,
+            currency_column,
+            amount_precision: options.amount_precision,
+            thousands_separator: options.lenient_amounts.then_some(options.thousands_separator),
+            skip_repeated_headers: options.tolerate_repeated_headers,
+            partial_disputes: options.allow_partial_disputes,
+            timestamp_column: options.timestamps,
+            fixed_width: match options.input_format {
+                InputFormat::Delimited => None,
+                InputFormat::FixedWidth(spec) => Some(spec),
+            },
+        }
+    }
+
+    /// The format [`Transaction::from_csv_line`] checks lines against:
+    /// `type,client,tx[,amount]` with a plain amount of at most 4 decimal
+    /// places, and no amount on disputes, resolves and chargebacks
+    pub(crate) fn strict(delimiter: char) -> Self {
+        Self {
+            delimiter,
+            decimal_separator: '.',
+            currency_column: false,
+            amount_precision: AmountPrecision::Reject,
+            thousands_separator: None,
+            skip_repeated_headers: false,
+            partial_disputes: false,
+            timestamp_column: false,
+            fixed_width: None,
+        }
+    }
+
+    /// Whether a line is a header, naming the `type`, `client` and `tx`
+    /// columns in that order, after a byte order mark if there is one.
+    /// Fixed-width records have no header.
+    pub(crate) fn is_header(&self, line: &str) -> bool {
+        if self.fixed_width.is_some() {
+            return false;
+        }
+        let mut fields = line.trim_start_matches('\u{feff}').split(self.delimiter).map(str::trim);
+        fields.next() == Some("type") && fields.next() == Some("client") && fields.next() == Some("tx")
+    }
+
+    /// Whether a header line names a currency column in the fifth position
+    fn has_currency_column(&self, header: &str) -> bool {
+        header.split(self.delimiter).nth(4).map(str::trim) == Some("currency")
+    }
+}
+
+/// Parse the line found at `line_number` of the input, wrapping any error in
+/// a [`PaymentEngineError::ParseError`] carrying the line and its content
+pub(crate) fn parse_line(line_number: usize, line: &str, format: LineFormat) -> Result<Transaction> {
+    parse_transaction_fields(line, format).map_err(|e| PaymentEngineError::parse_error(line_number, line, e))
+}
+
+/// Parse a client or transaction id no larger than `max`, telling ids that
+/// overflow it apart from malformed ones
+fn parse_id<T: FromStr<Err = ParseIntError>>(field: &'static str, value: &str, max: u64) -> Result<T> {
+    value.parse().map_err(|e: ParseIntError| match e.kind() {
+        IntErrorKind::PosOverflow => PaymentEngineError::IdOverflow { field, value: value.to_string(), max },
+        _ => PaymentEngineError::InvalidId { field, value: value.to_string() },
+    })
+}
+
+/// Parse a line into a Transaction. Fields are split in place instead of
+/// being collected into a Vec. Amounts are brought to 4 decimal places
+/// according to the format's `amount_precision`.
+pub(crate) fn parse_transaction_fields(line: &str, format: LineFormat) -> Result<Transaction> {
+    if let Some(spec) = format.fixed_width {
+        return parse_fixed_width_fields(line, spec, format);
+    }
+    let mut fields = line.split(format.delimiter).map(|s| s.trim());
+    
+    // Ensure we have the required fields (type, client, tx, [amount])
+    let (kind, client, tx) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(kind), Some(client), Some(tx)) => (kind, client, tx),
+        _ => return Err(PaymentEngineError::InvalidLineFormat(line.to_string())),
+    };
+    
+    // Parse the CSV fields
+    let transaction_type = parse_transaction_type(kind)?;
+    
+    let client: u16 = parse_id("client", client, u16::MAX.into())?;
+    let tx: TxId = parse_id("tx", tx, MAX_TX_ID)?;
+    
+    // Amount is optional (not present for dispute, resolve, chargeback)
+    let amount = match fields.next() {
+        Some(amount) if !amount.is_empty() => Some(parse_amount(amount, format)?),
+        _ => None,
+    };
+    check_amount(transaction_type, tx, amount, format.partial_disputes)?;
+
+    let currency = match format.currency_column.then(|| fields.next()).flatten() {
+        Some(currency) if !currency.is_empty() => {
+            Some(currency.parse().map_err(|_| PaymentEngineError::InvalidCurrency(currency.to_string()))?)
+        }
+        _ => None,
+    };
+
+    let timestamp = match format.timestamp_column.then(|| fields.next()).flatten() {
+        Some(timestamp) if !timestamp.is_empty() => {
+            Some(timestamp.parse().map_err(|_| PaymentEngineError::InvalidTimestamp(timestamp.to_string()))?)
+        }
+        _ => None,
+    };
+    
+    Ok(Transaction {
+        transaction_type,
+        client,
+        tx,
+        amount,
+        currency,
+        timestamp,
+    })
+}
+
+/// Parse a fixed-width record into a Transaction, slicing each field at the
+/// spec's offsets. The type, client and tx must lie within the line, while
+/// the amount may be cut short or missing, as trailing blanks often are.
+fn parse_fixed_width_fields(line: &str, spec: FixedWidthSpec, format: LineFormat) -> Result<Transaction> {
+    let invalid_line = || PaymentEngineError::InvalidLineFormat(line.to_string());
+    let field = |field: FixedWidthField| line.get(field.offset..field.end()).map(str::trim).ok_or_else(invalid_line);
+
+    let transaction_type = parse_transaction_type(field(spec.transaction_type)?)?;
+    let client: u16 = parse_id("client", field(spec.client)?, u16::MAX.into())?;
+    let tx: TxId = parse_id("tx", field(spec.tx)?, MAX_TX_ID)?;
+
+    let end = line.len();
+    let amount = line.get(spec.amount.offset.min(end)..spec.amount.end().min(end)).ok_or_else(invalid_line)?.trim();
+    let amount = match amount {
+        "" => None,
+        amount if spec.implied_decimal => {
+            let units = amount.parse::<i128>().ok().and_then(|units| Decimal::try_from_i128_with_scale(units, 4).ok());
+            Some(units.ok_or_else(|| PaymentEngineError::InvalidAmount(amount.to_string()))?)
+        }
+        amount => Some(parse_amount(amount, format)?),
+    };
+    check_amount(transaction_type, tx, amount, format.partial_disputes)?;
+
+    Ok(Transaction {
+        transaction_type,
+        client,
+        tx,
+        amount,
+        currency: None,
+        timestamp: None,
+    })
+}
+
+/// Parse a non-empty amount field with the format's separators, bringing it
+/// to 4 decimal places according to its `amount_precision`
+fn parse_amount(amount: &str, format: LineFormat) -> Result<Decimal> {
+    let invalid_amount = || PaymentEngineError::InvalidAmount(amount.to_string());
+    let lenient;
+    let amount = match format.thousands_separator {
+        Some(separator) => {
+            lenient = without_grouping(amount, separator, format.decimal_separator).ok_or_else(invalid_amount)?;
+            lenient.as_str()
+        }
+        None => amount,
+    };
+    let parsed: Decimal = if format.decimal_separator == '.' {
+        amount.parse().map_err(|_| invalid_amount())?
+    } else {
+        amount.replace(format.decimal_separator, ".").parse().map_err(|_| invalid_amount())?
+    };
+    format.amount_precision.apply(parsed).ok_or(PaymentEngineError::ExcessPrecision(parsed))
+}
+
+/// An amount written with a leading `+` or with `separator` between groups
+/// of thousands, such as `+1,234.56`, as a plain number. `None` if the
+/// groups aren't three digits after a first group of one to three, or if
+/// the separator is in the fraction.
+fn without_grouping(amount: &str, separator: char, decimal_separator: char) -> Option<String> {
+    let (sign, unsigned) = match amount.strip_prefix('+') {
+        Some(unsigned) => ("", unsigned),
+        None => amount.strip_prefix('-').map_or(("", amount), |unsigned| ("-", unsigned)),
+    };
+    let (integer, fraction) = unsigned.split_once(decimal_separator).unwrap_or((unsigned, ""));
+    if unsigned.starts_with(['+', '-']) || fraction.contains(separator) {
+        return None;
+    }
+    if integer.contains(separator) {
+        let mut groups = integer.split(separator);
+        let first = groups.next().unwrap_or_default();
+        if !(1..=3).contains(&first.len()) || groups.any(|group| group.len() != 3) {
+            return None;
+        }
+    }
+    Some(format!("{}{}", sign, unsigned.replace(separator, "")))
+}
+
+/// Parse the type column of a transaction
+/// Check that a transaction has an amount if and only if its type needs one:
+/// deposits, withdrawals and amendments always, disputes, resolves and
+/// chargebacks only with partial disputes, where it is optional
+pub(crate) fn check_amount(transaction_type: TransactionType, tx: TxId, amount: Option<Decimal>, partial_disputes: bool) -> Result<()> {
+    match (transaction_type, amount) {
+        (TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Amend, None) => {
+            Err(PaymentEngineError::MissingAmount(tx))
+        }
+        (TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback, Some(_)) if !partial_disputes => {
+            Err(PaymentEngineError::UnexpectedAmount(tx))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn parse_transaction_type(kind: &str) -> Result<TransactionType> {
+    match kind {
+        "deposit" => Ok(TransactionType::Deposit),
+        "withdrawal" => Ok(TransactionType::Withdrawal),
+        "dispute" => Ok(TransactionType::Dispute),
+        "resolve" => Ok(TransactionType::Resolve),
+        "chargeback" => Ok(TransactionType::Chargeback),
+        "amend" => Ok(TransactionType::Amend),
+        _ => Err(PaymentEngineError::InvalidTransactionType(kind.to_string())),
+    }
+}
+
+/// Statistics about an input file, gathered without applying any transactions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileSummary {
+    /// Lines in the file, including the header
+    pub lines: usize,
+    pub deposits: usize,
+    pub withdrawals: usize,
+    pub disputes: usize,
+    pub resolves: usize,
+    pub chargebacks: usize,
+    pub amendments: usize,
+    /// Distinct client ids across all transactions
+    pub distinct_clients: usize,
+    /// Distinct transaction ids across all transactions
+    pub distinct_transactions: usize,
+    #[serde(with = "decimal_string::option")]
+    pub min_amount: Option<Decimal>,
+    #[serde(with = "decimal_string::option")]
+    pub max_amount: Option<Decimal>,
+    /// Lines that could not be parsed as a transaction
+    pub parse_errors: usize,
+    /// Lines that could not be read, e.g. invalid UTF-8 or longer than `max_line_length`
+    pub read_errors: usize,
+}
+
+impl fmt::Display for FileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let amount = |amount: Option<Decimal>| amount.map_or_else(|| "-".to_string(), |a| a.to_string());
+        writeln!(f, "lines                  {}", self.lines)?;
+        writeln!(f, "deposits               {}", self.deposits)?;
+        writeln!(f, "withdrawals            {}", self.withdrawals)?;
+        writeln!(f, "disputes               {}", self.disputes)?;
+        writeln!(f, "resolves               {}", self.resolves)?;
+        writeln!(f, "chargebacks            {}", self.chargebacks)?;
+        writeln!(f, "amendments             {}", self.amendments)?;
+        writeln!(f, "distinct clients       {}", self.distinct_clients)?;
+        writeln!(f, "distinct transactions  {}", self.distinct_transactions)?;
+        writeln!(f, "min amount             {}", amount(self.min_amount))?;
+        writeln!(f, "max amount             {}", amount(self.max_amount))?;
+        writeln!(f, "parse errors           {}", self.parse_errors)?;
+        write!(f, "read errors            {}", self.read_errors)
+    }
+}
+
+/// Stream a CSV file once and gather statistics about its transactions
+/// without building any account state. Only the input options are used.
+pub async fn summarize_transactions(file_path: &Path, options: &ProcessingOptions) -> Result<FileSummary> {
+    let mut lines = LineSource::open(file_path, options.input_mode, options.max_line_length, options.encoding).await?;
+
+    let format = LineFormat::new(options, false);
+    let mut summary = FileSummary::default();
+    let mut clients: IdSet<u16> = IdSet::default();
+    let mut transactions: IdSet<TxId> = IdSet::default();
+
+    while let Some(line_result) = lines.next_line().await {
+        summary.lines += 1;
+        let Ok(line) = line_result else {
+            summary.read_errors += 1;
+            continue;
+        };
+        // The header, if the file has one, and repeated headers if tolerated
+        if format.is_header(&line) && (summary.lines == 1 || format.skip_repeated_headers) {
+            continue;
+        }
+        let Ok(transaction) = parse_transaction_fields(&line, format) else {
+            summary.parse_errors += 1;
+            continue;
+        };
+
+        match transaction.transaction_type {
+            TransactionType::Deposit => summary.deposits += 1,
+            TransactionType::Withdrawal => summary.withdrawals += 1,
+            TransactionType::Dispute => summary.disputes += 1,
+            TransactionType::Resolve => summary.resolves += 1,
+            TransactionType::Chargeback => summary.chargebacks += 1,
+            TransactionType::Amend => summary.amendments += 1,
+        }
+        clients.insert(transaction.client);
+        transactions.insert(transaction.tx);
+        if let Some(amount) = transaction.amount {
+            summary.min_amount = Some(summary.min_amount.map_or(amount, |min| min.min(amount)));
+            summary.max_amount = Some(summary.max_amount.map_or(amount, |max| max.max(amount)));
+        }
+    }
+
+    summary.distinct_clients = clients.len();
+    summary.distinct_transactions = transactions.len();
+    Ok(summary)
+}
+
+/// Write the accounts to `output`, unless they go to other files, and
+/// the run's status to `status` as `#` comment lines as far as the verbosity
+/// allows: nothing when quiet, the processing time and the summaries asked
+/// for normally, and the report's counts as well when verbose. Returns the
+/// digest of the accounts written to `output`, if `manifest_path` asks for one.
+pub(crate) fn write_output<O: Write, S: Write>(
+    engine: &PaymentEngine,
+    report: &ProcessingReport,
+    duration: Duration,
+    reconciliation: Option<(&Path, &AccountsDiff)>,
+    options: &ProcessingOptions,
+    mut output: O,
+    mut status: S,
+) -> Result<Option<OutputDigest>> {
+    let mut digest = None;
+    if options.output_partitions.is_none() && options.output_mode == OutputMode::Stdout {
+        digest = write_accounts_digested(engine, options, &mut output)?;
+    }
+    output.flush()?;
+    if options.verbosity == Verbosity::Quiet {
+        return Ok(digest);
+    }
+
+    writeln!(status, "# Processing completed in {:.2?}", duration)?;
+    if options.fee_summary {
+        write_fee_summary(report, &mut status)?;
+    }
+    if options.timing_summary {
+        write_timing_summary(report, &mut status)?;
+    }
+    if let Some((expected_path, diff)) = reconciliation {
+        write_reconciliation(diff, expected_path, &mut status)?;
+    }
+    if let Some(estimate) = &report.sample_estimate {
+        write_sample_estimate(report.records_sampled_out, estimate, &mut status)?;
+    }
+    if options.verbosity == Verbosity::Verbose {
+        write_run_summary(report, &mut status)?;
+        writeln!(status, "# Engine: {}", engine.stats())?;
+    }
+    status.flush()?;
+    Ok(digest)
+}
+
+/// Write the accounts CSV to `path`, compressing it as it's produced when the
+/// path ends in `.gz` or `compress_output` is set. Returns the digest of the
+/// uncompressed CSV, if `manifest_path` asks for one.
+fn write_accounts_file(engine: &PaymentEngine, options: &ProcessingOptions, path: &Path) -> Result<Option<OutputDigest>> {
+    let file = std::fs::File::create(path).map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?;
+    let file = BufWriter::new(file);
+    let digest = if options.compress_output || path.extension().is_some_and(|extension| extension == "gz") {
+        // `finish` writes the gzip trailer. An encoder dropped before that,
+        // by a failed write or a cancelled run, still tries to finish the
+        // stream on drop, so the file is never left without one.
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        let digest = write_accounts_digested(engine, options, &mut encoder)?;
+        encoder.finish()?.flush()?;
+        digest
+    } else {
+        let mut file = file;
+        let digest = write_accounts_digested(engine, options, &mut file)?;
+        file.flush()?;
+        digest
+    };
+    info!("Wrote accounts to {:?}", path);
+    Ok(digest)
+}
+
+/// Write the accounts CSV, hashing it on the way if `manifest_path` is set
+fn write_accounts_digested<W: Write>(engine: &PaymentEngine, options: &ProcessingOptions, output: W) -> Result<Option<OutputDigest>> {
+    if options.manifest_path.is_none() {
+        write_accounts(engine, options, output)?;
+        return Ok(None);
+    }
+    let mut output = DigestWriter::new(output);
+    write_accounts(engine, options, &mut output)?;
+    Ok(Some(output.finish()))
+}
+
+/// Write each client's withdrawal fees as comment lines
+fn write_fee_summary<W: Write>(report: &ProcessingReport, mut output: W) -> Result<()> {
+    for (client, fee) in &report.fees_by_client {
+        writeln!(output, "# Fees client {}: {}", client, fee.round_dp(4))?;
+    }
+    writeln!(output, "# Fees total: {}", report.fees_collected.round_dp(4))?;
+    Ok(())
+}
+
+/// Write how the final balances compare with the expected ones as comment
+/// lines: a summary line, then one line per client that differs
+fn write_reconciliation<W: Write>(diff: &AccountsDiff, expected_path: &Path, mut output: W) -> Result<()> {
+    if diff.is_empty() {
+        writeln!(output, "# Reconciliation against {:?} passed", expected_path)?;
+        return Ok(());
+    }
+    writeln!(output, "# Reconciliation against {:?} failed: {} clients differ", expected_path, diff.len())?;
+    for line in diff.labeled("expected", "actual").to_string().lines() {
+        writeln!(output, "# {}", line)?;
+    }
+    Ok(())
+}
+
+/// Write the time spent reading, parsing and applying and the run's
+/// throughput as comment lines
+fn write_timing_summary<W: Write>(report: &ProcessingReport, mut output: W) -> Result<()> {
+    writeln!(
+        output,
+        "# Timing: read {:.2?}, parse {:.2?}, apply {:.2?}, total {:.2?}",
+        report.read_time, report.parse_time, report.apply_time, report.elapsed
+    )?;
+    if !report.throttle_time.is_zero() {
+        writeln!(output, "# Throttled: {:.2?} waiting to stay under --max-tps", report.throttle_time)?;
+    }
+    writeln!(
+        output,
+        "# Throughput: {:.0} lines/s, {:.0} transactions/s",
+        report.lines_per_sec(),
+        report.transactions_per_sec()
+    )?;
+    Ok(())
+}
+
+/// Write the totals a sampled run extrapolates to all the clients as a comment line
+fn write_sample_estimate<W: Write>(sampled_out: usize, estimate: &SampleEstimate, mut output: W) -> Result<()> {
+    writeln!(
+        output,
+        "# Sample: {} sampled out, estimated {} transactions, {} deposited, {} withdrawn, {:.2?} applying for all clients",
+        sampled_out, estimate.transactions_processed, estimate.deposited_total, estimate.withdrawn_total, estimate.apply_time
+    )?;
+    Ok(())
+}
+
+/// Write the report's line and transaction counts as comment lines
+fn write_run_summary<W: Write>(report: &ProcessingReport, mut output: W) -> Result<()> {
+    writeln!(
+        output,
+        "# Lines: {} read, {} skipped, {} filtered, {} parse errors, {} read errors, {} oversized",
+        report.lines_read,
+        report.records_skipped,
+        report.records_filtered,
+        report.parse_errors,
+        report.read_errors,
+        report.oversized_lines
+    )?;
+    writeln!(
+        output,
+        "# Transactions: {} processed, {} rejected, {} duplicates, {} in failed batches",
+        report.transactions_processed,
+        report.transactions_rejected,
+        report.duplicates,
+        report.failed_batches.iter().map(|lines| lines.clone().count()).sum::<usize>()
+    )?;
+    if report.withdrawal_disputes_rejected > 0 {
+        writeln!(output, "# Disputes of withdrawals rejected: {}", report.withdrawal_disputes_rejected)?;
+    }
+    if report.disputes_expired > 0 {
+        writeln!(output, "# Disputes expired: {}", report.disputes_expired)?;
+    }
+    if report.records_locked_skipped > 0 {
+        writeln!(output, "# Locked clients' deposits and withdrawals skipped: {}", report.records_locked_skipped)?;
+    }
+    if let Some(max_tx_id) = report.max_tx_id {
+        writeln!(output, "# Highest tx id: {}", max_tx_id)?;
+    }
+    Ok(())
+}
+
+/// Write a progress line for the batch of `lines` just applied, with the
+/// counts of the run so far
+fn write_batch_progress<W: Write>(lines: &RangeInclusive<usize>, progress: &ProcessingReport, mut output: W) -> Result<()> {
+    writeln!(
+        output,
+        "# Applied lines {}-{}: {} transactions, {} rejected, {} errors so far",
+        lines.start(),
+        lines.end(),
+        progress.transactions_processed,
+        progress.transactions_rejected,
+        progress.error_count()
+    )?;
+    Ok(())
+}
+
+/// Write the engine's accounts as CSV. If any account holds a currency other
+/// than the base currency, every client gets one row per currency with a
+/// `currency` column; otherwise the usual single-currency layout is written.
+/// Extended output adds the lock reason and dispute counts of each client.
+pub(crate) fn write_accounts<W: Write>(engine: &PaymentEngine, options: &ProcessingOptions, output: W) -> Result<()> {
+    let (accounts, multi_currency) = output_accounts(engine, options);
+    write_account_rows(engine, accounts, multi_currency, options, output)
+}
+
+/// Write every account, closed or not, as [`write_accounts`] does, e.g. for
+/// a snapshot a later run may start from
+pub(crate) fn write_all_accounts<W: Write>(engine: &PaymentEngine, options: &ProcessingOptions, output: W) -> Result<()> {
+    let accounts = engine.get_accounts();
+    let multi_currency = accounts.iter().any(|account| !account.other_currencies.is_empty());
+    write_account_rows(engine, accounts, multi_currency, options, output)
+}
+
+/// The accounts to write, without closed ones unless they are included nor
+/// untouched loaded ones unless they are emitted, and whether any account,
+/// closed or not, holds a currency other than the base currency, so a
+/// separate file of closed accounts gets the same columns
+fn output_accounts(engine: &PaymentEngine, options: &ProcessingOptions) -> (Vec<Account>, bool) {
+    let mut accounts = engine.get_accounts();
+    let multi_currency = accounts.iter().any(|account| !account.other_currencies.is_empty());
+    if options.closed_account_handling != ClosedAccountHandling::IncludeInOutput {
+        accounts.retain(|account| !account.is_closed());
+    }
+    if !options.emit_untouched_accounts {
+        accounts.retain(|account| !engine.is_untouched(account.client));
+    }
+    (accounts, multi_currency)
+}
+
+/// Write the closed accounts to their own CSV at `path`, in the columns of
+/// the output
+fn write_closed_accounts(engine: &PaymentEngine, options: &ProcessingOptions, path: &Path) -> Result<()> {
+    let mut accounts = engine.get_accounts();
+    let multi_currency = accounts.iter().any(|account| !account.other_currencies.is_empty());
+    accounts.retain(Account::is_closed);
+    let count = accounts.len();
+    let file = std::fs::File::create(path).map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?;
+    write_account_rows(engine, accounts, multi_currency, options, file)?;
+    info!("Wrote {} closed accounts to {:?}", count, path);
+    Ok(())
+}
+
+/// Write the engine's accounts to `partitions` files named `accounts_<part>.csv`
+/// in `dir`, client `c` going to part `c % partitions`. Every file gets the
+/// header, even if empty, and the same columns; the parts are written concurrently.
+fn write_partitioned_accounts(
+    engine: &PaymentEngine,
+    options: &ProcessingOptions,
+    dir: &Path,
+    partitions: usize,
+) -> Result<()> {
+    let (accounts, multi_currency) = output_accounts(engine, options);
+    let mut parts = vec![Vec::new(); partitions];
+    for account in accounts {
+        parts[usize::from(account.client) % partitions].push(account);
+    }
+
+    std::fs::create_dir_all(dir)?;
+    std::thread::scope(|scope| {
+        let writers: Vec<_> = parts
+            .into_iter()
+            .enumerate()
+            .map(|(part, accounts)| {
+                let path = dir.join(format!("accounts_{}.csv", part));
+                scope.spawn(move || {
+                    let file = std::fs::File::create(&path).map_err(|source| PaymentEngineError::OpenFile { path, source })?;
+                    write_account_rows(engine, accounts, multi_currency, options, file)
+                })
+            })
+            .collect();
+        writers.into_iter().try_for_each(|writer| writer.join().expect("account writer panicked"))
+    })?;
+    info!("Wrote accounts to {} partitions in {:?}", partitions, dir);
+    Ok(())
+}
+
+/// Write each account to its own file at the per-client path template: the
+/// header and the account's rows, or its audit trail if history is recorded.
+/// The accounts are split between [`PER_CLIENT_WRITERS`] threads, each
+/// formatting a file into a reused buffer and writing it in one go, so only
+/// that many files are open at a time.
+fn write_per_client_files(engine: &PaymentEngine, options: &ProcessingOptions, template: &str) -> Result<()> {
+    let (accounts, multi_currency) = output_accounts(engine, options);
+    let history = options.record_history || options.history_out.is_some();
+    let count = accounts.len();
+    let mut chunks = vec![Vec::new(); PER_CLIENT_WRITERS];
+    for (i, account) in accounts.into_iter().enumerate() {
+        chunks[i % PER_CLIENT_WRITERS].push(account);
+    }
+
+    if let Some(dir) = client_output_path(template, 0).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::thread::scope(|scope| {
+        let writers: Vec<_> = chunks
+            .into_iter()
+            .map(|accounts| {
+                scope.spawn(move || {
+                    let mut buffer = Vec::new();
+                    for account in accounts {
+                        buffer.clear();
+                        let path = client_output_path(template, account.client);
+                        if history {
+                            crate::history::write_client_history(engine, account.client, &mut buffer)?;
+                        } else {
+                            format_account_rows(engine, [account], multi_currency, options, &mut buffer)?;
+                        }
+                        std::fs::write(&path, &buffer).map_err(|source| PaymentEngineError::OpenFile { path, source })?;
+                    }
+                    Ok::<_, PaymentEngineError>(())
+                })
+            })
+            .collect();
+        writers.into_iter().try_for_each(|writer| writer.join().expect("account writer panicked"))
+    })?;
+    info!("Wrote {} per-client account files to {}", count, template);
+    Ok(())
+}
+
+/// Write the given accounts with their header through a buffer, see
+/// [`format_account_rows`]
+fn write_account_rows<W: Write>(
+    engine: &PaymentEngine,
+    accounts: Vec<Account>,
+    multi_currency: bool,
+    options: &ProcessingOptions,
+    output: W,
+) -> Result<()> {
+    let mut output = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, output);
+    format_account_rows(engine, accounts, multi_currency, options, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Write the given accounts with their header, in the columns of the
+/// options, or the layout picked by `multi_currency` and the extended output
+/// option.
+///
+/// Rows are formatted by hand into one reused buffer rather than through
+/// serde, which dominated the time spent writing millions of accounts. No
+/// field can contain a delimiter or quote, so nothing needs escaping.
+fn format_account_rows<W: Write>(
+    engine: &PaymentEngine,
+    accounts: impl IntoIterator<Item = Account>,
+    multi_currency: bool,
+    options: &ProcessingOptions,
+    output: &mut W,
+) -> Result<()> {
+    use std::fmt::Write as _;
+
+    let columns = match &options.output_columns {
+        Some(columns) => Cow::Borrowed(columns),
+        None => Cow::Owned(OutputColumns::layout(multi_currency, options.extended_output)),
+    };
+    let columns = &columns.0;
+    let mut row = String::new();
+    for (i, (_, header)) in columns.iter().enumerate() {
+        if i > 0 {
+            row.push(',');
+        }
+        row.push_str(header);
+    }
+    row.push('\n');
+    output.write_all(row.as_bytes())?;
+
+    let needs_stats = columns.iter().any(|(column, _)| column.needs_stats());
+    let no_stats = AccountStats::default();
+    for account in accounts {
+        let base = Balance {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            chargeback_total: account.chargeback_total,
+            deposited_total: account.deposited_total,
+            withdrawn_total: account.withdrawn_total,
+        };
+        let balances = std::iter::once((options.base_currency, base)).chain(account.other_currencies);
+        let stats = if needs_stats { engine.get_account_stats(account.client).unwrap_or(&no_stats) } else { &no_stats };
+        for (currency, balance) in balances {
+            row.clear();
+            for (i, (column, _)) in columns.iter().enumerate() {
+                if i > 0 {
+                    row.push(',');
+                }
+                // Format amounts to 4 decimal places; writing to a String cannot fail
+                match column {
+                    OutputColumn::Client => push_integer(&mut row, u64::from(account.client)),
+                    OutputColumn::Currency => row.push_str(currency.as_str()),
+                    OutputColumn::Available => push_decimal(&mut row, balance.available.round_dp(4)),
+                    OutputColumn::Held => push_decimal(&mut row, balance.held.round_dp(4)),
+                    OutputColumn::Total => push_decimal(&mut row, balance.total.round_dp(4)),
+                    OutputColumn::Locked => row.push_str(if account.locked { "true" } else { "false" }),
+                    OutputColumn::LockReason => {
+                        if let Some(reason) = account.lock_reason {
+                            let _ = write!(row, "{}", reason);
+                        }
+                    }
+                    OutputColumn::DisputesOpened => push_integer(&mut row, u64::from(stats.disputes_opened)),
+                    OutputColumn::DisputesResolved => push_integer(&mut row, u64::from(stats.disputes_resolved)),
+                    OutputColumn::DisputesChargedBack => push_integer(&mut row, u64::from(stats.disputes_charged_back)),
+                    OutputColumn::ChargebackTotal => push_decimal(&mut row, balance.chargeback_total.round_dp(4)),
+                    OutputColumn::DepositedTotal => push_decimal(&mut row, balance.deposited_total.round_dp(4)),
+                    OutputColumn::WithdrawnTotal => push_decimal(&mut row, balance.withdrawn_total.round_dp(4)),
+                    OutputColumn::HeldTransactions => {
+                        for (i, tx) in stats.held_transactions.iter().enumerate() {
+                            let _ = write!(row, "{}{}", if i == 0 { "" } else { " " }, tx);
+                        }
+                    }
+                }
+            }
+            row.push('\n');
+            output.write_all(row.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Append the decimal digits of `value`
+fn push_integer(buf: &mut String, mut value: u64) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    buf.extend(digits[start..].iter().map(|&digit| char::from(digit)));
+}
+
+/// Append `value` as its `Display` would, keeping every digit of its scale.
+/// Mantissas that fit in 64 bits skip the formatter; anything else, and
+/// negative zero, falls back to it.
+fn push_decimal(buf: &mut String, value: Decimal) {
+    use std::fmt::Write as _;
+
+    let mantissa = value.mantissa();
+    let scale = value.scale();
+    let magnitude = match u64::try_from(mantissa.unsigned_abs()) {
+        Ok(magnitude) if scale <= 19 && !(mantissa == 0 && value.is_sign_negative()) => magnitude,
+        _ => {
+            let _ = write!(buf, "{}", value);
+            return;
+        }
+    };
+    if mantissa < 0 {
+        buf.push('-');
+    }
+    let divisor = 10u64.pow(scale);
+    push_integer(buf, magnitude / divisor);
+    if scale > 0 {
+        buf.push('.');
+        let fraction = magnitude % divisor;
+        let digits = fraction.checked_ilog10().map_or(1, |log| log + 1);
+        buf.extend(std::iter::repeat_n('0', (scale - digits) as usize));
+        push_integer(buf, fraction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineStats;
+    use crate::models::{LockReason, TransactionType};
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+    use std::fs::write;
+
+    /// Account output row as written through serde before rows were formatted
+    /// by hand, kept to check the output is unchanged
+    #[derive(Serialize)]
+    struct AccountRow {
+        client: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        locked: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lock_reason: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        disputes_opened: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        disputes_resolved: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        disputes_charged_back: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chargeback_total: Option<Decimal>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        deposited_total: Option<Decimal>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        withdrawn_total: Option<Decimal>,
+        /// Space-separated ids of the transactions currently held
+        #[serde(skip_serializing_if = "Option::is_none")]
+        held_transactions: Option<String>,
+    }
+
+    fn write_account_rows_with_serde<W: Write>(
+        engine: &PaymentEngine,
+        accounts: Vec<Account>,
+        multi_currency: bool,
+        options: &ProcessingOptions,
+        output: W,
+    ) {
+        let mut writer = csv::Writer::from_writer(output);
+        for account in accounts {
+            let base = Balance {
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                chargeback_total: account.chargeback_total,
+                deposited_total: account.deposited_total,
+                withdrawn_total: account.withdrawn_total,
+            };
+            let balances = std::iter::once((options.base_currency, base)).chain(account.other_currencies);
+            let stats = engine.get_account_stats(account.client).cloned().unwrap_or_default();
+            let extended = options.extended_output;
+            for (currency, balance) in balances {
+                writer
+                    .serialize(AccountRow {
+                        client: account.client,
+                        currency: multi_currency.then_some(currency),
+                        available: balance.available.round_dp(4),
+                        held: balance.held.round_dp(4),
+                        total: balance.total.round_dp(4),
+                        locked: account.locked,
+                        lock_reason: extended
+                            .then(|| account.lock_reason.map(|reason| reason.to_string()).unwrap_or_default()),
+                        disputes_opened: extended.then_some(stats.disputes_opened),
+                        disputes_resolved: extended.then_some(stats.disputes_resolved),
+                        disputes_charged_back: extended.then_some(stats.disputes_charged_back),
+                        chargeback_total: extended.then_some(balance.chargeback_total.round_dp(4)),
+                        deposited_total: extended.then_some(balance.deposited_total.round_dp(4)),
+                        withdrawn_total: extended.then_some(balance.withdrawn_total.round_dp(4)),
+                        held_transactions: extended.then(|| {
+                            stats.held_transactions.iter().map(TxId::to_string).collect::<Vec<_>>().join(" ")
+                        }),
+                    })
+                    .unwrap();
+            }
+        }
+        writer.flush().unwrap();
+    }
+    
+    #[test]
+    fn test_builder_defaults_match_default() {
+        let built = ProcessingOptions::builder().build().unwrap();
+        let default = ProcessingOptions::default();
+
+        assert_eq!(built.batch_size_mode, BatchSizeMode::Fixed(DEFAULT_BATCH_SIZE));
+        assert_eq!(built.max_line_length, default.max_line_length);
+        assert_eq!(built.input_mode, default.input_mode);
+        assert_eq!(built.purge_finalized, default.purge_finalized);
+        assert!(built.wal_path.is_none());
+        assert!(!built.parallel_batches);
+        assert!(!built.record_history);
+    }
+
+    #[test]
+    fn test_builder_chained_overrides() {
+        let options = ProcessingOptions::builder()
+            .batch_size(500)
+            .wal_path(PathBuf::from("run.wal"))
+            .parallel_batches(true)
+            .max_records(10)
+            .input_mode(InputMode::Mmap)
+            .purge_finalized(PurgePolicy::AfterChargeback)
+            .batch_size(250)
+            .build()
+            .unwrap();
+
+        assert_eq!(options.batch_size_mode, BatchSizeMode::Fixed(250));
+        assert_eq!(options.wal_path, Some(PathBuf::from("run.wal")));
+        assert!(options.parallel_batches);
+        assert_eq!(options.max_records, Some(10));
+        assert_eq!(options.input_mode, InputMode::Mmap);
+        assert_eq!(options.purge_finalized, PurgePolicy::AfterChargeback);
+
+        // Options can be cleared again with None
+        let options = ProcessingOptions::builder().max_records(10).max_records(None).build().unwrap();
+        assert_eq!(options.max_records, None);
+    }
+
+    #[test]
+    fn test_builder_rejects_zero_batch_size() {
+        let err = ProcessingOptions::builder().batch_size(0).build().unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidOptions(_)));
+        assert_eq!(err.to_string(), "Invalid processing options: batch_size must be at least 1");
+    }
+
+    #[test]
+    fn test_validate_rejects_each_invalid_option() {
+        let per_client = OutputMode::PerClientDir("out/{id}.csv".to_string());
+        let cases = [
+            (ProcessingOptions::builder().batch_size(0), OptionsError::Zero("batch_size")),
+            (
+                ProcessingOptions::builder().batch_size_mode(BatchSizeMode::Auto { min: 20, max: 10, target_batch_ms: 50 }),
+                OptionsError::AutoBatchSizes { min: 20, max: 10 },
+            ),
+            (
+                ProcessingOptions::builder().batch_size_mode(BatchSizeMode::Auto { min: 1, max: 10, target_batch_ms: 0 }),
+                OptionsError::Zero("target_batch_ms"),
+            ),
+            (ProcessingOptions::builder().max_line_length(0), OptionsError::Zero("max_line_length")),
+            (ProcessingOptions::builder().max_tps(0), OptionsError::Zero("max_tps")),
+            (ProcessingOptions::builder().timestamps(true).dispute_expiry(Duration::ZERO), OptionsError::ZeroDisputeExpiry),
+            (
+                ProcessingOptions::builder().dispute_expiry(Duration::from_secs(60)),
+                OptionsError::Requires("dispute_expiry", "timestamps"),
+            ),
+            (ProcessingOptions::builder().output_partitions(0), OptionsError::Zero("output_partitions")),
+            (
+                ProcessingOptions::builder().output_mode(per_client.clone()).output_partitions(2),
+                OptionsError::Conflict("per-client output", "output_partitions"),
+            ),
+            (
+                ProcessingOptions::builder().output_mode(OutputMode::File("accounts.csv".into())).output_partitions(2),
+                OptionsError::Conflict("output file", "output_partitions"),
+            ),
+            (ProcessingOptions::builder().compress_output(true), OptionsError::Requires("compress_output", "an output file")),
+            (ProcessingOptions::builder().delimiter('"'), OptionsError::Delimiter('"')),
+            (ProcessingOptions::builder().decimal_separator('_'), OptionsError::DecimalSeparator('_')),
+            (ProcessingOptions::builder().thousands_separator('.'), OptionsError::ThousandsSeparator('.')),
+            (
+                ProcessingOptions::builder().delimiter(';').decimal_separator(',').lenient_amounts(true),
+                OptionsError::SameSeparator("decimal_separator", "thousands_separator"),
+            ),
+            (ProcessingOptions::builder().snapshot_every(SnapshotEvery::Records(0)), OptionsError::ZeroSnapshotRecords),
+            (ProcessingOptions::builder().reconcile_tolerance(dec!(-0.01)), OptionsError::NegativeReconcileTolerance),
+            (ProcessingOptions::builder().snapshot_path_template(""), OptionsError::EmptySnapshotPathTemplate),
+            (ProcessingOptions::builder().follow(true).end_marker(""), OptionsError::EmptyEndMarker),
+            (ProcessingOptions::builder().max_deferred_disputes(0), OptionsError::Zero("max_deferred_disputes")),
+            (
+                ProcessingOptions::builder().input_format(InputFormat::FixedWidth(FixedWidthSpec {
+                    amount: FixedWidthField::new(26, 0),
+                    ..FixedWidthSpec::default()
+                })),
+                OptionsError::FixedWidth("amount field must not be empty".to_string()),
+            ),
+            (
+                ProcessingOptions::builder().input_format(InputFormat::FixedWidth(FixedWidthSpec {
+                    tx: FixedWidthField::new(12, 10),
+                    ..FixedWidthSpec::default()
+                })),
+                OptionsError::FixedWidth("fields client and tx overlap".to_string()),
+            ),
+            (
+                ProcessingOptions::builder().input_format(InputFormat::FixedWidth(FixedWidthSpec::default())).timestamps(true),
+                OptionsError::Conflict("fixed-width input", "timestamps"),
+            ),
+            (ProcessingOptions::builder().sample(ClientSample::new(0.0, 1)), OptionsError::SampleRate),
+            (ProcessingOptions::builder().sample(ClientSample::new(1.5, 1)), OptionsError::SampleRate),
+            (ProcessingOptions::builder().sample(ClientSample::new(f64::NAN, 1)), OptionsError::SampleRate),
+            (
+                ProcessingOptions::builder().follow(true).input_mode(InputMode::Mmap),
+                OptionsError::Conflict("follow", "mmap input"),
+            ),
+            (
+                ProcessingOptions::builder().skip_locked_fast_path(true).record_history(true),
+                OptionsError::Conflict("skip_locked_fast_path", "record_history"),
+            ),
+            (
+                ProcessingOptions::builder().skip_locked_fast_path(true).dry_run(TraceFormat::Csv),
+                OptionsError::Conflict("skip_locked_fast_path", "dry_run"),
+            ),
+            (
+                ProcessingOptions::builder().dry_run(TraceFormat::Text).output_mode(per_client),
+                OptionsError::Conflict("dry_run", "output_mode"),
+            ),
+            (
+                ProcessingOptions::builder()
+                    .dry_run(TraceFormat::Csv)
+                    .closed_account_handling(ClosedAccountHandling::SeparateFile(PathBuf::from("closed.csv"))),
+                OptionsError::Conflict("dry_run", "closed_account_handling"),
+            ),
+            (
+                ProcessingOptions::builder().dry_run(TraceFormat::Csv).change_stream(ChangeStream::File(PathBuf::from("changes.ndjson"))),
+                OptionsError::Conflict("dry_run", "change_stream"),
+            ),
+        ];
+        for (builder, expected) in cases {
+            let err = builder.build().unwrap_err();
+            assert!(matches!(&err, PaymentEngineError::InvalidOptions(e) if *e == expected), "{:?}", err);
+            assert_eq!(err.to_string(), format!("Invalid processing options: {}", expected));
+        }
+        assert_eq!(ProcessingOptions::default().validate(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_runs_validate_options_set_directly() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("valid.csv");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+
+        for options in [
+            ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(0), ..Default::default() },
+            ProcessingOptions { max_line_length: 0, ..Default::default() },
+        ] {
+            let result = process_transactions_with_options(&file_path, options).await;
+            assert!(matches!(result, Err(PaymentEngineError::InvalidOptions(OptionsError::Zero(_)))));
+        }
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_auto_batch_sizes() {
+        for (min, max, target_batch_ms) in [(0, 10, 50), (20, 10, 50), (10, 20, 0)] {
+            let mode = BatchSizeMode::Auto { min, max, target_batch_ms };
+            assert!(ProcessingOptions::builder().batch_size_mode(mode).build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_auto_batch_size_moves_toward_target() {
+        let mode = BatchSizeMode::Auto { min: 10, max: 5000, target_batch_ms: 10 };
+        assert_eq!(mode.initial_size(), DEFAULT_BATCH_SIZE);
+        assert_eq!(BatchSizeMode::Auto { min: 10, max: 500, target_batch_ms: 10 }.initial_size(), 500);
+
+        // 1000 in 8ms would take 10ms at 1250, within the doubling limit
+        assert_eq!(mode.next_size(1000, 1000, Duration::from_millis(8)), 1250);
+        // At most halving or doubling per batch, within min and max
+        assert_eq!(mode.next_size(1000, 1000, Duration::from_millis(100)), 500);
+        assert_eq!(mode.next_size(1000, 1000, Duration::from_millis(1)), 2000);
+        assert_eq!(mode.next_size(4000, 4000, Duration::ZERO), 5000);
+        assert_eq!(mode.next_size(12, 12, Duration::from_secs(1)), 10);
+        // A short batch is measured by what it applied: 100 in 1ms is 1000 in 10ms
+        assert_eq!(mode.next_size(1000, 100, Duration::from_millis(1)), 1000);
+
+        assert_eq!(BatchSizeMode::Fixed(7).next_size(7, 7, Duration::from_secs(1)), 7);
+    }
+
+    #[test]
+    fn test_parse_transaction_error_messages() {
+        let err = Transaction::from_csv_line("deposit,1", ',').unwrap_err();
+        assert_eq!(err.to_string(), "Invalid CSV line format: deposit,1");
+
+        let err = Transaction::from_csv_line("transfer,1,2,3", ',').unwrap_err();
+        assert_eq!(err.to_string(), "Invalid transaction type: transfer");
+
+        let format = LineFormat::new(&ProcessingOptions::default(), true);
+        let err = parse_transaction_fields("deposit,1,2,3,EURO", format).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::InvalidCurrency(currency) if currency == "EURO"));
+    }
+
+    #[test]
+    fn test_parse_fixed_width_records() {
+        let options = ProcessingOptions::builder().input_format(InputFormat::FixedWidth(FixedWidthSpec::default())).build().unwrap();
+        let format = LineFormat::new(&options, false);
+        let parse = |line: &str| parse_transaction_fields(line, format);
+
+        assert_eq!(parse("deposit   000001000000004200000001234500").unwrap(), Transaction::deposit(1, 42, dec!(123.45)));
+        assert_eq!(parse("withdrawal000002000000004300000000000001").unwrap(), Transaction::withdrawal(2, 43, dec!(0.0001)));
+        // Blank or trimmed trailing amounts are missing amounts
+        assert_eq!(parse("dispute   0000010000000042              ").unwrap(), Transaction::dispute(1, 42));
+        assert_eq!(parse("dispute   0000010000000042").unwrap(), Transaction::dispute(1, 42));
+        assert!(matches!(parse("deposit   0000010000000044"), Err(PaymentEngineError::MissingAmount(44))));
+        assert!(!format.is_header("type      client    tx        amount"));
+
+        // Short lines and non-numeric fields
+        assert!(matches!(parse("deposit   00000100000"), Err(PaymentEngineError::InvalidLineFormat(_))));
+        assert!(matches!(parse(""), Err(PaymentEngineError::InvalidLineFormat(_))));
+        assert!(matches!(parse("deposit   00000A000000004200000001234500"), Err(PaymentEngineError::InvalidId { field: "client", .. })));
+        assert!(matches!(parse("deposit   0000010000000042000000012345.0"), Err(PaymentEngineError::InvalidAmount(amount)) if amount == "000000012345.0"));
+        assert!(matches!(parse("deposit   0000010000000042   twelve    "), Err(PaymentEngineError::InvalidAmount(_))));
+        assert!(matches!(parse("transfer  000001000000004200000001234500"), Err(PaymentEngineError::InvalidTransactionType(_))));
+
+        // Without an implied decimal point the amount is read like a CSV amount
+        let spec = FixedWidthSpec { amount: FixedWidthField::new(26, 8), implied_decimal: false, ..FixedWidthSpec::default() };
+        let options = ProcessingOptions::builder().input_format(InputFormat::FixedWidth(spec)).build().unwrap();
+        let format = LineFormat::new(&options, false);
+        assert_eq!(parse_transaction_fields("deposit   0000010000000042  123.45", format).unwrap().amount, Some(dec!(123.45)));
+        assert!(matches!(
+            parse_transaction_fields("deposit   0000010000000042   1.2.3", format),
+            Err(PaymentEngineError::InvalidAmount(amount)) if amount == "1.2.3"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_amount_presence_is_checked_when_parsing() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("amounts.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,\n\
+             withdrawal,1,9,\n\
+             dispute,1,1,4.0\n\
+             resolve,1,1,\n",
+        )
+        .unwrap();
+
+        let format = LineFormat::new(&ProcessingOptions::default(), false);
+        let err = parse_line(3, "deposit,1,2,", format).unwrap_err();
+        assert!(matches!(&err, PaymentEngineError::ParseError { line: 3, source, .. } if matches!(**source, PaymentEngineError::MissingAmount(2))));
+        assert_eq!(err.field(), Some("amount"));
+        assert!(matches!(parse_transaction_fields("withdrawal,1,9,", format), Err(PaymentEngineError::MissingAmount(9))));
+        assert!(matches!(parse_transaction_fields("amend,1,5,", format), Err(PaymentEngineError::MissingAmount(5))));
+        assert_eq!(parse_transaction_fields("amend,1,5,90.0", format).unwrap(), Transaction::amend(1, 5, dec!(90.0)));
+        assert!(matches!(parse_transaction_fields("dispute,1,1,4.0", format), Err(PaymentEngineError::UnexpectedAmount(1))));
+        assert!(matches!(parse_transaction_fields("chargeback,1,1,4.0", format), Err(PaymentEngineError::UnexpectedAmount(1))));
+        assert_eq!(parse_transaction_fields("resolve,1,1,", format).unwrap().amount, None);
+
+        // The rows are parse errors, not engine rejections
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!((report.parse_errors, report.transactions_processed, report.transactions_rejected), (3, 2, 1));
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(10.0));
+
+        // Partial disputes may carry an amount
+        let options = ProcessingOptions { allow_partial_disputes: true, ..Default::default() };
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!((report.parse_errors, report.transactions_processed), (2, 3));
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(10.0));
+        assert_eq!(engine.transaction_state(1), Some(crate::models::TransactionState::Resolved));
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let parse = |amount: &str, options: &ProcessingOptions| {
+            let line = format!("deposit;1;1;{}", amount);
+            parse_transaction_fields(&line, LineFormat::new(options, false)).map(|tx| tx.amount)
+        };
+        let strict = ProcessingOptions::builder().delimiter(';').build().unwrap();
+        let lenient = ProcessingOptions::builder().delimiter(';').lenient_amounts(true).build().unwrap();
+        let underscores = ProcessingOptions { thousands_separator: '_', ..lenient.clone() };
+        let spaces = ProcessingOptions { thousands_separator: ' ', ..lenient.clone() };
+        let decimal_comma = ProcessingOptions { decimal_separator: ',', thousands_separator: ' ', ..lenient.clone() };
+
+        // Accepted either way
+        for options in [&strict, &lenient] {
+            assert_eq!(parse("1234.56", options).unwrap(), Some(dec!(1234.56)));
+            assert_eq!(parse("+100.00", options).unwrap(), Some(dec!(100.00)));
+            assert!(matches!(parse("", options), Err(PaymentEngineError::MissingAmount(1))));
+        }
+
+        // Only accepted with lenient amounts
+        for (amount, options, expected) in [
+            ("1,234.56", &lenient, dec!(1234.56)),
+            ("+1,234,567", &lenient, dec!(1234567)),
+            ("-1,000.5", &lenient, dec!(-1000.5)),
+            ("1 000", &spaces, dec!(1000)),
+            ("+1 234,5", &decimal_comma, dec!(1234.5)),
+        ] {
+            assert_eq!(parse(amount, options).unwrap(), Some(expected), "{}", amount);
+            assert!(parse(amount, &strict).is_err(), "{}", amount);
+        }
+        // The decimal parser itself skips underscores, so strict parsing
+        // reads them too, but only lenient parsing checks the grouping
+        assert_eq!(parse("12_345.6789", &underscores).unwrap(), Some(dec!(12345.6789)));
+        assert_eq!(parse("1__000", &strict).unwrap(), Some(dec!(1000)));
+
+        // Rejected either way
+        for options in [&strict, &lenient] {
+            for amount in ["12.3.4", "abc", "++1", "+-1", "1,23.4", "12,3456", ",123", "1,234.5,6", "1.234,5"] {
+                assert!(matches!(parse(amount, options), Err(PaymentEngineError::InvalidAmount(_))), "{}", amount);
+            }
+        }
+        assert!(parse("1__000", &underscores).is_err() && parse("1_000_", &underscores).is_err());
+        assert!(parse("1 0000", &spaces).is_err());
+
+        // A deposit still needs an amount
+        let line = parse_transaction_fields("deposit;1;1; ", LineFormat::new(&lenient, false));
+        assert!(matches!(line, Err(PaymentEngineError::MissingAmount(1))));
+
+        let invalid = [
+            ProcessingOptions::builder().thousands_separator('.').build(),
+            ProcessingOptions::builder().delimiter(';').decimal_separator(',').lenient_amounts(true).build(),
+        ];
+        for result in invalid {
+            assert!(matches!(result, Err(PaymentEngineError::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_line_error_context() {
+        let format = LineFormat::new(&ProcessingOptions::default(), false);
+        let err = parse_line(48210, "deposit,-7,1,100", format).unwrap_err();
+
+        assert_eq!(err.field(), Some("client"));
+        let PaymentEngineError::ParseError { line, content, source } = &err else { panic!("Wrong error variant") };
+        assert_eq!(*line, 48210);
+        assert_eq!(content, "deposit,-7,1,100");
+        assert!(matches!(**source, PaymentEngineError::InvalidId { field: "client", .. }));
+        assert!(err.to_string().starts_with("Failed to parse transaction on line 48210 (\"deposit,-7,1,100\"): "));
+
+        assert_eq!(parse_line(2, "deposit,1,1,1.5", format).unwrap().client, 1);
+    }
+
+    // Compares the reusable-buffer reader and in-place parser against the
+    // previous String-per-line reader with Vec-collecting parser on 5M
+    // generated rows. Run with `cargo test --release -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_line_reader_vs_lines_stream() {
+        use crate::input::LineReader;
+        use futures::stream::StreamExt;
+        use tokio::fs::File;
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio_stream::wrappers::LinesStream;
+
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("bench.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 5_000_000, seed: 1, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let start = Instant::now();
+        let reader = BufReader::new(File::open(&file_path).await.unwrap());
+        let mut old_lines = LinesStream::new(reader.lines()).skip(1);
+        let mut old_count = 0;
+        while let Some(line) = old_lines.next().await {
+            let line = line.unwrap();
+            let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+            let _: u16 = parts[1].parse().unwrap();
+            let _: u32 = parts[2].parse().unwrap();
+            if parts.len() > 3 && !parts[3].is_empty() {
+                let _: rust_decimal::Decimal = parts[3].parse().unwrap();
+            }
+            old_count += 1;
+        }
+        let old_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        let mut new_lines = LineReader::new(BufReader::new(File::open(&file_path).await.unwrap()), 0, InputEncoding::Utf8Strict);
+        new_lines.next_line().await;
+        let mut new_count = 0;
+        while let Some(line) = new_lines.next_line().await {
+            Transaction::from_csv_line(&line.unwrap(), ',').unwrap();
+            new_count += 1;
+        }
+        let new_elapsed = start.elapsed();
+
+        assert_eq!(old_count, new_count);
+        println!("LinesStream + Vec split: {:.2?}, LineReader + in-place split: {:.2?}", old_elapsed, new_elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_integration() {
+        // Create a temporary directory
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test_transactions.csv");
+        
+        // Create a test CSV file
+        let csv_content = "type,client,tx,amount\n\
+                          deposit,1,1,100.0\n\
+                          deposit,2,2,200.0\n\
+                          withdrawal,1,3,50.0\n\
+                          withdrawal,2,4,25.0\n";
+                          
+        write(&file_path, csv_content).unwrap();
+        
+        // Process the file
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        
+        // Check the results
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 2);
+        
+        // Find each client's account
+        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
+        let client2 = accounts.iter().find(|a| a.client == 2).unwrap();
+        
+        assert_eq!(client1.available, dec!(50.0));
+        assert_eq!(client1.total, dec!(50.0));
+        
+        assert_eq!(client2.available, dec!(175.0));
+        assert_eq!(client2.total, dec!(175.0));
+    }
+    
+    #[tokio::test]
+    async fn test_tsv_and_semicolon_inputs_match_csv() {
+        let dir = tempdir().unwrap();
+        let csv_content = "type,client,tx,amount\n\
+                           deposit,1,1,100.50\n\
+                           deposit,2,2,200.25\n\
+                           withdrawal,1,3,50.1234\n\
+                           dispute,2,2,\n\
+                           withdrawal,2,4,1.5\n";
+        let tsv_content = csv_content.replace(',', "\t");
+        let semicolon_content = "type;client;tx;amount\n\
+                                 deposit;1;1;100,50\n\
+                                 deposit;2;2;200,25\n\
+                                 withdrawal;1;3;50,1234\n\
+                                 dispute;2;2;\n\
+                                 withdrawal;2;4;1,5\n";
+
+        let mut results = Vec::new();
+        for (name, content, delimiter, decimal_separator) in [
+            ("standard.csv", csv_content.to_string(), ',', '.'),
+            ("lake.tsv", tsv_content, '\t', '.'),
+            ("export.csv", semicolon_content.to_string(), ';', ','),
+        ] {
+            let file_path = dir.path().join(name);
+            write(&file_path, content).unwrap();
+            let options = ProcessingOptions::builder()
+                .delimiter(delimiter)
+                .decimal_separator(decimal_separator)
+                .build()
+                .unwrap();
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            assert_eq!(report.parse_errors, 0, "{}", name);
+            let mut accounts: Vec<_> =
+                engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total)).collect();
+            accounts.sort_by_key(|a| a.0);
+            results.push(accounts);
+        }
+
+        assert_eq!(results[0], [(1, dec!(50.3766), dec!(0), dec!(50.3766)), (2, dec!(0), dec!(200.25), dec!(200.25))]);
+        assert_eq!(results[1], results[0]);
+        assert_eq!(results[2], results[0]);
+    }
+
+    #[test]
+    fn test_builder_rejects_conflicting_separators() {
+        let err = ProcessingOptions::builder().decimal_separator(',').build().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: delimiter and decimal_separator must differ");
+        assert!(ProcessingOptions::builder().decimal_separator('_').build().is_err());
+        assert!(ProcessingOptions::builder().delimiter('\n').build().is_err());
+        assert!(ProcessingOptions::builder().delimiter('|').decimal_separator(',').build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_dry_run_with_persistent_outputs() {
+        let err = ProcessingOptions::builder().dry_run(TraceFormat::Text).wal_path(PathBuf::from("run.wal")).build().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: dry_run can't be combined with wal_path");
+        assert!(ProcessingOptions::builder().dry_run(TraceFormat::Csv).output_partitions(2).build().is_err());
+        assert!(ProcessingOptions::builder().dry_run(TraceFormat::Csv).client_filter(ClientFilter::from(42)).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_validates_snapshots() {
+        let err = ProcessingOptions::builder().snapshot_every(SnapshotEvery::Records(0)).build().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: snapshot_every must be at least 1 record");
+        assert!(ProcessingOptions::builder().snapshot_every(SnapshotEvery::Duration(Duration::ZERO)).build().is_err());
+        assert!(ProcessingOptions::builder().snapshot_path_template("").build().is_err());
+        let err = ProcessingOptions::builder()
+            .dry_run(TraceFormat::Text)
+            .snapshot_every(SnapshotEvery::Records(10))
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: dry_run can't be combined with snapshot_every");
+    }
+
+    #[tokio::test]
+    async fn test_reconciliation_against_perturbed_expected_file() {
+        let dir = tempdir().unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+        let exact = fixture.with_file_name("disputes.expected.csv");
+        // Client 1's available balance is off, client 2 is missing and client 9 doesn't exist
+        let perturbed = dir.path().join("perturbed.csv");
+        write(&perturbed, "client,available,held,total,locked\n1,100.5,50.0,150.5,false\n9,1.0,0,1.0,false\n").unwrap();
+
+        let reconcile = |expected: &Path, tolerance: Decimal| {
+            ProcessingOptions::builder().expected_accounts(expected.to_path_buf()).reconcile_tolerance(tolerance).build().unwrap()
+        };
+        let report = process_transactions_with_options(&fixture, reconcile(&exact, Decimal::ZERO)).await.unwrap();
+        assert_eq!(report.reconciliation_mismatches, Some(0));
+        let report = process_transactions_with_options(&fixture, reconcile(&perturbed, Decimal::ZERO)).await.unwrap();
+        assert_eq!(report.reconciliation_mismatches, Some(3));
+        let report = process_transactions_with_options(&fixture, reconcile(&perturbed, dec!(0.5))).await.unwrap();
+        assert_eq!(report.reconciliation_mismatches, Some(2));
+        let report = process_transactions_with_options(&fixture, ProcessingOptions::default()).await.unwrap();
+        assert_eq!(report.reconciliation_mismatches, None);
+
+        // A missing expected file fails before processing
+        let err = process_transactions_with_options(&fixture, reconcile(&dir.path().join("missing.csv"), Decimal::ZERO)).await;
+        assert!(matches!(err, Err(PaymentEngineError::OpenFile { .. })));
+        assert!(ProcessingOptions::builder().reconcile_tolerance(dec!(-0.01)).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_output_by_verbosity() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+        let options = ProcessingOptions::default();
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&fixture, &mut engine, &options).await.unwrap();
+        let diff = diff_accounts(&engine.get_accounts(), &engine.get_accounts(), Decimal::ZERO);
+        let expected_path = Path::new("expected.csv");
+
+        let output = |options: &ProcessingOptions| {
+            let (mut accounts, mut status) = (Vec::new(), Vec::new());
+            let reconciliation = Some((expected_path, &diff));
+            write_output(&engine, &report, Duration::from_millis(12), reconciliation, options, &mut accounts, &mut status).unwrap();
+            (String::from_utf8(accounts).unwrap(), String::from_utf8(status).unwrap())
+        };
+        let mut csv = Vec::new();
+        write_accounts(&engine, &options, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+
+        let with = |verbosity| ProcessingOptions { verbosity, fee_summary: true, ..Default::default() };
+        assert_eq!(output(&with(Verbosity::Quiet)), (csv.clone(), String::new()));
+        let normal = "# Processing completed in 12.00ms\n\
+                      # Fees total: 0\n\
+                      # Reconciliation against \"expected.csv\" passed\n";
+        assert_eq!(output(&with(Verbosity::Normal)), (csv.clone(), normal.to_string()));
+        let verbose = format!(
+            "{}# Lines: 11 read, 0 skipped, 0 filtered, 0 parse errors, 0 read errors, 0 oversized\n\
+             # Transactions: 11 processed, 4 rejected, 0 duplicates, 0 in failed batches\n\
+             # Highest tx id: 99\n\
+             # Engine: 7 tx applied (3 deposits, 0 withdrawals), 2 disputes open, 0 accounts locked, 4 rejects\n",
+            normal
+        );
+        assert_eq!(output(&with(Verbosity::Verbose)), (csv, verbose));
+
+        // Partitioned accounts go to their files, leaving only the status
+        let partitioned = ProcessingOptions { output_partitions: Some(2), ..Default::default() };
+        assert_eq!(output(&partitioned).0, "");
+    }
+
+    #[tokio::test]
+    async fn test_capacity_hints_change_nothing() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for fixture in ["basic.csv", "chargebacks.csv", "disputes.csv", "locked_account_followups.csv", "malformed_lines.csv"] {
+            let hinted = ProcessingOptions::builder().expected_clients(10_000).expected_transactions(100_000).build().unwrap();
+            let mut runs = Vec::new();
+            for options in [ProcessingOptions::default(), hinted] {
+                let mut engine = create_engine(&options).unwrap();
+                let report = process_transactions_stream(&dir.join(fixture), &mut engine, &options).await.unwrap();
+                let mut accounts: Vec<_> =
+                    engine.get_accounts().iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+                accounts.sort();
+                let counts = (engine.account_count(), engine.stored_transaction_count(), engine.open_dispute_count());
+                runs.push((report.without_timings(), accounts, counts));
+            }
+            assert_eq!(runs[0], runs[1], "{}", fixture);
+        }
+
+        let mut engine = create_engine(&ProcessingOptions::default()).unwrap();
+        process_transactions_stream(&dir.join("disputes.csv"), &mut engine, &ProcessingOptions::default()).await.unwrap();
+        // Deposits 1 to 3 are stored, the withdrawal was rejected; 2 and 3 are still disputed
+        assert_eq!((engine.account_count(), engine.stored_transaction_count(), engine.open_dispute_count()), (2, 3, 2));
+    }
+
+    #[tokio::test]
+    async fn test_max_tx_id_counts_rows_whose_tx_parsed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("watermark.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,5,10.0\n\
+             deposit,1,abc,1.0\n\
+             deposit,1,99999999999999999999999,1.0\n\
+             withdrawal,1,7,50.0\n\
+             deposit,1,40,oops\n\
+             deposit,1\n\
+             deposit,2,12,1.0\n",
+        )
+        .unwrap();
+
+        // The rejected withdrawal and the bad amount count, the bad tx ids don't
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!((report.parse_errors, report.transactions_rejected), (4, 1));
+        assert_eq!((report.max_tx_id, engine.max_tx_id()), (Some(40), Some(40)));
+
+        // So do rows skipped, filtered out or dropped for a locked client
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,100,1.0\n\
+             deposit,1,5,10.0\n\
+             deposit,2,300,1.0\n\
+             dispute,1,5,\n\
+             chargeback,1,5,\n\
+             deposit,1,200,1.0\n",
+        )
+        .unwrap();
+        let options = ProcessingOptions::builder().skip_records(1).client_filter(ClientFilter::from(1)).build().unwrap();
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!((report.records_skipped, report.records_filtered, report.max_tx_id), (1, 1, Some(300)));
+        assert_eq!(engine.max_tx_id(), Some(300));
+
+        let options = ProcessingOptions::builder().skip_locked_fast_path(true).batch_size(1).pipeline_depth(0).build().unwrap();
+        let mut engine = create_engine(&options).unwrap();
+        write(&file_path, "type,client,tx,amount\ndeposit,1,5,10.0\ndispute,1,5,\nchargeback,1,5,\ndeposit,1,200,1.0\n").unwrap();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!((report.records_locked_skipped, engine.max_tx_id()), (1, Some(200)));
+    }
+
+    #[tokio::test]
+    async fn test_client_filter_skips_other_clients() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+        let options = ProcessingOptions { client_filter: Some(2.into()), record_history: true, ..Default::default() };
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&fixture, &mut engine, &options).await.unwrap();
+
+        assert_eq!((report.lines_read, report.records_filtered, report.transactions_processed), (11, 7, 4));
+        assert_eq!(engine.get_accounts().iter().map(|a| a.client).collect::<Vec<_>>(), [2]);
+
+        let mut trace = Vec::new();
+        crate::history::write_trace(&engine, TraceFormat::Text, &mut trace).unwrap();
+        assert_eq!(
+            String::from_utf8(trace).unwrap(),
+            "client 2, tx 3, deposit 20.0: applied; available 20.0, held 0, total 20.0\n\
+             client 2, tx 3, resolve: rejected (not_disputed); available 20.0, held 0, total 20.0\n\
+             client 2, tx 3, dispute: applied; available 0.0, held 20.0, total 20.0\n\
+             client 2, tx 4, withdrawal 5.0: rejected (insufficient_funds); available 0.0, held 20.0, total 20.0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_hook_remaps_and_drops() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("migrated.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,4.0\n\
+             withdrawal,1,3,3.0\n\
+             deposit,1,4,2.5\n\
+             dispute,1,4,\n\
+             withdrawal,2,5,1.0\n",
+        )
+        .unwrap();
+
+        for (parallel_batches, pipeline_depth) in [(false, 0), (true, 0), (false, 2)] {
+            let options = ProcessingOptions::builder()
+                .batch_size_mode(BatchSizeMode::Fixed(2))
+                .parallel_batches(parallel_batches)
+                .pipeline_depth(pipeline_depth)
+                .transaction_hook(|transaction| match transaction.transaction_type {
+                    TransactionType::Withdrawal => None,
+                    _ if transaction.client == 1 => Some(Transaction { client: 100, ..transaction }),
+                    _ => Some(transaction),
+                })
+                .build()
+                .unwrap();
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            assert_eq!((report.lines_read, report.records_filtered, report.transactions_processed), (6, 2, 4));
+            let mut accounts: Vec<_> = engine.get_accounts().iter().map(|a| (a.client, a.available, a.held)).collect();
+            accounts.sort_unstable();
+            assert_eq!(accounts, [(2, dec!(4.0), dec!(0)), (100, dec!(10.0), dec!(2.5))]);
+        }
+
+        // Amounts the hook returns are brought to precision like parsed ones
+        let rescale = |transaction: Transaction| Some(Transaction { amount: transaction.amount.map(|amount| amount / dec!(3)), ..transaction });
+        let options = ProcessingOptions::builder().transaction_hook(rescale).build().unwrap();
+        let mut engine = create_engine(&options).unwrap();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!(engine.get_account(2).unwrap().available, dec!(1.0)); // 1.3333 - 0.3333
+        let options = ProcessingOptions::builder().transaction_hook(rescale).amount_precision(AmountPrecision::Reject).build().unwrap();
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        // Every amount but the withdrawal of 3.0 gets more than 4 decimal places
+        assert_eq!(report.parse_errors, 4);
+    }
+
+    #[test]
+    fn test_client_filter_parsing() {
+        assert_eq!("100-200".parse::<ClientFilter>().unwrap(), ClientFilter::Range(100..=200));
+        assert_eq!("7".parse::<ClientFilter>().unwrap(), ClientFilter::from(7));
+        let filter: ClientFilter = "1, 5,100-102".parse().unwrap();
+        assert_eq!(filter, ClientFilter::Set(BTreeSet::from([1, 5, 100, 101, 102])));
+        assert!(filter.matches(101) && !filter.matches(2) && !filter.matches(103));
+
+        assert_eq!("".parse::<ClientFilter>().unwrap_err(), "Invalid client id: ");
+        assert_eq!("1,x".parse::<ClientFilter>().unwrap_err(), "Invalid client id: x");
+        assert_eq!("9-3".parse::<ClientFilter>().unwrap_err(), "Invalid client range: 9-3");
+        assert!("65536".parse::<ClientFilter>().is_err());
+
+        let odd = ClientFilter::predicate(|client| client % 2 == 1);
+        assert!(odd.matches(3) && !odd.matches(4));
+        assert_eq!(odd, odd.clone());
+        assert_ne!(odd, ClientFilter::predicate(|client| client % 2 == 1));
+    }
+
+    #[tokio::test]
+    async fn test_client_range_matches_unfiltered_run() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("mixed.csv");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=40u32 {
+            let client = tx % 10 + 1;
+            csv.push_str(&format!("deposit,{},{},{}.5\n", client, tx, tx));
+            if tx % 3 == 0 {
+                csv.push_str(&format!("withdrawal,{},{},1.25\n", client, tx + 100));
+            }
+        }
+        // Disputes are filtered by their own client, here all inside the range
+        csv.push_str("dispute,4,13,\ndispute,5,14,\nresolve,5,14,\ndispute,6,15,\nchargeback,6,15,\n");
+        write(&file_path, csv).unwrap();
+
+        let mut unfiltered = PaymentEngine::new();
+        let full_report = process_transactions_stream(&file_path, &mut unfiltered, &ProcessingOptions::default()).await.unwrap();
+
+        let options = ProcessingOptions::builder().client_filter(ClientFilter::Range(3..=6)).build().unwrap();
+        let mut filtered = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut filtered, &options).await.unwrap();
+
+        assert_eq!(report.lines_read, full_report.lines_read);
+        assert_eq!(report.records_filtered + report.transactions_processed, full_report.transactions_processed);
+        let mut accounts = filtered.get_accounts();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(accounts.iter().map(|a| a.client).collect::<Vec<_>>(), [3, 4, 5, 6]);
+        for account in &accounts {
+            let expected = unfiltered.get_account(account.client).unwrap();
+            assert_eq!(
+                (account.available, account.held, account.total, account.locked),
+                (expected.available, expected.held, expected.total, expected.locked),
+                "client {}",
+                account.client
+            );
+        }
+        assert!(accounts.iter().any(|a| a.held > Decimal::ZERO) && accounts.iter().any(|a| a.locked));
+    }
+
+    #[tokio::test]
+    async fn test_skip_locked_fast_path_matches_slow_path() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("locked.csv");
+        let mut csv = String::from("type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\ndispute,1,2,\nchargeback,1,2,\n");
+        csv.push_str("deposit,2,3,7.0\n");
+        for tx in 4..24 {
+            let kind = if tx % 2 == 0 { "deposit" } else { "withdrawal" };
+            csv.push_str(&format!("{},1,{},1.0\n", kind, tx));
+        }
+        // Dispute-family rows of a locked client still reach the engine
+        csv.push_str("dispute,1,1,\nwithdrawal,2,24,2.0\n");
+        write(&file_path, csv).unwrap();
+
+        let builder = || ProcessingOptions::builder().batch_size(4).pipeline_depth(0);
+        let mut slow = PaymentEngine::new();
+        let slow_report = process_transactions_stream(&file_path, &mut slow, &builder().build().unwrap()).await.unwrap();
+        let balances = |engine: &PaymentEngine| {
+            let mut accounts = engine.get_accounts();
+            accounts.sort_by_key(|account| account.client);
+            accounts.iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect::<Vec<_>>()
+        };
+
+        // Client 1 is locked by the first batch, so all its later deposits and withdrawals are dropped
+        let options = builder().skip_locked_fast_path(true).build().unwrap();
+        let mut fast = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut fast, &options).await.unwrap();
+        assert_eq!(balances(&fast), balances(&slow));
+        assert_eq!((report.records_locked_skipped, report.transactions_processed), (20, 7));
+        assert_eq!(report.transactions_by_type[&TransactionType::Dispute], 2);
+        assert_eq!((report.transactions_rejected, slow_report.transactions_rejected), (1, 21));
+        assert_eq!(report.lines_read, slow_report.lines_read);
+
+        // A reader running ahead of the engine may skip fewer, never more
+        let options = ProcessingOptions::builder().batch_size(4).pipeline_depth(2).skip_locked_fast_path(true).build().unwrap();
+        let mut pipelined = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut pipelined, &options).await.unwrap();
+        assert_eq!(balances(&pipelined), balances(&slow));
+        assert!(report.records_locked_skipped <= 20);
+        assert_eq!(report.records_locked_skipped + report.transactions_processed, slow_report.transactions_processed);
+
+        // Clients locked before the run are skipped from its first line
+        let options = ProcessingOptions::builder().skip_locked_fast_path(true).build().unwrap();
+        let report = process_transactions_stream(&file_path, &mut fast, &options).await.unwrap();
+        assert_eq!(report.records_locked_skipped, 22);
+    }
+
+    #[test]
+    fn test_client_sample_is_deterministic() {
+        let sampled = |sample: ClientSample| (0..=u16::MAX).filter(|client| sample.contains(*client)).collect::<Vec<_>>();
+        let clients = sampled(ClientSample::new(0.1, 42));
+        assert_eq!(clients, sampled(ClientSample::new(0.1, 42)));
+        assert_ne!(clients, sampled(ClientSample::new(0.1, 43)));
+        assert!((6_000..7_100).contains(&clients.len()), "{}", clients.len());
+        assert_eq!(sampled(ClientSample::new(1.0, 42)).len(), 65_536);
+    }
+
+    #[tokio::test]
+    async fn test_sampled_clients_match_full_run() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("sampled.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 5_000, clients: 200, seed: 3, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let mut full = PaymentEngine::new();
+        let full_report = process_transactions_stream(&file_path, &mut full, &ProcessingOptions::default()).await.unwrap();
+
+        let options = ProcessingOptions::builder().sample(ClientSample::new(0.25, 42)).build().unwrap();
+        let run = |options: ProcessingOptions| {
+            let file_path = file_path.clone();
+            async move {
+                let mut engine = create_engine(&options).unwrap();
+                let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+                let mut accounts: Vec<_> =
+                    engine.get_accounts().iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+                accounts.sort();
+                (report.without_timings(), accounts)
+            }
+        };
+        let (report, accounts) = run(options.clone()).await;
+        assert_eq!(run(options).await, (report.clone(), accounts.clone()));
+
+        assert_eq!(report.lines_read, full_report.lines_read);
+        assert_eq!(report.records_sampled_out + report.transactions_processed, full_report.transactions_processed);
+        assert!((30..70).contains(&accounts.len()), "{}", accounts.len());
+        for &(client, available, held, total, locked) in &accounts {
+            assert!(ClientSample::new(0.25, 42).contains(client));
+            let expected = full.get_account(client).unwrap();
+            assert_eq!((available, held, total, locked), (expected.available, expected.held, expected.total, expected.locked));
+        }
+
+        let estimate = report.sample_estimate.unwrap();
+        assert_eq!(estimate.transactions_processed, report.transactions_processed * 4);
+        assert_eq!(estimate.deposited_total, report.deposited_total * Decimal::from(4));
+        let ratio = estimate.transactions_processed as f64 / full_report.transactions_processed as f64;
+        assert!((0.6..1.4).contains(&ratio), "{}", ratio);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_with_dispute() {
+        // Create a temporary directory
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test_disputes.csv");
+        
+        // Create a test CSV file with disputes
+        let csv_content = "type,client,tx,amount\n\
+                          deposit,1,1,100.0\n\
+                          dispute,1,1,\n\
+                          resolve,1,1,\n\
                           deposit,2,2,200.0\n\
                           dispute,2,2,\n\
                           chargeback,2,2,\n";
                           
         write(&file_path, csv_content).unwrap();
-        
-        // Process the file
+        
+        // Process the file
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        
+        // Check the results
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 2);
+        
+        // Find each client's account
+        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
+        let client2 = accounts.iter().find(|a| a.client == 2).unwrap();
+        
+        // Client 1 - deposit was disputed then resolved, so back to original
+        assert_eq!(client1.available, dec!(100.0));
+        assert_eq!(client1.held, dec!(0.0));
+        assert_eq!(client1.total, dec!(100.0));
+        assert!(!client1.locked);
+        
+        // Client 2 - deposit was disputed then chargebacked, so account is locked
+        assert_eq!(client2.available, dec!(0.0));
+        assert_eq!(client2.held, dec!(0.0));
+        assert_eq!(client2.total, dec!(0.0));
+        assert!(client2.locked);
+    }
+
+    // Test with different batch sizes
+    #[tokio::test]
+    async fn test_batch_processing() {
+        // Create a temporary directory
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test_batch.csv");
+        
+        // Create a test CSV file with multiple transactions
+        let mut csv_content = String::from("type,client,tx,amount\n");
+        
+        // Add 100 deposit transactions
+        for i in 1..=100 {
+            csv_content.push_str(&format!("deposit,1,{},{}.0\n", i, i));
+        }
+        
+        write(&file_path, csv_content).unwrap();
+        
+        // Process with small batch size (10)
+        let small_batch = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(10), ..Default::default() };
+        let mut engine1 = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine1, &small_batch).await.unwrap();
+        
+        // Process with large batch size (50)
+        let large_batch = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(50), ..Default::default() };
+        let mut engine2 = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine2, &large_batch).await.unwrap();
+        
+        // Results should be the same regardless of batch size
+        let accounts1 = engine1.get_accounts();
+        let accounts2 = engine2.get_accounts();
+        
+        assert_eq!(accounts1.len(), 1);
+        assert_eq!(accounts2.len(), 1);
+        
+        let client1 = accounts1.iter().find(|a| a.client == 1).unwrap();
+        let client2 = accounts2.iter().find(|a| a.client == 1).unwrap();
+        
+        // Sum of 1..=100 is 5050
+        assert_eq!(client1.available, dec!(5050.0));
+        assert_eq!(client1.total, dec!(5050.0));
+        assert_eq!(client1.available, client2.available);
+        assert_eq!(client1.total, client2.total);
+    }
+
+    #[tokio::test]
+    async fn test_wal_crash_recovery_matches_clean_run() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test_wal.csv");
+        let partial_path = dir.path().join("test_wal_partial.csv");
+        let wal_path = dir.path().join("test.wal");
+
+        let lines = [
+            "deposit,1,1,100.0",
+            "deposit,2,2,50.0",
+            "withdrawal,1,3,20.0",
+            "dispute,1,1,",
+            "deposit,3,4,10.0",
+            "not,a,valid,line",
+            "resolve,1,1,",
+            "withdrawal,2,5,75.0",
+            "dispute,2,2,",
+            "chargeback,2,2,",
+            "deposit,2,6,5.0",
+            "withdrawal,3,7,2.5",
+        ];
+        let header = "type,client,tx,amount\n";
+        write(&file_path, format!("{}{}\n", header, lines.join("\n"))).unwrap();
+
+        // Simulate a crash after two batches by only feeding part of the input
+        write(&partial_path, format!("{}{}\n", header, lines[..8].join("\n"))).unwrap();
+
+        let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(3), ..Default::default() };
+        let wal_options = ProcessingOptions { wal_path: Some(wal_path.clone()), ..options.clone() };
+
+        let mut clean = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut clean, &options).await.unwrap();
+
+        let mut crashed = PaymentEngine::new();
+        process_transactions_stream(&partial_path, &mut crashed, &wal_options).await.unwrap();
+
+        // Recover from the WAL and continue with the full input
+        let mut recovered = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut recovered, &wal_options).await.unwrap();
+
+        let mut expected = clean.get_accounts();
+        let mut actual = recovered.get_accounts();
+        expected.sort_by_key(|a| a.client);
+        actual.sort_by_key(|a| a.client);
+
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!(e.client, a.client);
+            assert_eq!(e.available, a.available);
+            assert_eq!(e.held, a.held);
+            assert_eq!(e.total, a.total);
+            assert_eq!(e.locked, a.locked);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_recovery_skips_logged_transactions_when_filters_change() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("input.csv");
+        let partial_path = dir.path().join("partial.csv");
+        let wal_path = dir.path().join("test.wal");
+        let lines = ["deposit,1,1,100.0", "deposit,3,2,10.0", "deposit,2,3,50.0", "withdrawal,1,4,20.0", "withdrawal,2,5,5.0"];
+        let header = "type,client,tx,amount\n";
+        write(&file_path, format!("{}{}\n", header, lines.join("\n"))).unwrap();
+        write(&partial_path, format!("{}{}\n", header, lines[..3].join("\n"))).unwrap();
+
+        let mut clean = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut clean, &ProcessingOptions::default()).await.unwrap();
+
+        // The interrupted run left client 3 out; the restart applies its
+        // deposit instead of taking it for one of the two logged transactions
+        let filtered = ProcessingOptions {
+            client_filter: Some(ClientFilter::Set(BTreeSet::from([1, 2]))),
+            wal_path: Some(wal_path.clone()),
+            ..Default::default()
+        };
+        process_transactions_stream(&partial_path, &mut PaymentEngine::new(), &filtered).await.unwrap();
+        let mut recovered = PaymentEngine::new();
+        let options = ProcessingOptions { wal_path: Some(wal_path.clone()), ..Default::default() };
+        let report = process_transactions_stream(&file_path, &mut recovered, &options).await.unwrap();
+
+        assert_eq!(report.transactions_processed, 3);
+        let balances = |engine: &PaymentEngine| {
+            let mut accounts: Vec<_> = engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+            accounts.sort();
+            accounts
+        };
+        assert_eq!(balances(&recovered), balances(&clean));
+    }
+
+    #[tokio::test]
+    async fn test_mmap_input_matches_streaming() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test_mmap.csv");
+
+        // CRLF endings, a blank line, an invalid line and no trailing newline
+        let csv_content = "type,client,tx,amount\r\n\
+                           deposit,1,1,100.0\r\n\
+                           deposit,2,2,200.0\r\n\
+                           \r\n\
+                           withdrawal,1,3,30.0\r\n\
+                           bogus,1,4,1.0\r\n\
+                           dispute,2,2,\r\n\
+                           chargeback,2,2,";
+        write(&file_path, csv_content).unwrap();
+
+        let mut results = Vec::new();
+        for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+            let options = ProcessingOptions { input_mode, batch_size_mode: BatchSizeMode::Fixed(2), ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            let mut accounts = engine.get_accounts();
+            accounts.sort_by_key(|a| a.client);
+            results.push(accounts);
+        }
+
+        assert_eq!(results[0].len(), 2);
+        assert_eq!(results[0].len(), results[1].len());
+        for (e, a) in results[0].iter().zip(results[1].iter()) {
+            assert_eq!((e.client, e.available, e.held, e.total, e.locked), (a.client, a.available, a.held, a.total, a.locked));
+        }
+        assert_eq!(results[1][0].available, dec!(70.0));
+        assert!(results[1][1].locked);
+    }
+
+    // Runs the file through each pipeline depth and returns the reports and sorted accounts
+    async fn run_pipeline_depths(
+        file_path: &Path,
+        depths: &[usize],
+        batch_size: usize,
+    ) -> Vec<(ProcessingReport, Vec<(u16, Decimal, Decimal, Decimal, bool)>, std::time::Duration)> {
+        let mut results = Vec::new();
+        for &pipeline_depth in depths {
+            let options = ProcessingOptions { pipeline_depth, batch_size_mode: BatchSizeMode::Fixed(batch_size), ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let start = Instant::now();
+            let report = process_transactions_stream(file_path, &mut engine, &options).await.unwrap();
+            let elapsed = start.elapsed();
+            let mut accounts: Vec<_> =
+                engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+            accounts.sort_by_key(|a| a.0);
+            results.push((report.without_timings(), accounts, elapsed));
+        }
+        results
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_pipelined_matches_sequential() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("pipeline.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 20_000, clients: 50, seed: 3, ..Default::default() };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+
+        // Sprinkle in unparseable lines so error accounting is compared too
+        let mut content = String::new();
+        for (i, line) in String::from_utf8(csv).unwrap().lines().enumerate() {
+            content.push_str(line);
+            content.push('\n');
+            if i > 0 && i % 997 == 0 {
+                content.push_str("deposit,abc,1,1.0\n");
+            }
+        }
+        write(&file_path, content).unwrap();
+
+        let results = run_pipeline_depths(&file_path, &[0, 1, 2, 8], 64).await;
+        let (report, accounts, _) = &results[0];
+        assert_eq!(report.lines_read, 20_020);
+        assert_eq!(report.parse_errors, 20);
+        assert!(report.transactions_rejected > 0);
+        for (other_report, other_accounts, _) in &results[1..] {
+            assert_eq!(other_report, report);
+            assert_eq!(other_accounts, accounts);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_single_transaction_batches_stay_fast() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("tiny_batches.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 20_000, clients: 50, seed: 5, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let tiny = run_pipeline_depths(&file_path, &[DEFAULT_PIPELINE_DEPTH], 1).await.remove(0);
+        let default = run_pipeline_depths(&file_path, &[DEFAULT_PIPELINE_DEPTH], DEFAULT_BATCH_SIZE).await.remove(0);
+        assert_eq!(tiny.0, default.0);
+        assert_eq!(tiny.1, default.1);
+        // A coarse bound: batches of 1 take about 2.5x as long, but handing
+        // each to the engine task through the pipeline took 7x or more
+        assert!(tiny.2 < default.2 * 5, "Batches of 1 took {:?}, batches of 1000 {:?}", tiny.2, default.2);
+    }
+
+    // Compares reading and applying on one task against the default two-stage
+    // pipeline on 1M generated rows. Run with
+    // `cargo test --release bench_pipeline -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_pipeline_vs_sequential() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("bench.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 1_000_000, seed: 1, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let results = run_pipeline_depths(&file_path, &[0, DEFAULT_PIPELINE_DEPTH], DEFAULT_BATCH_SIZE).await;
+        assert_eq!(results[0].0, results[1].0);
+        assert_eq!(results[0].1, results[1].1);
+        println!("Sequential: {:.2?}, pipelined: {:.2?}", results[0].2, results[1].2);
+    }
+
+    // Compares the time spent applying 1M generated rows over 60k clients in
+    // batches of 100k with and without grouping each batch by client. Run with
+    // `cargo test --release bench_group_by_client -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_group_by_client_in_batch() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("bench.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 1_000_000, clients: 60_000, seed: 1, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let mut results = Vec::new();
+        for group_by_client_in_batch in [false, true] {
+            let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(100_000), group_by_client_in_batch, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            let mut accounts: Vec<_> = engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.locked)).collect();
+            accounts.sort_by_key(|a| a.0);
+            results.push((accounts, report.apply_time));
+        }
+        assert_eq!(results[0].0, results[1].0);
+        println!("In order: {:.2?}, grouped by client: {:.2?}", results[0].1, results[1].1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_panicking_batch_is_rolled_back() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("panic.csv");
+        let lines = [
+            "deposit,1,1,10.0",
+            "deposit,2,2,20.0",
+            "deposit,1,3,5.0",
+            // Lines 5-7: the engine panics after applying tx 6
+            "dispute,1,1,",
+            "deposit,2,5,1.0",
+            "withdrawal,1,6,2.0",
+            "deposit,2,7,3.0",
+            "dispute,1,3,",
+            "resolve,1,3,",
+            // Rejected as neither the dispute of tx 1 nor tx 5 was kept
+            "chargeback,1,1,",
+            "dispute,2,5,",
+        ];
+        write(&file_path, format!("type,client,tx,amount\n{}\n", lines.join("\n"))).unwrap();
+
+        for (parallel_batches, wal) in [(false, false), (true, false), (false, true)] {
+            let wal_path = dir.path().join(format!("panic_{}.wal", parallel_batches));
+            let options = ProcessingOptions {
+                batch_size_mode: BatchSizeMode::Fixed(3),
+                parallel_batches,
+                wal_path: wal.then(|| wal_path.clone()),
+                ..Default::default()
+            };
+            let mut engine = PaymentEngine::new();
+            engine.panic_after_tx = Some(6);
+            let run = LiveRun::new(engine);
+            let report = process_live_run(&file_path, &run, &options).await.unwrap();
+
+            assert_eq!(report.failed_batches, [5..=7]);
+            assert_eq!((report.transactions_processed, report.transactions_rejected), (11, 2));
+            let engine = run.engine.into_inner();
+            let balances = |engine: &PaymentEngine, client| {
+                let account = engine.get_account(client).unwrap();
+                (account.available, account.held, account.total, account.locked)
+            };
+            assert_eq!(balances(&engine, 1), (dec!(15.0), dec!(0), dec!(15.0), false));
+            assert_eq!(balances(&engine, 2), (dec!(23.0), dec!(0), dec!(23.0), false));
+
+            // The rolled-back batch is logged as failed, so replaying the WAL
+            // leads to the same balances
+            if wal {
+                let mut replayed = PaymentEngine::new();
+                assert_eq!(replay_wal(&mut replayed, &wal_path).await.unwrap().len(), 11);
+                for client in [1, 2] {
+                    assert_eq!(balances(&replayed, client), balances(&engine, client));
+                }
+            }
+        }
+    }
+
+    // Runs the file on a manual clock that applying moves forward by `delay`
+    // per transaction, and returns the report, sorted accounts and sizes of
+    // the applied batches
+    async fn run_with_apply_delay(
+        file_path: &Path,
+        options: &ProcessingOptions,
+        delay: Duration,
+    ) -> (ProcessingReport, Vec<(u16, Decimal, Decimal, Decimal, bool)>, Vec<usize>) {
+        use chrono::TimeZone;
+
+        let clock = Arc::new(crate::clock::ManualClock::new(chrono::Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()));
+        let options = ProcessingOptions { clock: clock.clone(), ..options.clone() };
+        let mut run = LiveRun::new(PaymentEngine::new());
+        run.hooks.apply_delay = Some((clock, delay));
+        let report = process_live_run(file_path, &run, &options).await.unwrap();
+        let mut accounts: Vec<_> =
+            run.engine.read().await.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+        accounts.sort_by_key(|a| a.0);
+        let sizes = run.hooks.batch_sizes.into_inner().unwrap();
+        (report.without_timings(), accounts, sizes)
+    }
+
+    #[tokio::test]
+    async fn test_auto_batch_size_shrinks_for_slow_engine() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("slow.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 2_000, clients: 20, seed: 7, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        // At 200µs per transaction, 5ms batches hold 25
+        let batch_size_mode = BatchSizeMode::Auto { min: 5, max: 400, target_batch_ms: 5 };
+        let options = ProcessingOptions { batch_size_mode, pipeline_depth: 0, ..Default::default() };
+        let (_, _, sizes) = run_with_apply_delay(&file_path, &options, Duration::from_micros(200)).await;
+
+        assert_eq!(&sizes[..5], &[400, 200, 100, 50, 25]);
+        assert!(sizes[5..].iter().all(|&size| size == 25), "{:?}", sizes);
+    }
+
+    #[tokio::test]
+    async fn test_auto_batch_size_grows_for_fast_engine() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("fast.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 10_000, clients: 20, seed: 7, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let batch_size_mode = BatchSizeMode::Auto { min: 10, max: 4000, target_batch_ms: 1000 };
+        let options = ProcessingOptions { batch_size_mode, pipeline_depth: 0, ..Default::default() };
+        let (_, _, sizes) = run_with_apply_delay(&file_path, &options, Duration::ZERO).await;
+        assert_eq!(sizes, vec![1000, 2000, 4000, 3000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_auto_batch_size_matches_fixed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("auto.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 5_000, clients: 50, seed: 11, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let fixed = ProcessingOptions::default();
+        let (report, accounts, _) = run_with_apply_delay(&file_path, &fixed, Duration::ZERO).await;
+        assert!(report.transactions_rejected > 0);
+
+        // Pipelined, so batches already queued keep their size
+        let batch_size_mode = BatchSizeMode::Auto { min: 5, max: 1000, target_batch_ms: 2 };
+        let auto = ProcessingOptions { batch_size_mode, ..Default::default() };
+        let (auto_report, auto_accounts, sizes) = run_with_apply_delay(&file_path, &auto, Duration::from_micros(20)).await;
+        assert!(sizes.len() > 5);
+        assert_eq!(auto_report, report);
+        assert_eq!(auto_accounts, accounts);
+    }
+
+    #[tokio::test]
+    async fn test_mmap_empty_file_does_not_panic() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("empty.csv");
+        write(&file_path, "").unwrap();
+
+        let options = ProcessingOptions { input_mode: InputMode::Mmap, ..Default::default() };
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_line_is_reported_and_skipped() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("long_line.csv");
+        let long_line = format!("deposit,1,3,{}", "9".repeat(1_000_000));
+        write(
+            &file_path,
+            format!("type,client,tx,amount\ndeposit,1,1,10.0\n{}\ndeposit,1,2,5.0\nbogus\n", long_line),
+        )
+        .unwrap();
+
+        for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+            let options = ProcessingOptions { input_mode, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            assert_eq!(
+                report.without_timings(),
+                ProcessingReport {
+                    lines_read: 4,
+                    transactions_processed: 2,
+                    transactions_by_type: BTreeMap::from([(TransactionType::Deposit, 2)]),
+                    duplicates: 0,
+                    parse_errors: 1,
+                    read_errors: 0,
+                    oversized_lines: 1,
+                    transactions_rejected: 0,
+                    limit_rejections: BTreeMap::new(),
+                    fees_by_client: BTreeMap::new(),
+                    fees_collected: Decimal::ZERO,
+                    deposited_total: dec!(15.0),
+                    // The oversized line's tx is never read
+                    max_tx_id: Some(2),
+                    max_records_reached: false,
+                    ..Default::default()
+                }
+            );
+            assert_eq!(engine.get_accounts()[0].available, dec!(15.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_detection() {
+        let dir = tempdir().unwrap();
+        let with_header = dir.path().join("with_header.csv");
+        write(&with_header, "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\n").unwrap();
+        let headerless = dir.path().join("headerless.csv");
+        write(&headerless, "deposit,1,1,10.0\nwithdrawal,1,2,4.0\n").unwrap();
+        // Three daily files joined with `cat`, each bringing its own header
+        let concatenated = dir.path().join("concatenated.csv");
+        write(
+            &concatenated,
+            "type,client,tx,amount\ndeposit,1,1,10.0\n\
+             type,client,tx,amount\nwithdrawal,1,2,4.0\n\
+             type, client, tx, amount\ndeposit,2,3,1.0\n\
+             type,client,tx,amount\n",
+        )
+        .unwrap();
+
+        for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+            for (file_path, lines_read) in [(&with_header, 2), (&headerless, 2)] {
+                let options = ProcessingOptions { input_mode, ..Default::default() };
+                let mut engine = PaymentEngine::new();
+                let report = process_transactions_stream(file_path, &mut engine, &options).await.unwrap();
+
+                assert_eq!(report.lines_read, lines_read, "{:?}", file_path);
+                assert_eq!(report.error_count(), 0, "{:?}", file_path);
+                assert_eq!(engine.get_accounts()[0].available, dec!(6.0), "{:?}", file_path);
+            }
+
+            let options = ProcessingOptions { input_mode, tolerate_repeated_headers: true, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&concatenated, &mut engine, &options).await.unwrap();
+            assert_eq!(report.lines_read, 3);
+            assert_eq!(report.repeated_headers, 3);
+            assert_eq!(report.error_count(), 0);
+            let accounts = engine.get_accounts();
+            assert_eq!(accounts.iter().find(|a| a.client == 1).unwrap().available, dec!(6.0));
+            assert_eq!(accounts.iter().find(|a| a.client == 2).unwrap().available, dec!(1.0));
+
+            // Without the option, repeated headers are malformed lines
+            let options = ProcessingOptions { input_mode, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&concatenated, &mut engine, &options).await.unwrap();
+            assert_eq!(report.repeated_headers, 0);
+            assert_eq!(report.parse_errors, 3);
+            assert_eq!(report.transactions_processed, 3);
+        }
+
+        let summary = summarize_transactions(&headerless, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!((summary.lines, summary.deposits, summary.withdrawals, summary.parse_errors), (2, 1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_line_numbers_count_skipped_headers() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("numbered.csv");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,1.0\ntype,client,tx,amount\nbogus\n").unwrap();
+
+        let options = ProcessingOptions { tolerate_repeated_headers: true, ..Default::default() };
+        let mut source = RecordSource::open_lines(&file_path, &options).await.unwrap();
+        assert!(matches!(source.next_record(source.line_number(1), None, None).await, Some(Ok(Record::Parsed(_)))));
+        let Some(Ok(Record::Malformed { error: err, .. })) = source.next_record(source.line_number(2), None, None).await else { panic!() };
+        assert!(matches!(err, PaymentEngineError::ParseError { line: 4, .. }), "{}", err);
+        assert_eq!(source.repeated_headers(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_report_counts_rejected_transactions() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("rejections.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount
+             deposit,1,1,10.0
+             withdrawal,1,2,50.0
+             dispute,1,99,
+             deposit,2,3,
+             not,a,transaction
+             deposit,2,4,1.0
+",
+        )
+        .unwrap();
+
+        for parallel_batches in [false, true] {
+            let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(2), parallel_batches, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            // The deposit without an amount never reaches the engine
+            assert_eq!(report.transactions_processed, 4);
+            assert_eq!(report.transactions_rejected, 2);
+            assert_eq!(report.parse_errors, 2);
+            assert_eq!(report.error_count(), 4);
+        }
+
+        let clean = ProcessingReport { lines_read: 3, transactions_processed: 3, ..Default::default() };
+        assert_eq!(clean.error_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_report_breaks_down_processing_time() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("timing.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 20_000, clients: 100, seed: 5, ..Default::default() };
+        crate::generator::generate_transactions(&config, std::fs::File::create(&file_path).unwrap()).unwrap();
+
+        let options = ProcessingOptions { pipeline_depth: 0, ..Default::default() };
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        assert!(!report.read_time.is_zero());
+        assert!(!report.parse_time.is_zero());
+        assert!(!report.apply_time.is_zero());
+        // Without pipelining the phases don't overlap, and little else happens
+        let phases = report.read_time + report.parse_time + report.apply_time;
+        assert!(phases <= report.elapsed, "{:?} > {:?}", phases, report.elapsed);
+        assert!(phases >= report.elapsed / 2, "{:?} < {:?} / 2", phases, report.elapsed);
+
+        let expected = report.transactions_processed as f64 / report.elapsed.as_secs_f64();
+        assert!((report.transactions_per_sec() - expected).abs() < 1e-6 * expected);
+        assert!(report.lines_per_sec() >= report.transactions_per_sec());
+        assert_eq!(ProcessingReport::default().lines_per_sec(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_report_counts_limit_rejections_per_client() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("limits.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,5000.0\n\
+             withdrawal,1,3,10.0\n\
+             withdrawal,1,4,10.0\n\
+             withdrawal,1,5,10.0\n\
+             deposit,3,6,100.0\n\
+             withdrawal,3,7,60.0\n\
+             withdrawal,3,8,500.0\n",
+        )
+        .unwrap();
+
+        let limits = Limits {
+            max_deposit: Some(dec!(1000)),
+            max_withdrawal: Some(dec!(50)),
+            max_withdrawals_per_client: Some(2),
+            ..Default::default()
+        };
+        for parallel_batches in [false, true] {
+            let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(3), parallel_batches, limits, ..Default::default() };
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            assert_eq!(report.limit_rejections, BTreeMap::from([(1, 1), (2, 1), (3, 2)]));
+            assert_eq!(report.transactions_rejected, 4);
+            assert_eq!(engine.get_account(1).unwrap().available, dec!(80));
+            assert!(engine.get_account(2).unwrap().available.is_zero());
+            assert_eq!(engine.get_account(3).unwrap().available, dec!(100));
+        }
+
+        // Without limits nothing is rejected for them
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert!(report.limit_rejections.is_empty());
+        assert_eq!(report.transactions_rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_report_attributes_withdrawal_fees() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("fees.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,10.0\n\
+             withdrawal,1,3,50.0\n\
+             withdrawal,2,4,9.9\n\
+             withdrawal,1,5,10.0\n\
+             deposit,3,6,5.0\n",
+        )
+        .unwrap();
+
+        for parallel_batches in [false, true] {
+            let options = ProcessingOptions {
+                batch_size_mode: BatchSizeMode::Fixed(2),
+                parallel_batches,
+                withdrawal_fee: WithdrawalFee::FlatPlusPercent(dec!(0.1), dec!(1)),
+                ..Default::default()
+            };
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            // Client 2 can't cover 9.9 plus a 0.199 fee
+            assert_eq!(report.fees_by_client, BTreeMap::from([(1, dec!(0.8))]));
+            assert_eq!(report.fees_collected, dec!(0.8));
+            assert_eq!(report.transactions_rejected, 1);
+            assert_eq!(engine.get_account(1).unwrap().total, dec!(39.2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_currency_balances() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("currencies.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,100.0,\n\
+             deposit,1,2,50.0,EUR\n\
+             deposit,1,3,20.0,eur\n\
+             withdrawal,1,4,20.0,EUR\n\
+             dispute,1,3,,EUR\n\
+             dispute,1,1,,GBP\n\
+             dispute,1,1,\n\
+             resolve,1,1,,USD\n\
+             deposit,2,5,5.0,XX\n",
+        )
+        .unwrap();
+
+        let options = ProcessingOptions { multi_currency: true, ..Default::default() };
+        let mut engine = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        // The GBP dispute doesn't match the currency of deposit 1
+        assert_eq!(report.transactions_rejected, 1);
+        assert_eq!(report.parse_errors, 1);
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (dec!(100), dec!(0), dec!(100)));
+        let eur = &account.other_currencies[&"EUR".parse().unwrap()];
+        assert_eq!((eur.available, eur.held, eur.total), (dec!(30), dec!(20), dec!(50)));
+
+        let mut output = Vec::new();
+        write_accounts(&engine, &options, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n\
+             1,USD,100.0,0.0,100.0,false\n\
+             1,EUR,30.0,20.0,50.0,false\n"
+        );
+
+        // Without the option the currency column is ignored
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert!(engine.get_account(1).unwrap().other_currencies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_extended_output_adds_columns() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("disputes.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.23456\n\
+             deposit,1,2,10.0\n\
+             deposit,1,3,5.0\n\
+             deposit,1,4,1.0\n\
+             deposit,2,5,5\n\
+             dispute,1,2,\n\
+             resolve,1,2,\n\
+             dispute,1,3,\n\
+             dispute,1,3,\n\
+             dispute,1,9,\n\
+             dispute,1,4,\n\
+             chargeback,1,3,\n",
+        )
+        .unwrap();
+        let options = ProcessingOptions::default();
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        // The repeated and unknown disputes were rejected and aren't counted
+        let stats = engine.get_account_stats(1).unwrap();
+        assert_eq!((stats.disputes_opened, stats.disputes_resolved, stats.disputes_charged_back), (3, 1, 1));
+        assert_eq!(stats.held_transactions, std::collections::BTreeSet::from([4]));
+        assert!(engine.get_account_stats(2).is_none());
+
+        let mut output = Vec::new();
+        write_accounts(&engine, &options, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["1,11.2346,1.0,12.2346,true", "2,5,0,5,false", "client,available,held,total,locked"]);
+
+        let mut output = Vec::new();
+        let extended = ProcessingOptions { extended_output: true, ..Default::default() };
+        write_accounts(&engine, &extended, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "1,11.2346,1.0,12.2346,true,chargeback:3,3,1,1,5.0,17.2346,0,4",
+                "2,5,0,5,false,,0,0,0,0,5,0,",
+                "client,available,held,total,locked,lock_reason,disputes_opened,disputes_resolved,disputes_charged_back,\
+                 chargeback_total,deposited_total,withdrawn_total,held_transactions",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_engine_stats_after_chargebacks_fixture() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chargebacks.csv");
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&fixture, &mut engine, &ProcessingOptions::default()).await.unwrap();
+
+        // Only the chargeback of client 2's undisputed deposit is rejected
+        let stats = engine.stats();
+        assert_eq!(
+            stats,
+            EngineStats {
+                transactions_applied: 8,
+                deposits_applied: 3,
+                withdrawals_applied: 1,
+                disputes_open: 0,
+                accounts_locked: 2,
+                transactions_rejected: 1,
+            }
+        );
+        assert_eq!(stats.to_string(), "8 tx applied (3 deposits, 1 withdrawals), 0 disputes open, 2 accounts locked, 1 rejects");
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_totals_per_client_and_run() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chargebacks.csv");
+        let options = ProcessingOptions { extended_output: true, ..Default::default() };
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&fixture, &mut engine, &options).await.unwrap();
+
+        // Client 1's 40.0 and client 2's 10.0 deposits were charged back; the
+        // undisputed chargeback of client 2 was rejected
+        assert_eq!(report.chargebacks, 2);
+        assert_eq!(report.chargebacks_by_client, BTreeMap::from([(1, dec!(40.0)), (2, dec!(10.0))]));
+        assert_eq!(report.chargeback_total, dec!(50.0));
+        assert_eq!(engine.get_account(1).unwrap().chargeback_total, dec!(40.0));
+        assert_eq!(engine.chargeback_totals(), report.chargebacks_by_client);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["chargebacks"], 2);
+        assert_eq!(json["chargeback_total"], "50.0");
+        assert_eq!(json["chargebacks_by_client"]["2"], "10.0");
+
+        let mut output = Vec::new();
+        write_accounts(&engine, &options, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = output.lines().skip(1).collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            ["1,70.0,0.0,70.0,true,chargeback:2,1,0,1,40.0,140.0,30.0,", "2,0.0,0.0,0.0,true,chargeback:4,1,0,1,10.0,10.0,0,"]
+        );
+
+        // A second run on the same engine only reports its own chargebacks
+        let report = process_transactions_stream(&fixture, &mut engine, &options).await.unwrap();
+        assert_eq!((report.chargebacks, report.chargeback_total), (0, Decimal::ZERO));
+        assert!(report.chargebacks_by_client.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gross_totals_ignore_chargebacks() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chargebacks.csv");
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&fixture, &mut engine, &ProcessingOptions::default()).await.unwrap();
+
+        // Client 1's charged-back 40.0 deposit still counts as deposited; its
+        // total went down by it instead, while the gross totals only ever grow
+        let one = engine.get_account(1).unwrap();
+        assert_eq!((one.deposited_total, one.withdrawn_total, one.chargeback_total), (dec!(140.0), dec!(30.0), dec!(40.0)));
+        assert_eq!(one.total, one.deposited_total - one.withdrawn_total - one.chargeback_total);
+        assert_eq!(engine.get_account(2).unwrap().deposited_total, dec!(10.0));
+        assert_eq!((report.deposited_total, report.withdrawn_total), (dec!(150.0), dec!(30.0)));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!((&json["deposited_total"], &json["withdrawn_total"]), (&"150.0".into(), &"30.0".into()));
+        let account = serde_json::to_value(engine.get_account(1).unwrap()).unwrap();
+        assert_eq!(account["deposited_total"], "140.0");
+
+        // Replaying the same input is all duplicates, so the second run moves nothing
+        let report = process_transactions_stream(&fixture, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!((report.deposited_total, report.withdrawn_total), (Decimal::ZERO, Decimal::ZERO));
+    }
+
+    #[test]
+    fn test_presets_set_documented_options() {
+        let strict = ProcessingOptions::preset(Preset::StrictSpec).build().unwrap();
+        assert_eq!(strict.amount_precision, AmountPrecision::Reject);
+        assert_eq!(strict.encoding, InputEncoding::Utf8Strict);
+        assert_eq!(strict.dispute_client_check, DisputeClientCheck::Strict);
+        assert!(!strict.lenient_amounts && !strict.tolerate_repeated_headers);
+        assert!(!strict.allow_partial_disputes && !strict.allow_withdrawal_disputes);
+
+        let lenient = ProcessingOptions::preset(Preset::Lenient).build().unwrap();
+        assert_eq!(lenient.amount_precision, AmountPrecision::RoundHalfEven);
+        assert_eq!(lenient.encoding, InputEncoding::Utf8Lossy);
+        assert_eq!(lenient.dispute_client_check, DisputeClientCheck::TrustOriginal);
+        assert!(lenient.lenient_amounts && lenient.tolerate_repeated_headers);
+        assert!(lenient.allow_partial_disputes && lenient.allow_withdrawal_disputes);
+
+        // Setters called after the preset override it, those before don't
+        let options = ProcessingOptions::builder()
+            .allow_partial_disputes(false)
+            .preset(Preset::Lenient)
+            .amount_precision(AmountPrecision::Truncate)
+            .build()
+            .unwrap();
+        assert_eq!(options.amount_precision, AmountPrecision::Truncate);
+        assert!(options.allow_partial_disputes);
+        assert_eq!("strict".parse(), Ok(Preset::StrictSpec));
+        assert!("spec".parse::<Preset>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_presets_on_differentiating_input() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/presets.csv");
+        for preset in [Preset::StrictSpec, Preset::Lenient] {
+            let options = ProcessingOptions::preset(preset).build().unwrap();
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&fixture, &mut engine, &options).await.unwrap();
+            let one = engine.get_account(1).unwrap();
+            let two = engine.get_account(2).unwrap();
+            let counts = (report.parse_errors, report.repeated_headers, report.transactions_rejected);
+            match preset {
+                // The 1.00005 deposit, the repeated header and the partial
+                // dispute's amount don't parse; the withdrawal dispute and
+                // client 1's dispute of client 2's deposit are rejected
+                Preset::StrictSpec => {
+                    assert_eq!(counts, (3, 0, 2));
+                    assert_eq!((one.available, one.held), (dec!(110.0), dec!(0)));
+                    assert_eq!((two.available, two.held), (dec!(50.0), dec!(0)));
+                }
+                // All of them apply, the deposit rounded to 1.0000
+                Preset::Lenient => {
+                    assert_eq!(counts, (0, 1, 0));
+                    assert_eq!((one.available, one.held, one.total), (dec!(101.0), dec!(10.0), dec!(111.0)));
+                    assert_eq!((two.available, two.held), (dec!(0), dec!(50.0)));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disputes_expire_between_batches() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("timed.csv");
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,50.0,1000
+deposit,2,2,20.0,1000
+dispute,1,1,,1000
+dispute,2,2,,1050
+deposit,3,3,1.0,1101
+chargeback,2,2,,1120
+deposit,3,4,1.0,noon
+deposit,3,5,1.0,
+";
+        write(&file_path, input).unwrap();
+
+        for parallel in [false, true] {
+            let options = ProcessingOptions::builder()
+                .batch_size(2)
+                .parallel_batches(parallel)
+                .timestamps(true)
+                .dispute_expiry(Duration::from_secs(100))
+                .build()
+                .unwrap();
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            // Client 1's dispute expires before the deposit at 1101; client
+            // 2's, opened at 1050, is charged back before it can
+            assert_eq!((report.disputes_expired, report.chargebacks, report.parse_errors), (1, 1, 1));
+            let one = engine.get_account(1).unwrap();
+            assert_eq!((one.available, one.held), (dec!(50.0), dec!(0)));
+            assert!(engine.get_account(2).unwrap().locked);
+            assert_eq!(engine.get_account(3).unwrap().total, dec!(2.0));
+        }
+
+        // Without timestamps the column is ignored and nothing expires
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!((report.disputes_expired, report.parse_errors), (0, 0));
+        assert_eq!(engine.get_account(1).unwrap().held, dec!(50.0));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_expiry_independent_of_batch_size() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("timed.csv");
+        // Client 2's deposit at 1200 expires client 1's dispute, so the
+        // withdrawal and the late resolve after it see the funds released
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,50.0,1000
+dispute,1,1,,1000
+deposit,3,3,5.0,1000
+dispute,3,3,,1150
+deposit,2,2,20.0,1200
+withdrawal,1,4,30.0,
+resolve,1,1,,1201
+chargeback,3,3,,1240
+dispute,2,2,,1250
+deposit,1,5,1.0,1400
+withdrawal,2,6,10.0,1400
+";
+        write(&file_path, input).unwrap();
+
+        let mut outputs = Vec::new();
+        for (batch_size, parallel) in [(1, false), (1000, false), (1000, true), (3, true)] {
+            let options = ProcessingOptions::builder()
+                .batch_size(batch_size)
+                .parallel_batches(parallel)
+                .timestamps(true)
+                .dispute_expiry(Duration::from_secs(100))
+                .build()
+                .unwrap();
+            let mut engine = create_engine(&options).unwrap();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            assert_eq!((report.disputes_expired, report.chargebacks), (2, 1), "batch size {}", batch_size);
+            let mut output = Vec::new();
+            write_accounts(&engine, &options, &mut output).unwrap();
+            let mut lines: Vec<String> = String::from_utf8(output).unwrap().lines().map(str::to_string).collect();
+            lines.sort();
+            outputs.push(lines);
+        }
+        assert!(outputs.iter().all(|output| *output == outputs[0]), "{:?}", outputs);
+        assert_eq!(
+            outputs[0],
+            ["1,21.0,0.0,21.0,false", "2,10.0,0.0,10.0,false", "3,0.0,0.0,0.0,true", "client,available,held,total,locked"]
+        );
+    }
+
+    #[test]
+    fn test_push_decimal_matches_display() {
+        let values = [
+            dec!(0),
+            dec!(0.0000),
+            dec!(-0.0001),
+            dec!(1.05),
+            dec!(-12.3400),
+            dec!(100),
+            Decimal::new(i64::MAX, 4),
+            Decimal::from_i128_with_scale(i128::from(u64::MAX) * 1000, 4),
+            Decimal::new(1, 28),
+            -Decimal::ZERO,
+        ];
+        for value in values {
+            let mut buf = String::new();
+            push_decimal(&mut buf, value);
+            assert_eq!(buf, value.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_output_columns_select_and_order() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("columns.csv");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\ndispute,1,2,\ndeposit,2,3,2.5\n").unwrap();
+        let mut engine = PaymentEngine::new();
+        process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+
+        let sorted_lines = |columns: &str| {
+            let options = ProcessingOptions::builder().output_columns(columns.parse::<OutputColumns>().unwrap()).build().unwrap();
+            let mut output = Vec::new();
+            write_accounts(&engine, &options, &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let mut lines: Vec<String> = output.lines().map(str::to_string).collect();
+            lines[1..].sort_unstable();
+            lines
+        };
+        // A subset, a reordered set with a renamed header, and a stats column
+        assert_eq!(sorted_lines("client,total"), ["client,total", "1,15.0", "2,2.5"]);
+        assert_eq!(
+            sorted_lines("locked,total=balance,client,held"),
+            ["locked,balance,client,held", "false,15.0,1,5.0", "false,2.5,2,0"]
+        );
+        assert_eq!(sorted_lines("client,held_transactions"), ["client,held_transactions", "1,2", "2,"]);
+    }
+
+    #[test]
+    fn test_invalid_output_columns() {
+        assert_eq!("client,balance".parse::<OutputColumns>().unwrap_err(), "Invalid output column: balance");
+        let columns: OutputColumns = [OutputColumn::Client, OutputColumn::Total].into_iter().collect();
+        assert_eq!(columns, "client, total".parse().unwrap());
+
+        let build = |columns: &str| ProcessingOptions::builder().output_columns(columns.parse::<OutputColumns>().unwrap()).build();
+        let err = build("client,total,client").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: output_columns lists client more than once");
+        assert!(build("client,total=").is_err());
+        assert!(build("client=\"id\"").is_err());
+        assert!(ProcessingOptions::builder().output_columns(OutputColumns(Vec::new())).build().is_err());
+        let err = ProcessingOptions::builder().extended_output(true).output_columns(columns).build().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid processing options: output_columns can't be combined with extended_output");
+    }
+
+    #[tokio::test]
+    async fn test_account_rows_match_serde_writer() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,100.5,\n\
+             deposit,1,2,20,EUR\n\
+             deposit,2,3,7.12345,\n\
+             dispute,2,3,,\n\
+             deposit,3,4,50,\n\
+             deposit,3,5,5,\n\
+             dispute,3,4,,\n\
+             dispute,3,5,,\n\
+             chargeback,3,4,,\n\
+             withdrawal,1,6,0.5,\n\
+             deposit,4,7,0,\n",
+        )
+        .unwrap();
+
+        for multi_currency in [false, true] {
+            for extended_output in [false, true] {
+                let options = ProcessingOptions { multi_currency, extended_output, ..Default::default() };
+                let mut engine = create_engine(&options).unwrap();
+                process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+                let mut expected = Vec::new();
+                let accounts = engine.get_accounts();
+                write_account_rows_with_serde(&engine, accounts, multi_currency, &options, &mut expected);
+                let mut output = Vec::new();
+                write_accounts(&engine, &options, &mut output).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), String::from_utf8(expected).unwrap());
+            }
+        }
+    }
+
+    // Compares hand-formatted account rows against the serde writer on 1M
+    // synthetic accounts. Run with
+    // `cargo test --release bench_account_writer -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_account_writer() {
+        let accounts: Vec<Account> = (0..1_000_000u32)
+            .map(|i| Account {
+                client: i as u16,
+                available: Decimal::new(i64::from(i) * 37, 4),
+                held: Decimal::new(i64::from(i % 1000), 2),
+                total: Decimal::new(i64::from(i) * 37 + i64::from(i % 1000) * 100, 4),
+                locked: i % 97 == 0,
+                ..Default::default()
+            })
+            .collect();
+        let engine = PaymentEngine::new();
+        let options = ProcessingOptions::default();
+
+        let start = Instant::now();
+        write_account_rows_with_serde(&engine, accounts.clone(), false, &options, std::io::sink());
+        let serde = start.elapsed();
+        let start = Instant::now();
+        write_account_rows(&engine, accounts, false, &options, std::io::sink()).unwrap();
+        println!("Serde: {:.2?}, hand-formatted: {:.2?}", serde, start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_closed_account_handling() {
+        let dir = tempdir().unwrap();
+        let file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/closures.csv");
+        let (output, closed) = (dir.path().join("accounts.csv"), dir.path().join("closed.csv"));
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        let header = "client,available,held,total,locked\n";
+        let (closed_row, locked_row, open_row) = ("1,0.0,0.0,0.0,true\n", "2,50.0,0.0,50.0,true\n", "3,10.0,0,10.0,false\n");
+
+        // Client 1 is closed; client 2 is locked but still holds funds
+        for (handling, expected, expected_closed) in [
+            (ClosedAccountHandling::IncludeInOutput, vec![closed_row, locked_row, open_row], None),
+            (ClosedAccountHandling::SeparateFile(closed.clone()), vec![locked_row, open_row], Some(closed_row)),
+            (ClosedAccountHandling::Exclude, vec![locked_row, open_row], None),
+        ] {
+            let _ = std::fs::remove_file(&closed);
+            let options = ProcessingOptions::builder()
+                .output_mode(OutputMode::File(output.clone()))
+                .closed_account_handling(handling.clone())
+                .build()
+                .unwrap();
+            process_transactions_with_options(&file_path, options).await.unwrap();
+
+            let written = read(&output);
+            let mut rows: Vec<&str> = written.split_inclusive('\n').collect();
+            rows[1..].sort();
+            assert_eq!(rows, [&[header][..], &expected].concat(), "{:?}", handling);
+            match expected_closed {
+                Some(row) => assert_eq!(read(&closed), format!("{}{}", header, row)),
+                None => assert!(!closed.exists(), "{:?}", handling),
+            }
+        }
+
+        // Partitioned and per-client output leave closed accounts out too
+        let options = ProcessingOptions::builder()
+            .output_mode(OutputMode::PerClientDir(dir.path().join("client_{id}.csv").display().to_string()))
+            .closed_account_handling(ClosedAccountHandling::Exclude)
+            .build()
+            .unwrap();
+        process_transactions_with_options(&file_path, options).await.unwrap();
+        assert!(!dir.path().join("client_1.csv").exists());
+        assert_eq!(read(&dir.path().join("client_2.csv")), format!("{}{}", header, locked_row));
+
+        let options = ProcessingOptions::builder()
+            .output_partitions(2)
+            .output_dir(dir.path().to_path_buf())
+            .closed_account_handling(ClosedAccountHandling::Exclude)
+            .build()
+            .unwrap();
+        process_transactions_with_options(&file_path, options).await.unwrap();
+        assert_eq!(read(&dir.path().join("accounts_1.csv")), format!("{}{}", header, open_row));
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_output_matches_single_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        let config = crate::generator::GeneratorConfig { transactions: 5_000, clients: 50, seed: 11, ..Default::default() };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+        write(&file_path, csv).unwrap();
+
+        let options = ProcessingOptions { extended_output: true, ..Default::default() };
+        let mut engine = create_engine(&options).unwrap();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        let mut single = Vec::new();
+        write_accounts(&engine, &options, &mut single).unwrap();
+        let single = String::from_utf8(single).unwrap();
+        let (header, rows) = single.split_once('\n').unwrap();
+        let mut expected: Vec<&str> = rows.lines().collect();
+        expected.sort();
+
+        let out_dir = dir.path().join("parts");
+        write_partitioned_accounts(&engine, &options, &out_dir, 4).unwrap();
+        let mut combined = Vec::new();
+        for part in 0..4 {
+            let content = std::fs::read_to_string(out_dir.join(format!("accounts_{}.csv", part))).unwrap();
+            let mut lines = content.lines();
+            assert_eq!(lines.next(), Some(header));
+            for line in lines {
+                let client: usize = line.split(',').next().unwrap().parse().unwrap();
+                assert_eq!(client % 4, part);
+                combined.push(line.to_string());
+            }
+        }
+        combined.sort();
+        assert_eq!(combined, expected);
+
+        // Parts without clients still get a header
+        let engine = create_engine(&options).unwrap();
+        write_partitioned_accounts(&engine, &options, &out_dir, 2).unwrap();
+        assert_eq!(std::fs::read_to_string(out_dir.join("accounts_1.csv")).unwrap(), format!("{}\n", header));
+    }
+
+    #[tokio::test]
+    async fn test_per_client_files() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("three.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.5\n\
+             withdrawal,1,3,4.0\n\
+             deposit,3,4,1.0\n\
+             withdrawal,2,5,9.0\n",
+        )
+        .unwrap();
+
+        let template = dir.path().join("out/client_{id}.csv").to_str().unwrap().to_string();
+        let options = ProcessingOptions::builder().output_mode(OutputMode::PerClientDir(template.clone())).build().unwrap();
+        process_transactions_with_options(&file_path, options).await.unwrap();
+        assert_eq!(std::fs::read_dir(dir.path().join("out")).unwrap().count(), 3);
+        for (client, row) in [(1, "1,6.0,0,6.0,false"), (2, "2,5.5,0,5.5,false"), (3, "3,1.0,0,1.0,false")] {
+            let content = std::fs::read_to_string(client_output_path(&template, client)).unwrap();
+            assert_eq!(content, format!("client,available,held,total,locked\n{}\n", row));
+        }
+
+        // With history recorded, each file holds the client's audit trail instead
+        let options = ProcessingOptions::builder()
+            .output_mode(OutputMode::PerClientDir(template.clone()))
+            .record_history(true)
+            .build()
+            .unwrap();
+        process_transactions_with_options(&file_path, options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(client_output_path(&template, 2)).unwrap(),
+            "client,tx,type,amount,outcome,reason,available,held,total\n\
+             2,2,deposit,5.5,applied,,5.5,0,5.5\n\
+             2,5,withdrawal,9.0,rejected,insufficient_funds,5.5,0,5.5\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_follow_fifo_across_producer_reconnects() {
+        use std::io::Write as _;
+
+        let dir = tempdir().unwrap();
+        let fifo = dir.path().join("transactions.fifo");
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+        // The producer opens and closes the pipe around each chunk, the
+        // second one ending halfway through a line
+        let producer_path = fifo.clone();
+        let producer = std::thread::spawn(move || {
+            for chunk in ["type,client,tx,amount\ndeposit,1,1,10.0\n", "deposit,2,2,5.0\nwithdrawal,1,3,2", ".5\n#EOF\n"] {
+                let mut pipe = std::fs::OpenOptions::new().write(true).open(&producer_path).unwrap();
+                pipe.write_all(chunk.as_bytes()).unwrap();
+                drop(pipe);
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let options = ProcessingOptions::builder().follow(true).batch_size(2).build().unwrap();
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&fifo, &mut engine, &options).await.unwrap();
+        producer.join().unwrap();
+        assert_eq!((report.lines_read, report.transactions_processed), (3, 3));
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(7.5));
+        assert_eq!(engine.get_account(2).unwrap().available, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_output_file_matches_uncompressed() {
+        let dir = tempdir().unwrap();
+        let file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chargebacks.csv");
+        let output = |path: PathBuf, compress: bool| {
+            ProcessingOptions::builder().output_mode(OutputMode::File(path)).compress_output(compress).build().unwrap()
+        };
+        let gunzip = |path: PathBuf| std::io::read_to_string(flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap())).unwrap();
+
+        let plain = dir.path().join("accounts.csv");
+        process_transactions_with_options(&file_path, output(plain.clone(), false)).await.unwrap();
+        let plain = std::fs::read_to_string(plain).unwrap();
+        assert!(plain.starts_with("client,available,held,total,locked\n"));
+
+        // By extension, or forced whatever the extension
+        let gz = dir.path().join("accounts.csv.gz");
+        process_transactions_with_options(&file_path, output(gz.clone(), false)).await.unwrap();
+        assert_eq!(gunzip(gz), plain);
+        let forced = dir.path().join("accounts.out");
+        process_transactions_with_options(&file_path, output(forced.clone(), true)).await.unwrap();
+        assert_eq!(gunzip(forced), plain);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_describes_fixture_run() {
+        use crate::manifest::RunManifest;
+        use sha2::{Digest, Sha256};
+
+        let dir = tempdir().unwrap();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let file_path = fixtures.join("disputes.csv");
+        let (output, manifest_path) = (dir.path().join("accounts.csv.gz"), dir.path().join("run.json"));
+        let options =
+            ProcessingOptions::builder().output_mode(OutputMode::File(output.clone())).manifest_path(manifest_path.clone()).build().unwrap();
+        let report = process_transactions_with_options(&file_path, options).await.unwrap();
+
+        let manifest = RunManifest::read(&manifest_path).unwrap();
+        assert!(manifest.succeeded && manifest.error.is_none());
+        assert_eq!(manifest.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(manifest.input.path, file_path.display().to_string());
+        assert_eq!(manifest.input.size, Some(std::fs::metadata(&file_path).unwrap().len()));
+        assert_eq!(manifest.options.manifest_path, Some(manifest_path.clone()));
+        assert_eq!(manifest.options.output_file, Some(output.clone()));
+        assert!(manifest.started_at <= manifest.finished_at && manifest.finished_at.ends_with('Z'));
+
+        let by_type = BTreeMap::from([
+            (TransactionType::Deposit, 3),
+            (TransactionType::Withdrawal, 1),
+            (TransactionType::Dispute, 5),
+            (TransactionType::Resolve, 2),
+        ]);
+        assert_eq!(manifest.counts.transactions_by_type, by_type);
+        assert_eq!((manifest.counts.lines_read, manifest.counts.transactions_processed), (11, 11));
+        assert_eq!(manifest.errors.transactions_rejected, report.transactions_rejected);
+        assert_eq!(manifest.errors.total, report.error_count());
+        // The rejected dispute of an unknown transaction still counts
+        assert_eq!((report.max_tx_id, manifest.counts.max_tx_id), (Some(99), Some(99)));
+
+        // The hash is of the CSV before compression, the expected fixture's rows in some order
+        let written = std::io::read_to_string(flate2::read::GzDecoder::new(std::fs::File::open(&output).unwrap())).unwrap();
+        let expected = std::fs::read_to_string(fixtures.join("disputes.expected.csv")).unwrap();
+        let sorted = |csv: &str| csv.lines().map(str::to_string).collect::<BTreeSet<_>>();
+        assert_eq!(sorted(&written), sorted(&expected));
+        let sha256: String = Sha256::digest(written.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect();
+        let manifest_output = manifest.output.unwrap();
+        assert_eq!(manifest_output.path, Some(output));
+        assert_eq!((manifest_output.digest.rows, manifest_output.digest.sha256), (2, sha256));
+        assert!(!dir.path().join("run.json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_manual_clock_times_the_run() {
+        use crate::clock::ManualClock;
+        use chrono::TimeZone;
+
+        let dir = tempdir().unwrap();
+        let file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+        let manifest_path = dir.path().join("run.json");
+        let clock = Arc::new(ManualClock::new(chrono::Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()));
+        let options = ProcessingOptions::builder()
+            .output_mode(OutputMode::File(dir.path().join("accounts.csv")))
+            .manifest_path(manifest_path.clone())
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let report = process_transactions_with_options(&file_path, options).await.unwrap();
+
+        // Time stood still through the run
+        assert_eq!((report.elapsed, report.apply_time), (Duration::ZERO, Duration::ZERO));
+        let manifest = crate::manifest::RunManifest::read(&manifest_path).unwrap();
+        assert_eq!(manifest.started_at, "2024-06-01T12:00:00.000Z");
+        assert_eq!(manifest.finished_at, manifest.started_at);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_written_for_failed_run() {
+        let dir = tempdir().unwrap();
+        let manifest_path = dir.path().join("run.json");
+        let options = ProcessingOptions::builder().manifest_path(manifest_path.clone()).build().unwrap();
+        let error = process_transactions_with_options(&dir.path().join("missing.csv"), options).await.unwrap_err();
+
+        let manifest = crate::manifest::RunManifest::read(&manifest_path).unwrap();
+        assert!(!manifest.succeeded);
+        assert_eq!(manifest.error, Some(error.to_string()));
+        assert_eq!((manifest.input.size, manifest.output), (None, None));
+        assert_eq!(manifest.counts, Default::default());
+    }
+
+    #[tokio::test]
+    async fn test_gzip_stream_is_finished_when_dropped_early() {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, dec!(2.5))).await.unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("accounts.csv.gz");
+        // Dropped without finishing, as when a run is cancelled mid-write
+        {
+            let mut encoder = GzEncoder::new(BufWriter::new(std::fs::File::create(&path).unwrap()), Compression::default());
+            write_accounts(&engine, &ProcessingOptions::default(), &mut encoder).unwrap();
+        }
+        let content = std::io::read_to_string(flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap())).unwrap();
+        assert_eq!(content, "client,available,held,total,locked\n1,2.5,0,2.5,false\n");
+    }
+
+    #[test]
+    fn test_per_client_template_validation() {
+        let per_client = |template: &str| {
+            ProcessingOptions::builder().output_mode(OutputMode::PerClientDir(template.to_string())).build()
+        };
+        assert!(per_client("client_{id}.csv").is_ok());
+        assert!(per_client("/data/out/{id}.csv").is_ok());
+        for template in ["out/accounts.csv", "../out/client_{id}.csv", "out/../../etc/{id}", "out/{id}/account.csv", "{id}/../{id}.csv"] {
+            assert!(matches!(per_client(template), Err(PaymentEngineError::InvalidOptions(_))), "{}", template);
+        }
+        let both = ProcessingOptions::builder().output_mode(OutputMode::PerClientDir("{id}.csv".to_string())).output_partitions(2);
+        assert!(both.build().is_err());
+        assert_eq!(client_output_path("out/client_{id}.csv", 65535), PathBuf::from("out/client_65535.csv"));
+    }
+
+    #[tokio::test]
+    async fn test_amount_precision_policies() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("precision.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,0.123456789\n\
+             deposit,2,2,0.000150000\n\
+             deposit,2,3,1.5\n\
+             dispute,1,1,\n\
+             dispute,2,2,\n",
+        )
+        .unwrap();
+
+        for (policy, client1, client2) in [
+            (AmountPrecision::Reject, dec!(0), dec!(0)),
+            (AmountPrecision::Truncate, dec!(0.1234), dec!(0.0001)),
+            (AmountPrecision::RoundHalfEven, dec!(0.1235), dec!(0.0002)),
+        ] {
+            let options = ProcessingOptions { amount_precision: policy, ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            // Held funds are exactly the normalized amount, so output rounding changes nothing
+            let account = engine.get_account(1).unwrap();
+            assert_eq!((account.available, account.held, account.total), (dec!(0), client1, client1));
+            assert_eq!(account.held, account.held.round_dp(4));
+            let account = engine.get_account(2).unwrap();
+            assert_eq!(account.held, client2);
+            assert_eq!(account.total, dec!(1.5) + client2);
+
+            let rejected = if policy == AmountPrecision::Reject { 2 } else { 0 };
+            assert_eq!(report.parse_errors, rejected);
+        }
+
+        let options = ProcessingOptions { amount_precision: AmountPrecision::Reject, ..Default::default() };
+        let err = parse_transaction_fields("deposit,1,1,0.12345", LineFormat::new(&options, false)).unwrap_err();
+        assert!(matches!(err, PaymentEngineError::ExcessPrecision(amount) if amount == dec!(0.12345)));
+    }
+
+    #[tokio::test]
+    async fn test_initial_accounts_continue_previous_run() {
+        let dir = tempdir().unwrap();
+        let file_a = dir.path().join("month1.csv");
+        let file_b = dir.path().join("month2.csv");
+        write(
+            &file_a,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.0\n\
+             deposit,3,3,20.0\n\
+             withdrawal,1,4,30.5\n\
+             deposit,2,5,10.0\n\
+             dispute,2,5,\n\
+             deposit,3,6,5.0\n\
+             dispute,3,6,\n\
+             chargeback,3,6,\n",
+        )
+        .unwrap();
+        write(
+            &file_b,
+            "type,client,tx,amount\n\
+             deposit,1,7,1.25\n\
+             withdrawal,2,8,40.0\n\
+             deposit,3,9,100.0\n\
+             deposit,4,10,7.0\n\
+             dispute,1,7,\n",
+        )
+        .unwrap();
+        let options = ProcessingOptions::default();
+
+        // Both months in one engine
+        let mut expected = PaymentEngine::new();
+        process_transactions_stream(&file_a, &mut expected, &options).await.unwrap();
+        process_transactions_stream(&file_b, &mut expected, &options).await.unwrap();
+
+        // Month one's closing balances as month two's starting point
+        let mut month1 = PaymentEngine::new();
+        process_transactions_stream(&file_a, &mut month1, &options).await.unwrap();
+        let closing = dir.path().join("closing.csv");
+        let extended = ProcessingOptions { extended_output: true, ..Default::default() };
+        write_accounts(&month1, &extended, std::fs::File::create(&closing).unwrap()).unwrap();
+
+        let options = ProcessingOptions { initial_accounts: Some(closing), ..Default::default() };
+        let mut month2 = create_engine(&options).unwrap();
+        process_transactions_stream(&file_b, &mut month2, &options).await.unwrap();
+
+        let mut expected = expected.get_accounts();
+        let mut actual = month2.get_accounts();
+        expected.sort_by_key(|a| a.client);
+        actual.sort_by_key(|a| a.client);
+        assert_eq!(actual.len(), 4);
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!((e.client, e.available, e.held, e.total, e.locked), (a.client, a.available, a.held, a.total, a.locked));
+        }
+        assert!(actual[2].locked);
+        assert_eq!(actual[2].lock_reason, Some(LockReason::Chargeback(6)));
+        assert_eq!(actual[2].total, dec!(20));
+
+        let bad = dir.path().join("bad.csv");
+        write(&bad, "client,available,held,total,locked\n1,10,0,11,false\n").unwrap();
+        let options = ProcessingOptions { initial_accounts: Some(bad), ..Default::default() };
+        assert!(matches!(create_engine(&options), Err(PaymentEngineError::InvalidAccountRow { row: 1, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_lone_dispute_creates_no_account_under_strict_preset() {
+        let dir = tempdir().unwrap();
+        let (input, output) = (dir.path().join("input.csv"), dir.path().join("out.csv"));
+        write(&input, "type,client,tx,amount\ndispute,99,1,\n").unwrap();
+        let header = "client,available,held,total,locked\n";
+
+        for (builder, expected) in [
+            (ProcessingOptions::builder(), format!("{}99,0,0,0,false\n", header)),
+            (ProcessingOptions::preset(Preset::StrictSpec), header.to_string()),
+        ] {
+            let options = builder.output_mode(OutputMode::File(output.clone())).build().unwrap();
+            let report = process_transactions_with_options(&input, options).await.unwrap();
+            assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+            assert_eq!(report.transactions_rejected, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_emit_untouched_accounts() {
+        let dir = tempdir().unwrap();
+        let (initial, input, output) = (dir.path().join("initial.csv"), dir.path().join("input.csv"), dir.path().join("out.csv"));
+        write(&initial, "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n2,7.5,0.0,7.5,false\n").unwrap();
+        // Client 2's only transaction is rejected, which leaves it untouched
+        write(&input, "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,2,2,100.0\ndeposit,3,3,2.5\n").unwrap();
+        let header = "client,available,held,total,locked\n";
+        let (touched_row, untouched_row, new_row) = ("1,15.0,0,15.0,false\n", "2,7.5,0,7.5,false\n", "3,2.5,0,2.5,false\n");
+
+        for parallel_batches in [false, true] {
+            for (emit, expected) in [(true, vec![touched_row, untouched_row, new_row]), (false, vec![touched_row, new_row])] {
+                let options = ProcessingOptions::builder()
+                    .initial_accounts(initial.clone())
+                    .output_mode(OutputMode::File(output.clone()))
+                    .emit_untouched_accounts(emit)
+                    .parallel_batches(parallel_batches)
+                    .build()
+                    .unwrap();
+                process_transactions_with_options(&input, options).await.unwrap();
+
+                let written = std::fs::read_to_string(&output).unwrap();
+                let mut rows: Vec<&str> = written.split_inclusive('\n').collect();
+                rows[1..].sort();
+                assert_eq!(rows, [&[header][..], &expected].concat(), "emit {} parallel {}", emit, parallel_batches);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarize_known_composition() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("summary.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.5\n\
+             deposit,2,2,0.0001\n\
+             withdrawal,1,3,200.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             deposit,3,4,1000\n\
+             resolve,2,2,\n\
+             amend,3,4,900\n\
+             bogus,line\n\
+             deposit,4,5,abc\n",
+        )
+        .unwrap();
+
+        for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+            let options = ProcessingOptions { input_mode, ..Default::default() };
+            let summary = summarize_transactions(&file_path, &options).await.unwrap();
+            assert_eq!(
+                summary,
+                FileSummary {
+                    lines: 11,
+                    deposits: 3,
+                    withdrawals: 1,
+                    disputes: 1,
+                    resolves: 1,
+                    chargebacks: 1,
+                    amendments: 1,
+                    distinct_clients: 3,
+                    distinct_transactions: 4,
+                    min_amount: Some(dec!(0.0001)),
+                    max_amount: Some(dec!(1000)),
+                    parse_errors: 2,
+                    read_errors: 0,
+                }
+            );
+        }
+
+        let empty = dir.path().join("empty.csv");
+        write(&empty, "").unwrap();
+        let summary = summarize_transactions(&empty, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!(summary, FileSummary::default());
+        assert!(summary.to_string().contains("min amount             -"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_in_ignored_column() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("mojibake.csv");
+        let mut content = b"type,client,tx,amount,note\n".to_vec();
+        content.extend_from_slice(b"deposit,1,1,10.0,\x93refund\x94\n");
+        content.extend_from_slice(b"deposit,1,2,5.0,plain\n");
+        content.extend_from_slice(b"withdrawal,1,3,2.5,caf\xe9\n");
+        write(&file_path, content).unwrap();
+
+        // Strict mode rejects both lines with invalid bytes; the others apply them
+        let expected = [
+            (InputEncoding::Utf8Strict, dec!(5.0), 2),
+            (InputEncoding::Utf8Lossy, dec!(12.5), 0),
+            (InputEncoding::Windows1252, dec!(12.5), 0),
+        ];
+        for (encoding, available, read_errors) in expected {
+            for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+                let options = ProcessingOptions { encoding, input_mode, ..Default::default() };
+                let mut engine = PaymentEngine::new();
+                let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+                assert_eq!(report.read_errors, read_errors, "{:?}", encoding);
+                assert_eq!(engine.get_accounts()[0].available, available, "{:?}", encoding);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_input_file() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing.csv");
+        let mut engine = PaymentEngine::new();
+
+        let err = process_transactions_stream(&missing, &mut engine, &ProcessingOptions::default()).await.unwrap_err();
+        assert!(matches!(err, PaymentEngineError::OpenFile { path, .. } if path == missing));
+        let err = summarize_transactions(&missing, &ProcessingOptions::default()).await.unwrap_err();
+        assert!(matches!(err, PaymentEngineError::OpenFile { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_resubmitted_file_is_skipped_as_duplicates() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        let config = crate::generator::GeneratorConfig {
+            transactions: 2_000,
+            clients: 20,
+            dispute_probability: 0.0,
+            seed: 5,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        crate::generator::generate_transactions(&config, &mut csv).unwrap();
+        write(&file_path, csv).unwrap();
+
+        let seen_path = dir.path().join("seen.bin");
+        let options = ProcessingOptions { seen_tx_ids_path: Some(seen_path.clone()), ..Default::default() };
+        let mut first = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut first, &options).await.unwrap();
+        assert_eq!((report.transactions_processed, report.duplicates), (2_000, 0));
+        assert_eq!(SeenTransactions::load(&seen_path).unwrap().len(), 2_000);
+        let closing = dir.path().join("closing.csv");
+        write_accounts(&first, &options, std::fs::File::create(&closing).unwrap()).unwrap();
+
+        // The same file again, continuing from the first run's balances
+        let options = ProcessingOptions { initial_accounts: Some(closing.clone()), ..options };
+        let mut second = create_engine(&options).unwrap();
+        let report = process_transactions_stream(&file_path, &mut second, &options).await.unwrap();
+        assert_eq!((report.lines_read, report.duplicates, report.transactions_processed), (2_000, 2_000, 0));
+        assert_eq!(report.transactions_rejected, 0);
+
+        let balances = |engine: &PaymentEngine| {
+            let mut accounts: Vec<_> =
+                engine.get_accounts().into_iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect();
+            accounts.sort_by_key(|a| a.0);
+            accounts
+        };
+        assert_eq!(balances(&second), balances(&first));
+        assert_eq!(SeenTransactions::load(&seen_path).unwrap().len(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn test_apply_batch_reuses_the_batch_vector() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("batch.wal");
+        let deposit = |tx, amount| Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount,
+            currency: None,
+            timestamp: None,
+        };
+
+        for mode in ["sequential", "parallel", "wal"] {
+            let mut engine = PaymentEngine::new();
+            let mut wal = (mode == "wal").then(|| WalWriter::open(&wal_path).unwrap());
+            let mut buffer = Vec::with_capacity(4);
+            let pointer = buffer.as_ptr();
+            for round in 0..3 {
+                buffer.extend([deposit(round * 3 + 1, Some(dec!(1))), deposit(round * 3 + 2, None), deposit(round * 3 + 3, Some(dec!(2)))]);
+                buffer = apply_batch(&mut engine, buffer, wal.as_mut(), mode == "parallel").await.unwrap();
+                assert!(buffer.is_empty());
+                assert_eq!((buffer.as_ptr(), buffer.capacity()), (pointer, 4));
+            }
+            assert_eq!(engine.get_account(1).unwrap().total, dec!(9));
+            assert_eq!(engine.rejected_transactions(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_records_stops_exactly() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("max_records.csv");
+        let mut csv_content = String::from("type,client,tx,amount\n");
+        for i in 1..=20 {
+            csv_content.push_str(&format!("deposit,1,{},1.0\n", i));
+        }
+        write(&file_path, csv_content).unwrap();
+
+        let options = ProcessingOptions { batch_size_mode: BatchSizeMode::Fixed(3), max_records: Some(10), ..Default::default() };
         let mut engine = PaymentEngine::new();
-        process_transactions_stream(&file_path, &mut engine, DEFAULT_BATCH_SIZE).await.unwrap();
-        
-        // Check the results
-        let accounts = engine.get_accounts();
-        assert_eq!(accounts.len(), 2);
-        
-        // Find each client's account
-        let client1 = accounts.iter().find(|a| a.client == 1).unwrap();
-        let client2 = accounts.iter().find(|a| a.client == 2).unwrap();
-        
-        // Client 1 - deposit was disputed then resolved, so back to original
-        assert_eq!(client1.available, dec!(100.0));
-        assert_eq!(client1.held, dec!(0.0));
-        assert_eq!(client1.total, dec!(100.0));
-        assert!(!client1.locked);
-        
-        // Client 2 - deposit was disputed then chargebacked, so account is locked
-        assert_eq!(client2.available, dec!(0.0));
-        assert_eq!(client2.held, dec!(0.0));
-        assert_eq!(client2.total, dec!(0.0));
-        assert!(client2.locked);
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        assert_eq!(report.lines_read, 10);
+        assert_eq!(report.transactions_processed, 10);
+        assert!(report.max_records_reached);
+        assert_eq!(engine.get_accounts()[0].available, dec!(10.0));
+
+        // A limit equal to the input size is not reported as reached
+        let options = ProcessingOptions { max_records: Some(20), ..Default::default() };
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!(report.transactions_processed, 20);
+        assert!(!report.max_records_reached);
     }
 
-    // Test with different batch sizes
     #[tokio::test]
-    async fn test_batch_processing() {
-        // Create a temporary directory
+    async fn test_max_tps_paces_run() {
         let dir = tempdir().unwrap();
-        let file_path = dir.path().join("test_batch.csv");
-        
-        // Create a test CSV file with multiple transactions
+        let file_path = dir.path().join("max_tps.csv");
         let mut csv_content = String::from("type,client,tx,amount\n");
-        
-        // Add 100 deposit transactions
-        for i in 1..=100 {
-            csv_content.push_str(&format!("deposit,1,{},{}.0\n", i, i));
+        for i in 1..=400 {
+            csv_content.push_str(&format!("deposit,{},{},1.0\n", i % 10, i));
         }
-        
         write(&file_path, csv_content).unwrap();
-        
-        // Process with small batch size (10)
-        let small_batch_size = 10;
-        let mut engine1 = PaymentEngine::new();
-        process_transactions_stream(&file_path, &mut engine1, small_batch_size).await.unwrap();
-        
-        // Process with large batch size (50)
-        let large_batch_size = 50;
-        let mut engine2 = PaymentEngine::new();
-        process_transactions_stream(&file_path, &mut engine2, large_batch_size).await.unwrap();
-        
-        // Results should be the same regardless of batch size
-        let accounts1 = engine1.get_accounts();
-        let accounts2 = engine2.get_accounts();
-        
-        assert_eq!(accounts1.len(), 1);
-        assert_eq!(accounts2.len(), 1);
-        
-        let client1 = accounts1.iter().find(|a| a.client == 1).unwrap();
-        let client2 = accounts2.iter().find(|a| a.client == 1).unwrap();
-        
-        // Sum of 1..=100 is 5050
-        assert_eq!(client1.available, dec!(5050.0));
-        assert_eq!(client1.total, dec!(5050.0));
-        assert_eq!(client1.available, client2.available);
-        assert_eq!(client1.total, client2.total);
+
+        // 400 transactions at 1000/s take at least 0.4s, with either loop
+        for pipeline_depth in [0, 2] {
+            let options = ProcessingOptions::builder().batch_size(50).pipeline_depth(pipeline_depth).max_tps(1000).build().unwrap();
+            let mut engine = PaymentEngine::new();
+            let started = Instant::now();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            let elapsed = started.elapsed();
+
+            assert_eq!(report.transactions_processed, 400);
+            assert!(elapsed >= Duration::from_millis(395), "took {:?}", elapsed);
+            assert!(report.throttle_time >= Duration::from_millis(300), "throttled {:?}", report.throttle_time);
+            assert!(report.transactions_per_sec() <= 1000.0 * 1.02, "{} transactions/s", report.transactions_per_sec());
+        }
+
+        // Unset means no pacing
+        let mut engine = PaymentEngine::new();
+        let started = Instant::now();
+        let report = process_transactions_stream(&file_path, &mut engine, &ProcessingOptions::default()).await.unwrap();
+        assert_eq!(report.throttle_time, Duration::ZERO);
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        assert!(ProcessingOptions::builder().max_tps(0).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_skip_and_take_window() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("window.csv");
+        let mut csv_content = String::from("type,client,tx,amount\n");
+        for i in 1..=20 {
+            csv_content.push_str(&format!("deposit,1,{},{}\n", i, i));
+        }
+        write(&file_path, csv_content).unwrap();
+
+        // Records 6 to 9 are deposits of 6 to 9
+        for input_mode in [InputMode::Streaming, InputMode::Mmap] {
+            let options = ProcessingOptions { input_mode, batch_size_mode: BatchSizeMode::Fixed(3), skip_records: 5, max_records: Some(4), ..Default::default() };
+            let mut engine = PaymentEngine::new();
+            let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+            assert_eq!((report.records_skipped, report.lines_read, report.transactions_processed), (5, 4, 4));
+            assert!(report.max_records_reached);
+            assert_eq!(engine.get_accounts()[0].available, dec!(30));
+        }
+
+        // The builder spells the window as skip and take
+        let options = ProcessingOptions::builder().skip_records(5).take_records(4).build().unwrap();
+        assert_eq!((options.skip_records, options.max_records), (5, Some(4)));
+
+        // Skipping past the end processes nothing
+        let options = ProcessingOptions { skip_records: 25, ..Default::default() };
+        let mut engine = PaymentEngine::new();
+        let report = process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        assert_eq!((report.records_skipped, report.lines_read), (20, 0));
+        assert!(engine.get_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_skipped_records_keep_absolute_line_numbers() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("offset.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ntype,client,tx,amount\ndeposit,1,2,1.0\ndeposit,1,3,1.0\nbogus\n",
+        )
+        .unwrap();
+
+        let options = ProcessingOptions { skip_records: 2, tolerate_repeated_headers: true, ..Default::default() };
+        let mut reader = BatchReader {
+            source: RecordSource::open_lines(&file_path, &options).await.unwrap(),
+            batch_size: Arc::new(AtomicUsize::new(1)),
+            skip_records: options.skip_records,
+            max_records: None,
+            stop_after: None,
+            client_filter: None,
+            client_sample: None,
+            locked: None,
+            transaction_hook: None,
+            amount_precision: AmountPrecision::default(),
+            recovered: RecoveredTransactions::default(),
+            seen: None,
+            sample: PhaseSample::default(),
+            clock: Arc::new(SystemClock),
+            report: ProcessingReport::default(),
+            batch_lines: 0..=0,
+        };
+        let batch = reader.next_batch(None).await.unwrap();
+        assert_eq!(batch[0].tx, 3);
+        assert_eq!(reader.batch_lines, 5..=5);
+        // The record after the header, a repeated header and two skipped records
+        let report = &reader.report;
+        assert_eq!(reader.source.line_number(report.records_skipped + report.lines_read), 5);
+
+        assert!(reader.next_batch(None).await.is_none());
+        assert_eq!(reader.report.parse_errors, 1);
+        let report = &reader.report;
+        assert_eq!(reader.source.line_number(report.records_skipped + report.lines_read), 6);
+    }
+
+    #[tokio::test]
+    async fn test_events_sent_to_channel() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("events.csv");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\nresolve,1,1,\n").unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let options = ProcessingOptions { event_sender: Some(sender), ..Default::default() };
+        let mut engine = create_engine(&options).unwrap();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+        drop(engine);
+        drop(options);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                EngineEvent::DepositApplied { client: 1, tx: 1, amount: dec!(10.0) },
+                EngineEvent::DisputeOpened { client: 1, tx: 1, amount: dec!(10.0) },
+                EngineEvent::DisputeResolved { client: 1, tx: 1, amount: dec!(10.0) },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_stream_sends_changed_accounts_per_batch() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("changes.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,4.0\ndispute,2,2,\nwithdrawal,3,4,1.0\ndeposit,1,5,1.0\n",
+        )
+        .unwrap();
+
+        for parallel in [false, true] {
+            let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+            let options = ProcessingOptions::builder()
+                .batch_size(2)
+                .parallel_batches(parallel)
+                .change_stream(ChangeStream::Channel(sender))
+                .build()
+                .unwrap();
+            let mut engine = create_engine(&options).unwrap();
+            // Loaded before the run and never touched by it
+            engine.load_accounts(vec![Account { available: dec!(7.0), total: dec!(7.0), ..Account::new(9) }]).unwrap();
+            process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+            drop(options);
+
+            let mut deltas = Vec::new();
+            while let Some(delta) = receiver.recv().await {
+                deltas.push(delta);
+            }
+            let sent: Vec<(u64, u16)> = deltas.iter().map(|delta| (delta.batch, delta.client)).collect();
+            // The rejected withdrawal still creates client 3's account
+            assert_eq!(sent, vec![(1, 1), (1, 2), (2, 1), (2, 2), (3, 1), (3, 3)]);
+
+            let mut latest = BTreeMap::new();
+            for delta in deltas {
+                latest.insert(delta.client, delta);
+            }
+            let mut accounts: Vec<AccountDelta> =
+                engine.get_accounts().iter().filter(|account| account.client != 9).map(|account| AccountDelta::new(0, account)).collect();
+            accounts.sort_by_key(|account| account.client);
+            let latest: Vec<AccountDelta> = latest.into_values().map(|delta| AccountDelta { batch: 0, ..delta }).collect();
+            assert_eq!(latest, accounts);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_stream_file_has_a_json_line_per_change() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("changes.csv");
+        let changes = dir.path().join("changes.ndjson");
+        write(&file_path, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\n").unwrap();
+
+        let options = ProcessingOptions::builder().batch_size(1).change_stream(ChangeStream::File(changes.clone())).build().unwrap();
+        let mut engine = create_engine(&options).unwrap();
+        process_transactions_stream(&file_path, &mut engine, &options).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&changes).unwrap(),
+            concat!(
+                r#"{"batch":1,"client":1,"available":"10.0","held":"0","total":"10.0","locked":false}"#,
+                "\n",
+                r#"{"batch":2,"client":1,"available":"0.0","held":"10.0","total":"10.0","locked":false}"#,
+                "\n",
+            )
+        );
     }
 }
@@ -0,0 +1,165 @@
+use crate::error::{PaymentEngineError, Result};
+use crate::input::{LineReader, LineSource};
+use crate::manifest::with_manifest;
+use crate::processor::{process_source, ProcessingOptions, ProcessingReport, RecordSource};
+use futures::TryStreamExt;
+use tokio::io::BufReader;
+use tokio_util::io::StreamReader;
+use tracing::info;
+
+/// Whether an input names a remote object (`http://`, `https://` or `s3://`)
+/// rather than a local file
+pub fn is_remote_url(input: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| input.starts_with(scheme))
+}
+
+/// Process transactions from a CSV object at an HTTP(S) or S3 URL and output
+/// account balances, like
+/// [`process_transactions_with_options`](crate::process_transactions_with_options).
+///
+/// The body is parsed as it is downloaded, so memory stays bounded by the
+/// batches rather than the object. `s3://bucket/key` is fetched over HTTPS
+/// without signing the request, so the object must be readable anonymously;
+/// private objects can be read through a presigned `https://` URL instead.
+/// The input is always streamed, whatever the input mode. A connection lost
+/// partway is reported as a read error and ends the input.
+pub async fn process_transactions_from_url(url: &str, options: ProcessingOptions) -> Result<ProcessingReport> {
+    options.validate()?;
+    info!("Processing transactions from: {} with batch size: {:?}", url, options.batch_size_mode);
+    with_manifest(url, None, &options.clone(), async {
+        let lines = open_url(url, &options).await?;
+        let source = RecordSource::from_lines(lines, url, &options).await?;
+        process_source(source, options).await
+    })
+    .await
+}
+
+/// Start downloading the object at `url` and read its body as lines
+pub(crate) async fn open_url(url: &str, options: &ProcessingOptions) -> Result<LineSource> {
+    let http_url = match url.strip_prefix("s3://") {
+        Some(location) => {
+            let endpoint = std::env::var("AWS_ENDPOINT_URL_S3").or_else(|_| std::env::var("AWS_ENDPOINT_URL")).ok();
+            let region = std::env::var("AWS_REGION").ok();
+            s3_http_url(location, endpoint.as_deref(), region.as_deref())?
+        }
+        None => url.to_string(),
+    };
+    let fetch_error = |source| PaymentEngineError::Fetch { url: url.to_string(), source };
+    let response = reqwest::get(&http_url).await.and_then(|r| r.error_for_status()).map_err(fetch_error)?;
+    info!("Streaming {} from {}", url, http_url);
+
+    let body = response.bytes_stream().map_err(std::io::Error::other);
+    let reader: std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>> = Box::pin(StreamReader::new(body));
+    Ok(LineSource::Remote(LineReader::new(BufReader::new(reader), options.max_line_length, options.encoding)))
+}
+
+/// HTTPS URL of `bucket/key`: path-style under `endpoint` if one is set (as
+/// for S3-compatible stores), otherwise the bucket's virtual-hosted AWS URL
+fn s3_http_url(location: &str, endpoint: Option<&str>, region: Option<&str>) -> Result<String> {
+    let (bucket, key) = location
+        .split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| PaymentEngineError::InvalidUrl(format!("s3://{} has no bucket or key", location)))?;
+    Ok(match (endpoint, region) {
+        (Some(endpoint), _) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        (None, Some(region)) => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key),
+        (None, None) => format!("https://{}.s3.amazonaws.com/{}", bucket, key),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{process_records, LiveRun};
+    use crate::test_util::run_fixture;
+    use crate::PaymentEngine;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve `body` with `status` to every connection, writing it in small
+    /// chunks so the client sees it arrive in pieces, and closing the
+    /// connection after `sent` bytes of it. Returns the base URL.
+    async fn serve(status: &'static str, body: Vec<u8>, sent: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    let _ = stream.read(&mut request).await;
+                    let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    for chunk in body[..sent].chunks(7) {
+                        stream.write_all(chunk).await.unwrap();
+                        stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_remote_urls() {
+        assert!(is_remote_url("s3://bucket/key.csv") && is_remote_url("https://host/tx.csv"));
+        assert!(!is_remote_url("transactions.csv") && !is_remote_url("ftp://host/tx.csv"));
+
+        assert_eq!(s3_http_url("bucket/in/tx.csv", None, None).unwrap(), "https://bucket.s3.amazonaws.com/in/tx.csv");
+        assert_eq!(
+            s3_http_url("bucket/tx.csv", None, Some("eu-west-1")).unwrap(),
+            "https://bucket.s3.eu-west-1.amazonaws.com/tx.csv"
+        );
+        assert_eq!(
+            s3_http_url("bucket/tx.csv", Some("http://localhost:9000/"), Some("eu-west-1")).unwrap(),
+            "http://localhost:9000/bucket/tx.csv"
+        );
+        assert!(matches!(s3_http_url("bucket", None, None), Err(PaymentEngineError::InvalidUrl(_))));
+        assert!(s3_http_url("bucket/", None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_input_matches_file() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+        let body = std::fs::read(&fixture).unwrap();
+        let url = format!("{}/disputes.csv", serve("200 OK", body.clone(), body.len()).await);
+
+        let options = ProcessingOptions::default();
+        let lines = open_url(&url, &options).await.unwrap();
+        let source = RecordSource::from_lines(lines, &url, &options).await.unwrap();
+        let run = LiveRun::new(PaymentEngine::new());
+        let report = process_records(source, &run, &options).await.unwrap();
+        assert_eq!(report.lines_read, 11);
+
+        let mut accounts = run.engine.into_inner().get_accounts();
+        accounts.sort_by_key(|account| account.client);
+        let expected = tokio::task::spawn_blocking(move || run_fixture(&fixture)).await.unwrap();
+        let balances = |accounts: &[crate::Account]| {
+            accounts.iter().map(|a| (a.client, a.available, a.held, a.total, a.locked)).collect::<Vec<_>>()
+        };
+        assert_eq!(balances(&accounts), balances(&expected));
+    }
+
+    #[tokio::test]
+    async fn test_http_error_status_is_reported() {
+        let url = format!("{}/missing.csv", serve("404 Not Found", b"no such object".to_vec(), 14).await);
+        let err = process_transactions_from_url(&url, ProcessingOptions::default()).await.unwrap_err();
+        assert!(matches!(&err, PaymentEngineError::Fetch { url: failed, .. } if *failed == url), "{}", err);
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_lost_connection_is_a_read_error() {
+        let body = b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n".to_vec();
+        let url = format!("{}/cut.csv", serve("200 OK", body, 40).await);
+
+        let options = ProcessingOptions::default();
+        let source = RecordSource::from_lines(open_url(&url, &options).await.unwrap(), &url, &options).await.unwrap();
+        let run = LiveRun::new(PaymentEngine::new());
+        let report = process_records(source, &run, &options).await.unwrap();
+
+        assert_eq!((report.transactions_processed, report.read_errors), (1, 1));
+        assert_eq!(run.engine.into_inner().get_account(1).unwrap().available, rust_decimal::Decimal::TEN);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::engine::PaymentEngine;
+use crate::error::Result;
+use crate::models::{Account, Transaction, TransactionState};
+use crate::processor::{process_records, LiveRun, ProcessingOptions, ProcessingReport, RecordSource, StopAfter};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::path::Path;
+use tracing::info;
+
+/// A single client's account and stored transactions, rebuilt from an input
+/// up to a cut point
+pub struct ClientReplay {
+    client: u16,
+    engine: PaymentEngine,
+    report: ProcessingReport,
+}
+
+impl ClientReplay {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// The client's account at the cut point, `None` if it had no
+    /// transactions before it
+    pub fn account(&self) -> Option<&Account> {
+        self.engine.get_account(self.client)
+    }
+
+    /// The client's stored transactions and their dispute states at the cut
+    /// point, in transaction id order
+    pub fn transactions(&self) -> Vec<(&Transaction, TransactionState)> {
+        self.engine.client_transactions(self.client)
+    }
+
+    /// What happened to the input up to the cut point. `stop_reached` is
+    /// false if the input ended before it.
+    pub fn report(&self) -> &ProcessingReport {
+        &self.report
+    }
+}
+
+/// Account row, then the stored transactions with the amount each holds
+impl fmt::Display for ClientReplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "client,available,held,total,locked")?;
+        if let Some(account) = self.account() {
+            writeln!(f, "{},{},{},{},{}", account.client, account.available, account.held, account.total, account.locked)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "tx,type,amount,state,held")?;
+        for (transaction, state) in self.transactions() {
+            let held = match state {
+                TransactionState::Disputed(amount) => amount,
+                _ => Decimal::ZERO,
+            };
+            let amount = transaction.amount.unwrap_or_default();
+            writeln!(f, "{},{},{},{},{}", transaction.tx, transaction.transaction_type, amount, state, held)?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply only `client`'s transactions from a CSV file, stopping after
+/// `stop_after` if given, to see the client's state at that point of the
+/// full run.
+///
+/// The engine rules in `options` should match the full run's. Options with
+/// side effects, such as the WAL, snapshots and previously seen transaction
+/// ids, are ignored, and every stored transaction is kept in memory.
+/// Disputes filed under another client's id, which only apply with
+/// [`DisputeClientCheck::TrustOriginal`](crate::models::DisputeClientCheck),
+/// are filtered out with that client.
+pub async fn replay_client(
+    file_path: &Path,
+    client: u16,
+    stop_after: Option<StopAfter>,
+    options: ProcessingOptions,
+) -> Result<ClientReplay> {
+    options.validate()?;
+    info!("Replaying client {} from {:?} up to {:?}", client, file_path, stop_after);
+    let options = ProcessingOptions {
+        client_filter: Some(client.into()),
+        stop_after,
+        wal_path: None,
+        seen_tx_ids_path: None,
+        snapshot_every: None,
+        max_in_memory_transactions: None,
+        event_sender: None,
+        #[cfg(feature = "http")]
+        serve_addr: None,
+        ..options
+    };
+    let run = LiveRun::new(crate::processor::create_engine(&options)?);
+    let source = RecordSource::open_lines(file_path, &options).await?;
+    let report = process_records(source, &run, &options).await?;
+    Ok(ClientReplay { client, engine: run.engine.into_inner(), report })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{generate_transactions, GeneratorConfig};
+    use crate::processor::process_transactions_stream;
+    use std::fs::{read_to_string, write};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_replay_matches_full_run_at_cut_point() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        let config = GeneratorConfig { transactions: 2_000, clients: 20, dispute_probability: 0.2, ..Default::default() };
+        let mut csv = Vec::new();
+        generate_transactions(&config, &mut csv).unwrap();
+        write(&file_path, &csv).unwrap();
+        let lines: Vec<_> = read_to_string(&file_path).unwrap().lines().map(str::to_string).collect();
+
+        for cut_line in [2, 700, 1_500, lines.len()] {
+            // The full run over the input cut after that line, for every client
+            let cut_path = dir.path().join(format!("cut_{}.csv", cut_line));
+            write(&cut_path, lines[..cut_line].join("\n")).unwrap();
+            let mut full = PaymentEngine::new();
+            process_transactions_stream(&cut_path, &mut full, &ProcessingOptions::default()).await.unwrap();
+
+            // Disputes reuse the id of the transaction they refer to, so only
+            // a deposit or withdrawal line is the first one with its id
+            let fields: Vec<_> = lines[cut_line - 1].split(',').collect();
+            let mut stops = vec![StopAfter::Line(cut_line)];
+            if matches!(fields[0], "deposit" | "withdrawal") {
+                stops.push(StopAfter::Tx(fields[2].parse().unwrap()));
+            }
+            for client in 1..=config.clients {
+                for &stop_after in &stops {
+                    let replay = replay_client(&file_path, client, Some(stop_after), ProcessingOptions::default()).await.unwrap();
+                    assert!(replay.report().stop_reached);
+                    let balances = |account: Option<&Account>| {
+                        account.map(|account| (account.available, account.held, account.total, account.locked))
+                    };
+                    assert_eq!(balances(replay.account()), balances(full.get_account(client)), "client {} at {:?}", client, stop_after);
+                    let states = |transactions: Vec<(&Transaction, TransactionState)>| {
+                        transactions.into_iter().map(|(transaction, state)| (transaction.clone(), state)).collect::<Vec<_>>()
+                    };
+                    assert_eq!(states(replay.transactions()), states(full.client_transactions(client)));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_output() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,7,1,10.0\n\
+             deposit,2,2,3.0\n\
+             deposit,7,3,5.0\n\
+             dispute,7,3,\n\
+             withdrawal,7,4,2.5\n\
+             resolve,7,3,\n",
+        )
+        .unwrap();
+
+        let replay = replay_client(&file_path, 7, Some(StopAfter::Tx(4)), ProcessingOptions::default()).await.unwrap();
+        assert_eq!(replay.client(), 7);
+        assert!(replay.report().stop_reached);
+        assert_eq!(replay.report().records_filtered, 1);
+        assert_eq!(
+            replay.to_string(),
+            "client,available,held,total,locked\n\
+             7,7.5,5.0,12.5,false\n\
+             \n\
+             tx,type,amount,state,held\n\
+             1,deposit,10.0,processed,0\n\
+             3,deposit,5.0,disputed,5.0\n\
+             4,withdrawal,2.5,processed,0\n"
+        );
+
+        // A cut point past the end replays the whole input
+        let replay = replay_client(&file_path, 7, Some(StopAfter::Tx(99)), ProcessingOptions::default()).await.unwrap();
+        assert!(!replay.report().stop_reached);
+        assert!(replay.to_string().contains("3,deposit,5.0,resolved,0\n"));
+
+        let replay = replay_client(&file_path, 9, None, ProcessingOptions::default()).await.unwrap();
+        assert!(replay.account().is_none());
+        assert_eq!(replay.to_string(), "client,available,held,total,locked\n\ntx,type,amount,state,held\n");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::error::{PaymentEngineError, Result};
+use crate::models::{Transaction, TransactionType};
+#[cfg(not(feature = "wide-tx-ids"))]
+use roaring::RoaringBitmap as IdBitmap;
+#[cfg(feature = "wide-tx-ids")]
+use roaring::RoaringTreemap as IdBitmap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Ids of the deposits and withdrawals applied by previous runs, persisted as
+/// a roaring bitmap so re-submitting the same input doesn't apply them twice.
+/// With `wide-tx-ids` the file holds a roaring treemap of 64-bit ids instead,
+/// which the 32-bit build can't read.
+///
+/// Ids seen in this run are kept apart from the loaded ones until [`save`],
+/// so a transaction id repeated within one input is not treated as a duplicate.
+///
+/// [`save`]: SeenTransactions::save
+#[derive(Debug)]
+pub struct SeenTransactions {
+    path: PathBuf,
+    previous: IdBitmap,
+    current: IdBitmap,
+}
+
+impl SeenTransactions {
+    /// Load the set stored at `path`, or start an empty one if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let previous = match File::open(path) {
+            Ok(file) => IdBitmap::deserialize_from(BufReader::new(file))
+                .map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => IdBitmap::new(),
+            Err(source) => return Err(PaymentEngineError::OpenFile { path: path.to_path_buf(), source }),
+        };
+        info!("Loaded {} seen transaction ids from {:?}", previous.len(), path);
+        Ok(Self { path: path.to_path_buf(), previous, current: IdBitmap::new() })
+    }
+
+    /// Whether a previous run already applied this transaction. Only deposits
+    /// and withdrawals are tracked; other transactions are never duplicates.
+    pub fn is_duplicate(&self, transaction: &Transaction) -> bool {
+        is_tracked(transaction) && self.previous.contains(transaction.tx)
+    }
+
+    /// Remember a transaction handed to the engine in this run
+    pub fn insert(&mut self, transaction: &Transaction) {
+        if is_tracked(transaction) {
+            self.current.insert(transaction.tx);
+        }
+    }
+
+    /// Number of ids from previous runs and this one
+    pub fn len(&self) -> u64 {
+        (&self.previous | &self.current).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty() && self.current.is_empty()
+    }
+
+    /// Write the ids of previous runs and this one back to the file. The new
+    /// set goes to a temporary file that replaces the old one, so an
+    /// interrupted save leaves the previous set intact.
+    pub fn save(&self) -> Result<()> {
+        let ids = &self.previous | &self.current;
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+
+        let file = File::create(&tmp_path).map_err(|source| PaymentEngineError::OpenFile { path: tmp_path.clone(), source })?;
+        let mut writer = BufWriter::new(file);
+        ids.serialize_into(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        info!("Saved {} seen transaction ids to {:?}", ids.len(), self.path);
+        Ok(())
+    }
+}
+
+fn is_tracked(transaction: &Transaction) -> bool {
+    matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TxId;
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+
+    fn transaction(transaction_type: TransactionType, tx: TxId) -> Transaction {
+        let amount = matches!(transaction_type, TransactionType::Deposit | TransactionType::Withdrawal).then_some(dec!(1));
+        Transaction { transaction_type, client: 1, tx, amount, currency: None, timestamp: None }
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("seen.bin");
+
+        let mut seen = SeenTransactions::load(&path).unwrap();
+        assert!(seen.is_empty());
+        seen.insert(&transaction(TransactionType::Deposit, 1));
+        seen.insert(&transaction(TransactionType::Withdrawal, TxId::MAX));
+        seen.insert(&transaction(TransactionType::Dispute, 7));
+        // Not a duplicate within the run that saw it
+        assert!(!seen.is_duplicate(&transaction(TransactionType::Deposit, 1)));
+        seen.save().unwrap();
+
+        let mut seen = SeenTransactions::load(&path).unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.is_duplicate(&transaction(TransactionType::Deposit, 1)));
+        assert!(seen.is_duplicate(&transaction(TransactionType::Withdrawal, TxId::MAX)));
+        assert!(!seen.is_duplicate(&transaction(TransactionType::Dispute, 1)));
+        assert!(!seen.is_duplicate(&transaction(TransactionType::Deposit, 7)));
+
+        seen.insert(&transaction(TransactionType::Deposit, 2));
+        seen.save().unwrap();
+        assert_eq!(SeenTransactions::load(&path).unwrap().len(), 3);
+        assert!(!dir.path().join("seen.bin.tmp").exists());
+    }
+
+    #[test]
+    fn test_corrupt_file_is_an_error() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("seen.bin");
+        std::fs::write(&path, b"not a bitmap").unwrap();
+        assert!(matches!(SeenTransactions::load(&path), Err(PaymentEngineError::OpenFile { .. })));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::engine::PaymentEngine;
+use crate::models::{Account, AccountStats};
+use crate::processor::{LiveRun, ProcessingReport};
+use crate::error::{PaymentEngineError, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+/// Bind the query server and serve it on a background task.
+///
+/// Returns the bound address, which differs from `addr` when port 0 is used.
+/// Readers share the engine's lock with the processor, so responses reflect
+/// the last completed batch.
+pub(crate) async fn spawn_server(addr: SocketAddr, run: Arc<LiveRun>) -> Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await.map_err(|source| PaymentEngineError::Bind { addr, source })?;
+    let local_addr = listener.local_addr()?;
+
+    let app = Router::new()
+        .route("/accounts", get(list_accounts))
+        .route("/accounts/{client}", get(get_account))
+        .route("/stats", get(stats))
+        .with_state(run);
+
+    let handle = tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Query server failed: {}", e);
+        }
+    });
+
+    Ok((local_addr, handle))
+}
+
+/// An account along with its dispute activity
+#[derive(Serialize)]
+struct AccountView {
+    #[serde(flatten)]
+    account: Account,
+    #[serde(flatten)]
+    stats: AccountStats,
+}
+
+/// Round monetary values to 4 decimal places, as in the CSV output
+fn view(engine: &PaymentEngine, account: &Account) -> AccountView {
+    AccountView {
+        account: Account {
+            available: account.available.round_dp(4),
+            held: account.held.round_dp(4),
+            total: account.total.round_dp(4),
+            chargeback_total: account.chargeback_total.round_dp(4),
+            deposited_total: account.deposited_total.round_dp(4),
+            withdrawn_total: account.withdrawn_total.round_dp(4),
+            ..account.clone()
+        },
+        stats: engine.get_account_stats(account.client).cloned().unwrap_or_default(),
+    }
+}
+
+async fn list_accounts(State(run): State<Arc<LiveRun>>) -> Json<Vec<AccountView>> {
+    let engine = run.engine.read().await;
+    let mut accounts: Vec<AccountView> = engine.get_accounts().iter().map(|a| view(&engine, a)).collect();
+    accounts.sort_by_key(|a| a.account.client);
+    Json(accounts)
+}
+
+async fn get_account(
+    State(run): State<Arc<LiveRun>>,
+    Path(client): Path<u16>,
+) -> Result<Json<AccountView>, StatusCode> {
+    let engine = run.engine.read().await;
+    engine.get_account(client).map(|a| Json(view(&engine, a))).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn stats(State(run): State<Arc<LiveRun>>) -> Result<Json<ProcessingReport>, StatusCode> {
+    let report = run.report.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(report.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{process_live_run, ProcessingOptions};
+    use serde_json::{json, Value};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_query_endpoints_match_engine_state() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("serve.csv");
+        write(
+            &file_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.12345\n\
+             withdrawal,1,3,25.5\n\
+             dispute,2,2,\n\
+             chargeback,2,2,\n\
+             bogus\n",
+        )
+        .unwrap();
+
+        let run = Arc::new(LiveRun::new(PaymentEngine::new()));
+        let (addr, _) = spawn_server(([127, 0, 0, 1], 0).into(), run.clone()).await.unwrap();
+        let report = process_live_run(&file_path, &run, &ProcessingOptions::default()).await.unwrap();
+
+        let base = format!("http://{}", addr);
+        let get = |path: &str| {
+            let url = format!("{}{}", base, path);
+            async move { reqwest::get(url).await.unwrap() }
+        };
+
+        let accounts: Value = get("/accounts").await.json().await.unwrap();
+        assert_eq!(
+            accounts,
+            json!([
+                {
+                    "client": 1, "available": "74.5", "held": "0", "total": "74.5", "locked": false,
+                    "deposited_total": "100.0", "withdrawn_total": "25.5",
+                    "disputes_opened": 0, "disputes_resolved": 0, "disputes_charged_back": 0, "disputes_expired": 0,
+                    "held_transactions": [],
+                },
+                {
+                    "client": 2, "available": "0.0000", "held": "0.0000", "total": "0.0000",
+                    "locked": true, "lock_reason": "chargeback:2", "chargeback_total": "50.1234",
+                    "deposited_total": "50.1234",
+                    "disputes_opened": 1, "disputes_resolved": 0, "disputes_charged_back": 1, "disputes_expired": 0,
+                    "held_transactions": [],
+                },
+            ])
+        );
+
+        let account: Value = get("/accounts/1").await.json().await.unwrap();
+        assert_eq!(account, accounts[0]);
+        assert_eq!(get("/accounts/9").await.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("/accounts/abc").await.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let stats: Value = get("/stats").await.json().await.unwrap();
+        assert_eq!(stats, serde_json::to_value(&report).unwrap());
+        assert_eq!(stats["parse_errors"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure() {
+        let run = Arc::new(LiveRun::new(PaymentEngine::new()));
+        let (addr, _) = spawn_server(([127, 0, 0, 1], 0).into(), run.clone()).await.unwrap();
+
+        let result = spawn_server(addr, run).await;
+        assert!(matches!(result, Err(PaymentEngineError::Bind { addr: a, .. }) if a == addr));
+    }
+}
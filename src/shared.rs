@@ -0,0 +1,201 @@
+use crate::engine::PaymentEngine;
+use crate::models::{Account, Transaction, TransactionOutcome};
+use crate::error::{PaymentEngineError, Result};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+// Submissions that can be queued before callers wait for the engine task
+const COMMAND_BUFFER: usize = 1024;
+
+enum Command {
+    Submit(Transaction, oneshot::Sender<Result<TransactionOutcome>>),
+    Snapshot(oneshot::Sender<Vec<Account>>),
+    Inspect(Box<dyn FnOnce(&PaymentEngine) + Send>),
+}
+
+/// Handle to a [`PaymentEngine`] owned by a background task, for feeding one
+/// engine from several concurrent tasks.
+///
+/// Handles are cheap to clone. Commands are applied one at a time in the order
+/// they reach the engine task, so transactions for a client stay ordered as
+/// long as each client's transactions are submitted by one task at a time;
+/// submissions racing for the same client are applied in arrival order, each
+/// against the state left by the previous one. The task stops, and
+/// [`SharedEngineTask::shutdown`] returns the engine, once every handle has
+/// been dropped.
+#[derive(Clone)]
+pub struct SharedPaymentEngine {
+    sender: mpsc::Sender<Command>,
+}
+
+/// Owns the engine task; returned alongside the first handle
+pub struct SharedEngineTask {
+    handle: JoinHandle<PaymentEngine>,
+}
+
+impl SharedPaymentEngine {
+    /// Move an engine onto a background task. Must be called within a tokio runtime.
+    pub fn spawn(mut engine: PaymentEngine) -> (Self, SharedEngineTask) {
+        let (sender, mut receiver) = mpsc::channel(COMMAND_BUFFER);
+
+        let handle = tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    Command::Submit(transaction, reply) => {
+                        let _ = reply.send(engine.process_transaction_with_outcome(transaction).await);
+                    }
+                    Command::Snapshot(reply) => {
+                        let _ = reply.send(engine.get_accounts());
+                    }
+                    Command::Inspect(inspect) => inspect(&engine),
+                }
+            }
+            engine
+        });
+
+        (Self { sender }, SharedEngineTask { handle })
+    }
+
+    /// Apply a transaction and wait for its outcome
+    pub async fn submit(&self, transaction: Transaction) -> Result<TransactionOutcome> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Submit(transaction, reply)).await?;
+        response.await.map_err(|_| stopped())?
+    }
+
+    /// Copy of all accounts, consistent with every transaction submitted before the call
+    pub async fn snapshot_accounts(&self) -> Result<Vec<Account>> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Snapshot(reply)).await?;
+        response.await.map_err(|_| stopped())
+    }
+
+    /// Run a function on the engine between two submissions and return its
+    /// result, e.g. to read counts or write the accounts out. The engine
+    /// applies nothing else while it runs.
+    pub async fn inspect<R: Send + 'static>(&self, inspect: impl FnOnce(&PaymentEngine) -> R + Send + 'static) -> Result<R> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Inspect(Box::new(move |engine| {
+            let _ = reply.send(inspect(engine));
+        })))
+        .await?;
+        response.await.map_err(|_| stopped())
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.sender.send(command).await.map_err(|_| stopped())
+    }
+}
+
+impl SharedEngineTask {
+    /// Wait for every handle to be dropped and return the engine
+    pub async fn shutdown(self) -> Result<PaymentEngine> {
+        Ok(self.handle.await?)
+    }
+}
+
+fn stopped() -> PaymentEngineError {
+    PaymentEngineError::EngineStopped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TxId;
+    use rust_decimal_macros::dec;
+
+    // Deposits, withdrawals and a dispute cycle for one client
+    fn client_transactions(client: u16) -> Vec<Transaction> {
+        let base = TxId::from(client) * 1000;
+        let mut transactions = Vec::new();
+        for i in 0..50 {
+            transactions.push(Transaction::deposit(client, base + i * 2, dec!(10)));
+            transactions.push(Transaction::withdrawal(client, base + i * 2 + 1, dec!(3.5)));
+        }
+        transactions.push(Transaction::dispute(client, base));
+        if client.is_multiple_of(2) {
+            transactions.push(Transaction::chargeback(client, base));
+        } else {
+            transactions.push(Transaction::resolve(client, base + 2));
+        }
+        transactions
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_feeders_match_sequential() {
+        let (shared, task) = SharedPaymentEngine::spawn(PaymentEngine::new());
+
+        let feeders: Vec<_> = (1..=8u16)
+            .map(|client| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    for transaction in client_transactions(client) {
+                        shared.submit(transaction).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for feeder in feeders {
+            feeder.await.unwrap();
+        }
+
+        let mut sequential = PaymentEngine::new();
+        for client in 1..=8u16 {
+            for transaction in client_transactions(client) {
+                sequential.process_transaction(transaction).await.unwrap();
+            }
+        }
+
+        let mut expected = sequential.get_accounts();
+        let mut actual = shared.snapshot_accounts().await.unwrap();
+        expected.sort_by_key(|a| a.client);
+        actual.sort_by_key(|a| a.client);
+        assert_eq!(actual.len(), 8);
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!((e.client, e.available, e.held, e.total, e.locked), (a.client, a.available, a.held, a.total, a.locked));
+        }
+        assert!(actual.iter().any(|a| a.locked));
+
+        drop(shared);
+        let engine = task.shutdown().await.unwrap();
+        assert_eq!(engine.get_accounts().len(), 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_racing_withdrawals_on_same_client() {
+        let (shared, _task) = SharedPaymentEngine::spawn(PaymentEngine::new());
+        let deposit = Transaction::deposit(1, 1, dec!(100));
+        assert_eq!(shared.submit(deposit).await.unwrap(), TransactionOutcome::Applied);
+
+        // Only one of the two withdrawals can be covered by the balance
+        let racers: Vec<_> = [2, 3]
+            .into_iter()
+            .map(|tx| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    shared.submit(Transaction::withdrawal(1, tx, dec!(60))).await.unwrap()
+                })
+            })
+            .collect();
+        let mut outcomes = Vec::new();
+        for racer in racers {
+            outcomes.push(racer.await.unwrap());
+        }
+
+        assert_eq!(outcomes.iter().filter(|o| **o == TransactionOutcome::Applied).count(), 1);
+        assert!(outcomes.contains(&TransactionOutcome::Rejected("insufficient_funds")));
+        let accounts = shared.snapshot_accounts().await.unwrap();
+        assert_eq!(accounts[0].available, dec!(40));
+    }
+
+    #[tokio::test]
+    async fn test_submit_after_engine_task_stopped() {
+        let (shared, task) = SharedPaymentEngine::spawn(PaymentEngine::new());
+        task.handle.abort();
+        assert!(matches!(task.shutdown().await, Err(PaymentEngineError::TaskFailed(_))));
+
+        let deposit = Transaction::deposit(1, 1, dec!(1));
+        assert!(matches!(shared.submit(deposit).await, Err(PaymentEngineError::EngineStopped)));
+        assert!(matches!(shared.snapshot_accounts().await, Err(PaymentEngineError::EngineStopped)));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::clock::Clock;
+use crate::error::{PaymentEngineError, Result};
+use crate::processor::{write_all_accounts, LiveRun, ProcessingOptions};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Placeholder in a snapshot path template replaced by the snapshot's number
+pub const SEQ_PLACEHOLDER: &str = "{seq}";
+
+/// How often to write a snapshot of the account balances during a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotEvery {
+    /// After each batch that brings the records read since the last snapshot
+    /// to at least this many
+    Records(u64),
+    /// After each batch that ends at least this long after the last snapshot
+    Duration(Duration),
+}
+
+/// Parses a record count such as `100000`, or a duration in seconds, minutes
+/// or hours such as `30s`, `5m` or `1h`
+impl FromStr for SnapshotEvery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid snapshot interval: {}", s);
+        let (number, unit_secs) = match s.char_indices().last() {
+            Some((i, 's')) => (&s[..i], 1),
+            Some((i, 'm')) => (&s[..i], 60),
+            Some((i, 'h')) => (&s[..i], 3600),
+            _ => return s.parse().map(Self::Records).map_err(|_| invalid()),
+        };
+        let count: u64 = number.parse().map_err(|_| invalid())?;
+        count.checked_mul(unit_secs).map(|secs| Self::Duration(Duration::from_secs(secs))).ok_or_else(invalid)
+    }
+}
+
+/// Path of snapshot number `seq`: the template with `{seq}` replaced by it.
+/// A template without `{seq}` names a single file each snapshot replaces.
+pub fn snapshot_path(template: &str, seq: u64) -> PathBuf {
+    PathBuf::from(template.replace(SEQ_PLACEHOLDER, &seq.to_string()))
+}
+
+/// Writes numbered snapshots of a run's accounts between batches.
+///
+/// The rows are formatted in memory under the engine's read lock, then
+/// written by a blocking task while processing goes on. At most one snapshot
+/// is being written at a time. A snapshot that can't be written is logged and
+/// doesn't stop the run.
+pub(crate) struct SnapshotWriter {
+    every: SnapshotEvery,
+    template: String,
+    seq: u64,
+    last_records: usize,
+    last_time: Instant,
+    clock: Arc<dyn Clock>,
+    pending: Option<JoinHandle<Result<PathBuf>>>,
+}
+
+impl SnapshotWriter {
+    pub(crate) fn new(every: SnapshotEvery, template: &str, clock: Arc<dyn Clock>) -> Self {
+        let last_time = clock.monotonic();
+        Self { every, template: template.to_string(), seq: 0, last_records: 0, last_time, clock, pending: None }
+    }
+
+    fn is_due(&self, records: usize) -> bool {
+        match self.every {
+            SnapshotEvery::Records(count) => (records - self.last_records) as u64 >= count,
+            SnapshotEvery::Duration(interval) => self.clock.elapsed(self.last_time) >= interval,
+        }
+    }
+
+    /// Start writing a snapshot if one is due, `records` having been read so far
+    pub(crate) async fn after_batch(&mut self, run: &LiveRun, records: usize, options: &ProcessingOptions) {
+        if !self.is_due(records) {
+            return;
+        }
+        self.last_records = records;
+        self.last_time = self.clock.monotonic();
+
+        let mut contents = Vec::new();
+        if let Err(e) = write_all_accounts(&*run.engine.read().await, options, &mut contents) {
+            error!("Failed to format account snapshot: {}", e);
+            return;
+        }
+        self.wait_pending().await;
+        self.seq += 1;
+        let path = snapshot_path(&self.template, self.seq);
+        self.pending = Some(tokio::task::spawn_blocking(move || write_atomically(&path, &contents).map(|()| path)));
+    }
+
+    /// Wait for the snapshot being written, if any
+    pub(crate) async fn finish(mut self) {
+        self.wait_pending().await;
+    }
+
+    async fn wait_pending(&mut self) {
+        let Some(pending) = self.pending.take() else { return };
+        match pending.await {
+            Ok(Ok(path)) => info!("Wrote account snapshot {:?}", path),
+            Ok(Err(e)) => error!("Failed to write account snapshot: {}", e),
+            Err(e) => error!("Account snapshot writer failed: {}", e),
+        }
+    }
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over
+/// `path`, so readers see either the previous file or the complete new one
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp_path).map_err(|source| PaymentEngineError::OpenFile { path: tmp_path.clone(), source })?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::diff::read_accounts_from_path;
+    use crate::engine::PaymentEngine;
+    use crate::processor::{process_transactions_stream, BatchSizeMode, LiveRun};
+    use rust_decimal::Decimal;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!("100000".parse(), Ok(SnapshotEvery::Records(100_000)));
+        assert_eq!("30s".parse(), Ok(SnapshotEvery::Duration(Duration::from_secs(30))));
+        assert_eq!("5m".parse(), Ok(SnapshotEvery::Duration(Duration::from_secs(300))));
+        assert_eq!("2h".parse(), Ok(SnapshotEvery::Duration(Duration::from_secs(7200))));
+        assert_eq!("5x".parse::<SnapshotEvery>(), Err("Invalid snapshot interval: 5x".to_string()));
+        assert!("m".parse::<SnapshotEvery>().is_err());
+        assert!("-1".parse::<SnapshotEvery>().is_err());
+        assert_eq!(snapshot_path("out/accounts_{seq}.csv", 3), PathBuf::from("out/accounts_3.csv"));
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_written_between_batches() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=250 {
+            csv.push_str(&format!("deposit,{},{},1.0\n", tx % 2 + 1, tx));
+        }
+        write(&input, csv).unwrap();
+
+        // Both with the reader on its own task and reading in turn with applying
+        for pipeline_depth in [0, 2] {
+            let out = dir.path().join(format!("depth_{}", pipeline_depth));
+            std::fs::create_dir(&out).unwrap();
+            let template = out.join("accounts_{seq}.csv").to_str().unwrap().to_string();
+            let options = ProcessingOptions::builder()
+                .batch_size_mode(BatchSizeMode::Fixed(50))
+                .pipeline_depth(pipeline_depth)
+                .snapshot_every(SnapshotEvery::Records(100))
+                .snapshot_path_template(template.clone())
+                .build()
+                .unwrap();
+            let mut engine = PaymentEngine::new();
+            process_transactions_stream(&input, &mut engine, &options).await.unwrap();
+
+            // Taken after the batches ending at records 100 and 200
+            for (seq, total) in [(1, Decimal::from(100)), (2, Decimal::from(200))] {
+                let mut accounts = read_accounts_from_path(&snapshot_path(&template, seq)).unwrap();
+                accounts.sort_by_key(|account| account.client);
+                assert_eq!(accounts.iter().map(|a| a.client).collect::<Vec<_>>(), [1, 2]);
+                assert_eq!(accounts.iter().map(|a| a.total).sum::<Decimal>(), total);
+                assert!(accounts.iter().all(|a| a.available == a.total && !a.locked));
+            }
+            assert!(!snapshot_path(&template, 3).exists());
+            assert_eq!(std::fs::read_dir(&out).unwrap().count(), 2, "temporary files left behind");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duration_snapshots_follow_the_clock() {
+        let dir = tempdir().unwrap();
+        let template = dir.path().join("accounts_{seq}.csv").to_str().unwrap().to_string();
+        let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+        let every = SnapshotEvery::Duration(Duration::from_secs(30));
+        let mut snapshots = SnapshotWriter::new(every, &template, clock.clone());
+        let (run, options) = (LiveRun::new(PaymentEngine::new()), ProcessingOptions::default());
+
+        snapshots.after_batch(&run, 10, &options).await;
+        clock.advance(Duration::from_secs(29));
+        snapshots.after_batch(&run, 20, &options).await;
+        assert_eq!(snapshots.seq, 0);
+        clock.advance(Duration::from_secs(1));
+        snapshots.after_batch(&run, 30, &options).await;
+        assert_eq!(snapshots.seq, 1);
+        // The interval restarts from the snapshot just taken
+        clock.advance(Duration::from_secs(29));
+        snapshots.after_batch(&run, 40, &options).await;
+        snapshots.finish().await;
+        assert!(snapshot_path(&template, 1).exists() && !snapshot_path(&template, 2).exists());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::models::{Transaction, TransactionType, TxId};
+use rust_decimal::Decimal;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Record layout: tx (4, or 8 with wide ids) | client (2) | type (1) |
+// has amount (1) | amount (16) | currency (3) | has currency (1). Timestamps
+// aren't kept: only deposits and withdrawals are stored, and nothing reads
+// their time back.
+const TX_SIZE: usize = std::mem::size_of::<TxId>();
+const RECORD_SIZE: usize = TX_SIZE + 24;
+
+// Distinguishes spill stores created by the same process
+static NEXT_STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A sorted, immutable file of transactions keyed by tx id
+struct Segment {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    min_tx: TxId,
+    max_tx: TxId,
+}
+
+impl Segment {
+    /// Binary search the segment file for a transaction
+    fn find(&self, tx_id: TxId) -> io::Result<Option<Transaction>> {
+        if tx_id < self.min_tx || tx_id > self.max_tx {
+            return Ok(None);
+        }
+
+        let mut file = &self.file;
+        let mut record = [0u8; RECORD_SIZE];
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            file.seek(SeekFrom::Start(mid * RECORD_SIZE as u64))?;
+            file.read_exact(&mut record)?;
+            let (id, transaction) = decode(&record)?;
+            match id.cmp(&tx_id) {
+                std::cmp::Ordering::Equal => return Ok(Some(transaction)),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Segment {
+    /// Read the segment's records in tx id order
+    fn records(&self) -> io::Result<impl Iterator<Item = io::Result<[u8; RECORD_SIZE]>> + '_> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        Ok((0..self.len).map(move |_| {
+            let mut record = [0u8; RECORD_SIZE];
+            reader.read_exact(&mut record)?;
+            Ok(record)
+        }))
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// On-disk tier of the transaction store, made of sorted segment files.
+///
+/// Segments are written when the in-memory store overflows and are removed
+/// when the store is dropped. Lookups search the newest segment first. The
+/// newest segments are merged while the older is at most twice the size of
+/// the newer, so each segment is more than twice the size of the next newer
+/// one and a store of `n` transactions has at most about `log2(n)` of them.
+pub struct SpillStore {
+    dir: PathBuf,
+    store_id: usize,
+    segments: Vec<Segment>,
+    // Number of segment files created, naming the next one
+    files_created: usize,
+}
+
+impl SpillStore {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            store_id: NEXT_STORE_ID.fetch_add(1, Ordering::Relaxed),
+            segments: Vec::new(),
+            files_created: 0,
+        })
+    }
+
+    /// Write transactions to a new segment, then merge it into the older
+    /// segments as far as their sizes call for
+    pub fn write_segment(&mut self, mut transactions: Vec<Transaction>) -> io::Result<()> {
+        if transactions.is_empty() {
+            return Ok(());
+        }
+        transactions.sort_by_key(|t| t.tx);
+        let segment = self.create_segment(transactions.iter().map(|transaction| Ok(encode(transaction))))?;
+        self.segments.push(segment);
+
+        while let [.., older, newer] = self.segments.as_slice() {
+            if older.len > 2 * newer.len {
+                break;
+            }
+            let newer = self.segments.pop().expect("two segments");
+            let older = self.segments.pop().expect("two segments");
+            let merged = self.merge(&older, &newer)?;
+            self.segments.push(merged);
+        }
+        Ok(())
+    }
+
+    /// Merge two segments into a new one, keeping the newer record of a tx id
+    /// found in both
+    fn merge(&mut self, older: &Segment, newer: &Segment) -> io::Result<Segment> {
+        let mut older = older.records()?.peekable();
+        let mut newer = newer.records()?.peekable();
+        // A record that failed to read has no id and sorts first, so the
+        // error is passed on
+        let merged = std::iter::from_fn(|| {
+            let id = |record: &io::Result<[u8; RECORD_SIZE]>| record.as_ref().ok().map(record_id);
+            match (older.peek().map(id), newer.peek().map(id)) {
+                (None, None) => None,
+                (Some(_), None) => older.next(),
+                (None, Some(_)) => newer.next(),
+                (Some(old), Some(new)) if old < new => older.next(),
+                (Some(old), Some(new)) if old == new => {
+                    older.next();
+                    newer.next()
+                }
+                _ => newer.next(),
+            }
+        });
+        self.create_segment(merged)
+    }
+
+    /// Write records, sorted by tx id, to a new segment file
+    fn create_segment(&mut self, records: impl Iterator<Item = io::Result<[u8; RECORD_SIZE]>>) -> io::Result<Segment> {
+        let path = self.dir.join(format!(
+            "payment_engine_spill_{}_{}_{}.seg",
+            std::process::id(),
+            self.store_id,
+            self.files_created
+        ));
+        self.files_created += 1;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        let mut writer = BufWriter::new(&file);
+        let (mut len, mut min_tx, mut max_tx) = (0, None, 0);
+        for record in records {
+            let record = record?;
+            writer.write_all(&record)?;
+            min_tx.get_or_insert(record_id(&record));
+            max_tx = record_id(&record);
+            len += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        Ok(Segment { path, file, len, min_tx: min_tx.unwrap_or_default(), max_tx })
+    }
+
+    /// Look up a transaction in the segments, newest first
+    pub fn get(&self, tx_id: TxId) -> io::Result<Option<Transaction>> {
+        for segment in self.segments.iter().rev() {
+            if let Some(transaction) = segment.find(tx_id)? {
+                return Ok(Some(transaction));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Number of segment files written so far
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Delete all segments
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+}
+
+impl std::fmt::Debug for SpillStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpillStore")
+            .field("dir", &self.dir)
+            .field("segments", &self.segments.len())
+            .finish()
+    }
+}
+
+fn record_id(record: &[u8; RECORD_SIZE]) -> TxId {
+    TxId::from_le_bytes(record[..TX_SIZE].try_into().expect("tx field has the id's size"))
+}
+
+fn encode(transaction: &Transaction) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    let (tx, fields) = record.split_at_mut(TX_SIZE);
+    tx.copy_from_slice(&transaction.tx.to_le_bytes());
+    fields[0..2].copy_from_slice(&transaction.client.to_le_bytes());
+    fields[2] = match transaction.transaction_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Amend => 5,
+    };
+    if let Some(amount) = transaction.amount {
+        fields[3] = 1;
+        fields[4..20].copy_from_slice(&amount.serialize());
+    }
+    if let Some(currency) = transaction.currency {
+        fields[20..23].copy_from_slice(currency.as_str().as_bytes());
+        fields[23] = 1;
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> io::Result<(TxId, Transaction)> {
+    let (tx, fields) = record.split_at(TX_SIZE);
+    let tx = TxId::from_le_bytes(tx.try_into().expect("tx field has the id's size"));
+    let client = u16::from_le_bytes([fields[0], fields[1]]);
+    let transaction_type = match fields[2] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Amend,
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid transaction type {} in spill segment", other),
+            ))
+        }
+    };
+    let amount = if fields[3] == 1 {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&fields[4..20]);
+        Some(Decimal::deserialize(bytes))
+    } else {
+        None
+    };
+    let currency = if fields[23] == 1 {
+        let code = std::str::from_utf8(&fields[20..23]).unwrap_or_default();
+        Some(code.parse().map_err(|e: String| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    } else {
+        None
+    };
+    Ok((tx, Transaction { transaction_type, client, tx, amount, currency, timestamp: None }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_spill_store_lookup() {
+        let dir = tempdir().unwrap();
+        let mut store = SpillStore::new(dir.path()).unwrap();
+
+        let transactions: Vec<Transaction> = (1..=50)
+            .rev()
+            .map(|i| Transaction {
+                transaction_type: if i % 2 == 0 { TransactionType::Deposit } else { TransactionType::Withdrawal },
+                client: (i % 7) as u16,
+                tx: i * 3,
+                amount: Some(dec!(1.2345) * Decimal::from(i)),
+                currency: if i % 5 == 0 { Some("EUR".parse().unwrap()) } else { None },
+                timestamp: None,
+            })
+            .collect();
+        store.write_segment(transactions.clone()).unwrap();
+        store
+            .write_segment(vec![Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: 9,
+                tx: 1000,
+                amount: None,
+                currency: None,
+                timestamp: None,
+            }])
+            .unwrap();
+        assert_eq!(store.segment_count(), 2);
+
+        for transaction in &transactions {
+            assert_eq!(store.get(transaction.tx).unwrap().as_ref(), Some(transaction));
+        }
+        assert_eq!(store.get(1000).unwrap().unwrap().amount, None);
+        assert!(store.get(4).unwrap().is_none());
+        assert!(store.get(0).unwrap().is_none());
+        assert!(store.get(2000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_largest_tx_ids_round_trip() {
+        let dir = tempdir().unwrap();
+        let mut store = SpillStore::new(dir.path()).unwrap();
+        let transactions = [Transaction::deposit(1, TxId::MAX - 1, dec!(2)), Transaction::withdrawal(2, TxId::MAX, dec!(1))];
+        store.write_segment(transactions.to_vec()).unwrap();
+
+        assert_eq!(RECORD_SIZE, std::mem::size_of::<TxId>() + 24);
+        for transaction in &transactions {
+            assert_eq!(store.get(transaction.tx).unwrap().as_ref(), Some(transaction));
+        }
+        assert!(store.get(TxId::MAX - 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_segments_are_merged_as_they_pile_up() {
+        let dir = tempdir().unwrap();
+        let mut store = SpillStore::new(dir.path()).unwrap();
+
+        // 1000 spills of 10 transactions, the last of each respilled with a
+        // new amount by the next spill as if loaded back and evicted again
+        for spill in 0..1000u32 {
+            let first = spill * 10 + 1;
+            let mut transactions: Vec<_> = (first..first + 10).map(|tx| Transaction::deposit(1, tx as TxId, dec!(1))).collect();
+            if spill > 0 {
+                transactions.push(Transaction::deposit(1, (first - 1) as TxId, dec!(2)));
+            }
+            store.write_segment(transactions).unwrap();
+            assert!(store.segment_count() <= 12, "{} segments after {} spills", store.segment_count(), spill + 1);
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), store.segment_count());
+
+        for tx in 1..10_000 {
+            let expected = if tx % 10 == 0 { dec!(2) } else { dec!(1) };
+            assert_eq!(store.get(tx).unwrap().and_then(|t| t.amount), Some(expected), "tx {}", tx);
+        }
+        assert_eq!(store.get(10_000).unwrap().unwrap().amount, Some(dec!(1)));
+        assert!(store.get(10_001).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_segments_removed_on_drop() {
+        let dir = tempdir().unwrap();
+        let mut store = SpillStore::new(dir.path()).unwrap();
+        store
+            .write_segment(vec![Transaction::deposit(1, 1, dec!(1))])
+            .unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        drop(store);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::engine::PaymentEngine;
+use crate::error::Result;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use tracing::info;
+
+/// Write all account balances to an SQLite database.
+///
+/// Creates the `accounts` table if needed and replaces its rows with this
+/// run's accounts, so writing another run to the same database leaves none
+/// of the previous run's clients behind. Monetary values are stored as exact
+/// decimal strings rounded to 4 decimal places, matching the CSV output.
+pub fn write_accounts_to_sqlite(engine: &PaymentEngine, path: &Path) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS accounts (
+            client INTEGER PRIMARY KEY,
+            available TEXT NOT NULL,
+            held TEXT NOT NULL,
+            total TEXT NOT NULL,
+            locked INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Replace all rows in a single transaction
+    let accounts = engine.get_accounts();
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM accounts", [])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO accounts (client, available, held, total, locked)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for account in &accounts {
+            stmt.execute(params![
+                account.client,
+                account.available.round_dp(4).to_string(),
+                account.held.round_dp(4).to_string(),
+                account.total.round_dp(4).to_string(),
+                account.locked,
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    info!("Wrote {} accounts to SQLite database {:?}", accounts.len(), path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+
+    fn read_accounts(path: &Path) -> Vec<Account> {
+        let conn = Connection::open(path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap();
+        stmt.query_map([], |row| {
+            let available: String = row.get(1)?;
+            let held: String = row.get(2)?;
+            let total: String = row.get(3)?;
+            Ok(Account {
+                client: row.get(0)?,
+                available: available.parse::<Decimal>().unwrap(),
+                held: held.parse::<Decimal>().unwrap(),
+                total: total.parse::<Decimal>().unwrap(),
+                locked: row.get(4)?,
+                ..Default::default()
+            })
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_write_accounts_to_sqlite() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("accounts.db");
+
+        let mut engine = PaymentEngine::new();
+        for (transaction_type, client, tx, amount) in [
+            (TransactionType::Deposit, 1, 1, Some(dec!(100.1234))),
+            (TransactionType::Deposit, 2, 2, Some(dec!(50))),
+            (TransactionType::Withdrawal, 1, 3, Some(dec!(0.0001))),
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::Chargeback, 2, 2, None),
+        ] {
+            engine
+                .process_transaction(Transaction { transaction_type, client, tx, amount, currency: None, timestamp: None })
+                .await
+                .unwrap();
+        }
+
+        write_accounts_to_sqlite(&engine, &db_path).unwrap();
+        // Writing again must replace rows rather than fail or duplicate them
+        write_accounts_to_sqlite(&engine, &db_path).unwrap();
+
+        let mut expected = engine.get_accounts();
+        expected.sort_by_key(|a| a.client);
+        let actual = read_accounts(&db_path);
+
+        assert_eq!(actual.len(), expected.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!(e.client, a.client);
+            assert_eq!(e.available, a.available);
+            assert_eq!(e.held, a.held);
+            assert_eq!(e.total, a.total);
+            assert_eq!(e.locked, a.locked);
+        }
+
+        // Decimals are stored as exact strings
+        let conn = Connection::open(&db_path).unwrap();
+        let available: String = conn
+            .query_row("SELECT available FROM accounts WHERE client = 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(available, "100.1233");
+    }
+
+    #[tokio::test]
+    async fn test_second_run_replaces_the_first() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("accounts.db");
+
+        let mut first = PaymentEngine::new();
+        first.process_transaction(Transaction::deposit(1, 1, dec!(10))).await.unwrap();
+        first.process_transaction(Transaction::deposit(2, 2, dec!(20))).await.unwrap();
+        write_accounts_to_sqlite(&first, &db_path).unwrap();
+
+        let mut second = PaymentEngine::new();
+        second.process_transaction(Transaction::deposit(2, 1, dec!(5))).await.unwrap();
+        second.process_transaction(Transaction::deposit(3, 2, dec!(7))).await.unwrap();
+        write_accounts_to_sqlite(&second, &db_path).unwrap();
+
+        let balances: Vec<_> = read_accounts(&db_path).into_iter().map(|a| (a.client, a.total)).collect();
+        assert_eq!(balances, [(2, dec!(5)), (3, dec!(7))]);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::diff::{diff_accounts, read_accounts_from_path};
+use crate::models::Account;
+use crate::processor::{create_engine, process_transactions_stream, ProcessingOptions, ProcessingReport};
+use rust_decimal::Decimal;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the file holding a fixture's expected accounts, next to the
+/// `<name>.csv` input: `<name>.expected.csv`
+pub const EXPECTED_SUFFIX: &str = ".expected.csv";
+
+/// Process a fixture input with the default options and return the
+/// resulting accounts in client order.
+///
+/// Panics if the input can't be processed. Runs its own runtime, so it must
+/// not be called from within an async context such as `#[tokio::test]`.
+pub fn run_fixture(input_path: &Path) -> Vec<Account> {
+    run_fixture_with_options(input_path, &ProcessingOptions::default())
+}
+
+/// Process a fixture input with the given options, see [`run_fixture`]
+pub fn run_fixture_with_options(input_path: &Path, options: &ProcessingOptions) -> Vec<Account> {
+    run_fixture_with_report(input_path, options).0
+}
+
+/// Process a fixture input with the given options and return the resulting
+/// accounts in client order along with the run's report, see [`run_fixture`]
+pub fn run_fixture_with_report(input_path: &Path, options: &ProcessingOptions) -> (Vec<Account>, ProcessingReport) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start a runtime for the fixture");
+    let mut engine = create_engine(options).unwrap_or_else(|e| panic!("Failed to create the engine for {:?}: {}", input_path, e));
+    let report = runtime
+        .block_on(process_transactions_stream(input_path, &mut engine, options))
+        .unwrap_or_else(|e| panic!("Failed to process fixture {:?}: {}", input_path, e));
+
+    let mut accounts = engine.get_accounts();
+    accounts.sort_by_key(|account| account.client);
+    (accounts, report)
+}
+
+/// Assert that `actual` holds the same accounts as the `expected_csv` file,
+/// written in the output format. Row order and decimal formatting don't
+/// matter, and balances that differ by no more than `tolerance` match.
+///
+/// Panics listing every differing client, with the expected file as "old".
+#[track_caller]
+pub fn assert_accounts_match(expected_csv: &Path, actual: &[Account], tolerance: Decimal) {
+    let expected = read_accounts_from_path(expected_csv)
+        .unwrap_or_else(|e| panic!("Failed to read expected accounts {:?}: {}", expected_csv, e));
+    let diff = diff_accounts(&expected, actual, tolerance);
+    assert!(diff.is_empty(), "Accounts differ from {:?}:\n{}", expected_csv, diff);
+}
+
+/// Run every `<name>.csv` fixture in `dir` and compare its accounts with
+/// `<name>.expected.csv`, in name order. Returns the number of fixtures run.
+///
+/// Panics on the first mismatch, or on an input without an expected file.
+#[track_caller]
+pub fn check_fixture_dir(dir: &Path, tolerance: Decimal) -> usize {
+    let entries = std::fs::read_dir(dir).unwrap_or_else(|e| panic!("Failed to read fixture directory {:?}: {}", dir, e));
+    let mut inputs: Vec<PathBuf> = entries
+        .map(|entry| entry.unwrap_or_else(|e| panic!("Failed to read fixture directory {:?}: {}", dir, e)).path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.ends_with(".csv") && !name.ends_with(EXPECTED_SUFFIX)
+        })
+        .collect();
+    inputs.sort();
+
+    for input in &inputs {
+        let expected = expected_path(input);
+        assert!(expected.is_file(), "Fixture {:?} has no expected output {:?}", input, expected);
+        assert_accounts_match(&expected, &run_fixture(input), tolerance);
+    }
+    inputs.len()
+}
+
+/// Expected output file of a fixture input: `basic.csv` -> `basic.expected.csv`
+fn expected_path(input: &Path) -> PathBuf {
+    let stem = input.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    input.with_file_name(format!("{}{}", stem, EXPECTED_SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fixture_matches_regardless_of_order_and_format() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("order.csv");
+        write(&input, "type,client,tx,amount\ndeposit,2,1,1.5\ndeposit,1,2,3\n").unwrap();
+        let expected = dir.path().join("order.expected.csv");
+        write(&expected, "client,available,held,total,locked\n2,1.5000,0,1.5,false\n1, 3 ,0.0,3.0000,false\n").unwrap();
+
+        let accounts = run_fixture(&input);
+        assert_eq!(accounts.iter().map(|a| a.client).collect::<Vec<_>>(), [1, 2]);
+        assert_accounts_match(&expected, &accounts, Decimal::ZERO);
+        assert_eq!(check_fixture_dir(dir.path(), Decimal::ZERO), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "client 1: available 3 -> 3.0001")]
+    fn test_mismatch_beyond_tolerance_panics() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("off.csv");
+        write(&input, "type,client,tx,amount\ndeposit,1,1,3.0001\n").unwrap();
+        let expected = dir.path().join("off.expected.csv");
+        write(&expected, "client,available,held,total,locked\n1,3,0,3,false\n").unwrap();
+
+        let accounts = run_fixture(&input);
+        assert_accounts_match(&expected, &accounts, dec!(0.0001));
+        assert_accounts_match(&expected, &accounts, Decimal::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "has no expected output")]
+    fn test_fixture_without_expected_output_panics() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("lonely.csv"), "type,client,tx,amount\n").unwrap();
+        check_fixture_dir(dir.path(), Decimal::ZERO);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::engine::PaymentEngine;
+use crate::error::{PaymentEngineError, Result};
+use crate::models::{IdMap, Transaction, TransactionType, TxId};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Outcome of applying a transaction, as recorded in the WAL
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WalOutcome {
+    Applied,
+    Failed,
+}
+
+/// A single WAL entry: the transaction handed to the engine and its outcome
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WalRecord {
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub outcome: WalOutcome,
+}
+
+/// Append-only write-ahead log of transactions applied by the engine.
+///
+/// Records are written as JSON lines and fsynced once per batch.
+pub struct WalWriter {
+    writer: BufWriter<File>,
+}
+
+impl WalWriter {
+    /// Open a WAL file for appending, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| PaymentEngineError::OpenFile { path: path.to_path_buf(), source })?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Append a record for a transaction that has been handed to the engine
+    pub fn append(&mut self, transaction: &Transaction, outcome: WalOutcome) -> Result<()> {
+        let record = WalRecord {
+            transaction: transaction.clone(),
+            outcome,
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Flush buffered records and fsync them to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Transactions found in a WAL, skipped when the input is read again.
+///
+/// They are keyed on type and tx id rather than counted off in input order,
+/// so a restart still skips the right transactions if its filters or hook
+/// drop other rows than the interrupted run did. Disputes, resolves and
+/// chargebacks share the id of the transaction they refer to, so each key
+/// is skipped as many times as it was logged.
+#[derive(Debug, Default)]
+pub struct RecoveredTransactions {
+    logged: IdMap<(TransactionType, TxId), usize>,
+    remaining: usize,
+}
+
+impl RecoveredTransactions {
+    fn insert(&mut self, transaction: &Transaction) {
+        *self.logged.entry((transaction.transaction_type, transaction.tx)).or_default() += 1;
+        self.remaining += 1;
+    }
+
+    /// Whether the transaction was logged and not skipped yet, in which case
+    /// it counts as skipped now
+    pub fn take(&mut self, transaction: &Transaction) -> bool {
+        let key = (transaction.transaction_type, transaction.tx);
+        let Some(count) = self.logged.get_mut(&key) else { return false };
+        *count -= 1;
+        if *count == 0 {
+            self.logged.remove(&key);
+        }
+        self.remaining -= 1;
+        true
+    }
+
+    /// Number of logged transactions not skipped yet
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Rebuild engine state by reapplying the transactions logged in a WAL.
+///
+/// Returns the transactions found, which were already handed to the engine
+/// before the crash. A final record cut short by a crash mid-write, or not
+/// yet ended by its newline, is ignored and truncated away, so records
+/// appended afterwards start on a line of their own.
+pub async fn replay_wal(engine: &mut PaymentEngine, wal_path: &Path) -> Result<RecoveredTransactions> {
+    let file = File::open(wal_path).map_err(|source| PaymentEngineError::OpenFile { path: wal_path.to_path_buf(), source })?;
+    let mut reader = BufReader::new(file);
+    let mut recovered = RecoveredTransactions::default();
+    let mut line = Vec::new();
+    // End of the last complete record
+    let mut complete_len = 0;
+    let mut truncated = false;
+
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        let record: WalRecord = match line.strip_suffix(b"\n") {
+            Some(json) => match serde_json::from_slice(json) {
+                Ok(record) => record,
+                Err(e) if reader.fill_buf()?.is_empty() => {
+                    warn!("Ignoring truncated final WAL record: {}", e);
+                    truncated = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            },
+            None => {
+                warn!("Ignoring final WAL record without a newline");
+                truncated = true;
+                break;
+            }
+        };
+        complete_len += read as u64;
+        recovered.insert(&record.transaction);
+
+        // Failed transactions left no trace in the engine, so there is nothing to reapply
+        if record.outcome == WalOutcome::Applied {
+            engine.process_transaction(record.transaction).await?;
+        }
+    }
+
+    if truncated {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(wal_path)
+            .map_err(|source| PaymentEngineError::OpenFile { path: wal_path.to_path_buf(), source })?;
+        file.set_len(complete_len)?;
+        file.sync_data()?;
+    }
+
+    // Expiries aren't logged; they happen again as the timestamps are replayed
+    engine.expire_disputes()?;
+    info!("Replayed {} transactions from WAL {:?}", recovered.len(), wal_path);
+
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_wal_round_trip() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("engine.wal");
+
+        let deposit = Transaction::deposit(1, 1, dec!(10.5));
+        let withdrawal = Transaction::withdrawal(1, 2, dec!(2.25));
+
+        let mut wal = WalWriter::open(&wal_path).unwrap();
+        wal.append(&deposit, WalOutcome::Applied).unwrap();
+        wal.append(&withdrawal, WalOutcome::Applied).unwrap();
+        wal.sync().unwrap();
+
+        let mut engine = PaymentEngine::new();
+        let recovered = replay_wal(&mut engine, &wal_path).await.unwrap();
+        assert_eq!(recovered.len(), 2);
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, dec!(8.25));
+        assert_eq!(accounts[0].total, dec!(8.25));
+    }
+
+    #[tokio::test]
+    async fn test_wal_ignores_truncated_final_record() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("engine.wal");
+
+        write(
+            &wal_path,
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"5.0\",\"outcome\":\"applied\"}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amou",
+        )
+        .unwrap();
+
+        let mut engine = PaymentEngine::new();
+        let recovered = replay_wal(&mut engine, &wal_path).await.unwrap();
+        assert_eq!(recovered.len(), 1);
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts[0].available, dec!(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_wal_appends_after_recovering_from_two_crashes() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("engine.wal");
+        let applied = |tx| format!("{{\"type\":\"deposit\",\"client\":1,\"tx\":{},\"amount\":\"1.0\",\"outcome\":\"applied\"}}\n", tx);
+
+        // Crash in the middle of the second record, then again after the
+        // third was written but before its newline
+        write(&wal_path, format!("{}{{\"type\":\"dep", applied(1))).unwrap();
+        let mut engine = PaymentEngine::new();
+        assert_eq!(replay_wal(&mut engine, &wal_path).await.unwrap().len(), 1);
+        let mut wal = WalWriter::open(&wal_path).unwrap();
+        wal.append(&Transaction::deposit(1, 2, dec!(1.0)), WalOutcome::Applied).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(applied(3).trim_end().as_bytes()).unwrap();
+        drop(file);
+
+        let mut engine = PaymentEngine::new();
+        assert_eq!(replay_wal(&mut engine, &wal_path).await.unwrap().len(), 2);
+        let mut wal = WalWriter::open(&wal_path).unwrap();
+        wal.append(&Transaction::deposit(1, 3, dec!(1.0)), WalOutcome::Applied).unwrap();
+        wal.sync().unwrap();
+
+        let mut engine = PaymentEngine::new();
+        assert_eq!(replay_wal(&mut engine, &wal_path).await.unwrap().len(), 3);
+        assert_eq!(engine.get_account(1).unwrap().available, dec!(3.0));
+        assert_eq!(std::fs::read_to_string(&wal_path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_recovered_transactions_are_skipped_by_key() {
+        let mut recovered = RecoveredTransactions::default();
+        for transaction in [Transaction::deposit(1, 1, dec!(1)), Transaction::dispute(1, 1), Transaction::dispute(1, 1)] {
+            recovered.insert(&transaction);
+        }
+
+        // Order doesn't matter, but each logged transaction is only skipped once
+        assert!(!recovered.take(&Transaction::deposit(1, 2, dec!(1))));
+        assert!(recovered.take(&Transaction::dispute(1, 1)));
+        assert!(recovered.take(&Transaction::deposit(1, 1, dec!(1))));
+        assert!(!recovered.take(&Transaction::deposit(1, 1, dec!(1))));
+        assert!(recovered.take(&Transaction::dispute(1, 1)));
+        assert!(!recovered.take(&Transaction::dispute(1, 1)));
+        assert!(recovered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wal_rejects_corrupt_middle_record() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("engine.wal");
+
+        write(
+            &wal_path,
+            "garbage\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"5.0\",\"outcome\":\"applied\"}\n",
+        )
+        .unwrap();
+
+        let mut engine = PaymentEngine::new();
+        assert!(matches!(replay_wal(&mut engine, &wal_path).await, Err(PaymentEngineError::WalRecord(_))));
+
+        let missing = dir.path().join("missing.wal");
+        assert!(matches!(replay_wal(&mut engine, &missing).await, Err(PaymentEngineError::OpenFile { .. })));
+    }
+}
@@ -0,0 +1,359 @@
+use assert_cmd::Command;
+use std::fs::write;
+use tempfile::tempdir;
+
+const CORRUPT_CSV: &str = "type,client,tx,amount\n\
+                           deposit,1,1,10.0\n\
+                           deposit,one,2,5.0\n\
+                           withdrawal,1,3,100.0\n\
+                           garbage\n";
+
+fn payment_engine(dir: &std::path::Path) -> Command {
+    let mut cmd = Command::cargo_bin("payment-engine").unwrap();
+    cmd.arg("--log-dir").arg(dir.join("logs"));
+    cmd
+}
+
+#[test]
+fn test_exit_status_reflects_errors() {
+    let dir = tempdir().unwrap();
+    let clean = dir.path().join("clean.csv");
+    let corrupt = dir.path().join("corrupt.csv");
+    write(&clean, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+    write(&corrupt, CORRUPT_CSV).unwrap();
+
+    payment_engine(dir.path()).arg(&clean).arg("--max-errors=0").assert().code(0);
+
+    // Errors are only logged unless a threshold is given
+    payment_engine(dir.path()).arg(&corrupt).assert().code(0);
+    payment_engine(dir.path()).arg(&corrupt).arg("--max-errors=3").assert().code(0);
+    payment_engine(dir.path()).arg(&corrupt).arg("--max-errors=2").assert().code(4);
+
+    // Apart from fatal and usage errors
+    payment_engine(dir.path()).arg(dir.path().join("missing.csv")).assert().code(1);
+    payment_engine(dir.path()).arg(&corrupt).arg("--max-errors=2").arg("--batch-size=0").assert().code(2);
+    payment_engine(dir.path()).arg(&corrupt).arg("--max-errors=two").assert().code(2);
+}
+
+#[test]
+fn test_invalid_options_are_usage_errors() {
+    let dir = tempdir().unwrap();
+    let clean = dir.path().join("clean.csv");
+    write(&clean, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+
+    let output = payment_engine(dir.path()).arg(&clean).arg("--batch-size=0").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: batch_size must be at least 1\n"), "{}", stderr);
+    assert!(stderr.contains("Usage:"), "{}", stderr);
+
+    // Also when the invalid value comes from the config file
+    let config = dir.path().join("config.toml");
+    write(&config, "delimiter = \"\\\"\"\n").unwrap();
+    let output = payment_engine(dir.path()).arg(&clean).arg("--config").arg(&config).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("error: delimiter '\"' is not allowed\n"));
+}
+
+#[test]
+fn test_summarize_prints_json() {
+    let dir = tempdir().unwrap();
+    let corrupt = dir.path().join("corrupt.csv");
+    write(&corrupt, CORRUPT_CSV).unwrap();
+
+    let output = Command::cargo_bin("payment-engine")
+        .unwrap()
+        .args(["summarize", "--format", "json"])
+        .arg(&corrupt)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["lines"], 5);
+    assert_eq!(summary["deposits"], 1);
+    assert_eq!(summary["withdrawals"], 1);
+    assert_eq!(summary["parse_errors"], 2);
+    assert_eq!(summary["max_amount"], "100.0");
+}
+
+#[test]
+fn test_diff_exit_status() {
+    let dir = tempdir().unwrap();
+    let old = dir.path().join("old.csv");
+    let new = dir.path().join("new.csv");
+    write(&old, "# Processing completed in 1ms\nclient,available,held,total,locked\n1,10,0,10,false\n").unwrap();
+    write(&new, "client,available,held,total,locked\n1,10,0,10,true\n2,5,0,5,false\n").unwrap();
+
+    Command::cargo_bin("payment-engine").unwrap().arg("diff").arg(&old).arg(&old).assert().code(0);
+    let output = Command::cargo_bin("payment-engine").unwrap().arg("diff").arg(&old).arg(&new).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client 2: only in new\nclient 1: locked false -> true\n"
+    );
+}
+
+#[test]
+fn test_generate_rejects_invalid_config() {
+    let dir = tempdir().unwrap();
+    let output_path = dir.path().join("generated.csv");
+    let generate = || {
+        let mut cmd = Command::cargo_bin("payment-engine").unwrap();
+        cmd.arg("generate").arg("--output").arg(&output_path).arg("--transactions=10");
+        cmd
+    };
+
+    let output = generate().arg("--deposit-ratio=1.5").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("deposit_ratio must be between 0 and 1, got 1.5") && !stderr.contains("panicked"), "{}", stderr);
+
+    let output = generate().args(["--min-amount=10", "--max-amount=1"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("min_amount 10 must not be greater than max_amount 1"));
+    assert!(!output_path.exists());
+
+    generate().assert().code(0);
+    assert_eq!(std::fs::read_to_string(&output_path).unwrap().lines().count(), 11);
+}
+
+#[test]
+fn test_replay_stops_at_cut_point() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("input.csv");
+    let config = dir.path().join("config.toml");
+    write(&input, "type,client,tx,amount\ndeposit,7,1,10.0\ndeposit,2,2,3.0\nwithdrawal,7,3,4.0\ndeposit,7,4,2.0\ndispute,7,4,\n").unwrap();
+    write(&config, "withdrawal_fee = \"0.5\"\n").unwrap();
+
+    let replay = |args: &[&str]| {
+        Command::cargo_bin("payment-engine").unwrap().arg("replay").arg(&input).args(args).output().unwrap()
+    };
+    let output = replay(&["--client", "7", "--until-tx", "3"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n7,6.0,0,6.0,false\n\n\
+         tx,type,amount,state,held\n1,deposit,10.0,processed,0\n3,withdrawal,4.0,processed,0\n"
+    );
+
+    // The run's engine rules come from its config
+    let config = config.to_str().unwrap();
+    let output = replay(&["--client", "7", "--until-line", "4", "--config", config]);
+    assert!(String::from_utf8(output.stdout).unwrap().contains("\n7,5.5,0,5.5,false\n"));
+
+    let output = replay(&["--client", "7", "--until-line", "99"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("4,deposit,2.0,disputed,2.0\n"));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("input ended before the cut point"));
+
+    assert!(!replay(&["--client", "7", "--until-tx", "3", "--until-line", "4"]).status.success());
+}
+
+#[test]
+fn test_output_partitions() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("transactions.csv");
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=40 {
+        csv.push_str(&format!("deposit,{},{},{}.5\n", tx % 13, tx, tx));
+    }
+    write(&input, csv).unwrap();
+
+    let output = payment_engine(dir.path()).arg(&input).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut expected: Vec<&str> = stdout.lines().skip(1).collect();
+    expected.sort();
+
+    let parts = dir.path().join("parts");
+    let output = payment_engine(dir.path())
+        .arg(&input)
+        .arg("--output-partitions=4")
+        .arg("--output-dir")
+        .arg(&parts)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(String::from_utf8(output.stderr).unwrap().lines().count(), 1);
+
+    let contents: Vec<String> =
+        (0..4).map(|part| std::fs::read_to_string(parts.join(format!("accounts_{}.csv", part))).unwrap()).collect();
+    let mut combined: Vec<&str> = contents.iter().flat_map(|content| content.lines().skip(1)).collect();
+    combined.sort();
+    assert_eq!(combined, expected);
+    assert!(contents.iter().all(|content| content.starts_with("client,available,held,total,locked\n")));
+}
+
+#[test]
+fn test_gzip_output_file() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("transactions.csv");
+    write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.5\nwithdrawal,1,3,4.0\n").unwrap();
+    let stdout = payment_engine(dir.path()).arg(&input).output().unwrap().stdout;
+
+    let gz = dir.path().join("accounts.csv.gz");
+    let output = payment_engine(dir.path()).arg(&input).arg("--output").arg(&gz).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let decompressed = std::io::read_to_string(flate2::read::GzDecoder::new(std::fs::File::open(&gz).unwrap())).unwrap();
+    assert_eq!(decompressed.as_bytes(), stdout);
+}
+
+#[test]
+fn test_manifest_hashes_stdout() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempdir().unwrap();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/basic.csv");
+    let manifest_path = dir.path().join("run.json");
+    let output = payment_engine(dir.path()).arg(&fixture).arg("--manifest").arg(&manifest_path).output().unwrap();
+    assert!(output.status.success());
+
+    let manifest = payment_engine::RunManifest::read(&manifest_path).unwrap();
+    assert!(manifest.succeeded);
+    assert!(manifest.environment.args.ends_with(&["--manifest".to_string(), manifest_path.display().to_string()]));
+    let sha256: String = Sha256::digest(&output.stdout).iter().map(|byte| format!("{:02x}", byte)).collect();
+    let manifest_output = manifest.output.unwrap();
+    assert_eq!(manifest_output.path, None);
+    assert_eq!(manifest_output.digest.sha256, sha256);
+    assert_eq!(manifest_output.digest.rows, output.stdout.iter().filter(|&&byte| byte == b'\n').count() - 1);
+}
+
+#[test]
+fn test_sample_prints_estimate() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("transactions.csv");
+    write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.5\nwithdrawal,1,3,4.0\n").unwrap();
+
+    let output = payment_engine(dir.path()).arg(&input).args(["--sample", "1", "--seed", "7"]).output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("# Sample: 0 sampled out, estimated 3 transactions, 13.5 deposited, 4.0 withdrawn"), "{}", stderr);
+
+    let output = payment_engine(dir.path()).arg(&input).args(["--seed", "7"]).output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_dry_run_traces_one_client() {
+    let dir = tempdir().unwrap();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+
+    let output = payment_engine(dir.path()).arg(&fixture).args(["--dry-run", "--client", "2"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client 2, tx 3, deposit 20.0: applied; available 20.0, held 0, total 20.0\n\
+         client 2, tx 3, resolve: rejected (not_disputed); available 20.0, held 0, total 20.0\n\
+         client 2, tx 3, dispute: applied; available 0.0, held 20.0, total 20.0\n\
+         client 2, tx 4, withdrawal 5.0: rejected (insufficient_funds); available 0.0, held 20.0, total 20.0\n"
+    );
+
+    let output = payment_engine(dir.path())
+        .arg(&fixture)
+        .args(["--dry-run", "--client", "2", "--format", "csv"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,tx,type,amount,outcome,reason,available,held,total\n\
+         2,3,deposit,20.0,applied,,20.0,0,20.0\n\
+         2,3,resolve,,rejected,not_disputed,20.0,0,20.0\n\
+         2,3,dispute,,applied,,0.0,20.0,20.0\n\
+         2,4,withdrawal,5.0,rejected,insufficient_funds,0.0,20.0,20.0\n"
+    );
+
+    // A dry run must not persist anything
+    payment_engine(dir.path()).arg(&fixture).arg("--dry-run").arg("--wal").arg(dir.path().join("run.wal")).assert().code(2);
+}
+
+#[test]
+fn test_reconciliation_failure_exits_with_status_3() {
+    let dir = tempdir().unwrap();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disputes.csv");
+    let expected = dir.path().join("ledger.csv");
+    write(&expected, "client,available,held,total,locked\n1,100.0,50.0,150.0,false\n2,0.01,20.0,20.01,false\n").unwrap();
+
+    let output = payment_engine(dir.path()).arg(&fixture).arg("--expected").arg(&expected).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("failed: 1 clients differ\n# client 2: available 0.01 -> 0.0 (-0.01) total 20.01 -> 20.0 (-0.01)\n"), "{}", stderr);
+
+    payment_engine(dir.path()).arg(&fixture).arg("--expected").arg(&expected).arg("--reconcile-tolerance=0.01").assert().code(0);
+}
+
+#[test]
+fn test_quiet_and_verbose_streams() {
+    let dir = tempdir().unwrap();
+    let corrupt = dir.path().join("corrupt.csv");
+    write(&corrupt, CORRUPT_CSV).unwrap();
+    let csv = "client,available,held,total,locked\n1,10.0,0,10.0,false\n";
+
+    // stdout only ever gets the accounts
+    let output = payment_engine(dir.path()).arg(&corrupt).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), csv);
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("# Processing completed in "));
+
+    let output = payment_engine(dir.path()).arg(&corrupt).args(["-q", "--max-errors=2"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!((String::from_utf8(output.stdout).unwrap().as_str(), output.stderr.as_slice()), (csv, &[][..]));
+    let output = payment_engine(dir.path()).arg(dir.path().join("missing.csv")).arg("--quiet").output().unwrap();
+    assert_eq!((output.status.code(), output.stdout.is_empty(), output.stderr.is_empty()), (Some(1), true, true));
+
+    let output = payment_engine(dir.path()).arg(&corrupt).args(["-v", "--batch-size=1"]).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), csv);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("# Applied lines 2-2: 1 transactions, 0 rejected, 0 errors so far\n"), "{}", stderr);
+    assert!(stderr.contains("# Transactions: 2 processed, 1 rejected"), "{}", stderr);
+    assert!(!stderr.contains("# Disputes of withdrawals rejected"), "{}", stderr);
+
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/withdrawal_disputes.csv");
+    let stderr = String::from_utf8(payment_engine(dir.path()).arg(&fixture).arg("-v").output().unwrap().stderr).unwrap();
+    assert!(stderr.contains("# Transactions: 11 processed, 6 rejected, 0 duplicates, 0 in failed batches
+# Disputes of withdrawals rejected: 3
+"), "{}", stderr);
+
+    payment_engine(dir.path()).arg(&corrupt).args(["-q", "-v"]).assert().code(2);
+}
+
+#[test]
+fn test_stdin_without_trailing_newline() {
+    let dir = tempdir().unwrap();
+    let input = "type,client,tx,amount\ndeposit,3,7,4.0\ndispute,3,7,\nchargeback,3,7";
+
+    // stdin is a pipe, so mmap mode falls back to streaming
+    for mode in ["streaming", "mmap"] {
+        let output = payment_engine(dir.path()).arg("/dev/stdin").arg(format!("--input-mode={}", mode)).write_stdin(input).output().unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "client,available,held,total,locked\n3,0.0,0.0,0.0,true\n");
+    }
+}
+
+#[test]
+fn test_parallel_files_merge_into_one_output() {
+    let dir = tempdir().unwrap();
+    let (east, west) = (dir.path().join("east.csv"), dir.path().join("west.csv"));
+    write(&east, "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,3,2.0\n").unwrap();
+    write(&west, "type,client,tx,amount\ndeposit,2,2,7.5\n").unwrap();
+
+    let output = payment_engine(dir.path()).arg(&east).arg(&west).arg("--parallel-files=2").output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut rows: Vec<&str> = stdout.lines().collect();
+    rows[1..].sort();
+    assert_eq!(rows, ["client,available,held,total,locked", "1,3.0,0,3.0,false", "2,7.5,0,7.5,false"]);
+
+    // A client in both files fails the run without writing any accounts
+    write(&west, "type,client,tx,amount\ndeposit,2,2,7.5\ndeposit,1,4,1.0\n").unwrap();
+    let output = payment_engine(dir.path()).arg(&east).arg(&west).arg("--parallel-files=2").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("client 1 is in both"));
+
+    // Several files without the flag, or no files at a time, are usage errors
+    payment_engine(dir.path()).arg(&east).arg(&west).assert().code(2);
+    payment_engine(dir.path()).arg(&east).arg("--parallel-files=0").assert().code(2);
+}
@@ -0,0 +1,103 @@
+use payment_engine::test_util::{assert_accounts_match, check_fixture_dir, run_fixture_with_options, run_fixture_with_report};
+use payment_engine::models::DisputeClientCheck;
+use payment_engine::{FixedWidthSpec, InputFormat, InputMode, ProcessingOptions};
+use rust_decimal::Decimal;
+use std::path::Path;
+
+/// Every `<name>.csv` in tests/fixtures must produce the accounts in its
+/// `<name>.expected.csv`; adding a scenario only takes those two files
+#[test]
+fn test_golden_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let count = check_fixture_dir(&dir, Decimal::ZERO);
+    assert!(count >= 5, "Only {} fixtures found in {:?}", count, dir);
+}
+
+/// The fixed-width copy of basic.csv must end with the same balances
+#[test]
+fn test_fixed_width_fixture_matches_csv() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let options = ProcessingOptions::builder()
+        .input_format(InputFormat::FixedWidth(FixedWidthSpec::default()))
+        .build()
+        .unwrap();
+    let accounts = run_fixture_with_options(&dir.join("basic.dat"), &options);
+    assert_accounts_match(&dir.join("basic.expected.csv"), &accounts, Decimal::ZERO);
+}
+
+/// A last line without a trailing newline is still a record in every input
+/// mode, whether it is a transaction, a malformed row or the header alone
+#[test]
+fn test_last_line_without_newline() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    for (name, transactions, parse_errors) in [("no_trailing_newline", 7, 0), ("malformed_last_line", 2, 1), ("header_only", 0, 0)] {
+        let input = dir.join(format!("{}.csv", name));
+        let content = std::fs::read(&input).unwrap();
+        assert!(!content.ends_with(b"\n"), "Fixture {:?} ends with a newline", input);
+        for mode in [InputMode::Streaming, InputMode::Mmap] {
+            let options = ProcessingOptions::builder().input_mode(mode).build().unwrap();
+            let (accounts, report) = run_fixture_with_report(&input, &options);
+            assert_accounts_match(&dir.join(format!("{}.expected.csv", name)), &accounts, Decimal::ZERO);
+            assert_eq!(report.transactions_processed, transactions, "{} in {:?} mode", name, mode);
+            assert_eq!(report.parse_errors, parse_errors, "{} in {:?} mode", name, mode);
+        }
+    }
+}
+
+/// Grouping each batch by client must not change the balances of a file
+/// whose clients are interleaved, whatever the batch size
+#[test]
+fn test_interleaved_fixture_grouped_by_client() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    for batch_size in [1, 4, 7, 1000] {
+        for check in [DisputeClientCheck::Strict, DisputeClientCheck::TrustOriginal] {
+            let options = ProcessingOptions::builder()
+                .batch_size(batch_size)
+                .group_by_client_in_batch(true)
+                .dispute_client_check(check)
+                .build()
+                .unwrap();
+            let (accounts, report) = run_fixture_with_report(&dir.join("interleaved.csv"), &options);
+            assert_accounts_match(&dir.join("interleaved.expected.csv"), &accounts, Decimal::ZERO);
+            assert_eq!(report.transactions_rejected, 5, "batch size {} with {:?}", batch_size, check);
+        }
+    }
+}
+
+/// Every fixture must end the same applied one transaction per batch as in
+/// the default batches of 1000, including what was rejected or malformed
+#[test]
+fn test_single_transaction_batches_match_default_batches() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    for name in ["basic", "chargebacks", "closures", "disputes", "interleaved", "locked_account_followups", "malformed_lines"] {
+        let input = dir.join(format!("{}.csv", name));
+        let runs: Vec<_> = [1, 1000]
+            .into_iter()
+            .map(|batch_size| {
+                let options = ProcessingOptions::builder().batch_size(batch_size).build().unwrap();
+                let (accounts, report) = run_fixture_with_report(&input, &options);
+                assert_accounts_match(&dir.join(format!("{}.expected.csv", name)), &accounts, Decimal::ZERO);
+                (report.transactions_processed, report.parse_errors, report.transactions_rejected)
+            })
+            .collect();
+        assert_eq!(runs[0], runs[1], "{} with batch sizes 1 and 1000", name);
+    }
+}
+
+/// Disputes of withdrawals are counted apart from the other rejections, and
+/// not at all once withdrawal disputes are allowed
+#[test]
+fn test_withdrawal_disputes_counted_apart() {
+    let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/withdrawal_disputes.csv");
+    let (accounts, report) = run_fixture_with_report(&input, &ProcessingOptions::default());
+    assert_accounts_match(&input.with_file_name("withdrawal_disputes.expected.csv"), &accounts, Decimal::ZERO);
+    assert_eq!(report.withdrawal_disputes_rejected, 3);
+    // The resolve and chargeback of undisputed withdrawals and the dispute
+    // of an unknown tx are rejected for other reasons
+    assert_eq!(report.transactions_rejected, 6);
+
+    let options = ProcessingOptions::builder().allow_withdrawal_disputes(true).build().unwrap();
+    let (_, report) = run_fixture_with_report(&input, &options);
+    assert_eq!(report.withdrawal_disputes_rejected, 0);
+    assert_eq!(report.transactions_rejected, 1);
+}